@@ -1,11 +1,25 @@
 fn main() {
     tonic_build::configure()
-        .bytes(&["."])
+        .bytes(["."])
+        // Messages defined for RPCs that are not served yet
+        .type_attribute("cuddlyproto.DeleteDirectoryRequest", "#[allow(dead_code)]")
+        .type_attribute("cuddlyproto.DeleteDirectoryResponse", "#[allow(dead_code)]")
+        .type_attribute("cuddlyproto.FileMetadata", "#[allow(dead_code)]")
+        // Protocol opcodes named after what they act on
+        .enum_attribute(
+            "cuddlyproto.DatanodeCommandProto.CommandType",
+            "#[allow(clippy::enum_variant_names)]",
+        )
+        .enum_attribute(
+            "cuddlyproto.Operation.OpCode",
+            "#[allow(clippy::enum_variant_names)]",
+        )
         .compile_protos(
             &[
                 "proto/common.proto",
                 "proto/auth.proto",
                 "proto/file.proto",
+                "proto/admin.proto",
                 "proto/node.proto",
                 "proto/directory.proto",
                 "proto/datanode.proto",
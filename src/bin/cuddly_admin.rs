@@ -0,0 +1,387 @@
+use std::{env, process::exit};
+
+use clap::{arg, command, value_parser, Command};
+use cuddlyfs::{
+    errors::{CuddlyError, CuddlyResult},
+    fs_client::{
+        BalancerStatus, CuddlyAdminClient, DatanodeAdminAction, DatanodeReport, FsckAction,
+        QuotaType, SafeModeAction, SafeModeStatus,
+    },
+};
+
+#[tokio::main]
+async fn main() -> CuddlyResult<()> {
+    env::set_var("RUST_LOG", "debug");
+    env_logger::init();
+
+    let matches = command!()
+        .about("Administers a cuddlyFS cluster.")
+        .arg(
+            arg!(--namenode <ADDRESS> "Address of the namenode.")
+                .default_value("http://localhost:50051"),
+        )
+        .subcommand(
+            Command::new("report")
+                .about("Reports the state and capacity of every datanode and of the cluster."),
+        )
+        .subcommand(
+            Command::new("decommission")
+                .about("Re-replicates all blocks of a datanode so it can be shut down.")
+                .arg(arg!(<datanode> "UUID or address of the datanode.")),
+        )
+        .subcommand(
+            Command::new("maintenance")
+                .about("Puts a datanode into maintenance, without re-replicating its blocks.")
+                .arg(arg!(<datanode> "UUID or address of the datanode."))
+                .arg(
+                    arg!(--duration <SECONDS> "Length of the maintenance window.")
+                        .value_parser(value_parser!(u64)),
+                ),
+        )
+        .subcommand(
+            Command::new("recommission")
+                .about("Returns a decommissioned or maintained datanode to normal operation.")
+                .arg(arg!(<datanode> "UUID or address of the datanode.")),
+        )
+        .subcommand(
+            Command::new("safemode")
+                .about("Queries, enters or leaves the namenode's safe mode.")
+                .arg(
+                    arg!(<action> "What to do with safe mode.")
+                        .value_parser(["get", "enter", "leave"]),
+                ),
+        )
+        .subcommand(Command::new("balancer").about("Reports the progress of the balancer."))
+        .subcommand(
+            Command::new("checkpoint")
+                .about("Saves the namespace image now and empties the edit log."),
+        )
+        .subcommand(
+            Command::new("dump-metadata")
+                .about("Prints the block map and the datanode map of the namenode."),
+        )
+        .subcommand(
+            Command::new("refresh-nodes")
+                .about("Re-reads the include and exclude files of the namenode."),
+        )
+        .subcommand(
+            Command::new("log-level")
+                .about("Changes the log level of the running namenode.")
+                .arg(
+                    arg!(<level> "The new log level.")
+                        .value_parser(["off", "error", "warn", "info", "debug", "trace"]),
+                ),
+        )
+        .subcommand(
+            Command::new("fsck")
+                .about("Checks the health of the blocks of every file below a path.")
+                .arg(arg!(<path> "The file or directory to check."))
+                .arg(arg!(--locations "List every file with the locations of its blocks."))
+                .arg(
+                    arg!(--"move" "Move files with missing or corrupt blocks to /lost+found.")
+                        .conflicts_with("delete"),
+                )
+                .arg(arg!(--delete "Delete files with missing or corrupt blocks.")),
+        )
+        .subcommand(
+            Command::new("set-quota")
+                .about("Limits the number of files and directories below a directory.")
+                .arg(arg!(<path> "The directory."))
+                .arg(
+                    arg!(<count> "Maximum number of files and directories, including the directory itself.")
+                        .value_parser(value_parser!(u64).range(1..)),
+                ),
+        )
+        .subcommand(
+            Command::new("clear-quota")
+                .about("Removes the namespace quota of a directory.")
+                .arg(arg!(<path> "The directory.")),
+        )
+        .subcommand(
+            Command::new("set-space-quota")
+                .about("Limits the bytes stored below a directory, counting every replica.")
+                .arg(arg!(<path> "The directory."))
+                .arg(arg!(<bytes> "Maximum number of bytes.").value_parser(value_parser!(u64).range(1..))),
+        )
+        .subcommand(
+            Command::new("clear-space-quota")
+                .about("Removes the space quota of a directory.")
+                .arg(arg!(<path> "The directory.")),
+        )
+        .get_matches();
+
+    let namenode = matches
+        .get_one::<String>("namenode")
+        .ok_or_else(|| CuddlyError::ArgMissingError("Namenode address required".to_owned()))?;
+    let admin = CuddlyAdminClient::new(namenode.clone()).await?;
+
+    match matches.subcommand() {
+        Some(("report", _)) => {
+            let report = admin.cluster_report().await?;
+            println!("Cluster:");
+            println!("\tDatanodes: {}", report.datanodes.len());
+            println!(
+                "\tUsed storage: {} of {} ({:.2}%)",
+                report.used_capacity,
+                report.total_capacity,
+                percentage(report.used_capacity, report.total_capacity)
+            );
+            println!(
+                "\tBlocks: {} ({} under-replicated)",
+                report.total_blocks, report.under_replicated_blocks
+            );
+            for storage_type in &report.storage_types {
+                println!(
+                    "\t{}: {} of {} ({:.2}%)",
+                    storage_type.storage_type,
+                    storage_type.used,
+                    storage_type.capacity,
+                    percentage(storage_type.used, storage_type.capacity)
+                );
+            }
+            println!();
+
+            println!("Datanodes:");
+            for datanode in &report.datanodes {
+                print_datanode(datanode);
+                println!();
+            }
+            print_safe_mode(&report.safe_mode);
+            print_balancer(&report.balancer);
+        }
+        Some((command @ ("decommission" | "maintenance" | "recommission"), sub_matches)) => {
+            let datanode = sub_matches
+                .get_one::<String>("datanode")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Datanode required".to_owned()))?;
+            let action = match command {
+                "decommission" => DatanodeAdminAction::Decommission,
+                "maintenance" => DatanodeAdminAction::Maintenance,
+                _ => DatanodeAdminAction::Recommission,
+            };
+            let duration = sub_matches
+                .try_get_one::<u64>("duration")
+                .ok()
+                .flatten()
+                .copied()
+                .unwrap_or(0);
+            let datanode = admin
+                .set_datanode_admin_state(datanode, action, duration)
+                .await?;
+            println!(
+                "Datanode {} ({}) is now {}",
+                datanode.uuid, datanode.address, datanode.state
+            );
+        }
+        Some(("safemode", sub_matches)) => {
+            let action = match sub_matches.get_one::<String>("action").map(String::as_str) {
+                Some("enter") => SafeModeAction::Enter,
+                Some("leave") => SafeModeAction::Leave,
+                _ => SafeModeAction::Get,
+            };
+            print_safe_mode(&admin.safe_mode(action).await?);
+        }
+        Some(("balancer", _)) => {
+            print_balancer(&admin.balancer_status().await?);
+        }
+        Some(("checkpoint", _)) => {
+            let summary = admin.checkpoint().await?;
+            println!(
+                "Saved namespace image with {} files and {} directories",
+                summary.files, summary.directories
+            );
+        }
+        Some(("dump-metadata", _)) => {
+            let (blocks, datanodes) = admin.dump_metadata().await?;
+            println!("Blocks:");
+            for block in blocks {
+                println!(
+                    "\t{} ({} bytes): [{}]",
+                    block.block_id,
+                    block.len,
+                    block.datanodes.join(", ")
+                );
+            }
+            println!("Datanodes:");
+            for datanode in datanodes {
+                println!(
+                    "\t{} ({}, {}): {} blocks",
+                    datanode.datanode.uuid,
+                    datanode.datanode.address,
+                    datanode.datanode.state,
+                    datanode.blocks.len()
+                );
+                for block_id in datanode.blocks {
+                    println!("\t\t{}", block_id);
+                }
+            }
+        }
+        Some(("refresh-nodes", _)) => {
+            let result = admin.refresh_nodes().await?;
+            println!(
+                "Included hosts: {}, excluded hosts: {}",
+                result.included_hosts, result.excluded_hosts
+            );
+            for datanode in result.decommissioning {
+                println!("\tDecommissioning {}", datanode);
+            }
+            for datanode in result.recommissioned {
+                println!("\tRecommissioned {}", datanode);
+            }
+        }
+        Some(("log-level", sub_matches)) => {
+            let level = sub_matches
+                .get_one::<String>("level")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Level required".to_owned()))?;
+            let previous = admin.set_log_level(level).await?;
+            println!("Log level changed from {} to {}", previous, level);
+        }
+        Some(("fsck", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("path")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+            let action = if sub_matches.get_flag("move") {
+                FsckAction::Move
+            } else if sub_matches.get_flag("delete") {
+                FsckAction::Delete
+            } else {
+                FsckAction::None
+            };
+            let report = admin
+                .fsck(path, sub_matches.get_flag("locations"), action)
+                .await?;
+            for file in &report.files {
+                println!("{}:", file.path);
+                for block in &file.blocks {
+                    println!(
+                        "	{} ({} bytes): {}, {} replicas [{}]",
+                        block.block_id,
+                        block.len,
+                        block.health,
+                        block.replicas,
+                        block.locations.join(", ")
+                    );
+                }
+            }
+            for path in &report.moved {
+                println!("Moved {} to /lost+found", path);
+            }
+            for path in &report.deleted {
+                println!("Deleted {}", path);
+            }
+            println!();
+            println!("Total files: {}", report.total_files);
+            println!("Total blocks: {}", report.total_blocks);
+            println!("	Healthy: {}", report.healthy_blocks);
+            println!("	Under-replicated: {}", report.under_replicated_blocks);
+            println!("	Missing: {}", report.missing_blocks);
+            println!("	Corrupt: {}", report.corrupt_blocks);
+            if report.is_healthy() {
+                println!("The filesystem under path '{}' is HEALTHY", path);
+            } else {
+                println!("The filesystem under path '{}' is CORRUPT", path);
+                exit(1);
+            }
+        }
+        Some((
+            command @ ("set-quota" | "clear-quota" | "set-space-quota" | "clear-space-quota"),
+            sub_matches,
+        )) => {
+            let path = sub_matches
+                .get_one::<String>("path")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+            let (quota_type, limit) = match command {
+                "set-quota" => (
+                    QuotaType::Namespace,
+                    sub_matches.get_one::<u64>("count").copied(),
+                ),
+                "clear-quota" => (QuotaType::Namespace, None),
+                "set-space-quota" => (
+                    QuotaType::Space,
+                    sub_matches.get_one::<u64>("bytes").copied(),
+                ),
+                _ => (QuotaType::Space, None),
+            };
+            admin.set_quota(path, quota_type, limit).await?;
+            match limit {
+                Some(limit) => println!("Set quota of {} to {}", path, limit),
+                None => println!("Cleared quota of {}", path),
+            }
+        }
+        Some((subcommand, _)) => {
+            eprintln!("Unrecognized command: '{}'", subcommand);
+            exit(1);
+        }
+        None => {
+            eprintln!("No subcommand was used.");
+            exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+fn percentage(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    100.0 * part as f64 / total as f64
+}
+
+fn print_datanode(datanode: &DatanodeReport) {
+    println!("\tUUID: {}", datanode.uuid);
+    println!("\tAddress: {}", datanode.address);
+    match datanode.state.as_str() {
+        "DECOMMISSIONED" => println!("\tState: DECOMMISSIONED (safe to shut down)"),
+        state => println!("\tState: {}", state),
+    }
+    println!(
+        "\tAvailable storage: {}",
+        datanode
+            .total_capacity
+            .saturating_sub(datanode.used_capacity)
+    );
+    println!(
+        "\tUsed storage: {} ({:.2}%)",
+        datanode.used_capacity,
+        percentage(datanode.used_capacity, datanode.total_capacity)
+    );
+}
+
+fn print_safe_mode(status: &SafeModeStatus) {
+    match (status.on, status.manual) {
+        (false, _) => println!("Safe mode is OFF"),
+        (true, true) => println!("Safe mode is ON (entered manually)"),
+        (true, false) => println!("Safe mode is ON"),
+    }
+    println!(
+        "\tReported blocks: {}/{} (threshold {:.1}%)",
+        status.reported_blocks,
+        status.total_blocks,
+        status.threshold * 100.0
+    );
+    println!(
+        "\tLive datanodes: {} (minimum {})",
+        status.live_datanodes, status.min_datanodes
+    );
+}
+
+fn print_balancer(balancer: &BalancerStatus) {
+    println!("Balancer:");
+    if balancer.enabled {
+        println!(
+            "\tAverage utilization: {:.2}% (threshold {:.2}%)",
+            balancer.average_utilization, balancer.threshold
+        );
+        println!(
+            "\tOver/under-utilized datanodes: {}/{}",
+            balancer.over_utilized_datanodes, balancer.under_utilized_datanodes
+        );
+        println!(
+            "\tMoves: {} in progress, {} completed, {} failed",
+            balancer.moves_in_progress, balancer.moves_completed, balancer.moves_failed
+        );
+        println!("\tBytes moved: {}", balancer.bytes_moved);
+    } else {
+        println!("\tDisabled");
+    }
+}
@@ -1,9 +1,9 @@
 use std::{env, process::exit};
 
-use clap::{arg, command, Command};
+use clap::{arg, command, value_parser, ArgGroup, Command};
 use cuddlyfs::{
     errors::{CuddlyError, CuddlyResult},
-    fs_client::CuddlyClient,
+    fs_client::{CuddlyClient, FileType, SnapshotDiffType},
 };
 
 #[tokio::main]
@@ -30,8 +30,134 @@ async fn main() -> CuddlyResult<()> {
             Command::new("put")
                 .about("Uploads a local file from `src` to remote `dst`")
                 .arg(arg!(<src> "Path to local file"))
+                .arg(arg!(<dst> "Path to remote file"))
+                .arg(
+                    arg!(-r --replication <n> "Replicas of each block, the cluster default if unset")
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    arg!(-b --"block-size" <bytes> "Block size, the cluster default if unset")
+                        .value_parser(value_parser!(u64)),
+                ),
+        )
+        .subcommand(
+            Command::new("append")
+                .about("Appends a local file `src` to the existing remote file `dst`")
+                .arg(arg!(<src> "Path to local file"))
                 .arg(arg!(<dst> "Path to remote file")),
         )
+        .subcommand(
+            Command::new("truncate")
+                .about("Shortens a remote file to `length` bytes")
+                .arg(arg!(<path> "Path to remote file"))
+                .arg(arg!(<length> "New length of the file").value_parser(value_parser!(u64))),
+        )
+        .subcommand(
+            Command::new("concat")
+                .about("Moves the contents of remote files `sources` to the end of `target`")
+                .arg(arg!(<target> "Path to remote file"))
+                .arg(arg!(<sources> ... "Paths to remote files, removed afterwards")),
+        )
+        .subcommand(
+            Command::new("count")
+                .about("Counts the directories, files and bytes below a path.")
+                .arg(arg!(<path> "The directory or file."))
+                .arg(arg!(-q --quota "Also shows the quotas and what remains of them.")),
+        )
+        .subcommand(
+            Command::new("rm")
+                .about("Deletes a remote file or directory, moving it to the trash.")
+                .arg(arg!(<path> "The file or directory."))
+                .arg(arg!(-r --recursive "Deletes directories and their contents."))
+                .arg(arg!(--"skip-trash" "Deletes right away instead of moving to the trash."))
+                .arg(arg!(--"no-follow" "Deletes a symbolic link instead of its target.")),
+        )
+        .subcommand(
+            Command::new("ln")
+                .about("Creates a symbolic link `link` pointing at `target`.")
+                .arg(arg!(<target> "The path the link points at, absolute or relative to the link."))
+                .arg(arg!(<link> "Path of the link."))
+                .arg(arg!(-f --force "Repoints an existing link.")),
+        )
+        .subcommand(
+            Command::new("setrep")
+                .about("Changes how many replicas the blocks of a remote file have.")
+                .arg(arg!(<replication> "Number of replicas").value_parser(value_parser!(u64)))
+                .arg(arg!(<path> "The file.")),
+        )
+        .subcommand(
+            Command::new("storagepolicy")
+                .about("Shows, sets or, with `-u`, unsets the storage policy of a remote path.")
+                .arg(arg!(<path> "The file or directory."))
+                .arg(arg!(-s --set <policy> "HOT, WARM, COLD, ONE_SSD or ALL_SSD."))
+                .arg(arg!(-u --unset "Inherits the policy of the parent instead."))
+                .group(ArgGroup::new("change").args(["set", "unset"])),
+        )
+        .subcommand(
+            Command::new("ec")
+                .about("Shows, sets or, with `-u`, unsets the erasure coding policy of a remote path.")
+                .arg(arg!(<path> "The file or directory."))
+                .arg(arg!(-s --set <policy> "RS-6-3 or RS-3-2, for a directory."))
+                .arg(arg!(-u --unset "Inherits the policy of the parent instead."))
+                .group(ArgGroup::new("change").args(["set", "unset"])),
+        )
+        .subcommand(
+            Command::new("stat")
+                .about("Shows the type and length of a remote path.")
+                .arg(arg!(<path> "The file, directory or link."))
+                .arg(arg!(--"no-follow" "Describes a symbolic link instead of its target.")),
+        )
+        .subcommand(
+            Command::new("setfattr")
+                .about("Sets or, with `-x`, removes an extended attribute of a remote path.")
+                .arg(arg!(<path> "The file or directory."))
+                .arg(arg!(-n --name <name> "Attribute name, starting with `user.` or `trusted.`."))
+                .arg(arg!(-v --value <value> "Attribute value."))
+                .arg(arg!(-x --remove <name> "Removes the attribute instead."))
+                .group(ArgGroup::new("attribute").args(["name", "remove"]).required(true)),
+        )
+        .subcommand(
+            Command::new("getfattr")
+                .about("Lists the extended attributes of a remote path.")
+                .arg(arg!(<path> "The file or directory."))
+                .arg(arg!(-n --name <name> "Shows only this attribute with its value."))
+                .arg(arg!(-d --dump "Shows the values of all attributes.")),
+        )
+        .subcommand(
+            Command::new("expunge")
+                .about("Checkpoints the trash and deletes expired checkpoints.")
+                .arg(arg!(--immediate "Deletes every checkpoint, emptying the trash.")),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Moves a path out of the trash, to where it was deleted from by default.")
+                .arg(arg!(<path> "The path in the trash."))
+                .arg(arg!([dst] "Where to restore it to.")),
+        )
+        .subcommand(
+            Command::new("create-snapshot")
+                .about("Takes a read-only snapshot of a directory, readable below `<path>/.snapshot/<name>`.")
+                .arg(arg!(<path> "The directory."))
+                .arg(arg!(<name> "Name of the snapshot.")),
+        )
+        .subcommand(
+            Command::new("delete-snapshot")
+                .about("Deletes a snapshot of a directory.")
+                .arg(arg!(<path> "The directory."))
+                .arg(arg!(<name> "Name of the snapshot.")),
+        )
+        .subcommand(
+            Command::new("list-snapshots")
+                .about("Lists the snapshots of a directory.")
+                .arg(arg!(<path> "The directory.")),
+        )
+        .subcommand(
+            Command::new("snapshot-diff")
+                .about("Lists what changed in a directory between two snapshots.")
+                .arg(arg!(<path> "The directory."))
+                .arg(arg!(<from> "Name of the older snapshot."))
+                .arg(arg!([to] "Name of the newer snapshot. Defaults to the current directory.")),
+        )
         .subcommand(
             Command::new("get")
                 .about("Downloads a remote file `src` to local destination `dst`")
@@ -49,6 +175,10 @@ async fn main() -> CuddlyResult<()> {
                     / (datanode.total_capacity - datanode.used_capacity) as f64
                     + datanode.used_capacity as f64;
                 println!("\tAddress: {}", datanode.socket_address);
+                match datanode.state().as_str_name() {
+                    "DECOMMISSIONED" => println!("\tState: DECOMMISSIONED (safe to shut down)"),
+                    state => println!("\tState: {}", state),
+                }
                 println!(
                     "\tAvailable storage (kB): {}",
                     (datanode.total_capacity - datanode.used_capacity)
@@ -83,9 +213,218 @@ async fn main() -> CuddlyResult<()> {
             let dst = sub_matches
                 .get_one::<String>("dst")
                 .ok_or_else(|| CuddlyError::ArgMissingError("Destination required".to_owned()))?;
-            dfs.put(src, dst).await?;
+            let replication = sub_matches.get_one::<u64>("replication").copied();
+            let block_size = sub_matches.get_one::<u64>("block-size").copied();
+            dfs.put(src, dst, replication, block_size).await?;
             println!("Successfully uploaded file from {}", src);
         }
+        Some(("append", sub_matches)) => {
+            let src = sub_matches
+                .get_one::<String>("src")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Source required".to_owned()))?;
+            let dst = sub_matches
+                .get_one::<String>("dst")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Destination required".to_owned()))?;
+            dfs.append(src, dst).await?;
+            println!("Successfully appended file from {}", src);
+        }
+        Some(("truncate", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("path")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+            let length = sub_matches
+                .get_one::<u64>("length")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Length required".to_owned()))?;
+            dfs.truncate(path, *length).await?;
+        }
+        Some(("concat", sub_matches)) => {
+            let target = sub_matches
+                .get_one::<String>("target")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Target required".to_owned()))?;
+            let sources = sub_matches
+                .get_many::<String>("sources")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Sources required".to_owned()))?
+                .cloned()
+                .collect();
+            dfs.concat(target, sources).await?;
+        }
+        Some(("count", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("path")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+            let summary = dfs.count(path).await?;
+            if sub_matches.get_flag("quota") {
+                let used = summary.directories + summary.files;
+                print!(
+                    "{:>12} {:>12} {:>15} {:>15} ",
+                    quota_or_none(summary.namespace_quota),
+                    remaining_or_inf(summary.namespace_quota, used),
+                    quota_or_none(summary.space_quota),
+                    remaining_or_inf(summary.space_quota, summary.space_consumed),
+                );
+            }
+            println!(
+                "{:>12} {:>12} {:>15} {}",
+                summary.directories, summary.files, summary.length, path
+            );
+        }
+        Some(("rm", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("path")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+            let recursive = sub_matches.get_flag("recursive");
+            let skip_trash = sub_matches.get_flag("skip-trash");
+            let no_follow = sub_matches.get_flag("no-follow");
+            match dfs.delete(path, recursive, skip_trash, no_follow).await? {
+                Some(trash_path) => println!("Moved '{}' to trash at '{}'", path, trash_path),
+                None => println!("Deleted '{}'", path),
+            }
+        }
+        Some(("ln", sub_matches)) => {
+            let target = sub_matches
+                .get_one::<String>("target")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Target required".to_owned()))?;
+            let link = sub_matches
+                .get_one::<String>("link")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Link required".to_owned()))?;
+            dfs.create_symlink(link, target, sub_matches.get_flag("force"))
+                .await?;
+        }
+        Some(("setrep", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("path")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+            let replication = sub_matches
+                .get_one::<u64>("replication")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Replication required".to_owned()))?;
+            dfs.set_replication(path, *replication).await?;
+            println!("Replication of {} set to {}", path, replication);
+        }
+        Some(("storagepolicy", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("path")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+            if let Some(policy) = sub_matches.get_one::<String>("set") {
+                dfs.set_storage_policy(path, Some(policy)).await?;
+                println!("Storage policy of {} set to {}", path, policy);
+            } else if sub_matches.get_flag("unset") {
+                dfs.set_storage_policy(path, None).await?;
+                println!("Storage policy of {} unset", path);
+            } else {
+                println!("{}", dfs.get_storage_policy(path).await?);
+            }
+        }
+        Some(("ec", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("path")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+            if let Some(policy) = sub_matches.get_one::<String>("set") {
+                dfs.set_erasure_coding_policy(path, Some(policy)).await?;
+                println!("Erasure coding policy of {} set to {}", path, policy);
+            } else if sub_matches.get_flag("unset") {
+                dfs.set_erasure_coding_policy(path, None).await?;
+                println!("Erasure coding policy of {} unset", path);
+            } else {
+                match dfs.get_erasure_coding_policy(path).await? {
+                    Some(policy) => println!("{}", policy),
+                    None => println!("Replicated"),
+                }
+            }
+        }
+        Some(("stat", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("path")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+            let status = dfs.stat(path, sub_matches.get_flag("no-follow")).await?;
+            match (status.file_type, status.symlink_target) {
+                (FileType::File, _) => println!(
+                    "file {:>15} replication {} block size {} {}",
+                    status.length, status.replication, status.block_size, path
+                ),
+                (FileType::Directory, _) => println!("directory {}", path),
+                (FileType::Symlink, target) => {
+                    println!("link {} -> {}", path, target.unwrap_or_default())
+                }
+            }
+        }
+        Some(("setfattr", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("path")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+            if let Some(name) = sub_matches.get_one::<String>("remove") {
+                dfs.remove_xattr(path, name).await?;
+            } else {
+                let name = sub_matches
+                    .get_one::<String>("name")
+                    .ok_or_else(|| CuddlyError::ArgMissingError("Name required".to_owned()))?;
+                let value = sub_matches
+                    .get_one::<String>("value")
+                    .map_or_else(Vec::new, |value| value.as_bytes().to_vec());
+                dfs.set_xattr(path, name, value).await?;
+            }
+        }
+        Some(("getfattr", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("path")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+            println!("# file: {}", path);
+            let names = sub_matches.get_one::<String>("name").cloned();
+            if names.is_none() && !sub_matches.get_flag("dump") {
+                for name in dfs.list_xattrs(path).await? {
+                    println!("{}", name);
+                }
+            } else {
+                for (name, value) in dfs.get_xattrs(path, names.into_iter().collect()).await? {
+                    println!("{}=\"{}\"", name, String::from_utf8_lossy(&value));
+                }
+            }
+        }
+        Some(("expunge", sub_matches)) => {
+            for checkpoint in dfs.expunge(sub_matches.get_flag("immediate")).await? {
+                println!("Deleted trash checkpoint '{}'", checkpoint);
+            }
+        }
+        Some(("restore", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("path")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+            let dst = sub_matches.get_one::<String>("dst").cloned();
+            let restored = dfs.restore(path, dst).await?;
+            println!("Restored '{}' to '{}'", path, restored);
+        }
+        Some(("create-snapshot", sub_matches)) => {
+            let (path, name) = snapshot_args(sub_matches)?;
+            dfs.create_snapshot(path, name).await?;
+        }
+        Some(("delete-snapshot", sub_matches)) => {
+            let (path, name) = snapshot_args(sub_matches)?;
+            dfs.delete_snapshot(path, name).await?;
+        }
+        Some(("list-snapshots", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("path")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+            for name in dfs.list_snapshots(path).await? {
+                println!("{}", name);
+            }
+        }
+        Some(("snapshot-diff", sub_matches)) => {
+            let path = sub_matches
+                .get_one::<String>("path")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+            let from = sub_matches
+                .get_one::<String>("from")
+                .ok_or_else(|| CuddlyError::ArgMissingError("Snapshot required".to_owned()))?;
+            let to = sub_matches.get_one::<String>("to").cloned();
+            for entry in dfs.snapshot_diff(path, from, to).await? {
+                let sign = match entry.diff_type {
+                    SnapshotDiffType::Created => '+',
+                    SnapshotDiffType::Deleted => '-',
+                    SnapshotDiffType::Modified => 'M',
+                };
+                println!("{}\t{}", sign, entry.path);
+            }
+        }
         Some(("get", sub_matches)) => {
             let src = sub_matches
                 .get_one::<String>("src")
@@ -107,3 +446,24 @@ async fn main() -> CuddlyResult<()> {
 
     Ok(())
 }
+
+fn snapshot_args(sub_matches: &clap::ArgMatches) -> CuddlyResult<(&String, &String)> {
+    let path = sub_matches
+        .get_one::<String>("path")
+        .ok_or_else(|| CuddlyError::ArgMissingError("Path required".to_owned()))?;
+    let name = sub_matches
+        .get_one::<String>("name")
+        .ok_or_else(|| CuddlyError::ArgMissingError("Snapshot name required".to_owned()))?;
+    Ok((path, name))
+}
+
+fn quota_or_none(quota: Option<u64>) -> String {
+    quota.map_or_else(|| "none".to_owned(), |quota| quota.to_string())
+}
+
+fn remaining_or_inf(quota: Option<u64>, used: u64) -> String {
+    quota.map_or_else(
+        || "inf".to_owned(),
+        |quota| (quota as i128 - used as i128).to_string(),
+    )
+}
@@ -3,18 +3,31 @@ use std::hash::{Hash, Hasher};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::cuddlyproto;
+use crate::{
+    cuddlyproto,
+    errors::{CuddlyError, CuddlyResult},
+    namenode::erasure_coding_policy::ErasureCodingPolicy,
+};
 
 #[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct Block {
     pub id: Uuid,
     pub len: u64,
     pub seq: u64,
+    /// Issued by the namenode when the block is allocated, and bumped on
+    /// each append or truncate. A replica with an older generation is stale.
+    #[serde(default)]
+    pub generation: u64,
 }
 
 impl Block {
     pub fn new(id: Uuid, len: u64, seq: u64) -> Self {
-        Self { id, len, seq }
+        Self {
+            id,
+            len,
+            seq,
+            generation: 0,
+        }
     }
 
     pub fn filename(&self) -> String {
@@ -24,7 +37,85 @@ impl Block {
 
 impl std::fmt::Display for Block {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Block {{ id: {}, len: {} }}", self.id, self.len)
+        write!(
+            f,
+            "Block {{ id: {}, len: {}, generation: {} }}",
+            self.id, self.len, self.generation
+        )
+    }
+}
+
+/// The blocks of an erasure-coded file that are encoded together: the file
+/// data is laid out cell by cell across the data blocks, and the parity
+/// blocks are computed from the cells at the same offset.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlockGroup {
+    pub policy: ErasureCodingPolicy,
+    pub cell_size: u64,
+    /// Data blocks then parity blocks.
+    pub blocks: Vec<Block>,
+}
+
+impl BlockGroup {
+    pub fn data_blocks(&self) -> &[Block] {
+        &self.blocks[..self.policy.data_units()]
+    }
+
+    /// Bytes of file data in the group.
+    pub fn data_len(&self) -> u64 {
+        self.data_blocks().iter().map(|block| block.len).sum()
+    }
+
+    /// The group with the locations of each of its blocks.
+    pub fn to_proto(&self, locations: Vec<Vec<String>>) -> cuddlyproto::BlockGroupProto {
+        cuddlyproto::BlockGroupProto {
+            policy: self.policy.to_string(),
+            cell_size: self.cell_size,
+            blocks: self
+                .blocks
+                .iter()
+                .zip(locations)
+                .map(|(block, locations)| cuddlyproto::BlockWithLocations {
+                    block: Some((*block).into()),
+                    locations,
+                })
+                .collect(),
+        }
+    }
+
+    /// Reads a group sent with the locations of its blocks.
+    pub fn from_proto(
+        group: cuddlyproto::BlockGroupProto,
+    ) -> CuddlyResult<(Self, Vec<Vec<String>>)> {
+        let policy = group.policy.parse::<ErasureCodingPolicy>()?;
+        if group.blocks.len() != policy.group_width() || group.cell_size == 0 {
+            return Err(CuddlyError::ProtoError(format!(
+                "Invalid {} block group with {} blocks and cells of {} bytes",
+                policy,
+                group.blocks.len(),
+                group.cell_size
+            )));
+        }
+        let (blocks, locations) = group
+            .blocks
+            .into_iter()
+            .map(|block| {
+                block
+                    .block
+                    .map(|inner| (Block::from(inner), block.locations))
+                    .ok_or_else(|| CuddlyError::ProtoError("Block group without block".to_owned()))
+            })
+            .collect::<CuddlyResult<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        Ok((
+            Self {
+                policy,
+                cell_size: group.cell_size,
+                blocks,
+            },
+            locations,
+        ))
     }
 }
 
@@ -44,16 +135,36 @@ impl Hash for Block {
 
 impl From<cuddlyproto::Block> for Block {
     fn from(value: cuddlyproto::Block) -> Self {
-        let cuddlyproto::Block { id, len, seq } = value;
+        let cuddlyproto::Block {
+            id,
+            len,
+            seq,
+            generation,
+        } = value;
         let id = Uuid::parse_str(&id).unwrap();
-        Self { id, len, seq }
+        Self {
+            id,
+            len,
+            seq,
+            generation,
+        }
     }
 }
 
 impl From<Block> for cuddlyproto::Block {
     fn from(value: Block) -> Self {
-        let Block { id, len, seq } = value;
+        let Block {
+            id,
+            len,
+            seq,
+            generation,
+        } = value;
         let id = id.to_string();
-        Self { id, len, seq }
+        Self {
+            id,
+            len,
+            seq,
+            generation,
+        }
     }
 }
@@ -3,6 +3,11 @@ use lazy_static::lazy_static;
 use serde::Deserialize;
 use std::{env, path::PathBuf};
 
+use crate::datanode::volume_chooser::VolumeChooserKind;
+use crate::namenode::block_placement_policy::BlockPlacementPolicyKind;
+use crate::namenode::storage_policy::StorageType;
+use crate::utils::fault_injection::FaultRule;
+
 lazy_static! {
     pub static ref APP_CONFIG: AppConfig = AppConfig::new().unwrap();
 }
@@ -13,6 +18,76 @@ pub struct DatanodeConfig {
     pub namenode_rpc_address: String,
     pub data_dir: PathBuf,
     pub disk_check_interval: u64,
+    #[serde(default = "default_network_location")]
+    pub network_location: String,
+    /// Seconds between two full block reports.
+    #[serde(default = "default_block_report_interval")]
+    pub block_report_interval: u64,
+    /// Storages blocks are kept on. Disk storages in `data_dirs` if empty.
+    #[serde(default)]
+    pub storages: Vec<StorageConfig>,
+    /// Directories of disk volumes, used when no storage is configured.
+    /// `data_dir` alone if empty.
+    #[serde(default)]
+    pub data_dirs: Vec<PathBuf>,
+    /// How new replicas are spread over the volumes of a storage type.
+    #[serde(default)]
+    pub volume_chooser: VolumeChooserKind,
+    /// Number of volumes that may fail before the datanode shuts down.
+    #[serde(default)]
+    pub failed_volumes_tolerated: usize,
+    /// Seconds between two checks that the volumes can be written to.
+    #[serde(default = "default_volume_check_interval")]
+    pub volume_check_interval: u64,
+    /// Seconds between two scans of the data of every replica against its
+    /// checksum.
+    #[serde(default = "default_block_scan_interval")]
+    pub block_scan_interval: u64,
+    /// Bytes of each volume left to other uses of its filesystem, unless
+    /// the storage sets its own.
+    #[serde(default)]
+    pub reserved_space: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[allow(unused)]
+pub struct StorageConfig {
+    #[serde(default)]
+    pub storage_type: StorageType,
+    pub dir: PathBuf,
+    /// Bytes of the volume left to other uses of its filesystem.
+    #[serde(default)]
+    pub reserved_space: Option<u64>,
+}
+
+impl DatanodeConfig {
+    /// The configured storages, or disk storages in `data_dirs` or
+    /// `data_dir`, each with its reserved space.
+    pub fn storages(&self) -> Vec<StorageConfig> {
+        let storages = if self.storages.is_empty() {
+            let data_dirs = match self.data_dirs.is_empty() {
+                true => std::slice::from_ref(&self.data_dir),
+                false => &self.data_dirs[..],
+            };
+            data_dirs
+                .iter()
+                .map(|dir| StorageConfig {
+                    storage_type: StorageType::Disk,
+                    dir: dir.clone(),
+                    reserved_space: None,
+                })
+                .collect()
+        } else {
+            self.storages.clone()
+        };
+        storages
+            .into_iter()
+            .map(|storage| StorageConfig {
+                reserved_space: storage.reserved_space.or(Some(self.reserved_space)),
+                ..storage
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -20,6 +95,169 @@ pub struct DatanodeConfig {
 pub struct NamenodeConfig {
     pub bind_address: String,
     pub name_dir: PathBuf,
+    #[serde(default)]
+    pub block_placement_policy: BlockPlacementPolicyKind,
+    #[serde(default)]
+    pub topology_mapping_file: Option<PathBuf>,
+    /// Hosts allowed to register as datanodes, one per line. Every host is
+    /// allowed if unset or empty.
+    #[serde(default)]
+    pub hosts_include_file: Option<PathBuf>,
+    /// Hosts that are decommissioned, one per line.
+    #[serde(default)]
+    pub hosts_exclude_file: Option<PathBuf>,
+    /// Seconds between two datanode liveness checks.
+    #[serde(default = "default_heartbeat_recheck_interval")]
+    pub heartbeat_recheck_interval: u64,
+    /// Seconds without heartbeat after which a datanode is stale.
+    #[serde(default = "default_stale_datanode_interval")]
+    pub stale_datanode_interval: u64,
+    /// Seconds without heartbeat after which a datanode is dead.
+    #[serde(default = "default_dead_datanode_interval")]
+    pub dead_datanode_interval: u64,
+    /// Default length in seconds of a maintenance window.
+    #[serde(default = "default_maintenance_duration")]
+    pub maintenance_duration: u64,
+    /// Fraction of known blocks that need a reported replica before the
+    /// namenode leaves safe mode.
+    #[serde(default = "default_safe_mode_threshold")]
+    pub safe_mode_threshold: f64,
+    /// Live datanodes needed before the namenode leaves safe mode.
+    #[serde(default)]
+    pub safe_mode_min_datanodes: u32,
+    #[serde(default)]
+    pub balancer: BalancerConfig,
+    #[serde(default)]
+    pub trash: TrashConfig,
+    /// Bytes an extended attribute may take, name and value together.
+    #[serde(default = "default_xattr_max_size")]
+    pub xattr_max_size: usize,
+    /// Extended attributes a file or directory may have.
+    #[serde(default = "default_max_xattrs_per_inode")]
+    pub max_xattrs_per_inode: usize,
+    /// Replication factors a file may be created with or set to.
+    #[serde(default = "default_min_replication")]
+    pub min_replication: u64,
+    #[serde(default = "default_max_replication")]
+    pub max_replication: u64,
+    /// Block sizes, in bytes, a file may be created with.
+    #[serde(default = "default_min_block_size")]
+    pub min_block_size: u64,
+    #[serde(default = "default_max_block_size")]
+    pub max_block_size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+#[allow(unused)]
+pub struct BalancerConfig {
+    pub enabled: bool,
+    /// Seconds between two balancer iterations.
+    pub interval: u64,
+    /// A datanode is over- or under-utilized if its utilization differs from
+    /// the cluster average by more than this many percentage points.
+    pub threshold: f64,
+    /// Bytes per second that may be moved between datanodes.
+    pub bandwidth: u64,
+}
+
+impl Default for BalancerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 30,
+            threshold: 10.0,
+            bandwidth: 10 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+#[allow(unused)]
+pub struct TrashConfig {
+    /// Seconds a trash checkpoint is kept before it is deleted. Deletes
+    /// bypass the trash if 0.
+    pub interval: u64,
+    /// Seconds between two trash checkpoints.
+    pub checkpoint_interval: u64,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self {
+            interval: 24 * 3600,
+            checkpoint_interval: 3600,
+        }
+    }
+}
+
+fn default_heartbeat_recheck_interval() -> u64 {
+    10
+}
+
+fn default_stale_datanode_interval() -> u64 {
+    30
+}
+
+fn default_dead_datanode_interval() -> u64 {
+    600
+}
+
+fn default_maintenance_duration() -> u64 {
+    3600
+}
+
+fn default_safe_mode_threshold() -> f64 {
+    0.999
+}
+
+fn default_xattr_max_size() -> usize {
+    16384
+}
+
+fn default_max_xattrs_per_inode() -> usize {
+    32
+}
+
+fn default_min_replication() -> u64 {
+    1
+}
+
+fn default_max_replication() -> u64 {
+    512
+}
+
+fn default_min_block_size() -> u64 {
+    64 * 1024
+}
+
+fn default_max_block_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_block_report_interval() -> u64 {
+    3600
+}
+
+fn default_volume_check_interval() -> u64 {
+    60
+}
+
+fn default_block_scan_interval() -> u64 {
+    3 * 7 * 24 * 3600
+}
+
+fn default_network_location() -> String {
+    "/default-rack".into()
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[allow(unused)]
+pub struct FaultInjectionConfig {
+    pub enabled: bool,
+    pub seed: u64,
+    pub rules: Vec<FaultRule>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -29,10 +267,13 @@ pub struct AppConfig {
     pub namenode: NamenodeConfig,
     pub datanode: DatanodeConfig,
     pub packet_size: u64,
+    /// Block size and replication factor of files created without one.
     pub block_size: u64,
     pub replication_factor: u64,
     pub xfer_port: u32,
     pub host_ip: String,
+    #[serde(default)]
+    pub fault_injection: FaultInjectionConfig,
 }
 
 impl AppConfig {
@@ -61,6 +302,7 @@ impl Default for AppConfig {
             replication_factor: 3,
             xfer_port: 50010,
             host_ip: "http://[::1]".into(),
+            fault_injection: FaultInjectionConfig::default(),
         }
     }
 }
@@ -71,6 +313,15 @@ impl Default for DatanodeConfig {
             namenode_rpc_address: "http://[::1]:50051".into(),
             data_dir: std::env::temp_dir().join("cuddlyfs").join("datanode"),
             disk_check_interval: 3000,
+            network_location: default_network_location(),
+            block_report_interval: default_block_report_interval(),
+            storages: vec![],
+            data_dirs: vec![],
+            volume_chooser: VolumeChooserKind::default(),
+            failed_volumes_tolerated: 0,
+            volume_check_interval: default_volume_check_interval(),
+            block_scan_interval: default_block_scan_interval(),
+            reserved_space: 0,
         }
     }
 }
@@ -83,7 +334,25 @@ impl Default for NamenodeConfig {
 
         Self {
             bind_address: "[::1]:50051".into(),
-            name_dir: PathBuf::from(namedir),
+            name_dir: namedir,
+            block_placement_policy: BlockPlacementPolicyKind::default(),
+            topology_mapping_file: None,
+            hosts_include_file: None,
+            hosts_exclude_file: None,
+            heartbeat_recheck_interval: default_heartbeat_recheck_interval(),
+            stale_datanode_interval: default_stale_datanode_interval(),
+            dead_datanode_interval: default_dead_datanode_interval(),
+            maintenance_duration: default_maintenance_duration(),
+            safe_mode_threshold: default_safe_mode_threshold(),
+            safe_mode_min_datanodes: 0,
+            balancer: BalancerConfig::default(),
+            trash: TrashConfig::default(),
+            xattr_max_size: default_xattr_max_size(),
+            max_xattrs_per_inode: default_max_xattrs_per_inode(),
+            min_replication: default_min_replication(),
+            max_replication: default_max_replication(),
+            min_block_size: default_min_block_size(),
+            max_block_size: default_max_block_size(),
         }
     }
 }
@@ -102,7 +371,17 @@ mod tests {
             std::env::temp_dir().join("cuddlyfs").join("datanode")
         );
         assert_eq!(config.datanode.disk_check_interval, 3000);
+        let storages = config.datanode.storages();
+        assert_eq!(storages.len(), 1);
+        assert_eq!(storages[0].storage_type, StorageType::Disk);
+        assert_eq!(storages[0].reserved_space, Some(0));
+        assert_eq!(
+            config.datanode.volume_chooser,
+            VolumeChooserKind::RoundRobin
+        );
+        assert_eq!(config.datanode.failed_volumes_tolerated, 0);
         assert_eq!(config.block_size, 64 * 1024 * 1024);
         assert_eq!(config.replication_factor, 3);
+        assert!(!config.fault_injection.enabled);
     }
 }
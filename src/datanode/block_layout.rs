@@ -0,0 +1,568 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+use uuid::Uuid;
+
+use crate::errors::{CuddlyError, CuddlyResult};
+use crate::utils::crc32::Crc32;
+
+/// Version of the layout written by this datanode, recorded in the `VERSION`
+/// file of each volume. Replicas are sharded into two levels of
+/// subdirectories derived from their id, as
+/// `blocks/subdir<a>/subdir<b>/block_<id>`, next to a `block_<id>.meta` file.
+pub(crate) const LAYOUT_VERSION: u32 = 2;
+
+/// Layout of the volumes without a `VERSION` file, with every replica
+/// directly in `blocks`.
+const FLAT_LAYOUT_VERSION: u32 = 1;
+
+/// Number of subdirectories on each level.
+const SUBDIRS: u8 = 32;
+
+fn subdir(root: &Path, id: &Uuid) -> PathBuf {
+    let bytes = id.as_bytes();
+    root.join(format!("subdir{}", bytes[0] % SUBDIRS))
+        .join(format!("subdir{}", bytes[1] % SUBDIRS))
+}
+
+/// Path of the finalized replica of the block `id` under `root`.
+pub(crate) fn block_path(root: &Path, id: &Uuid) -> PathBuf {
+    subdir(root, id).join(format!("block_{}", id))
+}
+
+/// Path of the replica of the block `id` while it is being written.
+pub(crate) fn temp_path(root: &Path, id: &Uuid) -> PathBuf {
+    subdir(root, id).join(format!("block_{}.tmp", id))
+}
+
+pub(crate) fn meta_path(root: &Path, id: &Uuid) -> PathBuf {
+    subdir(root, id).join(format!("block_{}.meta", id))
+}
+
+// Metadata is written there first, and renamed over the previous one
+fn meta_temp_path(root: &Path, id: &Uuid) -> PathBuf {
+    subdir(root, id).join(format!("block_{}.meta.tmp", id))
+}
+
+// Bytes of a replica read at once to compute its checksum or copy it
+const CHECKSUM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// CRC-32 of the replica at `path`.
+pub(crate) async fn checksum(path: &Path) -> CuddlyResult<u32> {
+    let mut file = fs::File::open(path).await?;
+    let mut crc = Crc32::new();
+    let mut chunk = vec![0; CHECKSUM_CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(crc.finish());
+        }
+        crc.update(&chunk[..read]);
+    }
+}
+
+/// Copies the first `len` bytes of the replica at `from` to a new file at
+/// `to`, and returns the running checksum of the copied data.
+pub(crate) async fn copy_replica(from: &Path, to: &Path, len: u64) -> CuddlyResult<Crc32> {
+    let mut source = fs::File::open(from).await?.take(len);
+    let mut target = fs::File::create(to).await?;
+    let mut crc = Crc32::new();
+    let mut chunk = vec![0; CHECKSUM_CHUNK_SIZE];
+    let mut copied = 0;
+    loop {
+        let read = source.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        crc.update(&chunk[..read]);
+        target.write_all(&chunk[..read]).await?;
+        copied += read as u64;
+    }
+    if copied < len {
+        return Err(CuddlyError::IOError(format!(
+            "Replica {:?} has only {} bytes, not {}",
+            from, copied, len
+        )));
+    }
+    target.flush().await?;
+    Ok(crc)
+}
+
+/// What the metadata file of a finalized replica records.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct ReplicaMeta {
+    pub(crate) id: Uuid,
+    pub(crate) len: u64,
+    /// Generation of the last append to the replica.
+    pub(crate) generation: u64,
+    /// CRC-32 of the data of the replica. Metadata written before replicas
+    /// had checksums, or written again at startup, has none until the block
+    /// scanner reads the replica.
+    #[serde(default)]
+    pub(crate) checksum: Option<u32>,
+}
+
+impl ReplicaMeta {
+    pub(crate) async fn read(root: &Path, id: &Uuid) -> CuddlyResult<Self> {
+        let contents = fs::read(meta_path(root, id)).await?;
+        serde_json::from_slice(&contents)
+            .map_err(|e| CuddlyError::IOError(format!("Invalid metadata of block {}: {}", id, e)))
+    }
+
+    pub(crate) async fn write(&self, root: &Path) -> CuddlyResult<()> {
+        let contents = serde_json::to_vec(self)
+            .map_err(|e| CuddlyError::IOError(format!("Could not encode metadata: {}", e)))?;
+        fs::write(meta_temp_path(root, &self.id), contents).await?;
+        fs::rename(meta_temp_path(root, &self.id), meta_path(root, &self.id)).await?;
+        Ok(())
+    }
+
+    /// Reads the metadata of the replica of `id`, if there is one.
+    fn read_blocking(root: &Path, id: &Uuid) -> CuddlyResult<Option<Self>> {
+        let contents = match std::fs::read(meta_path(root, id)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .map_err(|e| CuddlyError::IOError(format!("Invalid metadata of block {}: {}", id, e)))
+    }
+
+    fn write_blocking(&self, root: &Path) -> CuddlyResult<()> {
+        let contents = serde_json::to_vec(self)
+            .map_err(|e| CuddlyError::IOError(format!("Could not encode metadata: {}", e)))?;
+        std::fs::write(meta_temp_path(root, &self.id), contents)?;
+        std::fs::rename(meta_temp_path(root, &self.id), meta_path(root, &self.id))?;
+        Ok(())
+    }
+}
+
+/// The `VERSION` file of a volume.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct VolumeVersion {
+    pub(crate) layout_version: u32,
+    pub(crate) storage_uuid: Uuid,
+}
+
+impl VolumeVersion {
+    fn path(dir: &Path) -> PathBuf {
+        dir.join("VERSION")
+    }
+
+    /// Reads the `VERSION` file of the volume in `dir`, if there is one.
+    pub(crate) fn read(dir: &Path) -> CuddlyResult<Option<Self>> {
+        let contents = match std::fs::read_to_string(Self::path(dir)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let invalid = || CuddlyError::IOError(format!("Invalid VERSION file in {:?}", dir));
+        let (mut layout_version, mut storage_uuid) = (None, None);
+        for line in contents.lines() {
+            match line.split_once('=') {
+                Some(("layout_version", value)) => {
+                    layout_version = Some(value.trim().parse().map_err(|_| invalid())?)
+                }
+                Some(("storage_uuid", value)) => {
+                    storage_uuid = Some(Uuid::parse_str(value.trim()).map_err(|_| invalid())?)
+                }
+                _ => (),
+            }
+        }
+        Ok(Some(Self {
+            layout_version: layout_version.ok_or_else(invalid)?,
+            storage_uuid: storage_uuid.ok_or_else(invalid)?,
+        }))
+    }
+
+    pub(crate) fn write(&self, dir: &Path) -> CuddlyResult<()> {
+        std::fs::write(
+            Self::path(dir),
+            format!(
+                "layout_version={}\nstorage_uuid={}\n",
+                self.layout_version, self.storage_uuid
+            ),
+        )?;
+        Ok(())
+    }
+}
+
+/// Prepares the volume in `dir` for the current layout, upgrading it if it
+/// has an older one, and returns its version.
+pub(crate) fn open_volume(dir: &Path, root: &Path) -> CuddlyResult<VolumeVersion> {
+    std::fs::create_dir_all(dir)?;
+    let version = VolumeVersion::read(dir)?;
+    let layout_version = version.map_or(FLAT_LAYOUT_VERSION, |version| version.layout_version);
+    if layout_version > LAYOUT_VERSION {
+        return Err(CuddlyError::IOError(format!(
+            "Volume {:?} has layout version {}, only up to {} is supported",
+            dir, layout_version, LAYOUT_VERSION
+        )));
+    }
+    if layout_version == LAYOUT_VERSION {
+        if let Some(version) = version {
+            return Ok(version);
+        }
+    }
+
+    let moved = upgrade_flat_layout(root)?;
+    info!(
+        "Upgraded volume {:?} from layout version {} to {}, moved {} files",
+        dir, layout_version, LAYOUT_VERSION, moved
+    );
+    let version = VolumeVersion {
+        layout_version: LAYOUT_VERSION,
+        storage_uuid: version.map_or_else(Uuid::new_v4, |version| version.storage_uuid),
+    };
+    version.write(dir)?;
+    Ok(version)
+}
+
+/// Moves the replicas directly in `root` to their subdirectory, and writes
+/// the metadata file of the finalized ones.
+fn upgrade_flat_layout(root: &Path) -> CuddlyResult<usize> {
+    let entries = match std::fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut moved = 0;
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let file_name = entry.file_name();
+        let Some(name) = file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix("block_"))
+        else {
+            continue;
+        };
+        let (id, finalized) = match name.strip_suffix(".tmp") {
+            Some(id) => (id, false),
+            None => (name, true),
+        };
+        let Ok(id) = Uuid::parse_str(id) else {
+            continue;
+        };
+
+        let target = match finalized {
+            true => block_path(root, &id),
+            false => temp_path(root, &id),
+        };
+        std::fs::create_dir_all(subdir(root, &id))?;
+        std::fs::rename(entry.path(), &target)?;
+        if finalized {
+            ReplicaMeta {
+                id,
+                len: std::fs::metadata(&target)?.len(),
+                generation: 0,
+                checksum: None,
+            }
+            .write_blocking(root)?;
+        }
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+/// The replicas found on a volume when it is opened.
+#[derive(Debug, Default)]
+pub(crate) struct Replicas {
+    pub(crate) finalized: HashSet<Uuid>,
+    /// Replicas of a write that was interrupted, with their length. Whether
+    /// they are complete is up to the namenode.
+    pub(crate) being_written: HashMap<Uuid, u64>,
+    /// Bytes of the block files kept on the volume
+    pub(crate) dfs_used: u64,
+}
+
+/// Finds the replicas under `root` and recovers the ones a crash left
+/// behind. Only the lengths of the replicas are compared with their
+/// metadata, their data is left to the block scanner. A temporary replica
+/// as long as the metadata written for it was being finalized, and is moved
+/// in place. One next to a finalized replica is the copy of an append and is
+/// deleted, the others are replicas being written. A finalized replica whose
+/// length differs from its metadata is torn and is deleted. The metadata of
+/// one without any is written again. Its generation is lost with the
+/// metadata and written as 0, so unless the block is still at generation 0
+/// the namenode takes the replica for stale and has it deleted, and the block
+/// is re-replicated from its other replicas. A replica that cannot be
+/// recovered is left out, the namenode then re-replicates its block as well.
+pub(crate) fn recover(root: &Path) -> CuddlyResult<Replicas> {
+    let (mut finalized, mut temporary) = (HashMap::new(), HashMap::new());
+    for first in read_subdirs(root)? {
+        for second in read_subdirs(&first)? {
+            for entry in std::fs::read_dir(second)? {
+                let entry = entry?;
+                let file_name = entry.file_name();
+                let Some(name) = file_name
+                    .to_str()
+                    .and_then(|name| name.strip_prefix("block_"))
+                else {
+                    continue;
+                };
+                if name.ends_with(".meta.tmp") {
+                    // Interrupted write of metadata, the previous one is kept
+                    let _ = std::fs::remove_file(entry.path());
+                    continue;
+                }
+                let (id, replicas) = match name.strip_suffix(".tmp") {
+                    Some(id) => (id, &mut temporary),
+                    None => (name, &mut finalized),
+                };
+                let Ok(id) = Uuid::parse_str(id) else {
+                    continue;
+                };
+                match entry.metadata() {
+                    Ok(metadata) => {
+                        replicas.insert(id, metadata.len());
+                    }
+                    Err(e) => warn!("Skipping replica of block {}: {}", id, e),
+                }
+            }
+        }
+    }
+
+    let mut replicas = Replicas::default();
+    for (id, len) in temporary {
+        match recover_temporary(root, &id, len, finalized.contains_key(&id)) {
+            Ok(Recovered::Finalized) => {
+                finalized.insert(id, len);
+            }
+            Ok(Recovered::BeingWritten) => {
+                replicas.being_written.insert(id, len);
+                replicas.dfs_used += len;
+            }
+            Ok(Recovered::Deleted) => (),
+            Err(e) => warn!("Skipping replica of block {} being written: {}", id, e),
+        }
+    }
+    for (id, len) in finalized {
+        match recover_finalized(root, &id, len) {
+            Ok(Recovered::Finalized) => {
+                replicas.finalized.insert(id);
+                replicas.dfs_used += len;
+            }
+            Ok(_) => (),
+            Err(e) => warn!("Skipping replica of block {}: {}", id, e),
+        }
+    }
+    Ok(replicas)
+}
+
+/// What became of a replica found when opening a volume.
+enum Recovered {
+    Finalized,
+    BeingWritten,
+    Deleted,
+}
+
+fn recover_temporary(
+    root: &Path,
+    id: &Uuid,
+    len: u64,
+    has_finalized: bool,
+) -> CuddlyResult<Recovered> {
+    let finalizing = match ReplicaMeta::read_blocking(root, id) {
+        Ok(Some(meta)) => meta.len == len,
+        Ok(None) | Err(_) => false,
+    };
+    if finalizing {
+        info!("Finalizing replica of block {}", id);
+        std::fs::rename(temp_path(root, id), block_path(root, id))?;
+        Ok(Recovered::Finalized)
+    } else if has_finalized {
+        info!("Deleting the copy of an append to block {}", id);
+        std::fs::remove_file(temp_path(root, id))?;
+        Ok(Recovered::Deleted)
+    } else {
+        Ok(Recovered::BeingWritten)
+    }
+}
+
+fn recover_finalized(root: &Path, id: &Uuid, len: u64) -> CuddlyResult<Recovered> {
+    match ReplicaMeta::read_blocking(root, id) {
+        Ok(Some(meta)) if meta.len == len => (),
+        Ok(Some(meta)) => {
+            warn!(
+                "Deleting replica of block {}: {} bytes do not match the {} bytes of its metadata",
+                id, len, meta.len
+            );
+            std::fs::remove_file(block_path(root, id))?;
+            std::fs::remove_file(meta_path(root, id))?;
+            return Ok(Recovered::Deleted);
+        }
+        Ok(None) | Err(_) => {
+            warn!("Rewriting metadata of block {}", id);
+            ReplicaMeta {
+                id: *id,
+                len,
+                generation: 0,
+                checksum: None,
+            }
+            .write_blocking(root)?;
+        }
+    }
+    Ok(Recovered::Finalized)
+}
+
+fn read_subdirs(dir: &Path) -> CuddlyResult<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut subdirs = vec![];
+    for entry in entries {
+        let entry = entry?;
+        let is_subdir = entry.file_type()?.is_dir()
+            && entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with("subdir"));
+        if is_subdir {
+            subdirs.push(entry.path());
+        }
+    }
+    Ok(subdirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_flat_layout() {
+        let dir = std::env::temp_dir()
+            .join("cuddlyfs")
+            .join(format!("layout-{}", Uuid::new_v4()));
+        let root = dir.join("blocks");
+        std::fs::create_dir_all(&root).unwrap();
+        let (finalized, temp) = (Uuid::new_v4(), Uuid::new_v4());
+        std::fs::write(root.join(format!("block_{}", finalized)), [1; 10]).unwrap();
+        std::fs::write(root.join(format!("block_{}.tmp", temp)), [2; 5]).unwrap();
+        std::fs::write(root.join("unrelated"), b"").unwrap();
+
+        let version = open_volume(&dir, &root).unwrap();
+        assert_eq!(version.layout_version, LAYOUT_VERSION);
+        assert_eq!(VolumeVersion::read(&dir).unwrap(), Some(version));
+        assert!(!root.join(format!("block_{}", finalized)).exists());
+        assert_eq!(
+            std::fs::read(block_path(&root, &finalized)).unwrap(),
+            [1; 10]
+        );
+        assert!(temp_path(&root, &temp).exists());
+        assert!(root.join("unrelated").exists());
+
+        let contents = std::fs::read(meta_path(&root, &finalized)).unwrap();
+        let meta: ReplicaMeta = serde_json::from_slice(&contents).unwrap();
+        // The checksum is left to the block scanner
+        assert_eq!(
+            (meta.id, meta.len, meta.generation, meta.checksum),
+            (finalized, 10, 0, None)
+        );
+
+        let replicas = recover(&root).unwrap();
+        assert_eq!(replicas.finalized, HashSet::from([finalized]));
+        assert_eq!(replicas.being_written, HashMap::from([(temp, 5)]));
+        assert_eq!(replicas.dfs_used, 15);
+
+        // Opening it again keeps the storage
+        assert_eq!(open_volume(&dir, &root).unwrap(), version);
+        VolumeVersion {
+            layout_version: LAYOUT_VERSION + 1,
+            ..version
+        }
+        .write(&dir)
+        .unwrap();
+        assert!(open_volume(&dir, &root).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover() {
+        let root = std::env::temp_dir()
+            .join("cuddlyfs")
+            .join(format!("recover-{}", Uuid::new_v4()))
+            .join("blocks");
+        let write = |path: PathBuf, len: usize| {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, vec![0; len]).unwrap();
+        };
+        let checksum = |data: &[u8]| {
+            let mut crc = Crc32::new();
+            crc.update(data);
+            crc.finish()
+        };
+        let meta = |id: Uuid, len: u64| ReplicaMeta {
+            id,
+            len,
+            generation: 0,
+            checksum: Some(checksum(&vec![0; len as usize])),
+        };
+        let [valid, unchecked, torn, corrupt, unreadable, no_meta, finalizing, appended, being_written] =
+            [(); 9].map(|_| Uuid::new_v4());
+        for id in [valid, unchecked, torn, corrupt, no_meta, appended] {
+            write(block_path(&root, &id), 10);
+        }
+        // A replica that cannot be deleted does not fail the volume
+        std::fs::create_dir_all(block_path(&root, &unreadable)).unwrap();
+        for id in [valid, corrupt, unreadable, appended] {
+            meta(id, 10).write_blocking(&root).unwrap();
+        }
+        // Written before replicas had checksums
+        ReplicaMeta {
+            checksum: None,
+            ..meta(unchecked, 10)
+        }
+        .write_blocking(&root)
+        .unwrap();
+        meta(torn, 20).write_blocking(&root).unwrap();
+        // Only the block scanner reads the data of the replicas
+        std::fs::write(block_path(&root, &corrupt), [1; 10]).unwrap();
+        // Crashed while writing the metadata of a replica
+        std::fs::write(meta_temp_path(&root, &valid), b"{").unwrap();
+        // Crashed after writing the metadata of a finalized replica
+        write(temp_path(&root, &finalizing), 7);
+        meta(finalizing, 7).write_blocking(&root).unwrap();
+        // Crashed while appending to a finalized replica
+        write(temp_path(&root, &appended), 15);
+        write(temp_path(&root, &being_written), 5);
+
+        let replicas = recover(&root).unwrap();
+        assert_eq!(
+            replicas.finalized,
+            HashSet::from([valid, unchecked, corrupt, no_meta, finalizing, appended])
+        );
+        assert_eq!(replicas.being_written, HashMap::from([(being_written, 5)]));
+        assert_eq!(replicas.dfs_used, 10 + 10 + 10 + 10 + 7 + 10 + 5);
+        assert!(!block_path(&root, &torn).exists());
+        assert!(!meta_path(&root, &torn).exists());
+        assert!(block_path(&root, &finalizing).exists());
+        assert!(!temp_path(&root, &appended).exists());
+        assert!(!meta_temp_path(&root, &valid).exists());
+        assert_eq!(
+            ReplicaMeta::read_blocking(&root, &valid).unwrap(),
+            Some(meta(valid, 10))
+        );
+        assert_eq!(
+            ReplicaMeta::read_blocking(&root, &no_meta).unwrap(),
+            Some(ReplicaMeta {
+                checksum: None,
+                ..meta(no_meta, 10)
+            })
+        );
+        std::fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+}
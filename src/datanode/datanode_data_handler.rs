@@ -13,7 +13,12 @@ use tokio::{
     net::TcpStream,
 };
 
-use crate::block::Block;
+use crate::block::{Block, BlockGroup};
+use crate::errors::CuddlyError;
+use crate::io::striped_reader::StripedReader;
+use crate::namenode::storage_policy::StorageType;
+use crate::utils::crc32::Crc32;
+use crate::utils::fault_injection::{FaultAction, FaultInjector, FaultPoint};
 use crate::{errors::CuddlyResult, utils::parse_message};
 
 use self::cuddlyproto::WriteBlockOperation;
@@ -24,11 +29,15 @@ use super::{
     datanode_data_registry::DatanodeDataRegistry,
 };
 
+/// A replica written to this datanode, with the storage it was written to.
+pub(crate) type ReceivedBlock = (cuddlyproto::Block, cuddlyproto::DatanodeStorageProto);
+
 pub(crate) struct DatanodeDataHandler {
     stream: BufStream<TcpStream>,
     data_registry: Arc<DatanodeDataRegistry>,
     packet_size: u64,
-    block_sender: tokio::sync::mpsc::Sender<cuddlyproto::Block>,
+    block_sender: tokio::sync::mpsc::Sender<ReceivedBlock>,
+    fault_injector: Arc<FaultInjector>,
 }
 
 impl DatanodeDataHandler {
@@ -36,13 +45,15 @@ impl DatanodeDataHandler {
         stream: TcpStream,
         data_registry: Arc<DatanodeDataRegistry>,
         packet_size: u64,
-        block_sender: tokio::sync::mpsc::Sender<cuddlyproto::Block>,
+        block_sender: tokio::sync::mpsc::Sender<ReceivedBlock>,
+        fault_injector: Arc<FaultInjector>,
     ) -> Self {
         Self {
             stream: BufStream::new(stream),
             data_registry,
             packet_size,
             block_sender,
+            fault_injector,
         }
     }
 
@@ -59,6 +70,7 @@ impl DatanodeDataHandler {
         match op {
             op if op == OpCode::WriteBlock as i32 => self.handle_write().await?,
             op if op == OpCode::ReadBlock as i32 => self.handle_read().await?,
+            op if op == OpCode::AppendBlock as i32 => self.handle_append().await?,
             _ => unreachable!(),
         };
 
@@ -70,12 +82,9 @@ impl DatanodeDataHandler {
             parse_message::<ReadBlockOperation>(&mut self.stream).await?;
         let block: Block = block.unwrap().into();
         let mut buffer = vec![];
-        let mut blockfile = BufReader::new(
-            self.data_registry
-                .get_blockfile(&block.filename(), false)
-                .await?,
-        );
-        let mut remaining_to_send = blockfile.get_ref().metadata().await?.len();
+        let mut blockfile = BufReader::new(self.data_registry.get_blockfile(&block, false).await?);
+        // A replica not yet truncated holds bytes past the end of the block
+        let mut remaining_to_send = blockfile.get_ref().metadata().await?.len().min(block.len);
 
         while remaining_to_send > 0 {
             let packet_size = std::cmp::min(remaining_to_send, self.packet_size);
@@ -99,21 +108,43 @@ impl DatanodeDataHandler {
     }
 
     async fn handle_write(&mut self) -> CuddlyResult<()> {
-        let WriteBlockOperation { block, targets } =
-            parse_message::<WriteBlockOperation>(&mut self.stream).await?;
+        let WriteBlockOperation {
+            block,
+            targets,
+            storage_types,
+            ..
+        } = parse_message::<WriteBlockOperation>(&mut self.stream).await?;
         debug!("Received write request for block {:?}", block);
         let block: Block = block.unwrap().into();
-        let block_file = self.data_registry.start_block_creation(&block).await?;
+        // The first storage type is the one of this datanode's replica
+        let storage_type = storage_types
+            .first()
+            .map(|storage_type| StorageType::from_proto(*storage_type))
+            .unwrap_or_default();
+        let (block_file, storage) = self
+            .data_registry
+            .start_block_creation(&block, storage_type)
+            .await?;
+        let storage = storage.to_proto();
         debug!("Block file created successfully");
 
-        match self.write_block(block_file, &block, &targets[1..]).await {
-            Ok(()) => {
-                self.data_registry.finish_block_creation(&block).await?;
-                let response = WriteBlockResponse { success: true };
-                let mut buffer = vec![];
-                response.encode_length_delimited(&mut buffer)?;
-                self.stream.write_all(&buffer).await?;
-                self.stream.flush().await?;
+        match self
+            .write_block(
+                block_file,
+                storage,
+                &block,
+                &targets[1..],
+                storage_types.get(1..).unwrap_or_default(),
+                None,
+                Crc32::new(),
+            )
+            .await
+        {
+            Ok(checksum) => {
+                self.data_registry
+                    .finish_block_creation(&block, checksum)
+                    .await?;
+                self.acknowledge().await?;
                 info!("Block written successfully");
                 Ok(())
             }
@@ -125,12 +156,85 @@ impl DatanodeDataHandler {
         }
     }
 
+    /// Appends the received packets to a finalized replica, whose length has
+    /// to match the block's.
+    async fn handle_append(&mut self) -> CuddlyResult<()> {
+        let WriteBlockOperation {
+            block,
+            targets,
+            generation,
+            ..
+        } = parse_message::<WriteBlockOperation>(&mut self.stream).await?;
+        debug!(
+            "Received append request for block {:?} with generation {}",
+            block, generation
+        );
+        let block: Block = block.unwrap().into();
+        let (block_file, crc, storage) = self
+            .data_registry
+            .start_block_append(&block, generation)
+            .await?;
+        let storage = storage.to_proto();
+
+        match self
+            .write_block(
+                block_file,
+                storage,
+                &block,
+                &targets[1..],
+                &[],
+                Some(generation),
+                crc,
+            )
+            .await
+        {
+            Ok(checksum) => {
+                self.data_registry
+                    .finish_block_append(&block, generation, checksum)
+                    .await?;
+                self.acknowledge().await?;
+                info!("Block appended successfully");
+                Ok(())
+            }
+            Err(e) => {
+                self.data_registry.abort_block_creation(&block).await?;
+                warn!("Failed to append to block: {:?}", e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn acknowledge(&mut self) -> CuddlyResult<()> {
+        if let Some(fault) = self.fault_injector.check(FaultPoint::DatanodeAck) {
+            if fault.action == FaultAction::Delay {
+                tokio::time::sleep(std::time::Duration::from_millis(fault.delay_ms)).await;
+            }
+        }
+        let response = WriteBlockResponse { success: true };
+        let mut buffer = vec![];
+        response.encode_length_delimited(&mut buffer)?;
+        self.stream.write_all(&buffer).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Writes the received packets to `block_file`, on `storage`, and
+    /// forwards them to `targets`, whose replicas go to `storage_types`.
+    /// With a `generation`, the packets are appended to the existing
+    /// replicas of the block. `crc` is the running checksum of the data
+    /// already in `block_file`, the checksum of the whole replica is
+    /// returned.
+    #[allow(clippy::too_many_arguments)]
     async fn write_block(
         &mut self,
         block_file: fs::File,
+        storage: cuddlyproto::DatanodeStorageProto,
         block: &Block,
         targets: &[String],
-    ) -> CuddlyResult<()> {
+        storage_types: &[i32],
+        generation: Option<u64>,
+        mut crc: Crc32,
+    ) -> CuddlyResult<u32> {
         debug!("Writing block to file");
         let mut block_file = BufWriter::new(block_file);
 
@@ -139,16 +243,19 @@ impl DatanodeDataHandler {
             let address = &targets[0];
             let mut stream = BufStream::new(TcpStream::connect(address).await?);
 
-            cuddlyproto::Operation {
-                op: OpCode::WriteBlock as i32,
-            }
-            .encode_length_delimited(&mut buffer)?;
+            let op = match generation {
+                Some(_) => OpCode::AppendBlock,
+                None => OpCode::WriteBlock,
+            };
+            cuddlyproto::Operation { op: op as i32 }.encode_length_delimited(&mut buffer)?;
             stream.write_all(&buffer).await?;
             buffer.clear();
 
             WriteBlockOperation {
-                block: Some(block.clone().into()),
+                block: Some((*block).into()),
                 targets: targets.into(),
+                generation: generation.unwrap_or_default(),
+                storage_types: storage_types.into(),
             }
             .encode_length_delimited(&mut buffer)?;
             stream.write_all(&buffer).await?;
@@ -159,12 +266,35 @@ impl DatanodeDataHandler {
             None
         };
 
-        let mut total_block_length: u64 = 0;
+        let mut total_block_length: u64 = match generation {
+            Some(_) => block.len,
+            None => 0,
+        };
         loop {
             let Packet { size, last } = parse_message::<Packet>(&mut self.stream).await?;
             buffer.resize_with(size as usize, u8::default);
             total_block_length += self.stream.read_exact(&mut buffer).await? as u64;
+
+            if let Some(fault) = self.fault_injector.check(FaultPoint::DatanodePacket) {
+                match fault.action {
+                    FaultAction::DropConnection => {
+                        return Err(CuddlyError::IOError(format!(
+                            "Connection dropped while writing block {}",
+                            block
+                        )));
+                    }
+                    FaultAction::CorruptBytes => {
+                        if let Some(byte) = buffer.first_mut() {
+                            *byte = !*byte;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+
             block_file.write_all(&buffer).await?;
+            crc.update(&buffer);
+            self.data_registry.record_write(block, size);
 
             if let Some(ref mut stream) = next_node {
                 let packet = Packet { size, last };
@@ -198,15 +328,159 @@ impl DatanodeDataHandler {
             id: block.id.to_string(),
             len: total_block_length,
             seq: block.seq,
+            generation: generation.unwrap_or(block.generation),
         };
 
-        if self.block_sender.send(block.clone()).await.is_err() {
+        if self
+            .block_sender
+            .send((block.clone(), storage))
+            .await
+            .is_err()
+        {
             warn!(
                 "Receiver dropped. Namenode will not be informed of new written block {:?}",
                 block
             );
         }
 
-        Ok(())
+        Ok(crc.finish())
+    }
+}
+
+/// Copies a finalized block to `targets`, on `storage_types`, through the
+/// regular write pipeline. Used when the namenode asks for a block to be
+/// re-replicated or moved.
+pub(crate) async fn transfer_block(
+    data_registry: &DatanodeDataRegistry,
+    block: &Block,
+    targets: &[String],
+    storage_types: &[i32],
+    packet_size: u64,
+) -> CuddlyResult<()> {
+    let Some(first_target) = targets.first() else {
+        return Ok(());
+    };
+    let mut blockfile = BufReader::new(data_registry.get_blockfile(block, false).await?);
+    let mut stream = BufStream::new(TcpStream::connect(first_target).await?);
+
+    let mut buffer = vec![];
+    cuddlyproto::Operation {
+        op: OpCode::WriteBlock as i32,
+    }
+    .encode_length_delimited(&mut buffer)?;
+    WriteBlockOperation {
+        block: Some((*block).into()),
+        targets: targets.into(),
+        generation: 0,
+        storage_types: storage_types.into(),
+    }
+    .encode_length_delimited(&mut buffer)?;
+    stream.write_all(&buffer).await?;
+    buffer.clear();
+
+    let mut remaining_to_send = blockfile.get_ref().metadata().await?.len();
+    if remaining_to_send == 0 {
+        Packet {
+            size: 0,
+            last: true,
+        }
+        .encode_length_delimited(&mut buffer)?;
+        stream.write_all(&buffer).await?;
+        buffer.clear();
+    }
+    while remaining_to_send > 0 {
+        let size = std::cmp::min(remaining_to_send, packet_size);
+        remaining_to_send -= size;
+        Packet {
+            size,
+            last: remaining_to_send == 0,
+        }
+        .encode_length_delimited(&mut buffer)?;
+        stream.write_all(&buffer).await?;
+        buffer.clear();
+
+        buffer.resize_with(size as usize, u8::default);
+        blockfile.read_exact(&mut buffer).await?;
+        stream.write_all(&buffer).await?;
+        buffer.clear();
+    }
+    stream.flush().await?;
+
+    let WriteBlockResponse { success } = parse_message::<WriteBlockResponse>(&mut stream).await?;
+    if !success {
+        return Err(CuddlyError::IOError(format!(
+            "Transfer of block {} to {} failed",
+            block, first_target
+        )));
+    }
+    info!("Transferred block {} to {:?}", block, targets);
+    Ok(())
+}
+
+/// Rebuilds a lost block of an erasure-coded file from the other blocks of
+/// its group, found at `locations`, and writes it to `targets` through the
+/// regular write pipeline.
+pub(crate) async fn reconstruct_block(
+    block: &Block,
+    group: BlockGroup,
+    locations: Vec<Vec<String>>,
+    targets: &[String],
+    storage_types: &[i32],
+    packet_size: u64,
+) -> CuddlyResult<()> {
+    let Some(first_target) = targets.first() else {
+        return Ok(());
+    };
+    let index = group
+        .blocks
+        .iter()
+        .position(|internal| internal.id == block.id)
+        .ok_or_else(|| CuddlyError::FSError(format!("{} is not part of its group", block)))?;
+    let mut reader = StripedReader::new(group, locations);
+    let mut stream = BufStream::new(TcpStream::connect(first_target).await?);
+
+    let mut buffer = vec![];
+    cuddlyproto::Operation {
+        op: OpCode::WriteBlock as i32,
+    }
+    .encode_length_delimited(&mut buffer)?;
+    WriteBlockOperation {
+        block: Some((*block).into()),
+        targets: targets.into(),
+        generation: 0,
+        storage_types: storage_types.into(),
+    }
+    .encode_length_delimited(&mut buffer)?;
+    stream.write_all(&buffer).await?;
+    buffer.clear();
+
+    while let Some(cells) = reader.next_stripe(&[index]).await? {
+        for packet in cells[0].chunks(packet_size as usize) {
+            Packet {
+                size: packet.len() as u64,
+                last: false,
+            }
+            .encode_length_delimited(&mut buffer)?;
+            stream.write_all(&buffer).await?;
+            stream.write_all(packet).await?;
+            buffer.clear();
+        }
+    }
+    Packet {
+        size: 0,
+        last: true,
+    }
+    .encode_length_delimited(&mut buffer)?;
+    stream.write_all(&buffer).await?;
+    stream.flush().await?;
+
+    let WriteBlockResponse { success } = parse_message::<WriteBlockResponse>(&mut stream).await?;
+    if !success {
+        return Err(CuddlyError::IOError(format!(
+            "Writing rebuilt block {} to {} failed",
+            block, first_target
+        )));
     }
+    info!("Reconstructed block {} on {:?}", block, targets);
+    Ok(())
 }
@@ -1,57 +1,368 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     ops::{Deref, DerefMut},
-    path::{Path, PathBuf},
-    sync::Mutex,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
 };
 
-use tokio::fs::{self, File};
+use log::{error, info, warn};
+use tokio::fs::{self, File, OpenOptions};
+use uuid::Uuid;
 
 use crate::{
     block::Block,
+    config::StorageConfig,
+    cuddlyproto,
     errors::{CuddlyError, CuddlyResult},
+    namenode::storage_policy::StorageType,
+    utils::{
+        crc32::Crc32,
+        fault_injection::{FaultAction, FaultInjector, FaultPoint},
+    },
 };
 
-use super::datanode_disk_info::DiskInfo;
+use super::{
+    block_layout::{self, ReplicaMeta, Replicas},
+    datanode_disk_info::{DiskInfo, DiskUsage},
+    volume_chooser::{VolumeChooser, VolumeChooserKind},
+};
 
+/// A volume: a directory blocks are kept in, on one type of storage media.
+/// Once it fails an I/O check, it is no longer used.
 #[derive(Debug)]
-pub(crate) struct DatanodeDataRegistry {
-    disk_info: Mutex<DiskInfo>,
-    blocks_being_created: Mutex<HashSet<Block>>,
+pub(crate) struct Storage {
+    storage_uuid: Uuid,
+    storage_type: StorageType,
+    dir: PathBuf,
     block_directory: PathBuf,
+    // None if the volume failed before its capacity could be read
+    disk_info: Mutex<Option<DiskInfo>>,
+    // Finalized replicas on the volume
+    blocks: Mutex<HashSet<Uuid>>,
+    // Replicas whose write was interrupted by a restart, with their length,
+    // until the namenode tells whether they are complete
+    being_written: Mutex<HashMap<Uuid, u64>>,
+    failed: AtomicBool,
 }
 
-#[allow(dead_code)]
-impl DatanodeDataRegistry {
-    pub(crate) fn new(data_dir: &PathBuf) -> CuddlyResult<Self> {
-        let disk_info = Mutex::new(DiskInfo::new(data_dir)?);
-        let block_directory = data_dir.clone().join("blocks");
-        Ok(Self {
-            disk_info,
-            blocks_being_created: Mutex::new(HashSet::new()),
+impl Storage {
+    /// Opens a volume, upgrading its layout if needed, and recovers the
+    /// replicas on it. A volume that cannot be opened is marked failed.
+    fn new(config: &StorageConfig) -> Self {
+        let block_directory = config.dir.join("blocks");
+        let opened = block_layout::open_volume(&config.dir, &block_directory).and_then(|version| {
+            let replicas = block_layout::recover(&block_directory)?;
+            let reserved = config.reserved_space.unwrap_or_default();
+            let disk_info = DiskInfo::new(&config.dir, reserved, replicas.dfs_used)?;
+            Ok((version.storage_uuid, disk_info, replicas))
+        });
+        let (storage_uuid, disk_info, replicas) = match opened {
+            Ok((storage_uuid, disk_info, replicas)) => (storage_uuid, Some(disk_info), replicas),
+            Err(e) => {
+                error!("Volume {:?} failed: {}", config.dir, e);
+                (Uuid::new_v4(), None, Replicas::default())
+            }
+        };
+        Self {
+            storage_uuid,
+            storage_type: config.storage_type,
+            dir: config.dir.clone(),
             block_directory,
+            failed: AtomicBool::new(disk_info.is_none()),
+            disk_info: Mutex::new(disk_info),
+            blocks: Mutex::new(replicas.finalized),
+            being_written: Mutex::new(replicas.being_written),
+        }
+    }
+
+    pub(crate) fn is_failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Checks that a file can be written to the volume.
+    async fn check(&self) -> std::io::Result<()> {
+        let probe = self.block_directory.join(".volume_check");
+        fs::create_dir_all(&self.block_directory).await?;
+        fs::write(&probe, b"").await?;
+        fs::remove_file(&probe).await
+    }
+
+    /// Marks the volume failed and returns the replicas that were on it.
+    fn fail(&self) -> Vec<Uuid> {
+        self.failed.store(true, Ordering::SeqCst);
+        self.being_written.lock().unwrap().clear();
+        self.blocks.lock().unwrap().drain().collect()
+    }
+
+    fn available(&self) -> u64 {
+        match self.disk_info.lock().unwrap().as_mut() {
+            Some(disk_info) => disk_info.usage().map_or(0, |usage| usage.remaining),
+            None => 0,
+        }
+    }
+
+    fn add_dfs_used(&self, bytes: u64) {
+        if let Some(disk_info) = self.disk_info.lock().unwrap().as_mut() {
+            disk_info.add_dfs_used(bytes);
+        }
+    }
+
+    fn remove_dfs_used(&self, bytes: u64) {
+        if let Some(disk_info) = self.disk_info.lock().unwrap().as_mut() {
+            disk_info.remove_dfs_used(bytes);
+        }
+    }
+
+    fn holds(&self, block: &Block) -> bool {
+        !self.is_failed() && self.blocks.lock().unwrap().contains(&block.id)
+    }
+
+    pub(crate) fn to_proto(&self) -> cuddlyproto::DatanodeStorageProto {
+        cuddlyproto::DatanodeStorageProto {
+            storage_uuid: self.storage_uuid.to_string(),
+            state: cuddlyproto::datanode_storage_proto::StorageState::Normal as i32,
+            storage_type: self.storage_type.to_proto(),
+        }
+    }
+
+    /// Capacity and usage of the storage, sent with each heartbeat. A
+    /// failed storage has none.
+    pub(crate) fn report(&self) -> CuddlyResult<cuddlyproto::StorageReportProto> {
+        let usage = match self.disk_info.lock().unwrap().as_mut() {
+            Some(disk_info) if !self.is_failed() => disk_info.usage()?,
+            _ => DiskUsage::default(),
+        };
+        Ok(cuddlyproto::StorageReportProto {
+            storage: Some(self.to_proto()),
+            failed: self.is_failed(),
+            capacity: usage.capacity,
+            dfs_used: usage.dfs_used,
+            remaining: usage.remaining,
+            block_pool_used: usage.dfs_used,
+            non_dfs_used: usage.non_dfs_used,
+            mount: self.dir.display().to_string(),
         })
     }
+}
+
+/// The replicas of a storage sent with a block report.
+#[derive(Debug)]
+pub(crate) struct StorageBlocks<'a> {
+    pub(crate) storage: &'a Storage,
+    pub(crate) finalized: Vec<Block>,
+    /// Replicas found being written at startup, which the namenode may
+    /// accept as complete
+    pub(crate) being_written: Vec<Block>,
+}
+
+#[derive(Debug)]
+pub(crate) struct DatanodeDataRegistry {
+    storages: Vec<Storage>,
+    volume_chooser: VolumeChooser,
+    failed_volumes_tolerated: usize,
+    // Blocks being written, with the index of the storage they go to
+    blocks_being_created: Mutex<HashMap<Block, usize>>,
+    // Generation of the replicas finalized or truncated since the start
+    generations: Mutex<HashMap<Uuid, u64>>,
+    fault_injector: Arc<FaultInjector>,
+}
 
-    pub(crate) fn used(&self) -> CuddlyResult<u64> {
-        self.disk_info.lock().unwrap().get_used()
+impl DatanodeDataRegistry {
+    /// Opens the volumes of `storages`, blocking on their directories. Fails
+    /// if more than `failed_volumes_tolerated` of them, or all of them, cannot
+    /// be used.
+    pub(crate) fn new(
+        storages: &[StorageConfig],
+        volume_chooser: VolumeChooserKind,
+        failed_volumes_tolerated: usize,
+        fault_injector: Arc<FaultInjector>,
+    ) -> CuddlyResult<Self> {
+        if storages.is_empty() {
+            return Err(CuddlyError::IOError("No storage configured".to_owned()));
+        }
+        let registry = Self {
+            storages: storages.iter().map(Storage::new).collect(),
+            volume_chooser: VolumeChooser::new(volume_chooser),
+            failed_volumes_tolerated,
+            blocks_being_created: Mutex::new(HashMap::new()),
+            generations: Mutex::new(HashMap::new()),
+            fault_injector,
+        };
+        registry.check_failed_volumes()?;
+        Ok(registry)
     }
 
-    pub(crate) fn available(&self) -> CuddlyResult<u64> {
-        self.disk_info.lock().unwrap().get_available()
+    pub(crate) fn storages(&self) -> &[Storage] {
+        &self.storages
     }
 
-    pub(crate) fn total(&self) -> CuddlyResult<u64> {
-        let mut disk_info = self.disk_info.lock().unwrap();
-        Ok(disk_info.get_used()? + disk_info.get_available()?)
+    fn check_failed_volumes(&self) -> CuddlyResult<()> {
+        let failed = self
+            .storages
+            .iter()
+            .filter(|storage| storage.is_failed())
+            .count();
+        if failed > self.failed_volumes_tolerated || failed == self.storages.len() {
+            return Err(CuddlyError::IOError(format!(
+                "{} of {} volumes failed, {} tolerated",
+                failed,
+                self.storages.len(),
+                self.failed_volumes_tolerated
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks the volumes that have not failed yet, and returns the ones
+    /// that fail now with the blocks that were lost with them. Fails if more
+    /// volumes have failed than tolerated.
+    pub(crate) async fn check_volumes(&self) -> CuddlyResult<Vec<(&Storage, Vec<Uuid>)>> {
+        let mut failed = vec![];
+        for storage in self.storages.iter().filter(|storage| !storage.is_failed()) {
+            if let Err(e) = storage.check().await {
+                error!("Volume {:?} failed: {}", storage.dir, e);
+                failed.push((storage, storage.fail()));
+            }
+        }
+        self.check_failed_volumes()?;
+        Ok(failed)
     }
 
-    pub(crate) async fn get_blockfile<P: AsRef<Path>>(
+    /// Reads the finalized replicas on the working volumes, and deletes the
+    /// ones whose data does not match the checksum of their metadata. Returns
+    /// the corrupt replicas by storage. A replica without a checksum gets
+    /// one. Replicas changed while they are read are left to the next scan.
+    pub(crate) async fn scan_blocks(&self) -> Vec<(&Storage, Vec<Uuid>)> {
+        let mut corrupt = vec![];
+        for (index, storage) in self.storages.iter().enumerate() {
+            if storage.is_failed() {
+                continue;
+            }
+            let ids = storage.blocks.lock().unwrap().clone();
+            let mut lost = vec![];
+            for id in ids {
+                match self.scan_block(&Block::new(id, 0, 0), index).await {
+                    Ok(true) => (),
+                    Ok(false) => lost.push(id),
+                    Err(e) => warn!("Failed to scan replica of block {}: {}", id, e),
+                }
+            }
+            if !lost.is_empty() {
+                corrupt.push((storage, lost));
+            }
+        }
+        corrupt
+    }
+
+    /// Checks the replica of `block` on the storage at `index`, and deletes
+    /// it if it is corrupt. Returns whether it is kept.
+    async fn scan_block(&self, block: &Block, index: usize) -> CuddlyResult<bool> {
+        let root = &self.storages[index].block_directory;
+        let meta = ReplicaMeta::read(root, &block.id).await?;
+        let checksum = block_layout::checksum(&self.block_path(block, index)).await;
+
+        // Nothing else may change the replica until it is settled
+        if self.insert_in_progress_block(block, index).is_err() {
+            return Ok(true);
+        }
+        let unchanged = self.storages[index].holds(block)
+            && ReplicaMeta::read(root, &block.id).await.ok() == Some(meta);
+        let kept = match (unchanged, checksum, meta.checksum) {
+            (false, _, _) => Ok(true),
+            (true, Ok(checksum), None) => ReplicaMeta {
+                checksum: Some(checksum),
+                ..meta
+            }
+            .write(root)
+            .await
+            .map(|_| true),
+            (true, Ok(checksum), Some(expected)) if checksum == expected => Ok(true),
+            (true, checksum, _) => {
+                match checksum {
+                    Ok(checksum) => warn!(
+                        "Replica of block {} has checksum {}, not {:?}",
+                        block.id, checksum, meta.checksum
+                    ),
+                    Err(e) => warn!("Replica of block {} cannot be read: {}", block.id, e),
+                }
+                // A replica that cannot be deleted is no longer used either
+                if let Err(e) = self.delete_replica(block, index).await {
+                    warn!("{}", e);
+                    self.storages[index]
+                        .blocks
+                        .lock()
+                        .unwrap()
+                        .remove(&block.id);
+                }
+                Ok(false)
+            }
+        };
+        self.remove_in_progress_block(block)?;
+        kept
+    }
+
+    /// The volume new replicas of `storage_type` go to, among the working
+    /// volumes of that type. Falls back to the other working volumes if
+    /// there is none.
+    fn choose_storage(&self, storage_type: StorageType) -> CuddlyResult<usize> {
+        let volumes_of = |wanted: Option<StorageType>| {
+            self.storages
+                .iter()
+                .enumerate()
+                .filter(|(_, storage)| !storage.is_failed())
+                .filter(|(_, storage)| wanted.is_none_or(|wanted| storage.storage_type == wanted))
+                .map(|(index, storage)| (index, storage.available()))
+                .collect::<Vec<_>>()
+        };
+        if let Some(index) = self
+            .volume_chooser
+            .choose(storage_type, &volumes_of(Some(storage_type)))
+        {
+            return Ok(index);
+        }
+        let index = self
+            .volume_chooser
+            .choose(storage_type, &volumes_of(None))
+            .ok_or_else(|| CuddlyError::IOError("No working volume".to_owned()))?;
+        warn!(
+            "No {} storage, using {} instead",
+            storage_type, self.storages[index].storage_type
+        );
+        Ok(index)
+    }
+
+    /// The storage holding the finalized replica of `block`.
+    fn find_storage(&self, block: &Block) -> Option<usize> {
+        self.storages
+            .iter()
+            .position(|storage| storage.holds(block))
+    }
+
+    fn block_path(&self, block: &Block, index: usize) -> PathBuf {
+        block_layout::block_path(&self.storages[index].block_directory, &block.id)
+    }
+
+    fn temp_path(&self, block: &Block, index: usize) -> PathBuf {
+        block_layout::temp_path(&self.storages[index].block_directory, &block.id)
+    }
+
+    pub(crate) async fn get_blockfile(
         &self,
-        path: P,
+        block: &Block,
         create_if_missing: bool,
     ) -> CuddlyResult<File> {
-        let path = self.block_directory.join(path);
+        let index = self
+            .find_storage(block)
+            .or_else(|| {
+                self.storages
+                    .iter()
+                    .position(|storage| !storage.is_failed())
+            })
+            .ok_or_else(|| CuddlyError::IOError("No working volume".to_owned()))?;
+        let path = self.block_path(block, index);
 
         match path.try_exists() {
             Ok(true) => {}
@@ -85,24 +396,58 @@ impl DatanodeDataRegistry {
         Ok(file)
     }
 
-    pub(crate) async fn start_block_creation(&self, block: &Block) -> CuddlyResult<File> {
-        if self.block_exists(block) {
-            return Err(CuddlyError::IOError(format!(
-                "Block {:?} already exists",
-                block
-            )));
+    /// Creates the temporary file of a new replica on a storage of
+    /// `storage_type`, and returns it with the storage.
+    pub(crate) async fn start_block_creation(
+        &self,
+        block: &Block,
+        storage_type: StorageType,
+    ) -> CuddlyResult<(File, &Storage)> {
+        // Writing a finalized replica again is only allowed if it does not
+        // change it, the new one then replaces it on its storage
+        let finalized = self.find_storage(block);
+        if let Some(index) = finalized {
+            let len = fs::metadata(self.block_path(block, index)).await?.len();
+            if len != block.len {
+                return Err(CuddlyError::IOError(format!(
+                    "Block {} is already finalized with {} bytes, not {}",
+                    block, len, block.len
+                )));
+            }
+            let generation = self.generation(block, index).await;
+            if block.generation < generation {
+                return Err(CuddlyError::IOError(format!(
+                    "Block {} is already finalized with the newer generation {}",
+                    block, generation
+                )));
+            }
+        }
+
+        if let Some(fault) = self.fault_injector.check(FaultPoint::DatanodeStartBlock) {
+            if fault.action == FaultAction::DiskFull {
+                return Err(CuddlyError::IOError(format!(
+                    "No space left on device to create block {:?}",
+                    block
+                )));
+            }
         }
 
-        self.insert_in_progress_block(block)?;
+        let index = match finalized {
+            Some(index) => index,
+            None => self.choose_storage(storage_type)?,
+        };
+        self.insert_in_progress_block(block, index)?;
+        self.discard_being_written(block).await;
 
-        if let Err(e) = fs::create_dir_all(&self.block_directory).await {
+        let temp_path = self.temp_path(block, index);
+        if let Err(e) = fs::create_dir_all(temp_path.parent().unwrap()).await {
+            self.remove_in_progress_block(block)?;
             return Err(CuddlyError::IOError(format!(
                 "Failed to create directory: {}",
                 e
             )));
         }
-        let block_path = self.block_directory.join(block.filename() + ".tmp");
-        let file = match File::create(block_path).await {
+        let file = match File::create(temp_path).await {
             Ok(file) => file,
             Err(e) => {
                 let mut blocks_being_created = self.blocks_being_created.lock().unwrap();
@@ -111,56 +456,744 @@ impl DatanodeDataRegistry {
             }
         };
 
-        Ok(file)
+        Ok((file, &self.storages[index]))
+    }
+
+    /// Reopens the finalized replica of `block` for an append with a newer
+    /// generation. The data is appended to a copy, so the replica stays
+    /// readable until the append is finished. The copy stays on the storage
+    /// of the replica, which is returned with it and the running checksum
+    /// of the copied data.
+    pub(crate) async fn start_block_append(
+        &self,
+        block: &Block,
+        generation: u64,
+    ) -> CuddlyResult<(File, Crc32, &Storage)> {
+        let index = self
+            .find_storage(block)
+            .ok_or_else(|| CuddlyError::IOError(format!("Block {:?} does not exist", block)))?;
+        let block_path = self.block_path(block, index);
+        let len = fs::metadata(&block_path)
+            .await
+            .map_err(|_| CuddlyError::IOError(format!("Block {:?} does not exist", block)))?
+            .len();
+        if len != block.len {
+            return Err(CuddlyError::IOError(format!(
+                "Replica of block {} has {} bytes, but {} were expected",
+                block, len, block.len
+            )));
+        }
+        let current = self.generation(block, index).await;
+        if generation <= current {
+            return Err(CuddlyError::IOError(format!(
+                "Generation {} of block {} is not newer than {}",
+                generation, block, current
+            )));
+        }
+
+        self.insert_in_progress_block(block, index)?;
+        let temp_path = self.temp_path(block, index);
+        let copied = match block_layout::copy_replica(&block_path, &temp_path, len).await {
+            Ok(crc) => {
+                self.storages[index].add_dfs_used(len);
+                OpenOptions::new()
+                    .append(true)
+                    .open(&temp_path)
+                    .await
+                    .map(|file| (file, crc))
+                    .map_err(CuddlyError::from)
+            }
+            Err(e) => Err(e),
+        };
+        match copied {
+            Ok((file, crc)) => Ok((file, crc, &self.storages[index])),
+            Err(e) => {
+                self.remove_in_progress_block(block)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Generation of the last append to the replica of `block` on the
+    /// storage at `index`, as recorded in its metadata.
+    async fn generation(&self, block: &Block, index: usize) -> u64 {
+        let cached = self.generations.lock().unwrap().get(&block.id).copied();
+        match cached {
+            Some(generation) => generation,
+            None => ReplicaMeta::read(&self.storages[index].block_directory, &block.id)
+                .await
+                .map_or(0, |meta| meta.generation),
+        }
+    }
+
+    /// Replaces the replica with the appended copy, whose data has
+    /// `checksum`, and records its generation.
+    pub(crate) async fn finish_block_append(
+        &self,
+        block: &Block,
+        generation: u64,
+        checksum: u32,
+    ) -> CuddlyResult<()> {
+        self.finalize(block, generation, Some(checksum)).await
+    }
+
+    /// Counts `bytes` just written to the temporary file of `block` as used.
+    pub(crate) fn record_write(&self, block: &Block, bytes: u64) {
+        let index = self
+            .blocks_being_created
+            .lock()
+            .unwrap()
+            .get(block)
+            .copied();
+        if let Some(index) = index {
+            self.storages[index].add_dfs_used(bytes);
+        }
     }
 
     pub(crate) async fn abort_block_creation(&self, block: &Block) -> CuddlyResult<()> {
-        self.remove_in_progress_block(block)
+        let index = self.remove_in_progress_block(block)?;
+        let temp_path = self.temp_path(block, index);
+        // The file may not have been created
+        if let Ok(metadata) = fs::metadata(&temp_path).await {
+            if fs::remove_file(&temp_path).await.is_ok() {
+                self.storages[index].remove_dfs_used(metadata.len());
+            }
+        }
+        Ok(())
     }
 
-    pub(crate) async fn finish_block_creation(&self, block: &Block) -> CuddlyResult<()> {
-        self.remove_in_progress_block(block)?;
-        let filename = self.block_directory.join(block.filename());
-        let temp_filename = self
-            .block_directory
-            .join(format!("{}.tmp", block.filename()));
-        tokio::fs::rename(temp_filename, filename).await?;
+    /// Finalizes the written replica of `block`, whose data has `checksum`.
+    pub(crate) async fn finish_block_creation(
+        &self,
+        block: &Block,
+        checksum: u32,
+    ) -> CuddlyResult<()> {
+        self.finalize(block, block.generation, Some(checksum)).await
+    }
+
+    /// Moves the written replica of `block` in place, next to its metadata,
+    /// replacing the previous replica if there is one. Without a `checksum`,
+    /// the block scanner computes it.
+    async fn finalize(
+        &self,
+        block: &Block,
+        generation: u64,
+        checksum: Option<u32>,
+    ) -> CuddlyResult<()> {
+        if let Some(fault) = self.fault_injector.check(FaultPoint::DatanodeFinishBlock) {
+            if fault.action == FaultAction::Fail {
+                self.remove_in_progress_block(block)?;
+                return Err(CuddlyError::IOError(format!(
+                    "Failed to finish creation of block {:?}",
+                    block
+                )));
+            }
+        }
+
+        let index = self.remove_in_progress_block(block)?;
+        let (temp_path, block_path) = (self.temp_path(block, index), self.block_path(block, index));
+        let meta = ReplicaMeta {
+            id: block.id,
+            len: fs::metadata(&temp_path).await?.len(),
+            generation,
+            checksum,
+        };
+        let replaced = fs::metadata(&block_path)
+            .await
+            .map_or(0, |metadata| metadata.len());
+        meta.write(&self.storages[index].block_directory).await?;
+        tokio::fs::rename(temp_path, block_path).await?;
+        self.storages[index].remove_dfs_used(replaced);
+        self.storages[index].blocks.lock().unwrap().insert(block.id);
+        self.generations
+            .lock()
+            .unwrap()
+            .insert(block.id, generation);
 
         Ok(())
     }
 
-    fn insert_in_progress_block(&self, block: &Block) -> CuddlyResult<()> {
-        let mut blocks_being_created = self.blocks_being_created.lock().unwrap();
-        if blocks_being_created.deref().contains(block) {
+    /// Lists the blocks stored on the working volumes of this datanode,
+    /// with their length and generation, by storage.
+    pub(crate) async fn list_blocks(&self) -> CuddlyResult<Vec<StorageBlocks<'_>>> {
+        let mut reports = vec![];
+        for (index, storage) in self.storages.iter().enumerate() {
+            if storage.is_failed() {
+                continue;
+            }
+            let ids = storage.blocks.lock().unwrap().clone();
+            let mut finalized = vec![];
+            for id in ids {
+                let path = block_layout::block_path(&storage.block_directory, &id);
+                // The replica may have been deleted in the meantime
+                if let Ok(metadata) = fs::metadata(path).await {
+                    let block = Block::new(id, metadata.len(), 0);
+                    finalized.push(Block {
+                        generation: self.generation(&block, index).await,
+                        ..block
+                    });
+                }
+            }
+            let being_written = storage
+                .being_written
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(id, len)| Block::new(*id, *len, 0))
+                .collect();
+            reports.push(StorageBlocks {
+                storage,
+                finalized,
+                being_written,
+            });
+        }
+        Ok(reports)
+    }
+
+    /// Settles the replicas found being written at startup once the
+    /// namenode has seen them: the ones of the `complete` blocks are
+    /// finalized with the block's generation, the ones of the blocks
+    /// `under_construction`, whose files are still being written, are kept,
+    /// and the others deleted. A replica that cannot be settled is kept, for
+    /// the next start to settle it.
+    pub(crate) async fn recover_being_written(
+        &self,
+        complete: &[Block],
+        under_construction: &[Block],
+    ) {
+        for (index, storage) in self.storages.iter().enumerate() {
+            let being_written = storage.being_written.lock().unwrap().clone();
+            for (id, len) in being_written {
+                if under_construction.iter().any(|block| block.id == id) {
+                    info!("Keeping replica of block {} under construction", id);
+                    continue;
+                }
+                let block = Block::new(id, len, 0);
+                let settled = match complete.iter().find(|complete| complete.id == id) {
+                    // Nothing computed the checksum of the data written
+                    // before the restart
+                    Some(complete) => {
+                        info!("Recovered replica of block {}", id);
+                        match self.insert_in_progress_block(&block, index) {
+                            Ok(()) => self.finalize(&block, complete.generation, None).await,
+                            Err(err) => Err(err),
+                        }
+                    }
+                    None => {
+                        info!("Deleting incomplete replica of block {}", id);
+                        fs::remove_file(self.temp_path(&block, index))
+                            .await
+                            .map(|_| storage.remove_dfs_used(len))
+                            .map_err(CuddlyError::from)
+                    }
+                };
+                match settled {
+                    Ok(()) => {
+                        storage.being_written.lock().unwrap().remove(&id);
+                    }
+                    Err(err) => warn!("Failed to recover replica of block {}: {}", id, err),
+                }
+            }
+        }
+    }
+
+    /// Forgets the replica of `block` found being written at startup, as it
+    /// is written again.
+    async fn discard_being_written(&self, block: &Block) {
+        for (index, storage) in self.storages.iter().enumerate() {
+            let Some(len) = storage.being_written.lock().unwrap().remove(&block.id) else {
+                continue;
+            };
+            storage.remove_dfs_used(len);
+            if self.blocks_being_created.lock().unwrap().get(block) != Some(&index) {
+                let _ = fs::remove_file(self.temp_path(block, index)).await;
+            }
+        }
+    }
+
+    /// Removes the replica of a finalized block.
+    pub(crate) async fn delete_block(&self, block: &Block) -> CuddlyResult<()> {
+        let index = self
+            .find_storage(block)
+            .ok_or_else(|| CuddlyError::IOError(format!("Block {:?} does not exist", block)))?;
+        self.delete_replica(block, index).await
+    }
+
+    async fn delete_replica(&self, block: &Block, index: usize) -> CuddlyResult<()> {
+        let path = self.block_path(block, index);
+        let len = fs::metadata(&path)
+            .await
+            .map_or(0, |metadata| metadata.len());
+        fs::remove_file(&path).await.map_err(|err| {
+            CuddlyError::IOError(format!("Failed to delete block file {:?}: {}", path, err))
+        })?;
+        let _ = fs::remove_file(block_layout::meta_path(
+            &self.storages[index].block_directory,
+            &block.id,
+        ))
+        .await;
+        self.storages[index]
+            .blocks
+            .lock()
+            .unwrap()
+            .remove(&block.id);
+        self.storages[index].remove_dfs_used(len);
+        Ok(())
+    }
+
+    /// Shortens the replica of a finalized block to the block's length, and
+    /// gives it the block's generation. The shortened data is copied, and the
+    /// copy finalized in place of the replica.
+    pub(crate) async fn truncate_block(&self, block: &Block) -> CuddlyResult<()> {
+        let index = self
+            .find_storage(block)
+            .ok_or_else(|| CuddlyError::IOError(format!("Block {:?} does not exist", block)))?;
+        let path = self.block_path(block, index);
+        let len = fs::metadata(&path)
+            .await
+            .map_err(|_| CuddlyError::IOError(format!("Block {:?} does not exist", block)))?
+            .len();
+        if len < block.len {
             return Err(CuddlyError::IOError(format!(
-                "A creation of block {:?} is already in progress",
-                block
+                "Replica of block {} has only {} bytes",
+                block, len
             )));
         }
-        blocks_being_created.deref_mut().insert(*block);
-        Ok(())
+
+        self.insert_in_progress_block(block, index)?;
+        let temp_path = self.temp_path(block, index);
+        match block_layout::copy_replica(&path, &temp_path, block.len).await {
+            Ok(crc) => {
+                self.storages[index].add_dfs_used(block.len);
+                self.finalize(block, block.generation, Some(crc.finish()))
+                    .await
+            }
+            Err(e) => {
+                self.remove_in_progress_block(block)?;
+                let _ = fs::remove_file(&temp_path).await;
+                Err(e)
+            }
+        }
     }
 
-    fn remove_in_progress_block(&self, block: &Block) -> CuddlyResult<()> {
+    fn insert_in_progress_block(&self, block: &Block, index: usize) -> CuddlyResult<()> {
         let mut blocks_being_created = self.blocks_being_created.lock().unwrap();
-        if !blocks_being_created.deref().contains(block) {
+        if blocks_being_created.deref().contains_key(block) {
             return Err(CuddlyError::IOError(format!(
-                "Block creation for {:?} has not been initiated",
+                "A creation of block {:?} is already in progress",
                 block
             )));
         }
-        blocks_being_created.deref_mut().remove(block);
+        blocks_being_created.deref_mut().insert(*block, index);
         Ok(())
     }
 
-    fn block_exists(&self, block: &Block) -> bool {
-        self.block_directory
-            .join(block.filename())
-            .try_exists()
-            .unwrap_or(false)
+    /// Forgets a block being written and returns the index of its storage.
+    fn remove_in_progress_block(&self, block: &Block) -> CuddlyResult<usize> {
+        let mut blocks_being_created = self.blocks_being_created.lock().unwrap();
+        blocks_being_created
+            .deref_mut()
+            .remove(block)
+            .ok_or_else(|| {
+                CuddlyError::IOError(format!(
+                    "Block creation for {:?} has not been initiated",
+                    block
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn checksum(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.finish()
+    }
+
+    fn volume(dir: &Path) -> StorageConfig {
+        StorageConfig {
+            storage_type: StorageType::Disk,
+            dir: dir.to_path_buf(),
+            reserved_space: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dfs_used() {
+        use tokio::io::AsyncWriteExt;
+
+        let dir = std::env::temp_dir()
+            .join("cuddlyfs")
+            .join(format!("volumes-{}", Uuid::new_v4()));
+        let registry = DatanodeDataRegistry::new(
+            &[volume(&dir)],
+            VolumeChooserKind::RoundRobin,
+            0,
+            Arc::new(FaultInjector::disabled()),
+        )
+        .unwrap();
+        let dfs_used = || registry.storages()[0].report().unwrap().dfs_used;
+
+        let block = Block::new(Uuid::new_v4(), 0, 0);
+        let (mut file, _) = registry
+            .start_block_creation(&block, StorageType::Disk)
+            .await
+            .unwrap();
+        file.write_all(&[1; 100]).await.unwrap();
+        registry.record_write(&block, 100);
+        assert_eq!(dfs_used(), 100);
+        registry
+            .finish_block_creation(&block, checksum(&[1; 100]))
+            .await
+            .unwrap();
+
+        let block = Block::new(block.id, 100, 0);
+        let (mut file, mut crc, _) = registry.start_block_append(&block, 1).await.unwrap();
+        assert_eq!(dfs_used(), 200);
+        file.write_all(&[2; 50]).await.unwrap();
+        registry.record_write(&block, 50);
+        crc.update(&[2; 50]);
+        registry
+            .finish_block_append(&block, 1, crc.finish())
+            .await
+            .unwrap();
+        assert_eq!(dfs_used(), 150);
+
+        let block = Block::new(block.id, 120, 0);
+        registry.truncate_block(&block).await.unwrap();
+        assert_eq!(dfs_used(), 120);
+        assert!(registry.scan_blocks().await.is_empty());
+        registry.delete_block(&block).await.unwrap();
+        assert_eq!(dfs_used(), 0);
+
+        let report = registry.storages()[0].report().unwrap();
+        assert_eq!(
+            report.capacity,
+            report.dfs_used + report.non_dfs_used + report.remaining
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disk_full_fault() {
+        use crate::utils::fault_injection::{FaultAction, FaultPoint, FaultRule};
+
+        let dir = std::env::temp_dir()
+            .join("cuddlyfs")
+            .join(format!("volumes-{}", Uuid::new_v4()));
+        let fault = FaultRule {
+            point: FaultPoint::DatanodeStartBlock,
+            action: FaultAction::DiskFull,
+            after: 1,
+            probability: 1.0,
+            times: Some(1),
+            delay_ms: 0,
+        };
+        let registry = DatanodeDataRegistry::new(
+            &[volume(&dir)],
+            VolumeChooserKind::RoundRobin,
+            0,
+            Arc::new(FaultInjector::new(0, vec![fault])),
+        )
+        .unwrap();
+
+        let blocks = (0..3)
+            .map(|_| Block::new(Uuid::new_v4(), 0, 0))
+            .collect::<Vec<_>>();
+        let mut created = vec![];
+        for block in &blocks {
+            let result = registry
+                .start_block_creation(block, StorageType::Disk)
+                .await;
+            created.push(result.is_ok());
+            if result.is_ok() {
+                registry
+                    .finish_block_creation(block, checksum(&[]))
+                    .await
+                    .unwrap();
+            }
+        }
+        assert_eq!(created, vec![true, false, true]);
+        assert!(registry.find_storage(&blocks[1]).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_volume_failure() {
+        let root = std::env::temp_dir()
+            .join("cuddlyfs")
+            .join(format!("volumes-{}", Uuid::new_v4()));
+        let (first, second) = (root.join("first"), root.join("second"));
+        let registry = DatanodeDataRegistry::new(
+            &[volume(&first), volume(&second)],
+            VolumeChooserKind::RoundRobin,
+            1,
+            Arc::new(FaultInjector::disabled()),
+        )
+        .unwrap();
+
+        let blocks = [
+            Block::new(Uuid::new_v4(), 0, 0),
+            Block::new(Uuid::new_v4(), 0, 0),
+        ];
+        for block in &blocks {
+            registry
+                .start_block_creation(block, StorageType::Disk)
+                .await
+                .unwrap();
+            registry
+                .finish_block_creation(block, checksum(&[]))
+                .await
+                .unwrap();
+        }
+        assert!(registry.check_volumes().await.unwrap().is_empty());
+
+        // A volume whose directory turned into a file can no longer be written
+        std::fs::remove_dir_all(&first).unwrap();
+        std::fs::write(&first, b"").unwrap();
+        let failed = registry.check_volumes().await.unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].1, vec![blocks[0].id]);
+        assert!(registry.storages()[0].report().unwrap().failed);
+        assert!(registry.find_storage(&blocks[0]).is_none());
+        assert!(registry.find_storage(&blocks[1]).is_some());
+
+        // New blocks only go to the working volume
+        let block = Block::new(Uuid::new_v4(), 0, 0);
+        let (_, storage) = registry
+            .start_block_creation(&block, StorageType::Ssd)
+            .await
+            .unwrap();
+        assert_eq!(storage.dir, second);
+        registry.abort_block_creation(&block).await.unwrap();
+
+        std::fs::remove_dir_all(&second).unwrap();
+        std::fs::write(&second, b"").unwrap();
+        assert!(registry.check_volumes().await.is_err());
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
-    pub(crate) fn get_filepath_for_block_id(&self, block_id: &str) -> PathBuf {
-        self.block_directory.join(block_id)
+    #[tokio::test]
+    async fn test_recover_being_written() {
+        use tokio::io::AsyncWriteExt;
+
+        let dir = std::env::temp_dir()
+            .join("cuddlyfs")
+            .join(format!("volumes-{}", Uuid::new_v4()));
+        let open = || {
+            DatanodeDataRegistry::new(
+                &[volume(&dir)],
+                VolumeChooserKind::RoundRobin,
+                0,
+                Arc::new(FaultInjector::disabled()),
+            )
+            .unwrap()
+        };
+        let registry = open();
+        let blocks = [
+            Block::new(Uuid::new_v4(), 0, 0),
+            Block::new(Uuid::new_v4(), 0, 0),
+            Block::new(Uuid::new_v4(), 0, 0),
+            Block::new(Uuid::new_v4(), 0, 0),
+        ];
+        for block in &blocks {
+            let (mut file, _) = registry
+                .start_block_creation(block, StorageType::Disk)
+                .await
+                .unwrap();
+            file.write_all(&[1; 10]).await.unwrap();
+            file.flush().await.unwrap();
+        }
+
+        // The datanode restarts in the middle of all writes
+        let registry = open();
+        let reports = registry.list_blocks().await.unwrap();
+        assert!(reports[0].finalized.is_empty());
+        assert_eq!(reports[0].being_written.len(), 4);
+        assert_eq!(registry.storages()[0].report().unwrap().dfs_used, 40);
+
+        // The replica of the last block cannot be deleted
+        let temp_path = registry.temp_path(&blocks[3], 0);
+        std::fs::remove_file(&temp_path).unwrap();
+        std::fs::create_dir(&temp_path).unwrap();
+        let complete = Block {
+            generation: 5,
+            ..Block::new(blocks[0].id, 10, 0)
+        };
+        registry
+            .recover_being_written(&[complete], &[blocks[2]])
+            .await;
+        assert!(registry.find_storage(&blocks[0]).is_some());
+        assert!(registry.find_storage(&blocks[1]).is_none());
+        let reports = registry.list_blocks().await.unwrap();
+        assert_eq!(reports[0].finalized[0].generation, 5);
+        let mut kept: Vec<_> = reports[0].being_written.iter().map(|b| b.id).collect();
+        kept.sort();
+        let mut expected = vec![blocks[2].id, blocks[3].id];
+        expected.sort();
+        assert_eq!(kept, expected);
+        assert_eq!(registry.storages()[0].report().unwrap().dfs_used, 30);
+
+        // Both are settled by the next start
+        std::fs::remove_dir(&temp_path).unwrap();
+        std::fs::write(&temp_path, [1; 10]).unwrap();
+        let registry = open();
+        registry.recover_being_written(&[], &[]).await;
+        let reports = registry.list_blocks().await.unwrap();
+        assert!(reports[0].being_written.is_empty());
+        assert_eq!(registry.storages()[0].report().unwrap().dfs_used, 10);
+
+        // A finalized replica can only be written again with the same length
+        assert!(registry
+            .start_block_creation(&blocks[0], StorageType::Disk)
+            .await
+            .is_err());
+        let block = Block {
+            generation: 4,
+            ..complete
+        };
+        assert!(registry
+            .start_block_creation(&block, StorageType::Disk)
+            .await
+            .is_err());
+        let block = Block {
+            generation: 6,
+            ..complete
+        };
+        let (mut file, _) = registry
+            .start_block_creation(&block, StorageType::Disk)
+            .await
+            .unwrap();
+        file.write_all(&[2; 10]).await.unwrap();
+        registry.record_write(&block, 10);
+        registry
+            .finish_block_creation(&block, checksum(&[2; 10]))
+            .await
+            .unwrap();
+        assert_eq!(registry.storages()[0].report().unwrap().dfs_used, 10);
+        let reports = registry.list_blocks().await.unwrap();
+        assert_eq!(reports[0].finalized[0].generation, 6);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stale_generation() {
+        use tokio::io::AsyncWriteExt;
+
+        let dir = std::env::temp_dir()
+            .join("cuddlyfs")
+            .join(format!("volumes-{}", Uuid::new_v4()));
+        let open = || {
+            DatanodeDataRegistry::new(
+                &[volume(&dir)],
+                VolumeChooserKind::RoundRobin,
+                0,
+                Arc::new(FaultInjector::disabled()),
+            )
+            .unwrap()
+        };
+        let registry = open();
+        let block = Block {
+            generation: 5,
+            ..Block::new(Uuid::new_v4(), 0, 0)
+        };
+        let (mut file, _) = registry
+            .start_block_creation(&block, StorageType::Disk)
+            .await
+            .unwrap();
+        file.write_all(&[1; 10]).await.unwrap();
+        registry
+            .finish_block_creation(&block, checksum(&[1; 10]))
+            .await
+            .unwrap();
+
+        // An append needs a newer generation than the replica's
+        let block = Block { len: 10, ..block };
+        for generation in [4, 5] {
+            assert!(registry
+                .start_block_append(&block, generation)
+                .await
+                .is_err());
+        }
+        let (mut file, mut crc, _) = registry.start_block_append(&block, 6).await.unwrap();
+        file.write_all(&[2; 5]).await.unwrap();
+        crc.update(&[2; 5]);
+        registry
+            .finish_block_append(&block, 6, crc.finish())
+            .await
+            .unwrap();
+
+        // The generation of the replica is kept across restarts
+        let registry = open();
+        let block = Block { len: 15, ..block };
+        assert!(registry.start_block_append(&block, 6).await.is_err());
+        assert!(registry
+            .start_block_creation(&block, StorageType::Disk)
+            .await
+            .is_err());
+        let reports = registry.list_blocks().await.unwrap();
+        assert_eq!(reports[0].finalized[0].generation, 6);
+        // The checksum covers the data before the append too
+        assert!(registry.scan_blocks().await.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scan_blocks() {
+        use tokio::io::AsyncWriteExt;
+
+        let dir = std::env::temp_dir()
+            .join("cuddlyfs")
+            .join(format!("volumes-{}", Uuid::new_v4()));
+        let registry = DatanodeDataRegistry::new(
+            &[volume(&dir)],
+            VolumeChooserKind::RoundRobin,
+            0,
+            Arc::new(FaultInjector::disabled()),
+        )
+        .unwrap();
+        let blocks = [
+            Block::new(Uuid::new_v4(), 0, 0),
+            Block::new(Uuid::new_v4(), 0, 0),
+            Block::new(Uuid::new_v4(), 0, 0),
+        ];
+        for block in &blocks {
+            let (mut file, _) = registry
+                .start_block_creation(block, StorageType::Disk)
+                .await
+                .unwrap();
+            file.write_all(&[1; 10]).await.unwrap();
+            registry.record_write(block, 10);
+            registry
+                .finish_block_creation(block, checksum(&[1; 10]))
+                .await
+                .unwrap();
+        }
+        let root = &registry.storages()[0].block_directory;
+        // The data of one replica changed on disk, and one has no checksum
+        std::fs::write(registry.block_path(&blocks[0], 0), [2; 10]).unwrap();
+        let meta = ReplicaMeta::read(root, &blocks[1].id).await.unwrap();
+        ReplicaMeta {
+            checksum: None,
+            ..meta
+        }
+        .write(root)
+        .await
+        .unwrap();
+
+        let corrupt = registry.scan_blocks().await;
+        assert_eq!(corrupt.len(), 1);
+        assert_eq!(corrupt[0].1, vec![blocks[0].id]);
+        assert!(registry.find_storage(&blocks[0]).is_none());
+        assert!(!registry.block_path(&blocks[0], 0).exists());
+        assert_eq!(registry.storages()[0].report().unwrap().dfs_used, 20);
+        assert_eq!(ReplicaMeta::read(root, &blocks[1].id).await.unwrap(), meta);
+        assert!(registry.scan_blocks().await.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
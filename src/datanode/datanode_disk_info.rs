@@ -1,26 +1,45 @@
-use std::{path::PathBuf, process::Command};
+use std::{ffi::CString, os::unix::ffi::OsStrExt, path::PathBuf};
 
 use chrono::{DateTime, Duration, Utc};
 use log::info;
 
-use crate::{errors::CuddlyResult, APP_CONFIG};
+use crate::{
+    errors::{CuddlyError, CuddlyResult},
+    APP_CONFIG,
+};
+
+/// Space of a volume as reported to the namenode. `capacity` is the size of
+/// the filesystem less the reserved space, and splits into the other three.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct DiskUsage {
+    pub(crate) capacity: u64,
+    pub(crate) dfs_used: u64,
+    pub(crate) non_dfs_used: u64,
+    pub(crate) remaining: u64,
+}
 
 #[derive(Clone, Debug)]
 pub(crate) struct DiskInfo {
     data_dir: PathBuf,
-    used: u64,
+    // Bytes kept free for other uses of the filesystem
+    reserved: u64,
+    // Bytes of the block files on the volume, finalized or being written
+    dfs_used: u64,
+    // Size of the filesystem and bytes available to unprivileged users
+    total: u64,
     available: u64,
     last_update: DateTime<Utc>,
     update_interval: Duration,
 }
 
-#[allow(dead_code)]
 impl DiskInfo {
-    pub fn new(data_dir: &PathBuf) -> CuddlyResult<Self> {
+    pub fn new(data_dir: &PathBuf, reserved: u64, dfs_used: u64) -> CuddlyResult<Self> {
         info!("Creating DiskInfo for {:?}", data_dir);
         let mut disk_info = Self {
             data_dir: data_dir.clone(),
-            used: 0,
+            reserved,
+            dfs_used,
+            total: 0,
             available: 0,
             last_update: Utc::now(),
             update_interval: Duration::seconds(APP_CONFIG.datanode.disk_check_interval as i64),
@@ -34,19 +53,7 @@ impl DiskInfo {
 
     fn refresh(&mut self, force: bool) -> CuddlyResult<()> {
         if force || self.needs_update() {
-            let output = Command::new("df")
-                .arg("-k")
-                .arg("--output=used,avail")
-                .arg(&self.data_dir)
-                .output()?;
-
-            let output = String::from_utf8_lossy(&output.stdout);
-            let mut lines = output.lines();
-            lines.next();
-            let line = lines.next().unwrap();
-            let mut columns = line.split_whitespace();
-            self.used = columns.next().unwrap().parse().unwrap();
-            self.available = columns.next().unwrap().parse().unwrap();
+            (self.total, self.available) = statvfs(&self.data_dir)?;
             self.last_update = Utc::now();
         }
         Ok(())
@@ -56,28 +63,105 @@ impl DiskInfo {
         Utc::now().signed_duration_since(self.last_update) > self.update_interval
     }
 
-    pub(crate) fn get_used(&mut self) -> CuddlyResult<u64> {
-        self.refresh(false)?;
-        Ok(self.used)
+    pub(crate) fn add_dfs_used(&mut self, bytes: u64) {
+        self.dfs_used += bytes;
+    }
+
+    pub(crate) fn remove_dfs_used(&mut self, bytes: u64) {
+        self.dfs_used = self.dfs_used.saturating_sub(bytes);
     }
 
-    pub(crate) fn get_available(&mut self) -> CuddlyResult<u64> {
+    pub(crate) fn usage(&mut self) -> CuddlyResult<DiskUsage> {
         self.refresh(false)?;
-        Ok(self.available)
+        Ok(self.compute_usage())
+    }
+
+    fn compute_usage(&self) -> DiskUsage {
+        let capacity = self.total.saturating_sub(self.reserved);
+        let dfs_used = self.dfs_used.min(capacity);
+        let remaining = self
+            .available
+            .saturating_sub(self.reserved)
+            .min(capacity - dfs_used);
+        DiskUsage {
+            capacity,
+            dfs_used,
+            non_dfs_used: capacity - dfs_used - remaining,
+            remaining,
+        }
     }
 }
 
+/// Size in bytes of the filesystem holding `path`, and the bytes available
+/// on it to unprivileged users.
+fn statvfs(path: &PathBuf) -> CuddlyResult<(u64, u64)> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| CuddlyError::IOError(format!("Invalid path {:?}", path)))?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `c_path` is a valid C string and `stat` is only read once
+    // statvfs has filled it in.
+    let stat = unsafe {
+        if libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        stat.assume_init()
+    };
+    let fragment_size = stat.f_frsize as u64;
+    Ok((
+        stat.f_blocks as u64 * fragment_size,
+        stat.f_bavail as u64 * fragment_size,
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_disk_info() {
-        let mut disk_info = DiskInfo::new(&PathBuf::from("/tmp/cuddlyfs/datanode")).unwrap();
+        let mut disk_info = DiskInfo::new(&PathBuf::from("/tmp/cuddlyfs/datanode"), 0, 0).unwrap();
         disk_info.refresh(true).unwrap();
-        let used = disk_info.get_used().unwrap();
-        let available = disk_info.get_available().unwrap();
-        assert!(used > 0);
-        assert!(available > 0);
+        let usage = disk_info.usage().unwrap();
+        assert!(usage.capacity > 0);
+        assert!(usage.remaining > 0);
+        assert_eq!(
+            usage.capacity,
+            usage.dfs_used + usage.non_dfs_used + usage.remaining
+        );
+    }
+
+    #[test]
+    fn test_usage_with_reserved_space() {
+        let mut disk_info = DiskInfo {
+            data_dir: PathBuf::from("/tmp/cuddlyfs/datanode"),
+            reserved: 100,
+            dfs_used: 300,
+            total: 1000,
+            available: 500,
+            last_update: Utc::now(),
+            update_interval: Duration::seconds(3000),
+        };
+        assert_eq!(
+            disk_info.usage().unwrap(),
+            DiskUsage {
+                capacity: 900,
+                dfs_used: 300,
+                non_dfs_used: 200,
+                remaining: 400,
+            }
+        );
+
+        disk_info.remove_dfs_used(500);
+        disk_info.add_dfs_used(50);
+        disk_info.available = 50;
+        assert_eq!(
+            disk_info.usage().unwrap(),
+            DiskUsage {
+                capacity: 900,
+                dfs_used: 50,
+                non_dfs_used: 850,
+                remaining: 0,
+            }
+        );
     }
 }
@@ -1,13 +1,22 @@
-use std::{env, net::SocketAddr, sync::Arc};
+use std::{
+    env,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use crate::{
+    block::{Block, BlockGroup},
     config::APP_CONFIG,
     cuddlyproto::{self},
     errors::{CuddlyError, CuddlyResult},
+    utils::fault_injection::FaultInjector,
 };
 
 use chrono::Utc;
-use datanode_data_handler::DatanodeDataHandler;
+use datanode_data_handler::{DatanodeDataHandler, ReceivedBlock};
 use log::{error, info, warn};
 use tokio::{
     net::{TcpListener, TcpStream},
@@ -17,17 +26,36 @@ use tokio_util::sync::CancellationToken;
 use tonic::transport::Channel;
 use uuid::Uuid;
 
-use self::cuddlyproto::{node_service_client::NodeServiceClient, StorageReportProto};
+use self::cuddlyproto::node_service_client::NodeServiceClient;
 
+mod block_layout;
 mod datanode_data_handler;
 mod datanode_data_registry;
 mod datanode_disk_info;
+pub(crate) mod volume_chooser;
+
+/// The TCP data transfer port of a datanode is its RPC port plus this offset.
+pub(crate) const DATA_TRANSFER_PORT_OFFSET: u16 = 10000;
+
+/// Turns the RPC socket address a datanode registered with into the address
+/// of its data transfer server.
+fn data_transfer_address(socket_address: &str) -> CuddlyResult<String> {
+    let (host, port) = socket_address
+        .rsplit_once(':')
+        .ok_or_else(|| CuddlyError::IOError(format!("Invalid address {}", socket_address)))?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| CuddlyError::IOError(format!("Invalid port in {}", socket_address)))?;
+    Ok(format!("{}:{}", host, port + DATA_TRANSFER_PORT_OFFSET))
+}
 
 #[derive(Clone, Debug)]
 pub struct Datanode {
     pub datanode_id: cuddlyproto::DatanodeIdProto,
     datanode_data_registry: Arc<datanode_data_registry::DatanodeDataRegistry>,
     node_service_client: NodeServiceClient<Channel>,
+    fault_injector: Arc<FaultInjector>,
+    being_written_recovered: Arc<AtomicBool>,
     cancel_token: CancellationToken,
     shutdown_send: mpsc::UnboundedSender<i8>,
 }
@@ -45,6 +73,21 @@ impl Datanode {
         // let socket = SocketAddr::new(local_ip_address::local_ip().unwrap(), port);
         let socket = SocketAddr::new(local_ip_address::local_ip().unwrap(), port);
         info!("Datanode socket address: {}", socket);
+        let fault_injector = Arc::new(FaultInjector::from_config(&APP_CONFIG));
+        // Opening the volumes scans their directories
+        let datanode_data_registry = tokio::task::spawn_blocking({
+            let fault_injector = Arc::clone(&fault_injector);
+            move || {
+                datanode_data_registry::DatanodeDataRegistry::new(
+                    &APP_CONFIG.datanode.storages(),
+                    APP_CONFIG.datanode.volume_chooser,
+                    APP_CONFIG.datanode.failed_volumes_tolerated,
+                    fault_injector,
+                )
+            }
+        })
+        .await
+        .map_err(|e| CuddlyError::IOError(format!("Could not open the volumes: {}", e)))??;
 
         Ok(Datanode {
             datanode_id: cuddlyproto::DatanodeIdProto {
@@ -59,9 +102,7 @@ impl Datanode {
                 ipc_port: 50020,
                 info_secure_port: 50070,
             },
-            datanode_data_registry: Arc::new(datanode_data_registry::DatanodeDataRegistry::new(
-                &APP_CONFIG.datanode.data_dir,
-            )?),
+            datanode_data_registry: Arc::new(datanode_data_registry),
             node_service_client: NodeServiceClient::connect(
                 APP_CONFIG.datanode.namenode_rpc_address.clone(),
             )
@@ -72,13 +113,15 @@ impl Datanode {
                     APP_CONFIG.datanode.namenode_rpc_address, err
                 ))
             })?,
+            fault_injector,
+            being_written_recovered: Arc::new(AtomicBool::new(false)),
             cancel_token,
             shutdown_send,
         })
     }
 
     pub async fn run(self) -> CuddlyResult<()> {
-        let (received_block_tx, received_block_rx) = mpsc::channel::<cuddlyproto::Block>(8);
+        let (received_block_tx, received_block_rx) = mpsc::channel::<ReceivedBlock>(8);
         tokio::select! {
             n_res = self.run_namenode_services(received_block_rx) => {
                 if let Err(e) = n_res {
@@ -92,6 +135,7 @@ impl Datanode {
                     return Err(CuddlyError::RPCError(e.to_string()));
                 }
             },
+            _ = self.run_block_scanner() => {},
             _ = self.cancel_token.cancelled() => {
                 warn!("Datanode Shutting down...");
             },
@@ -107,7 +151,7 @@ impl Datanode {
 
     async fn run_client_services(
         &self,
-        received_block_tx: tokio::sync::mpsc::Sender<cuddlyproto::Block>,
+        received_block_tx: tokio::sync::mpsc::Sender<ReceivedBlock>,
     ) -> CuddlyResult<()> {
         // let listener = TcpListener::bind(format!(
         //     "{}:{}",
@@ -123,7 +167,7 @@ impl Datanode {
                 .expect("Could not get port")
                 .parse::<u16>()
                 .expect("Could not parse port")
-                + 10000
+                + DATA_TRANSFER_PORT_OFFSET
         );
         let listener = TcpListener::bind(bind_addr.clone()).await?;
         info!("Listening on {} for TCP requests", bind_addr);
@@ -147,14 +191,20 @@ impl Datanode {
     fn handle_incoming_request(
         &self,
         tcp_stream: TcpStream,
-        block_sender: tokio::sync::mpsc::Sender<cuddlyproto::Block>,
+        block_sender: tokio::sync::mpsc::Sender<ReceivedBlock>,
     ) {
         let data_registry = Arc::clone(&self.datanode_data_registry);
         let packet_size = APP_CONFIG.packet_size;
+        let fault_injector = Arc::clone(&self.fault_injector);
 
         tokio::spawn(async move {
-            let mut handler =
-                DatanodeDataHandler::new(tcp_stream, data_registry, packet_size, block_sender);
+            let mut handler = DatanodeDataHandler::new(
+                tcp_stream,
+                data_registry,
+                packet_size,
+                block_sender,
+                fault_injector,
+            );
             match handler.handle().await {
                 Ok(()) => (),
                 Err(e) => error!(
@@ -167,18 +217,39 @@ impl Datanode {
 
     async fn run_namenode_services(
         &self,
-        mut received_block_rx: tokio::sync::mpsc::Receiver<cuddlyproto::Block>,
+        mut received_block_rx: tokio::sync::mpsc::Receiver<ReceivedBlock>,
     ) -> CuddlyResult<()> {
         let mut heartbeat_interval = tokio::time::interval(std::time::Duration::from_secs(3));
+        let mut block_report_interval = tokio::time::interval(std::time::Duration::from_secs(
+            APP_CONFIG.datanode.block_report_interval,
+        ));
+        // The first block report is sent once the namenode knows this datanode.
+        block_report_interval.reset();
+        let mut volume_check_interval = tokio::time::interval(std::time::Duration::from_secs(
+            APP_CONFIG.datanode.volume_check_interval,
+        ));
+        let mut block_report_sent = false;
         let mut consecutive_errors = 0;
 
         loop {
             tokio::select! {
                 _ = heartbeat_interval.tick() => {
                     match self.send_heartbeat().await {
-                        Ok(_) => {
+                        Ok(response) => {
                             // info!("Heartbeat sent successfully");
+                            let response = response.into_inner();
+                            if let Some(status) = response.status.as_ref().filter(|status| {
+                                status.code == cuddlyproto::StatusEnum::EPerm as i32
+                            }) {
+                                error!("Namenode rejected this datanode: {}, initiating shutdown...", status.message);
+                                self.shutdown_send.send(1).unwrap();
+                                continue;
+                            }
                             consecutive_errors = 0;
+                            self.handle_commands(response.commands);
+                            if !block_report_sent {
+                                block_report_sent = self.send_block_report().await.is_ok();
+                            }
                         }
                         Err(e) => {
                             warn!("Failed to send heartbeat: {:?}", e);
@@ -191,6 +262,15 @@ impl Datanode {
                         }
                     }
                 },
+                _ = block_report_interval.tick() => {
+                    block_report_sent = self.send_block_report().await.is_ok();
+                },
+                _ = volume_check_interval.tick() => {
+                    if let Err(e) = self.check_volumes().await {
+                        error!("{}, initiating shutdown...", e);
+                        self.shutdown_send.send(1).unwrap();
+                    }
+                },
                 block = received_block_rx.recv() => {
                     match self.handle_received_block(block).await {
                         Ok(_) => (),
@@ -229,31 +309,229 @@ impl Datanode {
                     all_keys: vec![],
                 }),
                 software_version: "0.1.0".to_string(),
+                network_location: APP_CONFIG.datanode.network_location.clone(),
             }),
-            reports: vec![StorageReportProto {
-                storage: None,
-                failed: false,
-                capacity: self.datanode_data_registry.total().unwrap(),
-                dfs_used: self.datanode_data_registry.used().unwrap(),
-                remaining: self.datanode_data_registry.available().unwrap(),
-                block_pool_used: 0,
-                non_dfs_used: 0,
-                mount: "/".to_string(),
-            }],
+            reports: self
+                .datanode_data_registry
+                .storages()
+                .iter()
+                .map(|storage| storage.report())
+                .collect::<CuddlyResult<Vec<_>>>()?,
         });
 
         let mut client = self.get_node_service_client()?;
 
-        let response = client.heartbeat(req).await.unwrap();
+        let response = client.heartbeat(req).await?;
         Ok(response)
     }
 
-    async fn handle_received_block(&self, block: Option<cuddlyproto::Block>) -> CuddlyResult<()> {
-        if let Some(block) = block {
+    async fn send_block_report(&self) -> CuddlyResult<()> {
+        let reports = self.datanode_data_registry.list_blocks().await?;
+        info!(
+            "Sending block report with {} blocks",
+            reports
+                .iter()
+                .map(|report| report.finalized.len())
+                .sum::<usize>()
+        );
+        let request = tonic::Request::new(cuddlyproto::BlockReportRequest {
+            address: self.datanode_id.socket_addr.to_string(),
+            reports: reports
+                .into_iter()
+                .map(|report| cuddlyproto::StorageBlockReportProto {
+                    storage: Some(report.storage.to_proto()),
+                    blocks: report.finalized.into_iter().map(Into::into).collect(),
+                    being_written: report.being_written.into_iter().map(Into::into).collect(),
+                })
+                .collect(),
+        });
+        let mut client = self.get_node_service_client()?;
+        let response = match client.block_report(request).await {
+            Ok(response) => response.into_inner(),
+            Err(e) => {
+                warn!("Failed to send block report: {}", e);
+                return Err(e.into());
+            }
+        };
+        // Only the first report after the start settles the replicas found
+        // being written, the later ones would see replicas written since
+        if !self.being_written_recovered.swap(true, Ordering::SeqCst) {
+            let complete = response
+                .complete
+                .into_iter()
+                .map(Into::into)
+                .collect::<Vec<_>>();
+            let under_construction = response
+                .under_construction
+                .into_iter()
+                .map(Into::into)
+                .collect::<Vec<_>>();
+            self.datanode_data_registry
+                .recover_being_written(&complete, &under_construction)
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Checks the volumes and tells the namenode about the replicas lost
+    /// with the ones that failed. Fails if too many volumes have failed.
+    async fn check_volumes(&self) -> CuddlyResult<()> {
+        for (storage, block_ids) in self.datanode_data_registry.check_volumes().await? {
+            warn!("Lost {} blocks with a failed volume", block_ids.len());
+            self.report_lost_blocks(storage, &block_ids).await?;
+        }
+        Ok(())
+    }
+
+    /// Scans the replicas every `block_scan_interval` seconds, apart from
+    /// the other services as a scan reads every replica, and tells the
+    /// namenode about the corrupt ones.
+    async fn run_block_scanner(&self) {
+        let mut block_scan_interval = tokio::time::interval(std::time::Duration::from_secs(
+            APP_CONFIG.datanode.block_scan_interval,
+        ));
+        loop {
+            block_scan_interval.tick().await;
+            for (storage, block_ids) in self.datanode_data_registry.scan_blocks().await {
+                warn!("Deleted {} corrupt replicas", block_ids.len());
+                if let Err(e) = self.report_lost_blocks(storage, &block_ids).await {
+                    warn!("Failed to report corrupt replicas: {}", e);
+                }
+            }
+        }
+    }
+
+    async fn report_lost_blocks(
+        &self,
+        storage: &datanode_data_registry::Storage,
+        block_ids: &[Uuid],
+    ) -> CuddlyResult<()> {
+        let request = tonic::Request::new(cuddlyproto::BlocksLostRequest {
+            address: self.datanode_id.socket_addr.to_string(),
+            storage: Some(storage.to_proto()),
+            block_ids: block_ids.iter().map(|id| id.to_string()).collect(),
+        });
+        let mut client = self.get_node_service_client()?;
+        if let Err(e) = client.blocks_lost(request).await {
+            warn!("Failed to report lost blocks: {}", e);
+        }
+        Ok(())
+    }
+
+    fn handle_commands(&self, commands: Vec<cuddlyproto::DatanodeCommandProto>) {
+        use cuddlyproto::datanode_command_proto::CommandType;
+
+        for command in commands {
+            let Some(block) = command.block else {
+                warn!("Ignoring command without a block: {:?}", command);
+                continue;
+            };
+            let block: Block = block.into();
+            match CommandType::try_from(command.command_type) {
+                Ok(CommandType::ReplicateBlock) => {
+                    let targets = match command
+                        .targets
+                        .iter()
+                        .map(|target| data_transfer_address(target))
+                        .collect::<CuddlyResult<Vec<_>>>()
+                    {
+                        Ok(targets) => targets,
+                        Err(e) => {
+                            error!("Invalid targets for block {}: {}", block, e);
+                            continue;
+                        }
+                    };
+                    let storage_types = command.storage_types;
+                    let data_registry = Arc::clone(&self.datanode_data_registry);
+                    tokio::spawn(async move {
+                        if let Err(e) = datanode_data_handler::transfer_block(
+                            &data_registry,
+                            &block,
+                            &targets,
+                            &storage_types,
+                            APP_CONFIG.packet_size,
+                        )
+                        .await
+                        {
+                            error!("Failed to replicate block {}: {:?}", block, e);
+                        }
+                    });
+                }
+                Ok(CommandType::DeleteBlock) => {
+                    let data_registry = Arc::clone(&self.datanode_data_registry);
+                    tokio::spawn(async move {
+                        match data_registry.delete_block(&block).await {
+                            Ok(()) => info!("Deleted block {}", block),
+                            Err(e) => error!("Failed to delete block {}: {:?}", block, e),
+                        }
+                    });
+                }
+                Ok(CommandType::TruncateBlock) => {
+                    let data_registry = Arc::clone(&self.datanode_data_registry);
+                    tokio::spawn(async move {
+                        match data_registry.truncate_block(&block).await {
+                            Ok(()) => info!("Truncated block {}", block),
+                            Err(e) => error!("Failed to truncate block {}: {:?}", block, e),
+                        }
+                    });
+                }
+                Ok(CommandType::ReconstructBlock) => {
+                    let rebuilt = command
+                        .block_group
+                        .ok_or_else(|| CuddlyError::ProtoError("Missing block group".to_owned()))
+                        .and_then(BlockGroup::from_proto)
+                        .and_then(|(group, locations)| {
+                            let locations = locations
+                                .iter()
+                                .map(|addresses| {
+                                    addresses
+                                        .iter()
+                                        .map(|address| data_transfer_address(address))
+                                        .collect::<CuddlyResult<Vec<_>>>()
+                                })
+                                .collect::<CuddlyResult<Vec<_>>>()?;
+                            let targets = command
+                                .targets
+                                .iter()
+                                .map(|target| data_transfer_address(target))
+                                .collect::<CuddlyResult<Vec<_>>>()?;
+                            Ok((group, locations, targets))
+                        });
+                    let (group, locations, targets) = match rebuilt {
+                        Ok(rebuilt) => rebuilt,
+                        Err(e) => {
+                            error!("Invalid reconstruction of block {}: {}", block, e);
+                            continue;
+                        }
+                    };
+                    let storage_types = command.storage_types;
+                    tokio::spawn(async move {
+                        if let Err(e) = datanode_data_handler::reconstruct_block(
+                            &block,
+                            group,
+                            locations,
+                            &targets,
+                            &storage_types,
+                            APP_CONFIG.packet_size,
+                        )
+                        .await
+                        {
+                            error!("Failed to reconstruct block {}: {:?}", block, e);
+                        }
+                    });
+                }
+                Err(_) => warn!("Unknown command type {}", command.command_type),
+            }
+        }
+    }
+
+    async fn handle_received_block(&self, block: Option<ReceivedBlock>) -> CuddlyResult<()> {
+        if let Some((block, storage)) = block {
             info!("New block received {:?}", block);
             let message = cuddlyproto::BlockReceivedRequest {
                 address: self.datanode_id.socket_addr.to_string(),
                 block: Some(block),
+                storage: Some(storage),
             };
             let mut client = self.get_node_service_client()?;
             tokio::spawn(async move {
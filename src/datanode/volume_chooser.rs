@@ -0,0 +1,78 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use serde::Deserialize;
+
+use crate::namenode::storage_policy::StorageType;
+
+/// The volume choosers that can be selected through the config.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum VolumeChooserKind {
+    #[default]
+    RoundRobin,
+    AvailableSpace,
+}
+
+/// Decides which of the volumes of a storage type a new replica goes to.
+#[derive(Debug)]
+pub(crate) struct VolumeChooser {
+    kind: VolumeChooserKind,
+    // Next volume for each storage type, when choosing round-robin
+    next: Mutex<HashMap<StorageType, usize>>,
+}
+
+impl VolumeChooser {
+    pub(crate) fn new(kind: VolumeChooserKind) -> Self {
+        Self {
+            kind,
+            next: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Chooses among `volumes`, given as their index with the bytes they
+    /// have available, which all are of `storage_type`.
+    pub(crate) fn choose(
+        &self,
+        storage_type: StorageType,
+        volumes: &[(usize, u64)],
+    ) -> Option<usize> {
+        if volumes.is_empty() {
+            return None;
+        }
+        match self.kind {
+            VolumeChooserKind::RoundRobin => {
+                let mut next = self.next.lock().unwrap();
+                let next = next.entry(storage_type).or_default();
+                let (index, _) = volumes[*next % volumes.len()];
+                *next = (*next + 1) % volumes.len();
+                Some(index)
+            }
+            VolumeChooserKind::AvailableSpace => volumes
+                .iter()
+                .max_by_key(|(index, available)| (*available, std::cmp::Reverse(*index)))
+                .map(|(index, _)| *index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose_volume() {
+        let volumes = [(0, 100), (2, 300), (3, 300)];
+
+        let chooser = VolumeChooser::new(VolumeChooserKind::RoundRobin);
+        let chosen = (0..4)
+            .map(|_| chooser.choose(StorageType::Disk, &volumes).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(chosen, vec![0, 2, 3, 0]);
+        assert_eq!(chooser.choose(StorageType::Ssd, &volumes[1..]), Some(2));
+        assert_eq!(chooser.choose(StorageType::Disk, &[]), None);
+
+        let chooser = VolumeChooser::new(VolumeChooserKind::AvailableSpace);
+        assert_eq!(chooser.choose(StorageType::Disk, &volumes), Some(2));
+        assert_eq!(chooser.choose(StorageType::Disk, &volumes[..1]), Some(0));
+    }
+}
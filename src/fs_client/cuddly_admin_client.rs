@@ -0,0 +1,492 @@
+use log::{debug, error, info};
+use tonic::transport::Channel;
+
+use crate::cuddlyproto;
+use crate::cuddlyproto::admin_service_client::AdminServiceClient;
+use crate::errors::{CuddlyError, CuddlyResult};
+
+/// Administrative changes that can be made to a datanode.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DatanodeAdminAction {
+    Decommission,
+    Maintenance,
+    Recommission,
+}
+
+impl From<DatanodeAdminAction> for cuddlyproto::set_datanode_admin_state_request::AdminAction {
+    fn from(value: DatanodeAdminAction) -> Self {
+        match value {
+            DatanodeAdminAction::Decommission => Self::Decommission,
+            DatanodeAdminAction::Maintenance => Self::Maintenance,
+            DatanodeAdminAction::Recommission => Self::Recommission,
+        }
+    }
+}
+
+/// The kinds of quota a directory can have.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum QuotaType {
+    /// Number of files and directories, including the directory itself.
+    Namespace,
+    /// Bytes, counting every replica.
+    Space,
+}
+
+impl From<QuotaType> for cuddlyproto::set_quota_request::QuotaType {
+    fn from(value: QuotaType) -> Self {
+        match value {
+            QuotaType::Namespace => Self::Namespace,
+            QuotaType::Space => Self::Space,
+        }
+    }
+}
+
+/// Progress of the namenode's balancer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BalancerStatus {
+    pub enabled: bool,
+    pub threshold: f64,
+    pub average_utilization: f64,
+    pub over_utilized_datanodes: u32,
+    pub under_utilized_datanodes: u32,
+    pub moves_in_progress: u32,
+    pub moves_completed: u64,
+    pub moves_failed: u64,
+    pub bytes_moved: u64,
+    pub iterations: u64,
+}
+
+impl From<cuddlyproto::BalancerStatusProto> for BalancerStatus {
+    fn from(value: cuddlyproto::BalancerStatusProto) -> Self {
+        Self {
+            enabled: value.enabled,
+            threshold: value.threshold,
+            average_utilization: value.average_utilization,
+            over_utilized_datanodes: value.over_utilized_datanodes,
+            under_utilized_datanodes: value.under_utilized_datanodes,
+            moves_in_progress: value.moves_in_progress,
+            moves_completed: value.moves_completed,
+            moves_failed: value.moves_failed,
+            bytes_moved: value.bytes_moved,
+            iterations: value.iterations,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SafeModeAction {
+    Get,
+    Enter,
+    Leave,
+}
+
+impl From<SafeModeAction> for cuddlyproto::safe_mode_request::SafeModeAction {
+    fn from(value: SafeModeAction) -> Self {
+        match value {
+            SafeModeAction::Get => Self::Get,
+            SafeModeAction::Enter => Self::Enter,
+            SafeModeAction::Leave => Self::Leave,
+        }
+    }
+}
+
+/// Safe mode state of the namenode and its progress towards leaving it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SafeModeStatus {
+    pub on: bool,
+    pub manual: bool,
+    pub reported_blocks: u64,
+    pub total_blocks: u64,
+    pub live_datanodes: u32,
+    pub threshold: f64,
+    pub min_datanodes: u32,
+}
+
+impl From<cuddlyproto::SafeModeResponse> for SafeModeStatus {
+    fn from(value: cuddlyproto::SafeModeResponse) -> Self {
+        Self {
+            on: value.on,
+            manual: value.manual,
+            reported_blocks: value.reported_blocks,
+            total_blocks: value.total_blocks,
+            live_datanodes: value.live_datanodes,
+            threshold: value.threshold,
+            min_datanodes: value.min_datanodes,
+        }
+    }
+}
+
+/// A datanode as seen by the namenode.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatanodeReport {
+    pub uuid: String,
+    pub address: String,
+    /// One of LIVE, STALE, DEAD, DECOMMISSION_IN_PROGRESS, DECOMMISSIONED
+    /// or IN_MAINTENANCE.
+    pub state: String,
+    pub total_capacity: u64,
+    pub used_capacity: u64,
+}
+
+impl From<cuddlyproto::DatanodeInfo> for DatanodeReport {
+    fn from(value: cuddlyproto::DatanodeInfo) -> Self {
+        Self {
+            state: value.state().as_str_name().to_owned(),
+            uuid: value.datanode_uuid,
+            address: value.socket_address,
+            total_capacity: value.total_capacity,
+            used_capacity: value.used_capacity,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClusterReport {
+    pub datanodes: Vec<DatanodeReport>,
+    pub total_capacity: u64,
+    pub used_capacity: u64,
+    pub total_blocks: u64,
+    pub under_replicated_blocks: u64,
+    pub safe_mode: SafeModeStatus,
+    pub balancer: BalancerStatus,
+    pub storage_types: Vec<StorageTypeReport>,
+}
+
+/// Capacity of the working storages of one type, such as "DISK" or "SSD".
+#[derive(Clone, Debug, PartialEq)]
+pub struct StorageTypeReport {
+    pub storage_type: String,
+    pub capacity: u64,
+    pub used: u64,
+}
+
+impl From<cuddlyproto::StorageTypeReportProto> for StorageTypeReport {
+    fn from(value: cuddlyproto::StorageTypeReportProto) -> Self {
+        Self {
+            storage_type: value.storage_type().as_str_name().to_owned(),
+            capacity: value.capacity,
+            used: value.used,
+        }
+    }
+}
+
+/// The datanodes holding a replica of a block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockLocations {
+    pub block_id: String,
+    pub len: u64,
+    pub datanodes: Vec<String>,
+}
+
+/// The blocks held by a datanode.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatanodeBlocks {
+    pub datanode: DatanodeReport,
+    pub blocks: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RefreshNodesResult {
+    pub included_hosts: u32,
+    pub excluded_hosts: u32,
+    /// UUIDs of the datanodes that started decommissioning.
+    pub decommissioning: Vec<String>,
+    /// UUIDs of the datanodes that left the exclude file.
+    pub recommissioned: Vec<String>,
+}
+
+/// Size of the namespace image saved by a checkpoint.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CheckpointSummary {
+    pub files: u64,
+    pub directories: u64,
+}
+
+/// What fsck does with files that have missing or corrupt blocks.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FsckAction {
+    #[default]
+    None,
+    /// Moves them below `/lost+found`, keeping their path.
+    Move,
+    Delete,
+}
+
+impl From<FsckAction> for cuddlyproto::fsck_request::FsckAction {
+    fn from(value: FsckAction) -> Self {
+        match value {
+            FsckAction::None => Self::None,
+            FsckAction::Move => Self::Move,
+            FsckAction::Delete => Self::Delete,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FsckBlock {
+    pub block_id: String,
+    pub len: u64,
+    /// One of HEALTHY, UNDER_REPLICATED, MISSING or CORRUPT.
+    pub health: String,
+    pub replicas: u32,
+    pub locations: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FsckFile {
+    pub path: String,
+    pub blocks: Vec<FsckBlock>,
+}
+
+/// Health of the blocks below a path.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FsckReport {
+    /// Unhealthy files, or every file if locations were requested.
+    pub files: Vec<FsckFile>,
+    pub total_files: u64,
+    pub total_blocks: u64,
+    pub healthy_blocks: u64,
+    pub under_replicated_blocks: u64,
+    pub missing_blocks: u64,
+    pub corrupt_blocks: u64,
+    pub moved: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+impl FsckReport {
+    /// Whether every block can be read.
+    pub fn is_healthy(&self) -> bool {
+        self.missing_blocks == 0 && self.corrupt_blocks == 0
+    }
+}
+
+impl From<cuddlyproto::FsckResponse> for FsckReport {
+    fn from(value: cuddlyproto::FsckResponse) -> Self {
+        Self {
+            files: value
+                .files
+                .into_iter()
+                .map(|file| FsckFile {
+                    path: file.path,
+                    blocks: file
+                        .blocks
+                        .into_iter()
+                        .map(|entry| {
+                            let health = entry.health().as_str_name().to_owned();
+                            let block = entry.block.unwrap_or_default();
+                            FsckBlock {
+                                block_id: block.id,
+                                len: block.len,
+                                health,
+                                replicas: entry.replicas,
+                                locations: entry.locations,
+                            }
+                        })
+                        .collect(),
+                })
+                .collect(),
+            total_files: value.total_files,
+            total_blocks: value.total_blocks,
+            healthy_blocks: value.healthy_blocks,
+            under_replicated_blocks: value.under_replicated_blocks,
+            missing_blocks: value.missing_blocks,
+            corrupt_blocks: value.corrupt_blocks,
+            moved: value.moved,
+            deleted: value.deleted,
+        }
+    }
+}
+
+/// Client for the namenode's admin service.
+pub struct CuddlyAdminClient {
+    namenode_client: AdminServiceClient<Channel>,
+}
+
+impl CuddlyAdminClient {
+    pub async fn new(namenode_rpc_address: String) -> CuddlyResult<Self> {
+        debug!("Trying to connect to namenode at {}", namenode_rpc_address);
+        match AdminServiceClient::connect(namenode_rpc_address.clone()).await {
+            Ok(client) => {
+                info!("Connected to namenode at {}", namenode_rpc_address);
+                Ok(Self {
+                    namenode_client: client,
+                })
+            }
+            Err(err) => {
+                error!("Could not connect to namenode: {}", err);
+                Err(CuddlyError::RPCError(format!(
+                    "Could not connect to namenode: {}",
+                    err
+                )))
+            }
+        }
+    }
+
+    pub async fn cluster_report(&self) -> CuddlyResult<ClusterReport> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .cluster_report(cuddlyproto::ClusterReportRequest {})
+            .await?
+            .into_inner();
+        Ok(ClusterReport {
+            datanodes: response.datanodes.into_iter().map(|d| d.into()).collect(),
+            total_capacity: response.total_capacity,
+            used_capacity: response.used_capacity,
+            total_blocks: response.total_blocks,
+            under_replicated_blocks: response.under_replicated_blocks,
+            safe_mode: response.safe_mode.unwrap_or_default().into(),
+            balancer: response.balancer.unwrap_or_default().into(),
+            storage_types: response
+                .storage_types
+                .into_iter()
+                .map(|report| report.into())
+                .collect(),
+        })
+    }
+
+    /// Decommissions, maintains or recommissions the datanode with the given
+    /// UUID or address, and returns its new state.
+    pub async fn set_datanode_admin_state(
+        &self,
+        datanode: impl Into<String>,
+        action: DatanodeAdminAction,
+        maintenance_duration_secs: u64,
+    ) -> CuddlyResult<DatanodeReport> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .set_datanode_admin_state(cuddlyproto::SetDatanodeAdminStateRequest {
+                datanode: datanode.into(),
+                action: cuddlyproto::set_datanode_admin_state_request::AdminAction::from(action)
+                    as i32,
+                maintenance_duration_secs,
+            })
+            .await?;
+        response
+            .into_inner()
+            .datanode
+            .map(|datanode| datanode.into())
+            .ok_or_else(|| CuddlyError::RPCError("Response without datanode".to_owned()))
+    }
+
+    pub async fn balancer_status(&self) -> CuddlyResult<BalancerStatus> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .get_balancer_status(cuddlyproto::GetBalancerStatusRequest {})
+            .await?;
+        Ok(response.into_inner().into())
+    }
+
+    pub async fn safe_mode(&self, action: SafeModeAction) -> CuddlyResult<SafeModeStatus> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .safe_mode(cuddlyproto::SafeModeRequest {
+                action: cuddlyproto::safe_mode_request::SafeModeAction::from(action) as i32,
+            })
+            .await?;
+        Ok(response.into_inner().into())
+    }
+
+    /// Saves the namespace image and empties the edit log.
+    pub async fn checkpoint(&self) -> CuddlyResult<CheckpointSummary> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .checkpoint(cuddlyproto::CheckpointRequest {})
+            .await?
+            .into_inner();
+        Ok(CheckpointSummary {
+            files: response.files,
+            directories: response.directories,
+        })
+    }
+
+    /// Returns the block map and the datanode map of the namenode.
+    pub async fn dump_metadata(&self) -> CuddlyResult<(Vec<BlockLocations>, Vec<DatanodeBlocks>)> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .dump_metadata(cuddlyproto::DumpMetadataRequest {})
+            .await?
+            .into_inner();
+        let blocks = response
+            .blocks
+            .into_iter()
+            .map(|entry| {
+                let block = entry.block.unwrap_or_default();
+                BlockLocations {
+                    block_id: block.id,
+                    len: block.len,
+                    datanodes: entry.datanodes,
+                }
+            })
+            .collect();
+        let datanodes = response
+            .datanodes
+            .into_iter()
+            .map(|entry| DatanodeBlocks {
+                datanode: entry.datanode.unwrap_or_default().into(),
+                blocks: entry.blocks,
+            })
+            .collect();
+        Ok((blocks, datanodes))
+    }
+
+    /// Makes the namenode re-read its include and exclude files.
+    pub async fn refresh_nodes(&self) -> CuddlyResult<RefreshNodesResult> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .refresh_nodes(cuddlyproto::RefreshNodesRequest {})
+            .await?
+            .into_inner();
+        Ok(RefreshNodesResult {
+            included_hosts: response.included_hosts,
+            excluded_hosts: response.excluded_hosts,
+            decommissioning: response.decommissioning,
+            recommissioned: response.recommissioned,
+        })
+    }
+
+    /// Sets the log level of the namenode and returns the previous one.
+    pub async fn set_log_level(&self, level: impl Into<String>) -> CuddlyResult<String> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .set_log_level(cuddlyproto::SetLogLevelRequest {
+                level: level.into(),
+            })
+            .await?;
+        Ok(response.into_inner().previous_level)
+    }
+
+    /// Checks the blocks of every file at or below `path`.
+    pub async fn fsck(
+        &self,
+        path: impl Into<String>,
+        list_locations: bool,
+        action: FsckAction,
+    ) -> CuddlyResult<FsckReport> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .fsck(cuddlyproto::FsckRequest {
+                path: path.into(),
+                list_locations,
+                action: cuddlyproto::fsck_request::FsckAction::from(action) as i32,
+            })
+            .await?;
+        Ok(response.into_inner().into())
+    }
+    /// Sets the quota of a directory, or clears it if `limit` is `None`.
+    pub async fn set_quota(
+        &self,
+        path: impl Into<String>,
+        quota_type: QuotaType,
+        limit: Option<u64>,
+    ) -> CuddlyResult<()> {
+        let mut client = self.namenode_client.clone();
+        client
+            .set_quota(cuddlyproto::SetQuotaRequest {
+                path: path.into(),
+                quota_type: cuddlyproto::set_quota_request::QuotaType::from(quota_type) as i32,
+                limit: limit.unwrap_or_default(),
+            })
+            .await?;
+        Ok(())
+    }
+}
@@ -11,9 +11,113 @@ use crate::errors::{CuddlyError, CuddlyResult};
 use crate::io::cuddly_reader::CuddlyReader;
 use crate::io::cuddly_writer::CuddlyWriter;
 
+mod cuddly_admin_client;
+
+pub use cuddly_admin_client::{
+    BalancerStatus, BlockLocations, CheckpointSummary, ClusterReport, CuddlyAdminClient,
+    DatanodeAdminAction, DatanodeBlocks, DatanodeReport, FsckAction, FsckBlock, FsckFile,
+    FsckReport, QuotaType, RefreshNodesResult, SafeModeAction, SafeModeStatus,
+};
+
+/// What a directory tree consumes, and the quotas of its root.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ContentSummary {
+    pub directories: u64,
+    pub files: u64,
+    pub length: u64,
+    /// Bytes, counting every replica.
+    pub space_consumed: u64,
+    pub namespace_quota: Option<u64>,
+    pub space_quota: Option<u64>,
+}
+
+impl From<cuddlyproto::ContentSummaryResponse> for ContentSummary {
+    fn from(value: cuddlyproto::ContentSummaryResponse) -> Self {
+        Self {
+            directories: value.directories,
+            files: value.files,
+            length: value.length,
+            space_consumed: value.space_consumed,
+            namespace_quota: Some(value.namespace_quota).filter(|quota| *quota > 0),
+            space_quota: Some(value.space_quota).filter(|quota| *quota > 0),
+        }
+    }
+}
+
+/// How an entry changed between two snapshots.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SnapshotDiffType {
+    Created,
+    Deleted,
+    Modified,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SnapshotDiffEntry {
+    pub diff_type: SnapshotDiffType,
+    pub path: String,
+}
+
+impl From<cuddlyproto::SnapshotDiffEntry> for SnapshotDiffEntry {
+    fn from(value: cuddlyproto::SnapshotDiffEntry) -> Self {
+        use cuddlyproto::snapshot_diff_entry::DiffType;
+
+        let diff_type = match value.diff_type() {
+            DiffType::Created => SnapshotDiffType::Created,
+            DiffType::Deleted => SnapshotDiffType::Deleted,
+            DiffType::Modified => SnapshotDiffType::Modified,
+        };
+        Self {
+            diff_type,
+            path: value.path,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// What `stat` reports about a path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileStatus {
+    pub file_type: FileType,
+    /// Bytes of a file, 0 otherwise.
+    pub length: u64,
+    /// Replicas of each block of a file, 0 otherwise.
+    pub replication: u64,
+    /// Block size of a file, 0 otherwise.
+    pub block_size: u64,
+    pub symlink_target: Option<String>,
+}
+
+impl From<cuddlyproto::StatResponse> for FileStatus {
+    fn from(value: cuddlyproto::StatResponse) -> Self {
+        use cuddlyproto::stat_response::FileType as ProtoFileType;
+
+        let file_type = match value.file_type() {
+            ProtoFileType::File => FileType::File,
+            ProtoFileType::Directory => FileType::Directory,
+            ProtoFileType::Symlink => FileType::Symlink,
+        };
+        Self {
+            file_type,
+            length: value.length,
+            replication: value.replication,
+            block_size: value.block_size,
+            symlink_target: Some(value.symlink_target).filter(|target| !target.is_empty()),
+        }
+    }
+}
+
 pub struct CuddlyClient {
     namenode_rpc_address: String,
     namenode_client: FileServiceClient<Channel>,
+    /// Whose trash deleted paths are moved to.
+    user: String,
 }
 
 impl CuddlyClient {
@@ -26,14 +130,15 @@ impl CuddlyClient {
                 Ok(Self {
                     namenode_rpc_address: addr,
                     namenode_client: client,
+                    user: current_user(),
                 })
             }
             Err(err) => {
                 error!("Could not connect to namenode: {}", err);
-                return Err(CuddlyError::RPCError(format!(
+                Err(CuddlyError::RPCError(format!(
                     "Could not connect to namenode: {}",
                     err
-                )));
+                )))
             }
         }
     }
@@ -49,13 +154,16 @@ impl CuddlyClient {
 
     pub async fn mkdir(&self, path: impl Into<String>) -> CuddlyResult<()> {
         let mut client = self.namenode_client.clone();
-        client
+        let response = client
             .create_directory(cuddlyproto::CreateDirectoryRequest {
                 auth_token: None,
                 directory_path: path.into(),
             })
             .await?;
-        Ok(())
+        match response.into_inner().status {
+            Some(status) if !status.success => Err(CuddlyError::FSError(status.message)),
+            _ => Ok(()),
+        }
     }
 
     pub async fn ls(&self, path: impl Into<String>) -> CuddlyResult<Vec<String>> {
@@ -70,10 +178,21 @@ impl CuddlyClient {
         Ok(entries)
     }
 
-    pub async fn put(&self, src: &str, dst: impl Into<String>) -> CuddlyResult<()> {
+    /// Uploads a local file from `src` to the remote file `dst`. Its blocks
+    /// are stored `replication` times and hold up to `block_size` bytes, or
+    /// as configured for the cluster if unset.
+    pub async fn put(
+        &self,
+        src: &str,
+        dst: impl Into<String>,
+        replication: Option<u64>,
+        block_size: Option<u64>,
+    ) -> CuddlyResult<()> {
         info!("Uploading file from {}", src);
         let mut reader = BufReader::new(File::open(src).await?);
-        let mut writer = CuddlyWriter::create(dst, &self.namenode_rpc_address).await?;
+        let mut writer =
+            CuddlyWriter::create_with(dst, &self.namenode_rpc_address, replication, block_size)
+                .await?;
 
         let mut buf = vec![0; 128];
         loop {
@@ -89,6 +208,361 @@ impl CuddlyClient {
         Ok(())
     }
 
+    /// Appends a local file from `src` to the existing remote file `dst`.
+    pub async fn append(&self, src: &str, dst: impl Into<String>) -> CuddlyResult<()> {
+        info!("Appending file from {}", src);
+        let mut reader = BufReader::new(File::open(src).await?);
+        let mut writer = CuddlyWriter::append(dst, &self.namenode_rpc_address).await?;
+
+        let mut buf = vec![0; 128];
+        loop {
+            let read = reader.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            writer.write(&buf[..read]).await?;
+        }
+        writer.flush().await?;
+        writer.shutdown().await?;
+        info!("Successfully appended file from {}", src);
+        Ok(())
+    }
+
+    /// Counts the directories, files and bytes below `path`.
+    pub async fn count(&self, path: impl Into<String>) -> CuddlyResult<ContentSummary> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .content_summary(cuddlyproto::ContentSummaryRequest { path: path.into() })
+            .await?;
+        Ok(response.into_inner().into())
+    }
+
+    /// Shortens a remote file to `new_length` bytes.
+    pub async fn truncate(&self, path: impl Into<String>, new_length: u64) -> CuddlyResult<()> {
+        let mut client = self.namenode_client.clone();
+        client
+            .truncate(cuddlyproto::TruncateRequest {
+                file_path: path.into(),
+                new_length,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Moves the blocks of the remote files `sources` to the end of
+    /// `target` and removes the sources.
+    pub async fn concat(
+        &self,
+        target: impl Into<String>,
+        sources: Vec<String>,
+    ) -> CuddlyResult<()> {
+        let mut client = self.namenode_client.clone();
+        client
+            .concat(cuddlyproto::ConcatRequest {
+                target: target.into(),
+                sources,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Takes a read-only snapshot of the directory `path`, readable below
+    /// `<path>/.snapshot/<name>`.
+    pub async fn create_snapshot(
+        &self,
+        path: impl Into<String>,
+        name: impl Into<String>,
+    ) -> CuddlyResult<()> {
+        let mut client = self.namenode_client.clone();
+        client
+            .create_snapshot(cuddlyproto::SnapshotRequest {
+                path: path.into(),
+                name: name.into(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_snapshot(
+        &self,
+        path: impl Into<String>,
+        name: impl Into<String>,
+    ) -> CuddlyResult<()> {
+        let mut client = self.namenode_client.clone();
+        client
+            .delete_snapshot(cuddlyproto::SnapshotRequest {
+                path: path.into(),
+                name: name.into(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_snapshots(&self, path: impl Into<String>) -> CuddlyResult<Vec<String>> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .list_snapshots(cuddlyproto::ListSnapshotsRequest { path: path.into() })
+            .await?;
+        Ok(response.into_inner().names)
+    }
+
+    /// Lists what changed below `path` between the snapshot `from` and the
+    /// snapshot `to`, or the current directory if `to` is `None`.
+    pub async fn snapshot_diff(
+        &self,
+        path: impl Into<String>,
+        from: impl Into<String>,
+        to: Option<String>,
+    ) -> CuddlyResult<Vec<SnapshotDiffEntry>> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .snapshot_diff(cuddlyproto::SnapshotDiffRequest {
+                path: path.into(),
+                from: from.into(),
+                to: to.unwrap_or_default(),
+            })
+            .await?;
+        Ok(response
+            .into_inner()
+            .entries
+            .into_iter()
+            .map(SnapshotDiffEntry::from)
+            .collect())
+    }
+
+    /// Deletes a file, or a directory if it is empty or `recursive` is set.
+    /// Unless `skip_trash` is set, the path is moved into the user's trash
+    /// and the returned trash path can be restored until it expires. With
+    /// `no_follow` a symbolic link is deleted instead of its target.
+    pub async fn delete(
+        &self,
+        path: impl Into<String>,
+        recursive: bool,
+        skip_trash: bool,
+        no_follow: bool,
+    ) -> CuddlyResult<Option<String>> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .delete(cuddlyproto::DeleteRequest {
+                path: path.into(),
+                recursive,
+                skip_trash,
+                user: self.user.clone(),
+                no_follow,
+            })
+            .await?;
+        let trash_path = response.into_inner().trash_path;
+        Ok(Some(trash_path).filter(|path| !path.is_empty()))
+    }
+
+    /// Moves the user's trash into a checkpoint and deletes the expired
+    /// checkpoints, or all of them if `immediate` is set. Returns the
+    /// deleted checkpoints.
+    pub async fn expunge(&self, immediate: bool) -> CuddlyResult<Vec<String>> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .expunge(cuddlyproto::ExpungeRequest {
+                user: self.user.clone(),
+                immediate,
+            })
+            .await?;
+        Ok(response.into_inner().deleted)
+    }
+
+    /// Moves a path out of the trash, to where it was deleted from unless
+    /// `dst` is given. Returns where it was restored to.
+    pub async fn restore(
+        &self,
+        trash_path: impl Into<String>,
+        dst: Option<String>,
+    ) -> CuddlyResult<String> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .restore(cuddlyproto::RestoreRequest {
+                trash_path: trash_path.into(),
+                destination: dst.unwrap_or_default(),
+            })
+            .await?;
+        Ok(response.into_inner().path)
+    }
+
+    /// Creates a symbolic link at `path` pointing at `target`. With
+    /// `overwrite` an existing link at `path` is repointed.
+    pub async fn create_symlink(
+        &self,
+        path: impl Into<String>,
+        target: impl Into<String>,
+        overwrite: bool,
+    ) -> CuddlyResult<()> {
+        let mut client = self.namenode_client.clone();
+        client
+            .create_symlink(cuddlyproto::CreateSymlinkRequest {
+                path: path.into(),
+                target: target.into(),
+                overwrite,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Describes a path, or with `no_follow` the symbolic link at its end.
+    pub async fn stat(&self, path: impl Into<String>, no_follow: bool) -> CuddlyResult<FileStatus> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .stat(cuddlyproto::StatRequest {
+                path: path.into(),
+                no_follow,
+            })
+            .await?;
+        Ok(response.into_inner().into())
+    }
+
+    /// Changes how many replicas the blocks of a file have. The namenode
+    /// adds or deletes replicas in the background.
+    pub async fn set_replication(
+        &self,
+        path: impl Into<String>,
+        replication: u64,
+    ) -> CuddlyResult<()> {
+        let mut client = self.namenode_client.clone();
+        client
+            .set_replication(cuddlyproto::SetReplicationRequest {
+                path: path.into(),
+                replication,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Sets the storage policy of a file or directory, such as "HOT" or
+    /// "ONE_SSD", or with `None` makes it inherit the one of its parent.
+    /// Existing replicas are moved in the background.
+    pub async fn set_storage_policy(
+        &self,
+        path: impl Into<String>,
+        policy: Option<&str>,
+    ) -> CuddlyResult<()> {
+        let mut client = self.namenode_client.clone();
+        client
+            .set_storage_policy(cuddlyproto::SetStoragePolicyRequest {
+                path: path.into(),
+                policy: policy.unwrap_or_default().to_owned(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// The storage policy that applies to a file or directory, set on it or
+    /// inherited.
+    pub async fn get_storage_policy(&self, path: impl Into<String>) -> CuddlyResult<String> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .get_storage_policy(cuddlyproto::GetStoragePolicyRequest { path: path.into() })
+            .await?
+            .into_inner();
+        Ok(response.policy)
+    }
+
+    /// Sets the erasure coding policy of a directory, such as "RS-6-3", or
+    /// with `None` makes it inherit the one of its parent. Only files created
+    /// afterwards are erasure-coded.
+    pub async fn set_erasure_coding_policy(
+        &self,
+        path: impl Into<String>,
+        policy: Option<&str>,
+    ) -> CuddlyResult<()> {
+        let mut client = self.namenode_client.clone();
+        client
+            .set_erasure_coding_policy(cuddlyproto::SetErasureCodingPolicyRequest {
+                path: path.into(),
+                policy: policy.unwrap_or_default().to_owned(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// The erasure coding policy of a file or directory, set on it or
+    /// inherited, or `None` if it is replicated.
+    pub async fn get_erasure_coding_policy(
+        &self,
+        path: impl Into<String>,
+    ) -> CuddlyResult<Option<String>> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .get_erasure_coding_policy(cuddlyproto::GetErasureCodingPolicyRequest {
+                path: path.into(),
+            })
+            .await?
+            .into_inner();
+        Ok(Some(response.policy).filter(|policy| !policy.is_empty()))
+    }
+
+    /// Sets an extended attribute of a file or directory. Names start with
+    /// a namespace, `user.` or `trusted.`.
+    pub async fn set_xattr(
+        &self,
+        path: impl Into<String>,
+        name: impl Into<String>,
+        value: Vec<u8>,
+    ) -> CuddlyResult<()> {
+        let mut client = self.namenode_client.clone();
+        client
+            .set_x_attr(cuddlyproto::SetXAttrRequest {
+                path: path.into(),
+                xattr: Some(cuddlyproto::XAttr {
+                    name: name.into(),
+                    value: value.into(),
+                }),
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Names and values of extended attributes, all of them if `names` is
+    /// empty.
+    pub async fn get_xattrs(
+        &self,
+        path: impl Into<String>,
+        names: Vec<String>,
+    ) -> CuddlyResult<Vec<(String, Vec<u8>)>> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .get_x_attrs(cuddlyproto::GetXAttrsRequest {
+                path: path.into(),
+                names,
+            })
+            .await?;
+        Ok(response
+            .into_inner()
+            .xattrs
+            .into_iter()
+            .map(|xattr| (xattr.name, xattr.value.to_vec()))
+            .collect())
+    }
+
+    pub async fn list_xattrs(&self, path: impl Into<String>) -> CuddlyResult<Vec<String>> {
+        let mut client = self.namenode_client.clone();
+        let response = client
+            .list_x_attrs(cuddlyproto::ListXAttrsRequest { path: path.into() })
+            .await?;
+        Ok(response.into_inner().names)
+    }
+
+    pub async fn remove_xattr(
+        &self,
+        path: impl Into<String>,
+        name: impl Into<String>,
+    ) -> CuddlyResult<()> {
+        let mut client = self.namenode_client.clone();
+        client
+            .remove_x_attr(cuddlyproto::RemoveXAttrRequest {
+                path: path.into(),
+                name: name.into(),
+            })
+            .await?;
+        Ok(())
+    }
+
     pub async fn get(&self, src: &str, dst: &str) -> CuddlyResult<()> {
         let mut reader = CuddlyReader::open(self.namenode_rpc_address.clone(), src).await?;
         // check if the file directory exists
@@ -112,3 +586,9 @@ impl CuddlyClient {
         Ok(())
     }
 }
+
+fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "anonymous".to_owned())
+}
@@ -1,5 +1,3 @@
-use std::io::Read;
-
 use log::debug;
 use log::warn;
 use prost::Message;
@@ -7,21 +5,48 @@ use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::{io::BufStream, net::TcpStream};
 
+use crate::block::BlockGroup;
 use crate::utils::parse_message;
 use crate::{
     cuddlyproto::{
         self, file_service_client::FileServiceClient, BlockWithLocations, OpenFileRequest,
     },
-    errors::CuddlyResult,
+    errors::{CuddlyError, CuddlyResult},
 };
 
+use super::striped_reader::StripedReader;
+
+// Where the data of the block being read comes from
+enum BlockSource {
+    Replica(BufStream<TcpStream>),
+    // A block group of an erasure-coded file, read a stripe at a time
+    Group(Box<StripedReader>),
+}
+
+fn docker_address(location: &str) -> String {
+    format!(
+        "{}:{}",
+        "localhost",
+        location
+            .split(':')
+            .next_back()
+            .expect("Could not parse port")
+            .parse::<u16>()
+            .expect("Could not parse port")
+            + 10000
+    )
+}
+
 #[allow(dead_code)]
 pub struct CuddlyReader {
     blocks_with_locations: Vec<BlockWithLocations>,
+    // Set instead of the blocks for an erasure-coded file, with the locations
+    // of each block
+    block_groups: Vec<(BlockGroup, Vec<Vec<String>>)>,
     block_index: usize,
     total_file_size: u64,
     current_file_pos: u64,
-    current_reader: BufStream<TcpStream>,
+    current_reader: Option<BlockSource>,
     current_block_size: u64,
     current_block_pos: u64,
     current_packet_size: u64,
@@ -57,6 +82,9 @@ impl CuddlyReader {
             .await?
             .into_inner();
 
+        if !res.block_groups.is_empty() {
+            return Self::open_striped(res.block_groups);
+        }
         let mut blocks_with_locations = res.blocks_with_locations;
 
         let total_file_size = blocks_with_locations
@@ -75,22 +103,7 @@ impl CuddlyReader {
         let blocks_with_locations: Vec<BlockWithLocations> = blocks_with_locations
             .iter_mut()
             .map(|b| {
-                b.locations = b
-                    .locations
-                    .iter()
-                    .map(|l| {
-                        format!(
-                            "{}:{}",
-                            "localhost",
-                            l.split(':')
-                                .last()
-                                .expect("Could not parse port")
-                                .parse::<u16>()
-                                .expect("Could not parse port")
-                                + 10000
-                        )
-                    })
-                    .collect();
+                b.locations = b.locations.iter().map(|l| docker_address(l)).collect();
                 b.clone()
             })
             .collect();
@@ -116,6 +129,7 @@ impl CuddlyReader {
 
         Ok(Self {
             blocks_with_locations,
+            block_groups: vec![],
             block_index: 0,
             total_file_size,
             current_file_pos: 0,
@@ -123,31 +137,89 @@ impl CuddlyReader {
             current_packet_size: 0,
             current_block_pos: 0,
             current_packet_pos: 0,
-            current_reader,
+            current_reader: Some(BlockSource::Replica(current_reader)),
+            buffer: Vec::new(),
+        })
+    }
+
+    fn open_striped(block_groups: Vec<cuddlyproto::BlockGroupProto>) -> CuddlyResult<Self> {
+        let mut block_groups = block_groups
+            .into_iter()
+            .map(BlockGroup::from_proto)
+            .collect::<CuddlyResult<Vec<_>>>()?;
+        for (_, locations) in block_groups.iter_mut() {
+            for addresses in locations.iter_mut() {
+                *addresses = addresses.iter().map(|l| docker_address(l)).collect();
+            }
+        }
+        let total_file_size = block_groups.iter().map(|(group, _)| group.data_len()).sum();
+        let (group, locations) = block_groups[0].clone();
+
+        Ok(Self {
+            blocks_with_locations: vec![],
+            block_index: 0,
+            total_file_size,
+            current_file_pos: 0,
+            current_block_size: group.data_len(),
+            current_packet_size: 0,
+            current_block_pos: 0,
+            current_packet_pos: 0,
+            current_reader: Some(BlockSource::Group(Box::new(StripedReader::new(
+                group, locations,
+            )))),
+            block_groups,
             buffer: Vec::new(),
         })
     }
 
     pub async fn read(&mut self, buf: &mut [u8]) -> CuddlyResult<usize> {
-        if self.current_file_pos == self.total_file_size {
-            return Ok(0);
-        } else if self.current_block_pos == self.current_block_size {
-            self.next_block().await?;
-        } else if self.current_packet_pos == self.current_packet_size {
-            self.next_packet().await?;
+        loop {
+            if self.current_file_pos >= self.total_file_size {
+                return Ok(0);
+            } else if self.current_block_pos >= self.current_block_size {
+                self.next_block().await?;
+            } else if self.current_packet_pos >= self.current_packet_size {
+                self.next_packet().await?;
+            } else {
+                break;
+            }
         }
 
+        // Never read past the block or the file, whatever a datanode sends
+        let available = (self.current_packet_size - self.current_packet_pos)
+            .min(self.current_block_size - self.current_block_pos)
+            .min(self.total_file_size - self.current_file_pos);
+        let bytes_read = buf.len().min(available as usize);
         let buffer_start_pos = self.current_packet_pos as usize;
-        let bytes_read = Read::read(&mut (&self.buffer[buffer_start_pos..]), buf)? as u64;
-        self.current_file_pos += bytes_read;
-        self.current_block_pos += bytes_read;
-        self.current_packet_pos += bytes_read;
+        buf[..bytes_read]
+            .copy_from_slice(&self.buffer[buffer_start_pos..buffer_start_pos + bytes_read]);
+        self.current_file_pos += bytes_read as u64;
+        self.current_block_pos += bytes_read as u64;
+        self.current_packet_pos += bytes_read as u64;
 
-        Ok(bytes_read as usize)
+        Ok(bytes_read)
     }
 
     pub async fn next_block(&mut self) -> CuddlyResult<()> {
         self.block_index += 1;
+        let blocks = match self.block_groups.is_empty() {
+            true => self.blocks_with_locations.len(),
+            false => self.block_groups.len(),
+        };
+        if self.block_index >= blocks {
+            return Err(CuddlyError::IOError("File ended early".to_owned()));
+        }
+        self.current_packet_pos = 0;
+        self.current_packet_size = 0;
+        if !self.block_groups.is_empty() {
+            let (group, locations) = self.block_groups[self.block_index].clone();
+            self.current_block_size = group.data_len();
+            self.current_block_pos = 0;
+            self.current_reader = Some(BlockSource::Group(Box::new(StripedReader::new(
+                group, locations,
+            ))));
+            return self.next_packet().await;
+        }
         let current_block = self.blocks_with_locations[self.block_index]
             .block
             .as_ref()
@@ -155,6 +227,10 @@ impl CuddlyReader {
 
         self.current_block_size = current_block.len;
         self.current_block_pos = 0;
+        if current_block.len == 0 {
+            self.current_reader = None;
+            return Ok(());
+        }
 
         let mut current_reader = BufStream::new(
             TcpStream::connect(&self.blocks_with_locations[self.block_index].locations[0]).await?,
@@ -176,20 +252,34 @@ impl CuddlyReader {
         current_reader.write_all(&self.buffer).await?;
         current_reader.flush().await?;
 
-        self.current_reader = current_reader;
+        self.current_reader = Some(BlockSource::Replica(current_reader));
         self.next_packet().await?;
 
         Ok(())
     }
 
     pub async fn next_packet(&mut self) -> CuddlyResult<()> {
-        let cuddlyproto::Packet { size, last: _ } =
-            parse_message::<cuddlyproto::Packet>(&mut self.current_reader).await?;
+        match self.current_reader.as_mut() {
+            Some(BlockSource::Replica(reader)) => {
+                let cuddlyproto::Packet { size, last: _ } =
+                    parse_message::<cuddlyproto::Packet>(reader).await?;
+                self.buffer.clear();
+                self.buffer.resize_with(size as usize, u8::default);
+                reader.read_exact(&mut self.buffer).await?;
+            }
+            // A stripe holds the next cell of each data block
+            Some(BlockSource::Group(reader)) => {
+                let data_units = (0..reader.data_units()).collect::<Vec<_>>();
+                let cells = reader
+                    .next_stripe(&data_units)
+                    .await?
+                    .ok_or_else(|| CuddlyError::IOError("Block group ended early".to_owned()))?;
+                self.buffer = cells.concat();
+            }
+            None => return Err(CuddlyError::IOError("No block to read".to_owned())),
+        }
         self.current_packet_pos = 0;
-        self.current_packet_size = size;
-        self.buffer.clear();
-        self.buffer.resize_with(size as usize, u8::default);
-        self.current_reader.read_exact(&mut self.buffer).await?;
+        self.current_packet_size = self.buffer.len() as u64;
         Ok(())
     }
 }
@@ -10,10 +10,12 @@ use tonic::transport::Channel;
 
 use crate::cuddlyproto::file_service_client::FileServiceClient;
 use crate::errors::{CuddlyError, CuddlyResult};
+use crate::namenode::erasure_coding_policy::ErasureCodingPolicy;
 use crate::utils::parse_message;
 use crate::{cuddlyproto, APP_CONFIG};
 
 use self::cuddlyproto::WriteBlockResponse;
+use super::striping::{encode_stripe, split_stripe};
 
 async fn new_backup_file() -> CuddlyResult<BufStream<File>> {
     let backup_dir = std::env::temp_dir();
@@ -22,6 +24,7 @@ async fn new_backup_file() -> CuddlyResult<BufStream<File>> {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(true)
         .open(backup_dir.join(filename))
         .await?;
     Ok(BufStream::new(file))
@@ -29,17 +32,41 @@ async fn new_backup_file() -> CuddlyResult<BufStream<File>> {
 
 pub struct CuddlyWriter {
     namenode_client: FileServiceClient<Channel>,
+    // Replicas of each block of a new file, or the namenode's default
+    replication: Option<u64>,
+    // Set if the file is written in block groups instead of replicated
+    erasure_coding_policy: Option<ErasureCodingPolicy>,
     block_size: u64,
     packet_size: u64,
     path: String,
     file_started: bool,
     bytes_written_to_block: u64,
+    // Bytes already in the block being written, if it was reopened for append
+    block_offset: u64,
+    // Block handed out by the namenode when the file was reopened
+    pending_block: Option<cuddlyproto::BlockWithTargets>,
+    // Generation of the pending block, if it was reopened
+    generation: Option<u64>,
     backup_buffer: BufStream<File>,
 }
 
 impl CuddlyWriter {
     pub async fn create(path: impl Into<String>, namenode_rpc_address: &str) -> CuddlyResult<Self> {
-        let client = match FileServiceClient::connect(String::from(namenode_rpc_address)).await {
+        Self::create_with(path, namenode_rpc_address, None, None).await
+    }
+
+    /// Creates a file whose blocks are stored `replication` times and hold
+    /// up to `block_size` bytes. Unset values are taken from the config.
+    /// Below a directory with an erasure coding policy, the blocks are
+    /// written in groups instead and the replication is ignored.
+    pub async fn create_with(
+        path: impl Into<String>,
+        namenode_rpc_address: &str,
+        replication: Option<u64>,
+        block_size: Option<u64>,
+    ) -> CuddlyResult<Self> {
+        let mut client = match FileServiceClient::connect(String::from(namenode_rpc_address)).await
+        {
             Ok(client) => {
                 debug!(
                     "Connected to namenode at {} for file service",
@@ -55,22 +82,87 @@ impl CuddlyWriter {
             }
         };
 
+        let path = path.into();
+        let policy = client
+            .get_erasure_coding_policy(cuddlyproto::GetErasureCodingPolicyRequest {
+                path: path.clone(),
+            })
+            .await?
+            .into_inner()
+            .policy;
+        let erasure_coding_policy = match policy.as_str() {
+            "" => None,
+            name => Some(name.parse::<ErasureCodingPolicy>()?),
+        };
+
         Ok(Self {
             namenode_client: client,
-            block_size: APP_CONFIG.block_size,
+            replication,
+            erasure_coding_policy,
+            block_size: block_size.unwrap_or(APP_CONFIG.block_size),
             packet_size: APP_CONFIG.packet_size,
-            path: path.into(),
+            path,
             file_started: false,
             bytes_written_to_block: 0,
+            block_offset: 0,
+            pending_block: None,
+            generation: None,
             backup_buffer: new_backup_file().await?,
         })
     }
 
+    /// Opens an existing file for appending. Data is first appended to the
+    /// last block of the file if it is not full.
+    pub async fn append(path: impl Into<String>, namenode_rpc_address: &str) -> CuddlyResult<Self> {
+        let mut writer = Self::create(path, namenode_rpc_address).await?;
+        let cuddlyproto::AppendResponse {
+            block_with_targets,
+            reopened,
+            generation,
+            block_size,
+        } = writer
+            .namenode_client
+            .append(cuddlyproto::AppendRequest {
+                file_path: writer.path.clone(),
+            })
+            .await?
+            .into_inner();
+        let block_with_targets = block_with_targets.ok_or_else(|| {
+            CuddlyError::FSError("Namenode did not hand out a block to append to".to_owned())
+        })?;
+        let block = block_with_targets
+            .block
+            .as_ref()
+            .ok_or_else(|| CuddlyError::FSError("Block to append to is missing".to_owned()))?;
+        writer.block_size = block_size;
+        if reopened {
+            info!(
+                "Appending to block {} with {} bytes, generation {}",
+                block.id, block.len, generation
+            );
+            writer.block_offset = block.len;
+            writer.generation = Some(generation);
+        }
+        writer.pending_block = Some(block_with_targets);
+        writer.file_started = true;
+        Ok(writer)
+    }
+
+    /// Bytes buffered before they are written out as a block or, for an
+    /// erasure-coded file, as a block group.
+    fn capacity(&self) -> u64 {
+        match self.erasure_coding_policy {
+            Some(policy) => policy.group_capacity(self.block_size),
+            None => self.block_size - self.block_offset,
+        }
+    }
+
     pub async fn write(&mut self, buf: &[u8]) -> CuddlyResult<()> {
-        let start_pos = if self.bytes_written_to_block + buf.len() as u64 >= self.block_size {
-            let bytes_to_fill_block = self.block_size - self.bytes_written_to_block;
+        let capacity = self.capacity();
+        let start_pos = if self.bytes_written_to_block + buf.len() as u64 >= capacity {
+            let bytes_to_fill_block = capacity - self.bytes_written_to_block;
             let buf = &buf[..(bytes_to_fill_block as usize)];
-            self.backup_buffer.write_all(&buf).await?;
+            self.backup_buffer.write_all(buf).await?;
             self.bytes_written_to_block += bytes_to_fill_block;
             self.flush().await?;
 
@@ -89,7 +181,7 @@ impl CuddlyWriter {
     pub async fn flush(&mut self) -> CuddlyResult<()> {
         self.backup_buffer.flush().await?;
 
-        if self.bytes_written_to_block >= self.block_size {
+        if self.bytes_written_to_block >= self.capacity() {
             self.write_block().await?;
         }
 
@@ -101,6 +193,15 @@ impl CuddlyWriter {
             self.backup_buffer.flush().await?;
             self.write_block().await?;
         }
+        // Nothing was appended, so the block handed out is not needed
+        if let Some(cuddlyproto::BlockWithTargets { block, .. }) = self.pending_block.take() {
+            self.namenode_client
+                .abort_block_write(cuddlyproto::AbortBlockWriteRequest {
+                    block,
+                    path: self.path.clone(),
+                })
+                .await?;
+        }
 
         let max_retries = 10;
         let mut sleep_time = time::Duration::from_millis(500);
@@ -109,6 +210,7 @@ impl CuddlyWriter {
                 .namenode_client
                 .finish_file_create(cuddlyproto::CreateFileRequest {
                     file_path: self.path.clone(),
+                    ..Default::default()
                 })
                 .await;
             match response {
@@ -134,31 +236,54 @@ impl CuddlyWriter {
         )))
     }
 
-    async fn next_block(
+    async fn next_block(&mut self) -> CuddlyResult<cuddlyproto::BlockWithTargets> {
+        if let Some(pending_block) = self.pending_block.take() {
+            return Ok(pending_block);
+        }
+        let block_with_targets =
+            self.allocate().await?.0.ok_or_else(|| {
+                CuddlyError::FSError("Namenode did not hand out a block".to_owned())
+            })?;
+        info!(
+            "Starting new Block. Namenode returned target: {:?}",
+            block_with_targets.targets
+        );
+
+        Ok(block_with_targets)
+    }
+
+    /// Asks the namenode for the next block of the file, or the next block
+    /// group of an erasure-coded file.
+    async fn allocate(
         &mut self,
-    ) -> CuddlyResult<(cuddlyproto::Block, Vec<cuddlyproto::DatanodeInfo>)> {
-        let cuddlyproto::BlockWithTargets { block, targets } = if self.file_started {
+    ) -> CuddlyResult<(
+        Option<cuddlyproto::BlockWithTargets>,
+        Option<cuddlyproto::BlockGroupWithTargets>,
+    )> {
+        let allocated = if self.file_started {
             self.following_block().await?
         } else {
-            self.namenode_client
+            let response = self
+                .namenode_client
                 .start_file_create(cuddlyproto::CreateFileRequest {
                     file_path: self.path.clone(),
+                    replication: self.replication.unwrap_or_default(),
+                    block_size: self.block_size,
                 })
                 .await?
-                .into_inner()
-                .block_with_targets
-                .unwrap()
+                .into_inner();
+            (response.block_with_targets, response.block_group)
         };
         self.file_started = true;
-        info!(
-            "Starting new Block. Namenode returned target: {:?}",
-            targets
-        );
-
-        Ok((block.unwrap(), targets))
+        Ok(allocated)
     }
 
-    async fn following_block(&mut self) -> CuddlyResult<cuddlyproto::BlockWithTargets> {
+    async fn following_block(
+        &mut self,
+    ) -> CuddlyResult<(
+        Option<cuddlyproto::BlockWithTargets>,
+        Option<cuddlyproto::BlockGroupWithTargets>,
+    )> {
         let max_retries = 10;
         let mut sleep_time = time::Duration::from_millis(500);
         for _ in 0..max_retries {
@@ -170,7 +295,10 @@ impl CuddlyWriter {
                 .await;
 
             match response {
-                Ok(message) => return Ok(message.into_inner().block_with_targets.unwrap()),
+                Ok(message) => {
+                    let response = message.into_inner();
+                    return Ok((response.block_with_targets, response.block_group));
+                }
                 Err(status) => {
                     // The code is set to Unavailable if the blocks have not been
                     // fully replicated yet. Wait for some time to allow the block
@@ -192,7 +320,15 @@ impl CuddlyWriter {
     }
 
     async fn write_block(&mut self) -> CuddlyResult<()> {
-        let (block, targets) = self.next_block().await?;
+        if let Some(policy) = self.erasure_coding_policy {
+            return self.write_block_group(policy).await;
+        }
+        let cuddlyproto::BlockWithTargets {
+            block,
+            targets,
+            storage_types,
+        } = self.next_block().await?;
+        let generation = self.generation.take();
         warn!("Make sure to remove http for tcp connections");
         warn!("Changing socket address to localhost for testing purposes in docker");
         let ext_targets = targets
@@ -203,7 +339,7 @@ impl CuddlyWriter {
                     "localhost",
                     info.socket_address
                         .split(':')
-                        .last()
+                        .next_back()
                         .expect("Could not parse port")
                         .parse::<u16>()
                         .expect("Could not parse port")
@@ -212,6 +348,7 @@ impl CuddlyWriter {
                 datanode_uuid: info.datanode_uuid.clone(),
                 used_capacity: info.used_capacity,
                 total_capacity: info.total_capacity,
+                state: info.state,
             })
             .collect::<Vec<_>>();
         warn!("Now using targets: {:?}", ext_targets);
@@ -224,14 +361,17 @@ impl CuddlyWriter {
         debug!("Connected to datanode: {:?}", ext_targets[0].socket_address);
         let mut buffer = vec![];
         let op = cuddlyproto::Operation {
-            op: cuddlyproto::operation::OpCode::WriteBlock as i32,
+            op: match generation {
+                Some(_) => cuddlyproto::operation::OpCode::AppendBlock as i32,
+                None => cuddlyproto::operation::OpCode::WriteBlock as i32,
+            },
         };
         op.encode_length_delimited(&mut buffer)?;
         datanode.write_all(&buffer).await?;
         buffer.clear();
 
         let write_op = cuddlyproto::WriteBlockOperation {
-            block: Some(block),
+            block,
             targets: targets
                 .iter()
                 .map(|info| {
@@ -248,6 +388,8 @@ impl CuddlyWriter {
                     address
                 })
                 .collect(),
+            generation: generation.unwrap_or_default(),
+            storage_types,
         };
         write_op.encode_length_delimited(&mut buffer)?;
         datanode.write_all(&buffer).await?;
@@ -289,7 +431,120 @@ impl CuddlyWriter {
             .seek(SeekFrom::Start(0u64))
             .await?;
         self.bytes_written_to_block = 0;
+        self.block_offset = 0;
 
         Ok(())
     }
+
+    /// Writes the buffered data as a block group. The data is split into
+    /// stripes of one cell per data block, and the parity cells of each
+    /// stripe go to the parity blocks. Each block is written to its only
+    /// target.
+    async fn write_block_group(&mut self, policy: ErasureCodingPolicy) -> CuddlyResult<()> {
+        let cuddlyproto::BlockGroupWithTargets {
+            cell_size, blocks, ..
+        } = self.allocate().await?.1.ok_or_else(|| {
+            CuddlyError::FSError("Namenode did not hand out a block group".to_owned())
+        })?;
+        if blocks.len() != policy.group_width() || cell_size == 0 {
+            return Err(CuddlyError::FSError(format!(
+                "Invalid {} block group with {} blocks",
+                policy,
+                blocks.len()
+            )));
+        }
+        info!("Starting new block group of {} blocks", blocks.len());
+
+        let mut datanodes = vec![];
+        let mut buffer = vec![];
+        for cuddlyproto::BlockWithTargets {
+            block,
+            targets,
+            storage_types,
+        } in blocks
+        {
+            let target = targets
+                .first()
+                .ok_or_else(|| CuddlyError::FSError("Block without target".to_owned()))?;
+            let port = target
+                .socket_address
+                .split(':')
+                .next_back()
+                .expect("Could not parse port")
+                .parse::<u16>()
+                .expect("Could not parse port")
+                + 10000;
+            let mut datanode =
+                BufStream::new(TcpStream::connect(format!("localhost:{}", port)).await?);
+            cuddlyproto::Operation {
+                op: cuddlyproto::operation::OpCode::WriteBlock as i32,
+            }
+            .encode_length_delimited(&mut buffer)?;
+            cuddlyproto::WriteBlockOperation {
+                block,
+                targets: vec![format!("localhost:{}", port)],
+                generation: 0,
+                storage_types,
+            }
+            .encode_length_delimited(&mut buffer)?;
+            datanode.write_all(&buffer).await?;
+            buffer.clear();
+            datanodes.push(datanode);
+        }
+
+        let codec = policy.codec();
+        let stripe_size = cell_size * policy.data_units() as u64;
+        let mut remaining = self.bytes_written_to_block;
+        self.backup_buffer
+            .get_mut()
+            .seek(SeekFrom::Start(0u64))
+            .await?;
+        while remaining > 0 {
+            let size = std::cmp::min(remaining, stripe_size);
+            remaining -= size;
+            let mut stripe = vec![0u8; size as usize];
+            self.backup_buffer.read_exact(&mut stripe).await?;
+            let cells = split_stripe(&stripe, cell_size, policy.data_units());
+            let parity = encode_stripe(&codec, &cells)?;
+            for (datanode, cell) in datanodes.iter_mut().zip(cells.iter().chain(&parity)) {
+                for packet in cell.chunks(self.packet_size as usize) {
+                    cuddlyproto::Packet {
+                        size: packet.len() as u64,
+                        last: false,
+                    }
+                    .encode_length_delimited(&mut buffer)?;
+                    datanode.write_all(&buffer).await?;
+                    datanode.write_all(packet).await?;
+                    buffer.clear();
+                }
+            }
+        }
+
+        for datanode in datanodes.iter_mut() {
+            cuddlyproto::Packet {
+                size: 0,
+                last: true,
+            }
+            .encode_length_delimited(&mut buffer)?;
+            datanode.write_all(&buffer).await?;
+            datanode.flush().await?;
+            buffer.clear();
+        }
+        for datanode in datanodes.iter_mut() {
+            let WriteBlockResponse { success } =
+                parse_message::<WriteBlockResponse>(datanode).await?;
+            if !success {
+                return Err(CuddlyError::FSError(
+                    "Writing block group was not successful".to_owned(),
+                ));
+            }
+        }
+
+        self.backup_buffer
+            .get_mut()
+            .seek(SeekFrom::Start(0u64))
+            .await?;
+        self.bytes_written_to_block = 0;
+        Ok(())
+    }
 }
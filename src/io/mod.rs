@@ -1,2 +1,4 @@
 pub mod cuddly_reader;
 pub mod cuddly_writer;
+pub(crate) mod striped_reader;
+pub(crate) mod striping;
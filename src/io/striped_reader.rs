@@ -0,0 +1,207 @@
+use log::warn;
+use prost::Message;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+
+use crate::block::{Block, BlockGroup};
+use crate::cuddlyproto;
+use crate::errors::{CuddlyError, CuddlyResult};
+use crate::utils::parse_message;
+use crate::utils::reed_solomon::ReedSolomon;
+
+use super::striping::{cell_len, reconstruct_stripe};
+
+/// Reads one block from a datanode, packet by packet.
+struct BlockStream {
+    stream: BufStream<TcpStream>,
+    packet: Vec<u8>,
+    // Bytes of `packet` already read
+    packet_pos: usize,
+    // Offset in the block of the next byte read
+    position: u64,
+}
+
+impl BlockStream {
+    async fn open(address: &str, block: &Block) -> CuddlyResult<Self> {
+        let mut stream = BufStream::new(TcpStream::connect(address).await?);
+        let mut buffer = vec![];
+        cuddlyproto::Operation {
+            op: cuddlyproto::operation::OpCode::ReadBlock as i32,
+        }
+        .encode_length_delimited(&mut buffer)?;
+        cuddlyproto::ReadBlockOperation {
+            block: Some((*block).into()),
+        }
+        .encode_length_delimited(&mut buffer)?;
+        stream.write_all(&buffer).await?;
+        stream.flush().await?;
+        Ok(Self {
+            stream,
+            packet: vec![],
+            packet_pos: 0,
+            position: 0,
+        })
+    }
+
+    /// Reads up to `len` bytes of the current packet, receiving the next
+    /// packet if the current one is used up.
+    async fn next_bytes(&mut self, len: usize) -> CuddlyResult<&[u8]> {
+        if self.packet_pos == self.packet.len() {
+            let cuddlyproto::Packet { size, .. } =
+                parse_message::<cuddlyproto::Packet>(&mut self.stream).await?;
+            if size == 0 {
+                return Err(CuddlyError::IOError("Block ended early".to_owned()));
+            }
+            self.packet.resize(size as usize, 0);
+            self.stream.read_exact(&mut self.packet).await?;
+            self.packet_pos = 0;
+        }
+        let start = self.packet_pos;
+        let len = len.min(self.packet.len() - start);
+        self.packet_pos += len;
+        self.position += len as u64;
+        Ok(&self.packet[start..start + len])
+    }
+
+    async fn read(&mut self, len: usize) -> CuddlyResult<Vec<u8>> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let bytes = self.next_bytes(len - data.len()).await?;
+            data.extend_from_slice(bytes);
+        }
+        Ok(data)
+    }
+
+    async fn skip_to(&mut self, position: u64) -> CuddlyResult<()> {
+        while self.position < position {
+            self.next_bytes((position - self.position) as usize).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a block group stripe by stripe. Only as many blocks as there are
+/// data blocks are read, the data blocks first, and blocks that cannot be
+/// read are rebuilt from the others.
+pub(crate) struct StripedReader {
+    group: BlockGroup,
+    // Data transfer addresses of the replica of each block
+    locations: Vec<Vec<String>>,
+    codec: ReedSolomon,
+    streams: Vec<Option<BlockStream>>,
+    failed: Vec<bool>,
+    stripe: u64,
+}
+
+impl StripedReader {
+    pub(crate) fn new(group: BlockGroup, locations: Vec<Vec<String>>) -> Self {
+        let width = group.blocks.len();
+        Self {
+            codec: group.policy.codec(),
+            group,
+            locations,
+            streams: (0..width).map(|_| None).collect(),
+            failed: vec![false; width],
+            stripe: 0,
+        }
+    }
+
+    pub(crate) fn data_units(&self) -> usize {
+        self.group.policy.data_units()
+    }
+
+    fn stripes(&self) -> u64 {
+        self.group.blocks[0].len.div_ceil(self.group.cell_size)
+    }
+
+    fn cell_len(&self, index: usize, stripe: u64) -> u64 {
+        cell_len(self.group.blocks[index].len, stripe, self.group.cell_size)
+    }
+
+    /// Reads the next stripe and returns the cells of the blocks at `wanted`,
+    /// rebuilt if they could not be read. Returns `None` after the last
+    /// stripe.
+    pub(crate) async fn next_stripe(
+        &mut self,
+        wanted: &[usize],
+    ) -> CuddlyResult<Option<Vec<Vec<u8>>>> {
+        if self.stripe >= self.stripes() {
+            return Ok(None);
+        }
+        let stripe = self.stripe;
+        self.stripe += 1;
+
+        let data_units = self.group.policy.data_units();
+        let mut shards = vec![None; self.group.blocks.len()];
+        let mut read = 0;
+        for (index, shard) in shards.iter_mut().enumerate() {
+            if read == data_units {
+                break;
+            }
+            if self.failed[index] {
+                continue;
+            }
+            match self.read_cell(index, stripe).await {
+                Ok(cell) => {
+                    *shard = Some(cell);
+                    read += 1;
+                }
+                Err(e) => {
+                    warn!(
+                        "Cannot read {}, rebuilding it from its group: {:?}",
+                        self.group.blocks[index], e
+                    );
+                    self.failed[index] = true;
+                    self.streams[index] = None;
+                }
+            }
+        }
+        if read < data_units {
+            return Err(CuddlyError::IOError(format!(
+                "Only {} blocks of a {} group can be read, {} are needed",
+                read, self.group.policy, data_units
+            )));
+        }
+
+        if wanted.iter().any(|index| shards[*index].is_none()) {
+            let cell_lens = (0..shards.len())
+                .map(|index| self.cell_len(index, stripe))
+                .collect::<Vec<_>>();
+            reconstruct_stripe(&self.codec, &mut shards, &cell_lens)?;
+        }
+        Ok(Some(
+            wanted
+                .iter()
+                .map(|index| shards[*index].take().unwrap_or_default())
+                .collect(),
+        ))
+    }
+
+    async fn read_cell(&mut self, index: usize, stripe: u64) -> CuddlyResult<Vec<u8>> {
+        let len = self.cell_len(index, stripe);
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        if self.streams[index].is_none() {
+            self.streams[index] = Some(self.connect(index).await?);
+        }
+        let stream = self.streams[index].as_mut().unwrap();
+        // A block first read after another one failed starts mid-way
+        stream.skip_to(stripe * self.group.cell_size).await?;
+        stream.read(len as usize).await
+    }
+
+    async fn connect(&self, index: usize) -> CuddlyResult<BlockStream> {
+        let block = &self.group.blocks[index];
+        for location in &self.locations[index] {
+            match BlockStream::open(location, block).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => warn!("Cannot read {} from {}: {:?}", block, location, e),
+            }
+        }
+        Err(CuddlyError::IOError(format!(
+            "No replica of {} can be read",
+            block
+        )))
+    }
+}
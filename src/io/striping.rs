@@ -0,0 +1,113 @@
+use crate::errors::CuddlyResult;
+use crate::utils::reed_solomon::ReedSolomon;
+
+/// Splits a stripe of a block group into one cell per data block. Only the
+/// last stripe of a group can be shorter than `data_units` cells, its last
+/// cells are then short or empty.
+pub(crate) fn split_stripe(stripe: &[u8], cell_size: u64, data_units: usize) -> Vec<Vec<u8>> {
+    let mut cells = stripe
+        .chunks(cell_size as usize)
+        .map(<[u8]>::to_vec)
+        .collect::<Vec<_>>();
+    cells.resize(data_units, vec![]);
+    cells
+}
+
+/// Computes the parity cells of a stripe. The last stripe may be short, its
+/// cells are padded to the first one for encoding, so the parity cells are
+/// as long as the first cell.
+pub(crate) fn encode_stripe(codec: &ReedSolomon, cells: &[Vec<u8>]) -> CuddlyResult<Vec<Vec<u8>>> {
+    let cell_len = cells.first().map_or(0, Vec::len);
+    let padded = cells
+        .iter()
+        .map(|cell| {
+            let mut cell = cell.clone();
+            cell.resize(cell_len, 0);
+            cell
+        })
+        .collect::<Vec<_>>();
+    codec.encode(&padded)
+}
+
+/// Length of the cell at `stripe` of a block of `block_len` bytes. A parity
+/// block is as long as the first data block, so its cells are as long as the
+/// first data block's.
+pub(crate) fn cell_len(block_len: u64, stripe: u64, cell_size: u64) -> u64 {
+    block_len.saturating_sub(stripe * cell_size).min(cell_size)
+}
+
+/// Rebuilds the missing cells of a stripe, given the length of the cell of
+/// each block in it.
+pub(crate) fn reconstruct_stripe(
+    codec: &ReedSolomon,
+    shards: &mut [Option<Vec<u8>>],
+    cell_lens: &[u64],
+) -> CuddlyResult<()> {
+    // Shorter cells were padded with zeros when encoding
+    let len = cell_lens[0] as usize;
+    for shard in shards.iter_mut().flatten() {
+        shard.resize(len, 0);
+    }
+    codec.reconstruct(shards)?;
+    for (shard, cell_len) in shards.iter_mut().zip(cell_lens) {
+        if let Some(shard) = shard {
+            shard.truncate(*cell_len as usize);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_last_stripe() {
+        let (data_units, parity_units, cell_size) = (3, 2, 4);
+        let codec = ReedSolomon::new(data_units, parity_units).unwrap();
+        // Two full stripes and one that fills a cell and a byte
+        let data = (0..29).map(|i| (i * 7 + 3) as u8).collect::<Vec<_>>();
+
+        let mut blocks = vec![vec![]; data_units + parity_units];
+        for stripe in data.chunks(cell_size as usize * data_units) {
+            let cells = split_stripe(stripe, cell_size, data_units);
+            let parity = encode_stripe(&codec, &cells).unwrap();
+            assert!(parity.iter().all(|cell| cell.len() == cells[0].len()));
+            for (block, cell) in blocks.iter_mut().zip(cells.iter().chain(&parity)) {
+                block.extend_from_slice(cell);
+            }
+        }
+        let block_lens = blocks
+            .iter()
+            .map(|block| block.len() as u64)
+            .collect::<Vec<_>>();
+        assert_eq!(block_lens, vec![12, 9, 8, 12, 12]);
+
+        // Data blocks missing from the last stripe, with a short or no cell
+        // there, are rebuilt to their own length
+        for missing in [1, 2] {
+            let mut read = vec![];
+            for stripe in 0..block_lens[0].div_ceil(cell_size) {
+                let cell_lens = block_lens
+                    .iter()
+                    .map(|len| cell_len(*len, stripe, cell_size))
+                    .collect::<Vec<_>>();
+                let mut shards = blocks
+                    .iter()
+                    .zip(&cell_lens)
+                    .map(|(block, len)| {
+                        let start = (stripe * cell_size) as usize;
+                        Some(block[start..start + *len as usize].to_vec())
+                    })
+                    .collect::<Vec<_>>();
+                shards[missing] = None;
+                reconstruct_stripe(&codec, &mut shards, &cell_lens).unwrap();
+                for (shard, len) in shards.iter().zip(&cell_lens) {
+                    assert_eq!(shard.as_ref().unwrap().len() as u64, *len);
+                }
+                read.extend(shards.into_iter().take(data_units).flatten().flatten());
+            }
+            assert_eq!(read, data, "missing {}", missing);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::{block::Block, config::BalancerConfig, cuddlyproto};
+
+use super::{block_placement_policy::BlockPlacementPolicy, datanode_info::DatanodeInfo};
+
+/// A replica that is copied from `source` to `target`, after which the copy
+/// on `source` is deleted.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct BlockMove {
+    pub(super) block: Block,
+    pub(super) source: Uuid,
+    pub(super) target: DatanodeInfo,
+    pub(super) started: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct BalancerStatus {
+    pub(crate) enabled: bool,
+    pub(crate) threshold: f64,
+    pub(crate) average_utilization: f64,
+    pub(crate) over_utilized_datanodes: u32,
+    pub(crate) under_utilized_datanodes: u32,
+    pub(crate) moves_in_progress: u32,
+    pub(crate) moves_completed: u64,
+    pub(crate) moves_failed: u64,
+    pub(crate) bytes_moved: u64,
+    pub(crate) iterations: u64,
+}
+
+impl From<BalancerStatus> for cuddlyproto::BalancerStatusProto {
+    fn from(value: BalancerStatus) -> Self {
+        Self {
+            enabled: value.enabled,
+            threshold: value.threshold,
+            average_utilization: value.average_utilization,
+            over_utilized_datanodes: value.over_utilized_datanodes,
+            under_utilized_datanodes: value.under_utilized_datanodes,
+            moves_in_progress: value.moves_in_progress,
+            moves_completed: value.moves_completed,
+            moves_failed: value.moves_failed,
+            bytes_moved: value.bytes_moved,
+            iterations: value.iterations,
+        }
+    }
+}
+
+/// Used capacity of a datanode in percent.
+pub(super) fn utilization(node: &DatanodeInfo) -> f64 {
+    if node.total_capacity == 0 {
+        return 0.0;
+    }
+    100.0 * node.used_capacity as f64 / node.total_capacity as f64
+}
+
+/// Evens out disk usage by moving replicas from datanodes whose utilization
+/// is above the cluster average by more than `threshold` percentage points to
+/// datanodes below it by more than `threshold`.
+#[derive(Debug)]
+pub(super) struct Balancer {
+    threshold: f64,
+    moves: Mutex<HashMap<Uuid, BlockMove>>,
+    status: Mutex<BalancerStatus>,
+}
+
+impl Balancer {
+    pub(super) fn new(config: &BalancerConfig) -> Self {
+        Self {
+            threshold: config.threshold,
+            moves: Mutex::new(HashMap::new()),
+            status: Mutex::new(BalancerStatus {
+                enabled: config.enabled,
+                threshold: config.threshold,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Plans the moves of one iteration. `replicas_of` returns the blocks held
+    /// by a datanode together with all datanodes holding them. At most
+    /// `budget` bytes are in flight at once, counting moves still in progress.
+    pub(super) fn plan(
+        &self,
+        nodes: &[DatanodeInfo],
+        replicas_of: impl Fn(&Uuid) -> Vec<(Block, HashSet<Uuid>)>,
+        policy: &dyn BlockPlacementPolicy,
+        budget: u64,
+        now: DateTime<Utc>,
+    ) -> Vec<BlockMove> {
+        let total_capacity = nodes.iter().map(|node| node.total_capacity).sum::<u64>();
+        let total_used = nodes.iter().map(|node| node.used_capacity).sum::<u64>();
+        let average = if total_capacity == 0 {
+            0.0
+        } else {
+            100.0 * total_used as f64 / total_capacity as f64
+        };
+
+        let mut over_utilized = nodes
+            .iter()
+            .filter(|node| utilization(node) > average + self.threshold)
+            .copied()
+            .collect::<Vec<_>>();
+        over_utilized.sort_by(|a, b| utilization(b).total_cmp(&utilization(a)));
+        let mut under_utilized = nodes
+            .iter()
+            .filter(|node| utilization(node) < average - self.threshold)
+            .copied()
+            .collect::<Vec<_>>();
+
+        {
+            let mut status = self.status.lock().unwrap();
+            status.iterations += 1;
+            status.average_utilization = average;
+            status.over_utilized_datanodes = over_utilized.len() as u32;
+            status.under_utilized_datanodes = under_utilized.len() as u32;
+        }
+
+        let mut moves = self.moves.lock().unwrap();
+        let in_flight = moves.values().map(|m| m.block.len).sum::<u64>();
+        let mut budget = budget.saturating_sub(in_flight);
+        let mut planned = vec![];
+
+        'sources: for mut source in over_utilized {
+            for (block, holders) in replicas_of(&source.datanode_uuid) {
+                if utilization(&source) <= average {
+                    break;
+                }
+                if block.len > budget {
+                    break 'sources;
+                }
+                if moves.contains_key(&block.id) {
+                    continue;
+                }
+                let candidates = under_utilized
+                    .iter()
+                    .filter(|node| utilization(node) < average)
+                    .copied()
+                    .collect::<Vec<_>>();
+                let Some(target) = policy
+                    .choose_targets(&candidates, 1, &holders, None, block.len)
+                    .pop()
+                else {
+                    continue;
+                };
+
+                debug!(
+                    "Moving block {} from {} to {}",
+                    block, source.datanode_uuid, target.datanode_uuid
+                );
+                let block_move = BlockMove {
+                    block,
+                    source: source.datanode_uuid,
+                    target,
+                    started: now,
+                };
+                moves.insert(block.id, block_move);
+                planned.push(block_move);
+                budget -= block.len;
+                source.used_capacity = source.used_capacity.saturating_sub(block.len);
+                if let Some(node) = under_utilized
+                    .iter_mut()
+                    .find(|node| node.datanode_uuid == target.datanode_uuid)
+                {
+                    node.used_capacity += block.len;
+                }
+            }
+        }
+
+        if !planned.is_empty() {
+            info!(
+                "Balancer scheduled {} moves, average utilization {:.2}%",
+                planned.len(),
+                average
+            );
+        }
+        planned
+    }
+
+    /// Finishes the move of `block_id` if `receiver` is its target, and
+    /// returns it so the source replica can be deleted.
+    pub(super) fn complete(&self, block_id: &Uuid, receiver: &Uuid) -> Option<BlockMove> {
+        let mut moves = self.moves.lock().unwrap();
+        if moves.get(block_id)?.target.datanode_uuid != *receiver {
+            return None;
+        }
+        let block_move = moves.remove(block_id)?;
+        let mut status = self.status.lock().unwrap();
+        status.moves_completed += 1;
+        status.bytes_moved += block_move.block.len;
+        Some(block_move)
+    }
+
+    /// Gives up on moves that did not finish within `timeout` seconds.
+    pub(super) fn expire(&self, now: DateTime<Utc>, timeout: i64) {
+        let mut moves = self.moves.lock().unwrap();
+        let before = moves.len();
+        moves.retain(|_, m| now.signed_duration_since(m.started).num_seconds() <= timeout);
+        let failed = before - moves.len();
+        if failed > 0 {
+            warn!("{} balancer moves timed out", failed);
+            self.status.lock().unwrap().moves_failed += failed as u64;
+        }
+    }
+
+    pub(super) fn status(&self) -> BalancerStatus {
+        let moves_in_progress = self.moves.lock().unwrap().len() as u32;
+        BalancerStatus {
+            moves_in_progress,
+            ..*self.status.lock().unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::namenode::block_placement_policy::{
+        new_block_placement_policy, BlockPlacementPolicyKind,
+    };
+
+    use super::*;
+
+    fn node(id: u128, used: u64) -> DatanodeInfo {
+        DatanodeInfo::new(
+            ([10, 0, 0, id as u8], 50052),
+            Uuid::from_u128(id),
+            1000,
+            used,
+        )
+    }
+
+    #[test]
+    fn test_moves_from_full_to_empty_nodes_within_budget() {
+        let config = BalancerConfig {
+            enabled: true,
+            threshold: 10.0,
+            ..Default::default()
+        };
+        let balancer = Balancer::new(&config);
+        let policy =
+            new_block_placement_policy(BlockPlacementPolicyKind::Random, Default::default());
+        let nodes = [node(1, 900), node(2, 500), node(3, 100)];
+        let replicas_of = |uuid: &Uuid| {
+            if *uuid != Uuid::from_u128(1) {
+                return vec![];
+            }
+            (0..9)
+                .map(|i| {
+                    (
+                        Block::new(Uuid::from_u128(100 + i), 100, 0),
+                        HashSet::from([*uuid, Uuid::from_u128(2)]),
+                    )
+                })
+                .collect()
+        };
+
+        let now = Utc::now();
+        let moves = balancer.plan(&nodes, replicas_of, policy.as_ref(), 250, now);
+        assert_eq!(moves.len(), 2);
+        assert!(moves
+            .iter()
+            .all(|m| m.target.datanode_uuid == Uuid::from_u128(3)));
+
+        // The budget is used up by the moves in flight.
+        assert!(balancer
+            .plan(&nodes, replicas_of, policy.as_ref(), 250, now)
+            .is_empty());
+
+        let first = moves[0];
+        assert!(balancer
+            .complete(&first.block.id, &Uuid::from_u128(2))
+            .is_none());
+        assert!(balancer
+            .complete(&first.block.id, &Uuid::from_u128(3))
+            .is_some());
+        balancer.expire(now + chrono::Duration::seconds(1000), 300);
+
+        let status = balancer.status();
+        assert_eq!(status.iterations, 2);
+        assert_eq!(status.over_utilized_datanodes, 1);
+        assert_eq!(status.under_utilized_datanodes, 1);
+        assert_eq!(status.moves_completed, 1);
+        assert_eq!(status.moves_failed, 1);
+        assert_eq!(status.bytes_moved, 100);
+        assert_eq!(status.moves_in_progress, 0);
+    }
+}
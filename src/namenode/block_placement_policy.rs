@@ -0,0 +1,344 @@
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use rand::{seq::SliceRandom, thread_rng};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use super::{
+    datanode_info::DatanodeInfo,
+    network_topology::{NetworkTopology, DEFAULT_RACK},
+};
+
+/// The block placement policies that can be selected through the config.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockPlacementPolicyKind {
+    #[default]
+    Random,
+    AvailableSpace,
+    RoundRobin,
+    Topology,
+}
+
+/// Decides on which datanodes the replicas of a block are placed.
+///
+/// Used for new blocks as well as for re-replication and balancing, where
+/// `excluded` holds the nodes that already have a replica.
+pub(crate) trait BlockPlacementPolicy: std::fmt::Debug + Send + Sync {
+    /// Chooses up to `replication` targets out of `candidates`. Nodes in
+    /// `excluded` and nodes without room for `block_size` more bytes are never
+    /// chosen. If the writer runs on one of the candidates, that node comes
+    /// first, so it becomes the head of the write pipeline.
+    fn choose_targets(
+        &self,
+        candidates: &[DatanodeInfo],
+        replication: usize,
+        excluded: &HashSet<Uuid>,
+        writer_location: Option<IpAddr>,
+        block_size: u64,
+    ) -> Vec<DatanodeInfo>;
+}
+
+pub(crate) fn new_block_placement_policy(
+    kind: BlockPlacementPolicyKind,
+    topology: Arc<RwLock<NetworkTopology>>,
+) -> Box<dyn BlockPlacementPolicy> {
+    match kind {
+        BlockPlacementPolicyKind::Random => Box::new(RandomPlacementPolicy),
+        BlockPlacementPolicyKind::AvailableSpace => Box::new(AvailableSpacePlacementPolicy),
+        BlockPlacementPolicyKind::RoundRobin => Box::new(RoundRobinPlacementPolicy::default()),
+        BlockPlacementPolicyKind::Topology => Box::new(TopologyPlacementPolicy { topology }),
+    }
+}
+
+fn eligible_nodes(
+    candidates: &[DatanodeInfo],
+    excluded: &HashSet<Uuid>,
+    block_size: u64,
+) -> Vec<DatanodeInfo> {
+    candidates
+        .iter()
+        .filter(|node| !excluded.contains(&node.datanode_uuid))
+        .filter(|node| node.free_capacity() > block_size)
+        .copied()
+        .collect()
+}
+
+/// Takes the writer's own node out of `nodes`, if it is one of them.
+fn take_local_node(
+    nodes: &mut Vec<DatanodeInfo>,
+    writer_location: Option<IpAddr>,
+) -> Option<DatanodeInfo> {
+    let writer_location = writer_location?;
+    let index = nodes
+        .iter()
+        .position(|node| node.socket_address.ip() == writer_location)?;
+    Some(nodes.remove(index))
+}
+
+fn take_first(
+    nodes: &mut Vec<DatanodeInfo>,
+    predicate: impl Fn(&DatanodeInfo) -> bool,
+) -> Option<DatanodeInfo> {
+    let index = nodes.iter().position(predicate)?;
+    Some(nodes.remove(index))
+}
+
+/// Picks uniformly random nodes.
+#[derive(Debug)]
+pub(crate) struct RandomPlacementPolicy;
+
+impl BlockPlacementPolicy for RandomPlacementPolicy {
+    fn choose_targets(
+        &self,
+        candidates: &[DatanodeInfo],
+        replication: usize,
+        excluded: &HashSet<Uuid>,
+        writer_location: Option<IpAddr>,
+        block_size: u64,
+    ) -> Vec<DatanodeInfo> {
+        let mut nodes = eligible_nodes(candidates, excluded, block_size);
+        let mut targets = take_local_node(&mut nodes, writer_location)
+            .into_iter()
+            .collect::<Vec<_>>();
+        nodes.shuffle(&mut thread_rng());
+        targets.extend(nodes);
+        targets.truncate(replication);
+        targets
+    }
+}
+
+/// Picks random nodes, weighted by their free capacity, so that emptier nodes
+/// fill up faster.
+#[derive(Debug)]
+pub(crate) struct AvailableSpacePlacementPolicy;
+
+impl BlockPlacementPolicy for AvailableSpacePlacementPolicy {
+    fn choose_targets(
+        &self,
+        candidates: &[DatanodeInfo],
+        replication: usize,
+        excluded: &HashSet<Uuid>,
+        writer_location: Option<IpAddr>,
+        block_size: u64,
+    ) -> Vec<DatanodeInfo> {
+        let mut nodes = eligible_nodes(candidates, excluded, block_size);
+        let mut targets = take_local_node(&mut nodes, writer_location)
+            .into_iter()
+            .collect::<Vec<_>>();
+        let remaining = replication.saturating_sub(targets.len());
+        match nodes.choose_multiple_weighted(&mut thread_rng(), remaining, |node| {
+            node.free_capacity() as f64
+        }) {
+            Ok(chosen) => targets.extend(chosen.copied()),
+            Err(_) => {
+                nodes.shuffle(&mut thread_rng());
+                targets.extend(nodes);
+            }
+        }
+        targets.truncate(replication);
+        targets
+    }
+}
+
+/// Cycles through the nodes in a fixed order.
+#[derive(Debug, Default)]
+pub(crate) struct RoundRobinPlacementPolicy {
+    next_index: Mutex<usize>,
+}
+
+impl BlockPlacementPolicy for RoundRobinPlacementPolicy {
+    fn choose_targets(
+        &self,
+        candidates: &[DatanodeInfo],
+        replication: usize,
+        excluded: &HashSet<Uuid>,
+        writer_location: Option<IpAddr>,
+        block_size: u64,
+    ) -> Vec<DatanodeInfo> {
+        let mut nodes = eligible_nodes(candidates, excluded, block_size);
+        let mut targets = take_local_node(&mut nodes, writer_location)
+            .into_iter()
+            .collect::<Vec<_>>();
+        if nodes.is_empty() {
+            return targets;
+        }
+        nodes.sort_by_key(|node| node.datanode_uuid);
+
+        let mut next_index = self.next_index.lock().unwrap();
+        let start = *next_index % nodes.len();
+        let remaining = replication.saturating_sub(targets.len()).min(nodes.len());
+        targets.extend(nodes.iter().cycle().skip(start).take(remaining).copied());
+        *next_index = (start + remaining) % nodes.len();
+        targets
+    }
+}
+
+/// Spreads replicas over failure domains: the first replica goes to the
+/// writer's node or rack, the second to a different rack and the third to the
+/// second's rack. Any further replicas are placed randomly.
+#[derive(Debug)]
+pub(crate) struct TopologyPlacementPolicy {
+    topology: Arc<RwLock<NetworkTopology>>,
+}
+
+impl BlockPlacementPolicy for TopologyPlacementPolicy {
+    fn choose_targets(
+        &self,
+        candidates: &[DatanodeInfo],
+        replication: usize,
+        excluded: &HashSet<Uuid>,
+        writer_location: Option<IpAddr>,
+        block_size: u64,
+    ) -> Vec<DatanodeInfo> {
+        let mut nodes = eligible_nodes(candidates, excluded, block_size);
+        nodes.shuffle(&mut thread_rng());
+
+        let topology = self.topology.read().unwrap();
+        let rack_of = |node: &DatanodeInfo| {
+            topology
+                .location_of(&node.datanode_uuid)
+                .unwrap_or(DEFAULT_RACK)
+                .to_owned()
+        };
+
+        let mut targets = vec![];
+        if replication == 0 {
+            return targets;
+        }
+
+        let writer_rack = writer_location
+            .and_then(|ip| topology.location_of_ip(ip))
+            .map(str::to_owned);
+        let first = take_local_node(&mut nodes, writer_location)
+            .or_else(|| {
+                let writer_rack = writer_rack.as_ref()?;
+                take_first(&mut nodes, |node| rack_of(node) == *writer_rack)
+            })
+            .or_else(|| nodes.pop());
+        let Some(first) = first else {
+            return targets;
+        };
+        targets.push(first);
+
+        if targets.len() < replication {
+            let first_rack = rack_of(&first);
+            let second =
+                take_first(&mut nodes, |node| rack_of(node) != first_rack).or_else(|| nodes.pop());
+            if let Some(second) = second {
+                targets.push(second);
+                if targets.len() < replication {
+                    let second_rack = rack_of(&second);
+                    let third = take_first(&mut nodes, |node| rack_of(node) == second_rack)
+                        .or_else(|| nodes.pop());
+                    targets.extend(third);
+                }
+            }
+        }
+
+        let remaining = replication.saturating_sub(targets.len());
+        targets.extend(nodes.into_iter().take(remaining));
+        targets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(count: u8) -> Vec<DatanodeInfo> {
+        (0..count)
+            .map(|i| {
+                DatanodeInfo::new(
+                    ([10, 0, 0, i], 50052),
+                    Uuid::from_u128(i as u128),
+                    1000,
+                    100 * i as u64,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_policies_respect_exclusions_and_capacity() {
+        let candidates = nodes(6);
+        let excluded = HashSet::from([Uuid::from_u128(0)]);
+        for kind in [
+            BlockPlacementPolicyKind::Random,
+            BlockPlacementPolicyKind::AvailableSpace,
+            BlockPlacementPolicyKind::RoundRobin,
+            BlockPlacementPolicyKind::Topology,
+        ] {
+            let policy = new_block_placement_policy(kind, Default::default());
+            // Nodes 4 and 5 have less than 650 bytes free.
+            let targets = policy.choose_targets(&candidates, 3, &excluded, None, 650);
+            let uuids = targets
+                .iter()
+                .map(|node| node.datanode_uuid.as_u128())
+                .collect::<HashSet<_>>();
+            assert_eq!(uuids, HashSet::from([1, 2, 3]), "policy {:?}", kind);
+        }
+    }
+
+    #[test]
+    fn test_writer_node_comes_first() {
+        let candidates = nodes(5);
+        let policy =
+            new_block_placement_policy(BlockPlacementPolicyKind::Random, Default::default());
+        let writer = Some(IpAddr::from([10, 0, 0, 3]));
+        let targets = policy.choose_targets(&candidates, 3, &HashSet::new(), writer, 0);
+        assert_eq!(targets.len(), 3);
+        assert_eq!(targets[0].datanode_uuid, Uuid::from_u128(3));
+    }
+
+    #[test]
+    fn test_round_robin_cycles() {
+        let candidates = nodes(4);
+        let policy = RoundRobinPlacementPolicy::default();
+        let first = policy.choose_targets(&candidates, 2, &HashSet::new(), None, 0);
+        let second = policy.choose_targets(&candidates, 2, &HashSet::new(), None, 0);
+        let third = policy.choose_targets(&candidates, 2, &HashSet::new(), None, 0);
+        let ids = |targets: &[DatanodeInfo]| {
+            targets
+                .iter()
+                .map(|node| node.datanode_uuid.as_u128())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&first), vec![0, 1]);
+        assert_eq!(ids(&second), vec![2, 3]);
+        assert_eq!(ids(&third), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_topology_spreads_over_racks() {
+        let candidates = nodes(6);
+        let mut topology = NetworkTopology::default();
+        for node in &candidates {
+            let rack = if node.datanode_uuid.as_u128() < 3 {
+                "/dc1/rack1"
+            } else {
+                "/dc1/rack2"
+            };
+            topology.add(node.datanode_uuid, node.socket_address.ip(), rack.into());
+        }
+        let topology = Arc::new(RwLock::new(topology));
+        let policy = new_block_placement_policy(BlockPlacementPolicyKind::Topology, topology);
+
+        for _ in 0..20 {
+            let writer = Some(IpAddr::from([10, 0, 0, 1]));
+            let targets = policy.choose_targets(&candidates, 3, &HashSet::new(), writer, 0);
+            let ids = targets
+                .iter()
+                .map(|node| node.datanode_uuid.as_u128())
+                .collect::<Vec<_>>();
+            assert_eq!(ids[0], 1);
+            assert!(ids[1] >= 3, "second replica must be off rack: {:?}", ids);
+            assert!(ids[2] >= 3, "third replica must share rack: {:?}", ids);
+            assert_ne!(ids[1], ids[2]);
+        }
+    }
+}
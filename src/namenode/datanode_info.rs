@@ -5,12 +5,100 @@ use uuid::Uuid;
 
 use crate::cuddlyproto;
 
+use super::storage_policy::StorageType;
+
+/// Liveness and administrative state of a datanode.
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub(crate) enum DatanodeState {
+    #[default]
+    Live,
+    /// Missed some heartbeats. Only used for reads and writes if there is no
+    /// live alternative.
+    Stale,
+    /// Missed heartbeats for too long. Its replicas are re-replicated.
+    Dead,
+    /// Replicas are being copied elsewhere before the node is retired.
+    DecommissionInProgress,
+    /// Every block is replicated elsewhere, the node can be shut down.
+    Decommissioned,
+    /// Temporarily down for patching. Its replicas still count, so they are
+    /// not re-replicated until the maintenance window ends.
+    InMaintenance,
+}
+
+impl DatanodeState {
+    /// The state after a heartbeat was received.
+    pub(crate) fn after_heartbeat(self) -> Self {
+        match self {
+            Self::Live | Self::Stale | Self::Dead => Self::Live,
+            Self::DecommissionInProgress | Self::Decommissioned | Self::InMaintenance => self,
+        }
+    }
+
+    /// The state after no heartbeat was received for `elapsed` seconds.
+    pub(crate) fn after_silence(self, elapsed: i64, stale_after: i64, dead_after: i64) -> Self {
+        if self == Self::InMaintenance {
+            self
+        } else if elapsed > dead_after {
+            Self::Dead
+        } else if elapsed > stale_after && self == Self::Live {
+            Self::Stale
+        } else {
+            self
+        }
+    }
+
+    /// Whether new replicas may be placed on a node in this state.
+    pub(crate) fn accepts_new_replicas(self) -> bool {
+        matches!(self, Self::Live | Self::Stale)
+    }
+
+    /// Whether a replica on a node in this state counts towards the
+    /// replication factor of its block.
+    pub(crate) fn counts_as_replica(self) -> bool {
+        matches!(self, Self::Live | Self::Stale | Self::InMaintenance)
+    }
+
+    /// Whether a replica on a node in this state should be read from when
+    /// there are alternatives.
+    pub(crate) fn preferred_for_reads(self) -> bool {
+        matches!(self, Self::Live | Self::DecommissionInProgress)
+    }
+}
+
+impl From<cuddlyproto::DatanodeStateProto> for DatanodeState {
+    fn from(value: cuddlyproto::DatanodeStateProto) -> Self {
+        match value {
+            cuddlyproto::DatanodeStateProto::Live => Self::Live,
+            cuddlyproto::DatanodeStateProto::Stale => Self::Stale,
+            cuddlyproto::DatanodeStateProto::Dead => Self::Dead,
+            cuddlyproto::DatanodeStateProto::DecommissionInProgress => Self::DecommissionInProgress,
+            cuddlyproto::DatanodeStateProto::Decommissioned => Self::Decommissioned,
+            cuddlyproto::DatanodeStateProto::InMaintenance => Self::InMaintenance,
+        }
+    }
+}
+
+impl From<DatanodeState> for cuddlyproto::DatanodeStateProto {
+    fn from(value: DatanodeState) -> Self {
+        match value {
+            DatanodeState::Live => Self::Live,
+            DatanodeState::Stale => Self::Stale,
+            DatanodeState::Dead => Self::Dead,
+            DatanodeState::DecommissionInProgress => Self::DecommissionInProgress,
+            DatanodeState::Decommissioned => Self::Decommissioned,
+            DatanodeState::InMaintenance => Self::InMaintenance,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub(crate) struct DatanodeInfo {
     pub(crate) socket_address: SocketAddr,
     pub(crate) datanode_uuid: Uuid,
     pub(crate) total_capacity: u64,
     pub(crate) used_capacity: u64,
+    pub(crate) state: DatanodeState,
 }
 
 #[allow(dead_code)]
@@ -26,6 +114,7 @@ impl DatanodeInfo {
             datanode_uuid: datanode_uuid.into(),
             total_capacity,
             used_capacity,
+            state: DatanodeState::default(),
         }
     }
 
@@ -48,31 +137,52 @@ impl DatanodeInfo {
     pub(crate) fn free_capacity(&self) -> u64 {
         self.total_capacity - self.used_capacity
     }
+
+    pub(crate) fn state(&self) -> DatanodeState {
+        self.state
+    }
+}
+
+/// The holders of a replica that readers are sent to. Dead holders are
+/// dropped, and stale, retired or maintained ones are only kept if no other
+/// holder is left.
+pub(crate) fn readable_holders(mut holders: Vec<DatanodeInfo>) -> Vec<DatanodeInfo> {
+    holders.retain(|holder| holder.state != DatanodeState::Dead);
+    if holders
+        .iter()
+        .any(|holder| holder.state.preferred_for_reads())
+    {
+        holders.retain(|holder| holder.state.preferred_for_reads());
+    }
+    holders
 }
 
 impl std::fmt::Display for DatanodeInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "DatanodeInfo {{ socket_address: {}, datanode_uuid: {}, total_capacity: {}, used_capacity: {} }}",
-            self.socket_address, self.datanode_uuid, self.total_capacity, self.used_capacity
+            "DatanodeInfo {{ socket_address: {}, datanode_uuid: {}, total_capacity: {}, used_capacity: {}, state: {:?} }}",
+            self.socket_address, self.datanode_uuid, self.total_capacity, self.used_capacity, self.state
         )
     }
 }
 
 impl From<cuddlyproto::DatanodeInfo> for DatanodeInfo {
     fn from(value: cuddlyproto::DatanodeInfo) -> Self {
+        let state = value.state().into();
         let cuddlyproto::DatanodeInfo {
             socket_address,
             datanode_uuid,
             total_capacity,
             used_capacity,
+            state: _,
         } = value;
         Self {
             socket_address: socket_address.parse().unwrap(),
             datanode_uuid: Uuid::parse_str(&datanode_uuid).unwrap(),
             total_capacity,
             used_capacity,
+            state,
         }
     }
 }
@@ -84,6 +194,7 @@ impl From<DatanodeInfo> for cuddlyproto::DatanodeInfo {
             datanode_uuid,
             total_capacity,
             used_capacity,
+            state,
         } = value;
         let socket_address = socket_address.to_string();
         let datanode_uuid = datanode_uuid.to_string();
@@ -92,6 +203,80 @@ impl From<DatanodeInfo> for cuddlyproto::DatanodeInfo {
             datanode_uuid,
             total_capacity,
             used_capacity,
+            state: cuddlyproto::DatanodeStateProto::from(state) as i32,
+        }
+    }
+}
+
+/// A storage of a datanode as of its last heartbeat.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) struct DatanodeStorage {
+    pub(crate) storage_uuid: Uuid,
+    pub(crate) storage_type: StorageType,
+    pub(crate) capacity: u64,
+    pub(crate) dfs_used: u64,
+    pub(crate) remaining: u64,
+    pub(crate) failed: bool,
+}
+
+impl From<&cuddlyproto::StorageReportProto> for DatanodeStorage {
+    /// Reports without a storage are taken as coming from a disk.
+    fn from(value: &cuddlyproto::StorageReportProto) -> Self {
+        let (storage_uuid, storage_type) = match &value.storage {
+            Some(storage) => (
+                Uuid::parse_str(&storage.storage_uuid).unwrap_or_default(),
+                StorageType::from_proto(storage.storage_type),
+            ),
+            None => (Uuid::nil(), StorageType::Disk),
+        };
+        Self {
+            storage_uuid,
+            storage_type,
+            capacity: value.capacity,
+            dfs_used: value.dfs_used,
+            remaining: value.remaining,
+            failed: value.failed,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_transitions() {
+        let state = DatanodeState::Live;
+        assert_eq!(state.after_silence(10, 30, 600), DatanodeState::Live);
+        let state = state.after_silence(31, 30, 600);
+        assert_eq!(state, DatanodeState::Stale);
+        assert_eq!(state.after_heartbeat(), DatanodeState::Live);
+        let state = state.after_silence(601, 30, 600);
+        assert_eq!(state, DatanodeState::Dead);
+        assert_eq!(state.after_heartbeat(), DatanodeState::Live);
+
+        let state = DatanodeState::DecommissionInProgress;
+        assert_eq!(state.after_silence(31, 30, 600), state);
+        assert_eq!(state.after_heartbeat(), state);
+        assert!(!state.accepts_new_replicas());
+
+        let state = DatanodeState::InMaintenance;
+        assert_eq!(state.after_silence(601, 30, 600), state);
+        assert!(state.counts_as_replica());
+        assert!(!state.accepts_new_replicas());
+    }
+
+    #[test]
+    fn test_readable_holders() {
+        let holder = |port: u16, state: DatanodeState| DatanodeInfo {
+            state,
+            ..DatanodeInfo::new(([127, 0, 0, 1], port), Uuid::new_v4(), 100, 0)
+        };
+        let live = holder(1, DatanodeState::Live);
+        let stale = holder(2, DatanodeState::Stale);
+        let dead = holder(3, DatanodeState::Dead);
+        assert_eq!(readable_holders(vec![stale, dead, live]), vec![live]);
+        // Stale copies are better than none
+        assert_eq!(readable_holders(vec![dead, stale]), vec![stale]);
+    }
+}
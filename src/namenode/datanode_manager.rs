@@ -0,0 +1,575 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    sync::RwLock,
+};
+
+use chrono::{DateTime, Utc};
+use log::info;
+use uuid::Uuid;
+
+use crate::utils::key_to_data_and_id_map::KeyToDataAndIdMap;
+
+use super::{
+    datanode_info::{DatanodeInfo, DatanodeState, DatanodeStorage},
+    storage_policy::StorageType,
+};
+
+#[derive(Debug)]
+struct DatanodeTable {
+    datanodes: KeyToDataAndIdMap<Uuid, DatanodeInfo, Uuid>,
+    last_heartbeats: HashMap<Uuid, DateTime<Utc>>,
+    by_address: HashMap<SocketAddr, Uuid>,
+    host_names: HashMap<Uuid, String>,
+    maintenance_ends: HashMap<Uuid, DateTime<Utc>>,
+    storages: HashMap<Uuid, Vec<DatanodeStorage>>,
+    // Storage type of each replica, by datanode and block
+    replica_types: HashMap<(Uuid, Uuid), StorageType>,
+}
+
+/// Keeps track of every datanode that ever sent a heartbeat, indexed by UUID
+/// and by address, together with its last heartbeat, its storages and the
+/// blocks it holds.
+///
+/// Nodes are never evicted; dead nodes stay known with their state set to
+/// `Dead` until they send a heartbeat again. All state lives behind a single
+/// lock, which is never held while calling out of the manager. Callers that
+/// also lock `block_to_datanodes` must take that lock first.
+#[derive(Debug)]
+pub(super) struct DatanodeManager {
+    table: RwLock<DatanodeTable>,
+}
+
+impl DatanodeManager {
+    pub(super) fn new() -> Self {
+        Self {
+            table: RwLock::new(DatanodeTable {
+                datanodes: KeyToDataAndIdMap::new(),
+                last_heartbeats: HashMap::new(),
+                by_address: HashMap::new(),
+                host_names: HashMap::new(),
+                maintenance_ends: HashMap::new(),
+                storages: HashMap::new(),
+                replica_types: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Records a heartbeat and returns whether the datanode was previously
+    /// unknown. The administrative state of known nodes is kept.
+    pub(super) fn heartbeat(
+        &self,
+        datanode_uuid: Uuid,
+        socket_address: SocketAddr,
+        host_name: &str,
+        total_capacity: u64,
+        used_capacity: u64,
+        now: DateTime<Utc>,
+    ) -> bool {
+        let mut table = self.table.write().unwrap();
+        let previous = table.datanodes.get_data(&datanode_uuid).copied();
+        let state = previous
+            .map(|info| info.state.after_heartbeat())
+            .unwrap_or_default();
+        if let Some(previous) = previous {
+            if previous.socket_address != socket_address {
+                table.by_address.remove(&previous.socket_address);
+            }
+        }
+
+        table.datanodes.update_data(
+            datanode_uuid,
+            DatanodeInfo {
+                socket_address,
+                datanode_uuid,
+                total_capacity,
+                used_capacity,
+                state,
+            },
+        );
+        table.last_heartbeats.insert(datanode_uuid, now);
+        table.by_address.insert(socket_address, datanode_uuid);
+        table.host_names.insert(datanode_uuid, host_name.to_owned());
+
+        if previous.is_none() {
+            info!("New Datanode Connected with uuid: {}", datanode_uuid);
+        }
+        previous.is_none()
+    }
+
+    /// Replaces the storages of a datanode with the ones of its last
+    /// heartbeat.
+    pub(super) fn update_storages(&self, datanode_uuid: Uuid, storages: Vec<DatanodeStorage>) {
+        self.table
+            .write()
+            .unwrap()
+            .storages
+            .insert(datanode_uuid, storages);
+    }
+
+    /// The capacity and usage of the working storages of the datanodes, by
+    /// storage type.
+    pub(super) fn storage_type_usage(&self) -> BTreeMap<StorageType, (u64, u64)> {
+        let table = self.table.read().unwrap();
+        let mut usage = BTreeMap::new();
+        for storage in table.storages.values().flatten() {
+            if storage.failed {
+                continue;
+            }
+            let (capacity, used) = usage.entry(storage.storage_type).or_insert((0, 0));
+            *capacity += storage.capacity;
+            *used += storage.dfs_used;
+        }
+        usage
+    }
+
+    /// Keeps the nodes that have a working storage of `storage_type`, with
+    /// their capacity narrowed down to the storages of that type.
+    pub(super) fn with_storage_type(
+        &self,
+        nodes: &[DatanodeInfo],
+        storage_type: StorageType,
+    ) -> Vec<DatanodeInfo> {
+        let table = self.table.read().unwrap();
+        nodes
+            .iter()
+            .filter_map(|node| {
+                let storages = table
+                    .storages
+                    .get(&node.datanode_uuid)?
+                    .iter()
+                    .filter(|storage| storage.storage_type == storage_type && !storage.failed)
+                    .collect::<Vec<_>>();
+                if storages.is_empty() {
+                    return None;
+                }
+                let total_capacity = storages.iter().map(|storage| storage.capacity).sum();
+                let remaining: u64 = storages.iter().map(|storage| storage.remaining).sum();
+                Some(DatanodeInfo {
+                    total_capacity,
+                    used_capacity: total_capacity - remaining.min(total_capacity),
+                    ..*node
+                })
+            })
+            .collect()
+    }
+
+    /// The storage type of the replica of `block_id` on a datanode.
+    pub(super) fn storage_type_of(
+        &self,
+        datanode_uuid: &Uuid,
+        block_id: &Uuid,
+    ) -> Option<StorageType> {
+        self.table
+            .read()
+            .unwrap()
+            .replica_types
+            .get(&(*datanode_uuid, *block_id))
+            .copied()
+    }
+
+    pub(super) fn uuid_for_address(&self, socket_address: &SocketAddr) -> Option<Uuid> {
+        self.table
+            .read()
+            .unwrap()
+            .by_address
+            .get(socket_address)
+            .copied()
+    }
+
+    /// Finds a datanode by its UUID or by its socket address.
+    pub(super) fn resolve(&self, datanode: &str) -> Option<Uuid> {
+        if let Ok(datanode_uuid) = Uuid::parse_str(datanode) {
+            return self
+                .table
+                .read()
+                .unwrap()
+                .datanodes
+                .contains_key(&datanode_uuid)
+                .then_some(datanode_uuid);
+        }
+        let socket_address = datanode.parse::<SocketAddr>().ok()?;
+        self.uuid_for_address(&socket_address)
+    }
+
+    pub(super) fn get(&self, datanode_uuid: &Uuid) -> Option<DatanodeInfo> {
+        self.table
+            .read()
+            .unwrap()
+            .datanodes
+            .get_data(datanode_uuid)
+            .copied()
+    }
+
+    /// The host name a datanode reported in its last heartbeat.
+    pub(super) fn host_name(&self, datanode_uuid: &Uuid) -> String {
+        self.table
+            .read()
+            .unwrap()
+            .host_names
+            .get(datanode_uuid)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Every known datanode, including dead ones.
+    pub(super) fn all_datanodes(&self) -> Vec<DatanodeInfo> {
+        self.table
+            .read()
+            .unwrap()
+            .datanodes
+            .iter_data()
+            .map(|(_, info)| *info)
+            .collect()
+    }
+
+    /// Datanodes that are not dead.
+    pub(super) fn alive_datanodes(&self) -> Vec<DatanodeInfo> {
+        self.table
+            .read()
+            .unwrap()
+            .datanodes
+            .iter_data()
+            .map(|(_, info)| *info)
+            .filter(|info| info.state != DatanodeState::Dead)
+            .collect()
+    }
+
+    pub(super) fn datanodes_in_state(&self, state: DatanodeState) -> Vec<Uuid> {
+        self.table
+            .read()
+            .unwrap()
+            .datanodes
+            .iter_data()
+            .filter(|(_, info)| info.state == state)
+            .map(|(uuid, _)| *uuid)
+            .collect()
+    }
+
+    pub(super) fn blocks_of(&self, datanode_uuid: &Uuid) -> HashSet<Uuid> {
+        self.table
+            .read()
+            .unwrap()
+            .datanodes
+            .get_ids_for_key(datanode_uuid)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Sets the administrative state of a datanode. `maintenance_end` is only
+    /// used for `InMaintenance`. Returns the updated datanode, or `None` if it
+    /// is unknown.
+    pub(super) fn set_state(
+        &self,
+        datanode_uuid: &Uuid,
+        state: DatanodeState,
+        maintenance_end: Option<DateTime<Utc>>,
+    ) -> Option<DatanodeInfo> {
+        let mut guard = self.table.write().unwrap();
+        let table = &mut *guard;
+        let info = table.datanodes.get_data_mut(datanode_uuid)?;
+        info!(
+            "Datanode {} changed from {:?} to {:?}",
+            datanode_uuid, info.state, state
+        );
+        info.state = state;
+        let info = *info;
+        match (state, maintenance_end) {
+            (DatanodeState::InMaintenance, Some(end)) => {
+                table.maintenance_ends.insert(*datanode_uuid, end);
+            }
+            _ => {
+                table.maintenance_ends.remove(datanode_uuid);
+            }
+        }
+        Some(info)
+    }
+
+    /// Records that `datanode_uuid` holds `block_id` on a storage of
+    /// `storage_type`. Returns false if the datanode is unknown.
+    pub(super) fn add_block(
+        &self,
+        datanode_uuid: Uuid,
+        block_id: Uuid,
+        storage_type: StorageType,
+    ) -> bool {
+        let mut table = self.table.write().unwrap();
+        if !table.datanodes.contains_key(&datanode_uuid) {
+            return false;
+        }
+        table
+            .datanodes
+            .insert_id_for_key_if_present(datanode_uuid, block_id);
+        table
+            .replica_types
+            .insert((datanode_uuid, block_id), storage_type);
+        true
+    }
+
+    pub(super) fn remove_block(&self, datanode_uuid: &Uuid, block_id: &Uuid) {
+        let mut table = self.table.write().unwrap();
+        table.datanodes.remove_id_for_key(datanode_uuid, block_id);
+        table.replica_types.remove(&(*datanode_uuid, *block_id));
+    }
+
+    /// Applies the state transitions for missed heartbeats and returns the
+    /// nodes that just became dead.
+    pub(super) fn update_states(
+        &self,
+        now: DateTime<Utc>,
+        stale_after: i64,
+        dead_after: i64,
+    ) -> Vec<Uuid> {
+        let mut guard = self.table.write().unwrap();
+        let table = &mut *guard;
+
+        let ended = table
+            .maintenance_ends
+            .iter()
+            .filter(|(_, end)| **end <= now)
+            .map(|(uuid, _)| *uuid)
+            .collect::<Vec<_>>();
+        for uuid in ended {
+            table.maintenance_ends.remove(&uuid);
+            if let Some(info) = table.datanodes.get_data_mut(&uuid) {
+                info!("Maintenance of datanode {} ended", uuid);
+                info.state = DatanodeState::Live;
+            }
+        }
+
+        let mut dead = vec![];
+        for (uuid, last_heartbeat) in &table.last_heartbeats {
+            let Some(info) = table.datanodes.get_data_mut(uuid) else {
+                continue;
+            };
+            if info.state == DatanodeState::Dead {
+                continue;
+            }
+            let elapsed = now.signed_duration_since(*last_heartbeat).num_seconds();
+            let state = info.state.after_silence(elapsed, stale_after, dead_after);
+            if state != info.state {
+                info!(
+                    "Datanode {} changed from {:?} to {:?}",
+                    uuid, info.state, state
+                );
+                info.state = state;
+                if state == DatanodeState::Dead {
+                    dead.push(*uuid);
+                }
+            }
+        }
+        dead
+    }
+
+    /// Forgets the address and the blocks of a dead datanode and returns the
+    /// blocks it held. The node itself stays known as dead.
+    pub(super) fn remove_dead(&self, datanode_uuid: &Uuid) -> HashSet<Uuid> {
+        let mut table = self.table.write().unwrap();
+        let Some(info) = table.datanodes.get_data(datanode_uuid).copied() else {
+            return HashSet::new();
+        };
+        if table.by_address.get(&info.socket_address) == Some(datanode_uuid) {
+            table.by_address.remove(&info.socket_address);
+        }
+        table.storages.remove(datanode_uuid);
+        let blocks = table.datanodes.take_ids_for_key(datanode_uuid);
+        for block_id in &blocks {
+            table.replica_types.remove(&(*datanode_uuid, *block_id));
+        }
+        blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use chrono::Duration;
+
+    use super::*;
+
+    fn address(i: usize) -> SocketAddr {
+        SocketAddr::from(([10, (i >> 16) as u8, (i >> 8) as u8, i as u8], 50052))
+    }
+
+    #[test]
+    fn test_state_changes_and_dead_nodes() {
+        let manager = DatanodeManager::new();
+        let start = Utc::now();
+        let uuid = Uuid::from_u128(1);
+        assert!(manager.heartbeat(uuid, address(1), "", 1000, 0, start));
+        assert!(manager.add_block(uuid, Uuid::from_u128(42), StorageType::Disk));
+        assert!(!manager.add_block(Uuid::from_u128(2), Uuid::from_u128(42), StorageType::Disk));
+
+        assert!(manager
+            .update_states(start + Duration::seconds(31), 30, 600)
+            .is_empty());
+        assert_eq!(manager.get(&uuid).unwrap().state, DatanodeState::Stale);
+
+        let dead = manager.update_states(start + Duration::seconds(601), 30, 600);
+        assert_eq!(dead, vec![uuid]);
+        assert_eq!(
+            manager.remove_dead(&uuid),
+            HashSet::from([Uuid::from_u128(42)])
+        );
+        assert_eq!(manager.uuid_for_address(&address(1)), None);
+        assert!(manager.alive_datanodes().is_empty());
+        assert_eq!(manager.all_datanodes().len(), 1);
+
+        assert!(!manager.heartbeat(uuid, address(1), "", 1000, 0, start));
+        assert_eq!(manager.get(&uuid).unwrap().state, DatanodeState::Live);
+        assert_eq!(manager.uuid_for_address(&address(1)), Some(uuid));
+    }
+
+    #[test]
+    fn test_storages() {
+        let manager = DatanodeManager::new();
+        let now = Utc::now();
+        let storage = |storage_type, capacity, remaining, failed| DatanodeStorage {
+            storage_uuid: Uuid::new_v4(),
+            storage_type,
+            capacity,
+            dfs_used: capacity - remaining,
+            remaining,
+            failed,
+        };
+        let (first, second) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        manager.heartbeat(first, address(1), "", 3000, 0, now);
+        manager.heartbeat(second, address(2), "", 1000, 0, now);
+        manager.update_storages(
+            first,
+            vec![
+                storage(StorageType::Ssd, 1000, 400, false),
+                storage(StorageType::Disk, 1000, 1000, false),
+                storage(StorageType::Disk, 1000, 500, false),
+            ],
+        );
+        manager.update_storages(second, vec![storage(StorageType::Ssd, 1000, 0, true)]);
+
+        let nodes = manager.all_datanodes();
+        let ssd = manager.with_storage_type(&nodes, StorageType::Ssd);
+        assert_eq!(ssd.len(), 1);
+        assert_eq!((ssd[0].total_capacity, ssd[0].used_capacity), (1000, 600));
+        let disk = manager.with_storage_type(&nodes, StorageType::Disk);
+        assert_eq!(disk[0].free_capacity(), 1500);
+        assert!(manager
+            .with_storage_type(&nodes, StorageType::Archive)
+            .is_empty());
+        assert_eq!(
+            manager.storage_type_usage().into_iter().collect::<Vec<_>>(),
+            vec![
+                (StorageType::Disk, (2000, 500)),
+                (StorageType::Ssd, (1000, 600))
+            ]
+        );
+
+        let block_id = Uuid::from_u128(42);
+        manager.add_block(first, block_id, StorageType::Ssd);
+        assert_eq!(
+            manager.storage_type_of(&first, &block_id),
+            Some(StorageType::Ssd)
+        );
+        manager.remove_block(&first, &block_id);
+        assert_eq!(manager.storage_type_of(&first, &block_id), None);
+    }
+
+    #[test]
+    fn test_maintenance_window() {
+        let manager = DatanodeManager::new();
+        let start = Utc::now();
+        let uuid = Uuid::from_u128(1);
+        manager.heartbeat(uuid, address(1), "", 1000, 0, start);
+        assert_eq!(manager.resolve(&uuid.to_string()), Some(uuid));
+        assert_eq!(manager.resolve(&address(1).to_string()), Some(uuid));
+        assert_eq!(manager.resolve("10.9.9.9:1"), None);
+
+        manager.set_state(
+            &uuid,
+            DatanodeState::InMaintenance,
+            Some(start + Duration::seconds(1000)),
+        );
+        assert!(manager
+            .update_states(start + Duration::seconds(900), 30, 600)
+            .is_empty());
+        assert_eq!(
+            manager.datanodes_in_state(DatanodeState::InMaintenance),
+            vec![uuid]
+        );
+
+        // Once the window ends, the missed heartbeats count again.
+        let dead = manager.update_states(start + Duration::seconds(1000), 30, 600);
+        assert_eq!(dead, vec![uuid]);
+    }
+
+    /// Simulates several rounds of heartbeats from thousands of datanodes,
+    /// sent from multiple threads while the liveness check runs.
+    #[test]
+    fn test_heartbeat_load() {
+        const DATANODES: usize = 5000;
+        const THREADS: usize = 8;
+        const ROUNDS: usize = 5;
+
+        let manager = Arc::new(DatanodeManager::new());
+        let start = Utc::now();
+
+        let workers = (0..THREADS)
+            .map(|thread_index| {
+                let manager = Arc::clone(&manager);
+                thread::spawn(move || {
+                    for round in 0..ROUNDS {
+                        let now = start + Duration::seconds(3 * round as i64);
+                        for i in (thread_index..DATANODES).step_by(THREADS) {
+                            manager.heartbeat(
+                                Uuid::from_u128(i as u128),
+                                address(i),
+                                "",
+                                1000,
+                                i as u64 % 1000,
+                                now,
+                            );
+                            manager.add_block(
+                                Uuid::from_u128(i as u128),
+                                Uuid::from_u128(round as u128),
+                                StorageType::Disk,
+                            );
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let checker = {
+            let manager = Arc::clone(&manager);
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    assert!(manager.update_states(start, 30, 600).is_empty());
+                    manager.alive_datanodes();
+                }
+            })
+        };
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        checker.join().unwrap();
+
+        assert_eq!(manager.all_datanodes().len(), DATANODES);
+        assert_eq!(manager.alive_datanodes().len(), DATANODES);
+        for i in [0, 101, DATANODES - 1] {
+            assert_eq!(
+                manager.uuid_for_address(&address(i)),
+                Some(Uuid::from_u128(i as u128))
+            );
+        }
+
+        // Half of the nodes stop sending heartbeats.
+        let late = start + Duration::seconds(700);
+        for i in (0..DATANODES).step_by(2) {
+            manager.heartbeat(Uuid::from_u128(i as u128), address(i), "", 1000, 0, late);
+        }
+        let dead = manager.update_states(late, 30, 600);
+        assert_eq!(dead.len(), DATANODES / 2);
+        for uuid in &dead {
+            assert_eq!(manager.remove_dead(uuid).len(), ROUNDS);
+        }
+        assert_eq!(manager.alive_datanodes().len(), DATANODES / 2);
+    }
+}
@@ -0,0 +1,110 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{CuddlyError, CuddlyResult},
+    utils::reed_solomon::ReedSolomon,
+};
+
+/// Largest unit of data written to one block of a group before moving on
+/// to the next one.
+pub const CELL_SIZE: u64 = 1024 * 1024;
+
+/// Stores the files of a directory as groups of data blocks with parity
+/// blocks computed by Reed-Solomon, instead of replicating their blocks.
+/// Set on a directory and inherited by everything created below it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum ErasureCodingPolicy {
+    /// Six data blocks and three parity blocks.
+    Rs6_3,
+    /// Three data blocks and two parity blocks.
+    Rs3_2,
+}
+
+impl ErasureCodingPolicy {
+    pub const ALL: [ErasureCodingPolicy; 2] =
+        [ErasureCodingPolicy::Rs6_3, ErasureCodingPolicy::Rs3_2];
+
+    pub fn data_units(self) -> usize {
+        match self {
+            Self::Rs6_3 => 6,
+            Self::Rs3_2 => 3,
+        }
+    }
+
+    pub fn parity_units(self) -> usize {
+        match self {
+            Self::Rs6_3 => 3,
+            Self::Rs3_2 => 2,
+        }
+    }
+
+    /// Blocks in a group, each on a different datanode.
+    pub fn group_width(self) -> usize {
+        self.data_units() + self.parity_units()
+    }
+
+    pub fn codec(self) -> ReedSolomon {
+        ReedSolomon::new(self.data_units(), self.parity_units()).expect("policies have valid codes")
+    }
+
+    /// Cells are as large as blocks of files with a smaller block size.
+    pub fn cell_size(block_size: u64) -> u64 {
+        CELL_SIZE.min(block_size).max(1)
+    }
+
+    /// Bytes of file data held by a group whose blocks are at most
+    /// `block_size` long, a whole number of cells per block.
+    pub fn group_capacity(self, block_size: u64) -> u64 {
+        let cell_size = Self::cell_size(block_size);
+        block_size / cell_size * cell_size * self.data_units() as u64
+    }
+}
+
+impl fmt::Display for ErasureCodingPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Rs6_3 => "RS-6-3",
+            Self::Rs3_2 => "RS-3-2",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for ErasureCodingPolicy {
+    type Err = CuddlyError;
+
+    fn from_str(name: &str) -> CuddlyResult<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|policy| policy.to_string().eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                CuddlyError::FSError(format!("'{}': No such erasure coding policy", name))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_erasure_coding_policy() {
+        let policy = ErasureCodingPolicy::Rs6_3;
+        assert_eq!(policy.group_width(), 9);
+        assert_eq!(policy.group_capacity(64 * CELL_SIZE), 6 * 64 * CELL_SIZE);
+        assert_eq!(
+            ErasureCodingPolicy::Rs3_2.group_capacity(CELL_SIZE + 10),
+            3 * CELL_SIZE
+        );
+        assert_eq!(ErasureCodingPolicy::Rs3_2.group_capacity(1000), 3000);
+
+        assert_eq!(
+            "rs-3-2".parse::<ErasureCodingPolicy>().unwrap(),
+            ErasureCodingPolicy::Rs3_2
+        );
+        assert_eq!(ErasureCodingPolicy::Rs6_3.to_string(), "RS-6-3");
+        assert!("XOR-2-1".parse::<ErasureCodingPolicy>().is_err());
+    }
+}
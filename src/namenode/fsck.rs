@@ -0,0 +1,244 @@
+use std::net::SocketAddr;
+
+use crate::{block::Block, cuddlyproto};
+
+/// What fsck does with files that have missing or corrupt blocks.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(super) enum FsckAction {
+    #[default]
+    None,
+    /// Moves them below `/lost+found`, keeping their path.
+    Move,
+    Delete,
+}
+
+impl From<cuddlyproto::fsck_request::FsckAction> for FsckAction {
+    fn from(value: cuddlyproto::fsck_request::FsckAction) -> Self {
+        match value {
+            cuddlyproto::fsck_request::FsckAction::None => Self::None,
+            cuddlyproto::fsck_request::FsckAction::Move => Self::Move,
+            cuddlyproto::fsck_request::FsckAction::Delete => Self::Delete,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum BlockHealth {
+    Healthy,
+    UnderReplicated,
+    /// No datanode holds a replica.
+    Missing,
+    /// Every replica that is held is corrupt.
+    Corrupt,
+}
+
+impl BlockHealth {
+    /// `replicas` counts the replicas towards the replication factor,
+    /// `locations` are all readable replicas and `corrupt` the replicas
+    /// reported with the wrong length.
+    pub(super) fn classify(
+        replicas: usize,
+        locations: usize,
+        corrupt: usize,
+        replication: usize,
+    ) -> Self {
+        if locations == 0 && corrupt > 0 {
+            Self::Corrupt
+        } else if locations == 0 {
+            Self::Missing
+        } else if replicas < replication {
+            Self::UnderReplicated
+        } else {
+            Self::Healthy
+        }
+    }
+}
+
+impl From<BlockHealth> for cuddlyproto::fsck_block_entry::BlockHealth {
+    fn from(value: BlockHealth) -> Self {
+        match value {
+            BlockHealth::Healthy => Self::Healthy,
+            BlockHealth::UnderReplicated => Self::UnderReplicated,
+            BlockHealth::Missing => Self::Missing,
+            BlockHealth::Corrupt => Self::Corrupt,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct FsckBlock {
+    pub(super) block: Block,
+    pub(super) health: BlockHealth,
+    pub(super) replicas: usize,
+    pub(super) locations: Vec<SocketAddr>,
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct FsckFile {
+    pub(super) path: String,
+    pub(super) blocks: Vec<FsckBlock>,
+}
+
+impl FsckFile {
+    /// Whether some block of the file cannot be read.
+    pub(super) fn is_corrupt(&self) -> bool {
+        self.blocks
+            .iter()
+            .any(|block| matches!(block.health, BlockHealth::Missing | BlockHealth::Corrupt))
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.blocks
+            .iter()
+            .all(|block| block.health == BlockHealth::Healthy)
+    }
+}
+
+/// Result of checking a subtree of the namespace.
+#[derive(Clone, Debug, Default)]
+pub(super) struct FsckReport {
+    /// Unhealthy files, or every file if locations were requested.
+    pub(super) files: Vec<FsckFile>,
+    pub(super) total_files: u64,
+    pub(super) total_blocks: u64,
+    pub(super) healthy_blocks: u64,
+    pub(super) under_replicated_blocks: u64,
+    pub(super) missing_blocks: u64,
+    pub(super) corrupt_blocks: u64,
+    pub(super) moved: Vec<String>,
+    pub(super) deleted: Vec<String>,
+}
+
+impl FsckReport {
+    /// Counts the blocks of `file` and keeps it if it is unhealthy or
+    /// `list_all` is set. Locations are only kept if `list_all` is set.
+    pub(super) fn add(&mut self, mut file: FsckFile, list_all: bool) {
+        self.total_files += 1;
+        for block in &mut file.blocks {
+            self.total_blocks += 1;
+            match block.health {
+                BlockHealth::Healthy => self.healthy_blocks += 1,
+                BlockHealth::UnderReplicated => self.under_replicated_blocks += 1,
+                BlockHealth::Missing => self.missing_blocks += 1,
+                BlockHealth::Corrupt => self.corrupt_blocks += 1,
+            }
+            if !list_all {
+                block.locations.clear();
+            }
+        }
+        if list_all || !file.is_healthy() {
+            self.files.push(file);
+        }
+    }
+
+    pub(super) fn corrupt_files(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .filter(|file| file.is_corrupt())
+            .map(|file| file.path.clone())
+            .collect()
+    }
+}
+
+impl From<FsckReport> for cuddlyproto::FsckResponse {
+    fn from(value: FsckReport) -> Self {
+        Self {
+            files: value
+                .files
+                .into_iter()
+                .map(|file| cuddlyproto::FsckFileEntry {
+                    path: file.path,
+                    blocks: file
+                        .blocks
+                        .into_iter()
+                        .map(|block| cuddlyproto::FsckBlockEntry {
+                            block: Some(block.block.into()),
+                            health: cuddlyproto::fsck_block_entry::BlockHealth::from(block.health)
+                                as i32,
+                            replicas: block.replicas as u32,
+                            locations: block
+                                .locations
+                                .iter()
+                                .map(|location| location.to_string())
+                                .collect(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+            total_files: value.total_files,
+            total_blocks: value.total_blocks,
+            healthy_blocks: value.healthy_blocks,
+            under_replicated_blocks: value.under_replicated_blocks,
+            missing_blocks: value.missing_blocks,
+            corrupt_blocks: value.corrupt_blocks,
+            moved: value.moved,
+            deleted: value.deleted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn file(path: &str, health: &[BlockHealth]) -> FsckFile {
+        FsckFile {
+            path: path.to_owned(),
+            blocks: health
+                .iter()
+                .enumerate()
+                .map(|(i, health)| FsckBlock {
+                    block: Block::new(Uuid::from_u128(i as u128), 10, i as u64),
+                    health: *health,
+                    replicas: 1,
+                    locations: vec![SocketAddr::from(([10, 0, 0, 1], 50052))],
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(BlockHealth::classify(3, 3, 0, 3), BlockHealth::Healthy);
+        assert_eq!(
+            BlockHealth::classify(1, 2, 1, 3),
+            BlockHealth::UnderReplicated
+        );
+        assert_eq!(
+            BlockHealth::classify(0, 1, 0, 3),
+            BlockHealth::UnderReplicated
+        );
+        assert_eq!(BlockHealth::classify(0, 0, 0, 3), BlockHealth::Missing);
+        assert_eq!(BlockHealth::classify(0, 0, 2, 3), BlockHealth::Corrupt);
+    }
+
+    #[test]
+    fn test_report_keeps_unhealthy_files() {
+        let mut report = FsckReport::default();
+        report.add(file("/a", &[BlockHealth::Healthy]), false);
+        report.add(
+            file("/b", &[BlockHealth::Healthy, BlockHealth::UnderReplicated]),
+            false,
+        );
+        report.add(
+            file("/c", &[BlockHealth::Missing, BlockHealth::Corrupt]),
+            false,
+        );
+
+        assert_eq!(report.total_files, 3);
+        assert_eq!(report.total_blocks, 5);
+        assert_eq!(report.healthy_blocks, 2);
+        assert_eq!(report.under_replicated_blocks, 1);
+        assert_eq!(report.missing_blocks, 1);
+        assert_eq!(report.corrupt_blocks, 1);
+        assert_eq!(report.files.len(), 2);
+        assert!(report.files[0].blocks[0].locations.is_empty());
+        assert_eq!(report.corrupt_files(), vec!["/c".to_owned()]);
+
+        let mut report = FsckReport::default();
+        report.add(file("/a", &[BlockHealth::Healthy]), true);
+        assert_eq!(report.files[0].blocks[0].locations.len(), 1);
+    }
+}
@@ -0,0 +1,120 @@
+use std::{collections::HashSet, net::SocketAddr, path::Path};
+
+use log::info;
+use uuid::Uuid;
+
+use crate::{cuddlyproto, errors::CuddlyResult};
+
+/// Decides which datanodes may register, based on an include and an exclude
+/// file. Each line of the files names a host by IP address, by `ip:port` or
+/// by host name; empty lines and lines starting with `#` are ignored.
+///
+/// An empty include list allows every host. Hosts in the exclude list may
+/// still register, but are decommissioned.
+#[derive(Debug, Default)]
+pub(super) struct HostFilter {
+    included: HashSet<String>,
+    excluded: HashSet<String>,
+}
+
+impl HostFilter {
+    pub(super) fn new(included: HashSet<String>, excluded: HashSet<String>) -> Self {
+        Self { included, excluded }
+    }
+
+    /// Reads the filter from the optional include and exclude files.
+    pub(super) fn load(
+        include_file: Option<&Path>,
+        exclude_file: Option<&Path>,
+    ) -> CuddlyResult<Self> {
+        let filter = Self::new(load_hosts(include_file)?, load_hosts(exclude_file)?);
+        info!(
+            "Loaded {} included and {} excluded hosts",
+            filter.included.len(),
+            filter.excluded.len()
+        );
+        Ok(filter)
+    }
+
+    pub(super) fn included_hosts(&self) -> usize {
+        self.included.len()
+    }
+
+    pub(super) fn excluded_hosts(&self) -> usize {
+        self.excluded.len()
+    }
+
+    /// Whether a datanode at `socket_address` called `host_name` may register.
+    pub(super) fn is_included(&self, socket_address: &SocketAddr, host_name: &str) -> bool {
+        self.included.is_empty() || matches(&self.included, socket_address, host_name)
+    }
+
+    /// Whether a datanode at `socket_address` called `host_name` has to be
+    /// decommissioned.
+    pub(super) fn is_excluded(&self, socket_address: &SocketAddr, host_name: &str) -> bool {
+        matches(&self.excluded, socket_address, host_name)
+    }
+}
+
+/// Outcome of re-reading the include and exclude files.
+#[derive(Debug, Default)]
+pub(super) struct RefreshedNodes {
+    pub(super) included_hosts: usize,
+    pub(super) excluded_hosts: usize,
+    pub(super) decommissioning: Vec<Uuid>,
+    pub(super) recommissioned: Vec<Uuid>,
+}
+
+impl From<RefreshedNodes> for cuddlyproto::RefreshNodesResponse {
+    fn from(value: RefreshedNodes) -> Self {
+        Self {
+            included_hosts: value.included_hosts as u32,
+            excluded_hosts: value.excluded_hosts as u32,
+            decommissioning: value.decommissioning.iter().map(Uuid::to_string).collect(),
+            recommissioned: value.recommissioned.iter().map(Uuid::to_string).collect(),
+        }
+    }
+}
+
+fn matches(hosts: &HashSet<String>, socket_address: &SocketAddr, host_name: &str) -> bool {
+    hosts.contains(&socket_address.to_string())
+        || hosts.contains(&socket_address.ip().to_string())
+        || (!host_name.is_empty() && hosts.contains(host_name))
+}
+
+fn load_hosts(path: Option<&Path>) -> CuddlyResult<HashSet<String>> {
+    let Some(path) = path else {
+        return Ok(HashSet::new());
+    };
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_address_ip_and_host_name() {
+        let filter = HostFilter::new(
+            HashSet::from(["10.0.0.1".to_owned(), "dn2".to_owned()]),
+            HashSet::from(["10.0.0.3:50052".to_owned()]),
+        );
+        let first = SocketAddr::from(([10, 0, 0, 1], 50052));
+        let second = SocketAddr::from(([10, 0, 0, 2], 50052));
+        let third = SocketAddr::from(([10, 0, 0, 3], 50052));
+
+        assert!(filter.is_included(&first, ""));
+        assert!(filter.is_included(&second, "dn2"));
+        assert!(!filter.is_included(&second, "dn3"));
+        assert!(!filter.is_included(&third, ""));
+        assert!(filter.is_excluded(&third, ""));
+        assert!(!filter.is_excluded(&SocketAddr::from(([10, 0, 0, 3], 50053)), ""));
+
+        assert!(HostFilter::default().is_included(&third, ""));
+    }
+}
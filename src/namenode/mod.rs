@@ -1,4 +1,5 @@
 use log::info;
+use namenode_admin_service::NamenodeAdminService;
 use namenode_data_registry::DataRegistry;
 use namenode_file_service::NamenodeFileService;
 use namenode_node_service::NamenodeNodeService;
@@ -8,21 +9,39 @@ use tokio_util::sync::CancellationToken;
 use tonic::transport::Server;
 
 use crate::{
-    cuddlyproto::{file_service_server::FileServiceServer, node_service_server::NodeServiceServer},
+    cuddlyproto::{
+        admin_service_server::AdminServiceServer, file_service_server::FileServiceServer,
+        node_service_server::NodeServiceServer,
+    },
     errors::CuddlyResult,
+    utils::fault_injection::FaultInjector,
+    APP_CONFIG,
 };
 
+mod balancer;
+pub(crate) mod block_placement_policy;
 mod datanode_info;
+mod datanode_manager;
+pub(crate) mod erasure_coding_policy;
+mod fsck;
+mod host_filter;
+mod mover;
+mod namenode_admin_service;
 mod namenode_data_registry;
 mod namenode_file_service;
 mod namenode_node_service;
 mod namenode_operation_logger;
 mod namenode_progress_tracker;
 mod namenode_state;
+mod network_topology;
+mod safe_mode;
+pub(crate) mod storage_policy;
+mod trash;
 
 #[derive(Debug)]
 pub struct Namenode {
     data_registry: Arc<DataRegistry>,
+    fault_injector: Arc<FaultInjector>,
     cancel_token: CancellationToken,
     _shutdown_send: UnboundedSender<i8>,
 }
@@ -34,19 +53,26 @@ impl Namenode {
     ) -> CuddlyResult<Self> {
         Ok(Self {
             data_registry: Arc::new(DataRegistry::new(cancel_token.clone())?),
+            fault_injector: Arc::new(FaultInjector::from_config(&APP_CONFIG)),
             cancel_token,
             _shutdown_send,
         })
     }
 
     pub async fn run(&self, addr: SocketAddr) -> CuddlyResult<()> {
+        self.data_registry.restore().await?;
+
         let rpc_service = Server::builder()
             .add_service(NodeServiceServer::new(NamenodeNodeService::new(
                 Arc::clone(&self.data_registry),
+                Arc::clone(&self.fault_injector),
             )))
             .add_service(FileServiceServer::new(NamenodeFileService::new(
                 Arc::clone(&self.data_registry),
             )))
+            .add_service(AdminServiceServer::new(NamenodeAdminService::new(
+                Arc::clone(&self.data_registry),
+            )))
             .serve(addr);
 
         tokio::select! {
@@ -0,0 +1,160 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use uuid::Uuid;
+
+use super::{
+    balancer::BlockMove,
+    storage_policy::{StoragePolicy, StorageType},
+};
+
+/// Migrates replicas whose storage type does not match the storage policy
+/// of their file, such as after the policy of a directory changed.
+///
+/// A misplaced replica is copied to a storage of the wanted type, and the
+/// original is deleted once the copy is reported, as for a balancer move.
+#[derive(Debug, Default)]
+pub(super) struct Mover {
+    // Blocks to check against their storage policy
+    queued: Mutex<HashSet<Uuid>>,
+    // Moves in flight, by block and target datanode
+    moves: Mutex<HashMap<(Uuid, Uuid), BlockMove>>,
+}
+
+impl Mover {
+    pub(super) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(super) fn queue(&self, blocks: impl IntoIterator<Item = Uuid>) {
+        self.queued.lock().unwrap().extend(blocks);
+    }
+
+    /// Takes the queued blocks. Blocks with moves in flight stay queued
+    /// until those are done.
+    pub(super) fn take_queued(&self) -> Vec<Uuid> {
+        let moves = self.moves.lock().unwrap();
+        let mut queued = self.queued.lock().unwrap();
+        let (moving, ready): (HashSet<_>, HashSet<_>) = queued
+            .drain()
+            .partition(|block_id| moves.keys().any(|(id, _)| id == block_id));
+        *queued = moving;
+        ready.into_iter().collect()
+    }
+
+    pub(super) fn start(&self, block_move: BlockMove) {
+        self.moves.lock().unwrap().insert(
+            (block_move.block.id, block_move.target.datanode_uuid),
+            block_move,
+        );
+    }
+
+    /// Finishes the move of `block_id` to `receiver`, if there is one, and
+    /// returns it so the source replica can be deleted.
+    pub(super) fn complete(&self, block_id: &Uuid, receiver: &Uuid) -> Option<BlockMove> {
+        self.moves.lock().unwrap().remove(&(*block_id, *receiver))
+    }
+
+    /// Gives up on moves that did not finish within `timeout` seconds and
+    /// queues their blocks again.
+    pub(super) fn expire(&self, now: DateTime<Utc>, timeout: i64) {
+        let mut expired = vec![];
+        self.moves.lock().unwrap().retain(|(block_id, _), m| {
+            let keep = now.signed_duration_since(m.started).num_seconds() <= timeout;
+            if !keep {
+                expired.push(*block_id);
+            }
+            keep
+        });
+        if !expired.is_empty() {
+            warn!("{} replica moves timed out", expired.len());
+            self.queue(expired);
+        }
+    }
+}
+
+/// Pairs the replicas of a block that are on the wrong storage type for
+/// `policy` with the storage type each should move to. `replicas` holds the
+/// datanode and storage type of every replica.
+pub(super) fn plan_moves(
+    policy: StoragePolicy,
+    replicas: &[(Uuid, StorageType)],
+) -> Vec<(Uuid, StorageType)> {
+    let existing = replicas
+        .iter()
+        .map(|(_, storage_type)| *storage_type)
+        .collect::<Vec<_>>();
+    let (missing, excess) = policy.mismatch(&existing);
+    let mut sources = vec![];
+    for storage_type in excess {
+        if let Some((datanode_uuid, _)) = replicas.iter().find(|(datanode_uuid, replica_type)| {
+            *replica_type == storage_type && !sources.contains(datanode_uuid)
+        }) {
+            sources.push(*datanode_uuid);
+        }
+    }
+    sources.into_iter().zip(missing).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{block::Block, namenode::datanode_info::DatanodeInfo};
+
+    use super::*;
+
+    #[test]
+    fn test_moves_misplaced_replicas() {
+        let replicas = [
+            (Uuid::from_u128(1), StorageType::Disk),
+            (Uuid::from_u128(2), StorageType::Ssd),
+            (Uuid::from_u128(3), StorageType::Disk),
+        ];
+        assert!(plan_moves(StoragePolicy::OneSsd, &replicas).is_empty());
+        assert_eq!(
+            plan_moves(StoragePolicy::Hot, &replicas),
+            vec![(Uuid::from_u128(2), StorageType::Disk)]
+        );
+        assert_eq!(
+            plan_moves(StoragePolicy::Cold, &replicas),
+            vec![
+                (Uuid::from_u128(1), StorageType::Archive),
+                (Uuid::from_u128(3), StorageType::Archive),
+                (Uuid::from_u128(2), StorageType::Archive),
+            ]
+        );
+
+        let mover = Mover::new();
+        let block = Block::new(Uuid::from_u128(100), 10, 0);
+        mover.queue([block.id]);
+        assert_eq!(mover.take_queued(), vec![block.id]);
+        let now = Utc::now();
+        let target = DatanodeInfo::new(([10, 0, 0, 4], 50052), Uuid::from_u128(4), 1000, 0);
+        mover.start(BlockMove {
+            block,
+            source: Uuid::from_u128(2),
+            target,
+            started: now,
+        });
+
+        // The block is checked again once its moves are done.
+        mover.queue([block.id]);
+        assert!(mover.take_queued().is_empty());
+        assert!(mover.complete(&block.id, &Uuid::from_u128(3)).is_none());
+        let done = mover.complete(&block.id, &Uuid::from_u128(4)).unwrap();
+        assert_eq!(done.source, Uuid::from_u128(2));
+        assert_eq!(mover.take_queued(), vec![block.id]);
+
+        mover.start(BlockMove {
+            block,
+            source: Uuid::from_u128(2),
+            target,
+            started: now,
+        });
+        mover.expire(now + chrono::Duration::seconds(1000), 300);
+        assert_eq!(mover.take_queued(), vec![block.id]);
+    }
+}
@@ -0,0 +1,237 @@
+use std::{str::FromStr, sync::Arc};
+
+use log::{info, LevelFilter};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    cuddlyproto::{
+        self, admin_service_server::AdminService, CheckpointRequest, CheckpointResponse,
+        ClusterReportRequest, ClusterReportResponse, DumpMetadataRequest, DumpMetadataResponse,
+        FsckRequest, FsckResponse, GetBalancerStatusRequest, RefreshNodesRequest,
+        RefreshNodesResponse, SafeModeRequest, SafeModeResponse, SetDatanodeAdminStateRequest,
+        SetDatanodeAdminStateResponse, SetLogLevelRequest, SetLogLevelResponse, SetQuotaRequest,
+        SetQuotaResponse,
+    },
+    errors::CuddlyError,
+    APP_CONFIG,
+};
+
+use super::namenode_data_registry::DataRegistry;
+
+/// Operates the cluster: datanode administration, safe mode, checkpoints and
+/// diagnostics. Kept apart from the `FileService` used by clients.
+pub struct NamenodeAdminService {
+    data_registry: Arc<DataRegistry>,
+    // Most verbose level the logger lets through, as set up from RUST_LOG
+    log_level_ceiling: LevelFilter,
+}
+
+impl NamenodeAdminService {
+    /// Must be created once the logger is installed, which sets the maximum
+    /// log level to the most verbose one it lets through.
+    pub fn new(data_registry: Arc<DataRegistry>) -> Self {
+        Self {
+            data_registry,
+            log_level_ceiling: log::max_level(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl AdminService for NamenodeAdminService {
+    async fn cluster_report(
+        &self,
+        _request: Request<ClusterReportRequest>,
+    ) -> Result<Response<ClusterReportResponse>, Status> {
+        let datanodes = self
+            .data_registry
+            .report_datanodes()
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let (total_blocks, under_replicated_blocks) = self.data_registry.block_counts();
+        Ok(Response::new(ClusterReportResponse {
+            total_capacity: datanodes.iter().map(|d| d.total_capacity).sum(),
+            used_capacity: datanodes.iter().map(|d| d.used_capacity).sum(),
+            datanodes: datanodes.into_iter().map(|d| d.into()).collect(),
+            total_blocks,
+            under_replicated_blocks,
+            safe_mode: Some(self.data_registry.safe_mode_status().into()),
+            balancer: Some(self.data_registry.balancer_status().into()),
+            storage_types: self
+                .data_registry
+                .storage_type_usage()
+                .into_iter()
+                .map(
+                    |(storage_type, (capacity, used))| cuddlyproto::StorageTypeReportProto {
+                        storage_type: storage_type.to_proto(),
+                        capacity,
+                        used,
+                    },
+                )
+                .collect(),
+        }))
+    }
+
+    async fn set_datanode_admin_state(
+        &self,
+        request: Request<SetDatanodeAdminStateRequest>,
+    ) -> Result<Response<SetDatanodeAdminStateResponse>, Status> {
+        use cuddlyproto::set_datanode_admin_state_request::AdminAction;
+
+        let request = request.into_inner();
+        info!("Received admin request: {:?}", request);
+        let res = match request.action() {
+            AdminAction::Decommission => {
+                self.data_registry.decommission_datanode(&request.datanode)
+            }
+            AdminAction::Maintenance => {
+                let duration = match request.maintenance_duration_secs {
+                    0 => APP_CONFIG.namenode.maintenance_duration,
+                    duration => duration,
+                };
+                self.data_registry
+                    .start_datanode_maintenance(&request.datanode, duration)
+            }
+            AdminAction::Recommission => {
+                self.data_registry.recommission_datanode(&request.datanode)
+            }
+        };
+
+        match res {
+            Ok(datanode) => Ok(Response::new(SetDatanodeAdminStateResponse {
+                datanode: Some(datanode.into()),
+            })),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn get_balancer_status(
+        &self,
+        _request: Request<GetBalancerStatusRequest>,
+    ) -> Result<Response<cuddlyproto::BalancerStatusProto>, Status> {
+        Ok(Response::new(self.data_registry.balancer_status().into()))
+    }
+
+    async fn safe_mode(
+        &self,
+        request: Request<SafeModeRequest>,
+    ) -> Result<Response<SafeModeResponse>, Status> {
+        use cuddlyproto::safe_mode_request::SafeModeAction;
+
+        let status = match request.into_inner().action() {
+            SafeModeAction::Get => self.data_registry.safe_mode_status(),
+            SafeModeAction::Enter => self.data_registry.enter_safe_mode(),
+            SafeModeAction::Leave => self.data_registry.leave_safe_mode(),
+        };
+        Ok(Response::new(status.into()))
+    }
+
+    async fn checkpoint(
+        &self,
+        _request: Request<CheckpointRequest>,
+    ) -> Result<Response<CheckpointResponse>, Status> {
+        match self.data_registry.checkpoint().await {
+            Ok((files, directories)) => {
+                Ok(Response::new(CheckpointResponse { files, directories }))
+            }
+            Err(err) => Err(Status::internal(err.to_string())),
+        }
+    }
+
+    async fn dump_metadata(
+        &self,
+        _request: Request<DumpMetadataRequest>,
+    ) -> Result<Response<DumpMetadataResponse>, Status> {
+        let (blocks, datanodes) = self.data_registry.dump_metadata();
+        Ok(Response::new(DumpMetadataResponse {
+            blocks: blocks
+                .into_iter()
+                .map(|(block, holders)| cuddlyproto::BlockLocationsEntry {
+                    block: Some(block.into()),
+                    datanodes: holders.iter().map(|uuid| uuid.to_string()).collect(),
+                })
+                .collect(),
+            datanodes: datanodes
+                .into_iter()
+                .map(|(datanode, blocks)| cuddlyproto::DatanodeBlocksEntry {
+                    datanode: Some(datanode.into()),
+                    blocks: blocks.iter().map(|id| id.to_string()).collect(),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn refresh_nodes(
+        &self,
+        _request: Request<RefreshNodesRequest>,
+    ) -> Result<Response<RefreshNodesResponse>, Status> {
+        match self.data_registry.refresh_nodes() {
+            Ok(refreshed) => Ok(Response::new(refreshed.into())),
+            Err(err) => Err(Status::failed_precondition(err.to_string())),
+        }
+    }
+
+    /// Changes the log level of the running namenode. Levels more verbose
+    /// than `RUST_LOG` allowed at startup are rejected, as the logger would
+    /// still filter them out.
+    async fn set_log_level(
+        &self,
+        request: Request<SetLogLevelRequest>,
+    ) -> Result<Response<SetLogLevelResponse>, Status> {
+        let request = request.into_inner();
+        let level = LevelFilter::from_str(&request.level).map_err(|_| {
+            Status::invalid_argument(format!("'{}': Invalid log level", request.level))
+        })?;
+        if level > self.log_level_ceiling {
+            return Err(Status::failed_precondition(format!(
+                "Log level {} is above the {} allowed by RUST_LOG at startup",
+                level, self.log_level_ceiling
+            )));
+        }
+        let previous_level = log::max_level();
+        log::set_max_level(level);
+        info!("Log level changed from {} to {}", previous_level, level);
+        Ok(Response::new(SetLogLevelResponse {
+            previous_level: previous_level.to_string(),
+        }))
+    }
+
+    async fn fsck(&self, request: Request<FsckRequest>) -> Result<Response<FsckResponse>, Status> {
+        let request = request.into_inner();
+        info!("Received fsck request: {:?}", request);
+        match self
+            .data_registry
+            .fsck(
+                &request.path,
+                request.list_locations,
+                request.action().into(),
+            )
+            .await
+        {
+            Ok(report) => Ok(Response::new(report.into())),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+    async fn set_quota(
+        &self,
+        request: Request<SetQuotaRequest>,
+    ) -> Result<Response<SetQuotaResponse>, Status> {
+        use cuddlyproto::set_quota_request::QuotaType;
+
+        let request = request.into_inner();
+        info!("Received quota request: {:?}", request);
+        let limit = Some(request.limit).filter(|limit| *limit > 0);
+        match self
+            .data_registry
+            .set_quota(
+                &request.path,
+                request.quota_type() == QuotaType::Namespace,
+                limit,
+            )
+            .await
+        {
+            Ok(()) => Ok(Response::new(SetQuotaResponse {})),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+}
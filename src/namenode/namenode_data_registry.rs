@@ -1,38 +1,69 @@
 use std::{
-    collections::HashSet,
-    net::SocketAddr,
-    num::NonZero,
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
     str::FromStr,
-    sync::{Mutex, RwLock},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
 };
 
 use chrono::{DateTime, Utc};
-use log::{debug, info};
-use lru::LruCache;
-use rand::{seq::SliceRandom, thread_rng};
+use log::{debug, info, warn};
 use tokio::time;
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 use crate::{
-    block::Block,
-    cuddlyproto,
+    block::{Block, BlockGroup},
+    cuddlyproto::{self, datanode_command_proto::CommandType},
     errors::{CuddlyError, CuddlyResult},
     utils::key_to_data_and_id_map::KeyToDataAndIdMap,
     APP_CONFIG,
 };
 
 use super::{
-    datanode_info::DatanodeInfo,
+    balancer::{Balancer, BalancerStatus, BlockMove},
+    block_placement_policy::{new_block_placement_policy, BlockPlacementPolicy},
+    datanode_info::{readable_holders, DatanodeInfo, DatanodeState, DatanodeStorage},
+    datanode_manager::DatanodeManager,
+    erasure_coding_policy::ErasureCodingPolicy,
+    fsck::{BlockHealth, FsckAction, FsckBlock, FsckFile, FsckReport},
+    host_filter::{HostFilter, RefreshedNodes},
+    mover::{plan_moves, Mover},
     namenode_operation_logger::{EditOperation, OperationLogger},
-    namenode_progress_tracker::NamenodeProgressTracker,
-    namenode_state::NamenodeState,
+    namenode_progress_tracker::{FileLayout, NamenodeProgressTracker},
+    namenode_state::{
+        ContentSummary, DiffType, FileStatus, FileType, NamenodeState, Quota, LOST_AND_FOUND,
+    },
+    network_topology::NetworkTopology,
+    safe_mode::{SafeMode, SafeModeStatus},
+    storage_policy::{StoragePolicy, StorageType},
+    trash,
 };
 
-// Create a const for cache size
-const CACHE_SIZE: usize = 100;
-const HEARTBEAT_TIMEOUT: i64 = 3 * 200;
-const HEARTBEAT_RECHECK_INTERVAL: u64 = 20;
+// Seconds after which a scheduled re-replication that was not reported is retried
+const PENDING_REPLICATION_TIMEOUT: i64 = 300;
+
+// Seconds after which the lease of a writer that neither added a block nor
+// finished its file expires
+const LEASE_HARD_LIMIT: i64 = 3600;
+
+// A datanode to place a replica on, with the type of storage to use there
+pub(crate) type Target = (DatanodeInfo, StorageType);
+
+/// Blocks handed to a writer.
+pub(crate) enum Allocation {
+    /// A block of a replicated file with the targets of its replicas.
+    Replicated(Block, Vec<Target>),
+    /// A group of an erasure-coded file with its cell size: data blocks then
+    /// parity blocks, each with its only target.
+    Striped(ErasureCodingPolicy, u64, Vec<(Block, Target)>),
+}
+
+// A block to append to with its targets, and its new generation if it was
+// reopened
+type AppendTarget = (Block, Vec<Target>, Option<u64>);
 
 /**
  * FSNamesystem is a container of both transient
@@ -60,43 +91,175 @@ const HEARTBEAT_RECHECK_INTERVAL: u64 = 20;
  * 3)  block {@literal -->} machinelist (kept in memory, rebuilt dynamically
  *     from reports)
  * 4)  machine {@literal -->} blocklist (inverted #2)
- * 5)  LRU cache of updated-heartbeat machines
+ * 5)  heartbeats and states of the machines
+ *
+ * Locks are taken in the order fs_directory, block_to_datanodes, topology,
+ * datanode_manager. datanode_manager only locks its table within its own
+ * methods, so it always comes last.
  */
 #[derive(Debug)]
 pub(super) struct DataRegistry {
-    heartbeat_cache: Mutex<LruCache<Uuid, DateTime<Utc>>>,
-    socket_to_uuid: RwLock<LruCache<SocketAddr, Uuid>>,
     cancel_token: CancellationToken,
     block_to_datanodes: RwLock<KeyToDataAndIdMap<Uuid, Block, Uuid>>,
-    datanode_to_blocks: RwLock<KeyToDataAndIdMap<Uuid, DatanodeInfo, Uuid>>,
+    datanode_manager: DatanodeManager,
     namenode_progress_tracker: RwLock<NamenodeProgressTracker>,
     fs_directory: RwLock<NamenodeState>,
     operation_logger: tokio::sync::Mutex<OperationLogger>,
+    placement_policy: Box<dyn BlockPlacementPolicy>,
+    topology: Arc<RwLock<NetworkTopology>>,
+    under_replicated_blocks: Mutex<HashSet<Uuid>>,
+    pending_replications: Mutex<HashMap<Uuid, DateTime<Utc>>>,
+    pending_commands: Mutex<HashMap<Uuid, Vec<cuddlyproto::DatanodeCommandProto>>>,
+    // Datanodes that reported a replica of a block with the wrong length
+    corrupt_replicas: Mutex<HashMap<Uuid, HashSet<Uuid>>>,
+    // Blocks shortened by a truncate. Longer replicas of them are truncated
+    // rather than treated as corrupt.
+    truncated_blocks: Mutex<HashSet<Uuid>>,
+    balancer: Balancer,
+    mover: Mover,
+    safe_mode: SafeMode,
+    host_filter: RwLock<HostFilter>,
+    // Generation handed out with the next block reopened for append. Seeded
+    // from the clock so it keeps growing across restarts.
+    next_generation: AtomicU64,
     // start_time: DateTime<Utc>,
     // fsname_to_blocks: HashMap<FsName, BlockList>,
     // valid_blocks: HashSet<Block>,
     // block_manager: BlockManager,
-    // datanode_manager: DatanodeManager,
     // lease_manager: LeaseManager,
 }
 
 impl DataRegistry {
     pub(super) fn new(cancel_token: CancellationToken) -> CuddlyResult<Self> {
+        Self::with_operation_logger(cancel_token, OperationLogger::open(&APP_CONFIG)?)
+    }
+
+    fn with_operation_logger(
+        cancel_token: CancellationToken,
+        operation_logger: OperationLogger,
+    ) -> CuddlyResult<Self> {
+        let topology_mapping = match &APP_CONFIG.namenode.topology_mapping_file {
+            Some(path) => NetworkTopology::load_mapping(path)?,
+            None => Default::default(),
+        };
+        let topology = Arc::new(RwLock::new(NetworkTopology::new(topology_mapping)));
+        let host_filter = HostFilter::load(
+            APP_CONFIG.namenode.hosts_include_file.as_deref(),
+            APP_CONFIG.namenode.hosts_exclude_file.as_deref(),
+        )?;
+
         let data_registry = Self {
             // start_time: Utc::now(),
-            heartbeat_cache: Mutex::new(LruCache::new(NonZero::new(CACHE_SIZE).unwrap())),
-            socket_to_uuid: RwLock::new(LruCache::new(NonZero::new(CACHE_SIZE).unwrap())),
             block_to_datanodes: RwLock::new(KeyToDataAndIdMap::new()),
-            datanode_to_blocks: RwLock::new(KeyToDataAndIdMap::new()),
+            datanode_manager: DatanodeManager::new(),
             namenode_progress_tracker: RwLock::new(NamenodeProgressTracker::new()),
             fs_directory: RwLock::new(NamenodeState::new()),
-            operation_logger: tokio::sync::Mutex::new(OperationLogger::open(&APP_CONFIG)?),
+            operation_logger: tokio::sync::Mutex::new(operation_logger),
+            placement_policy: new_block_placement_policy(
+                APP_CONFIG.namenode.block_placement_policy,
+                Arc::clone(&topology),
+            ),
+            topology,
+            under_replicated_blocks: Mutex::new(HashSet::new()),
+            pending_replications: Mutex::new(HashMap::new()),
+            pending_commands: Mutex::new(HashMap::new()),
+            corrupt_replicas: Mutex::new(HashMap::new()),
+            truncated_blocks: Mutex::new(HashSet::new()),
+            balancer: Balancer::new(&APP_CONFIG.namenode.balancer),
+            mover: Mover::new(),
+            safe_mode: SafeMode::new(
+                APP_CONFIG.namenode.safe_mode_threshold,
+                APP_CONFIG.namenode.safe_mode_min_datanodes,
+            ),
+            host_filter: RwLock::new(host_filter),
+            next_generation: AtomicU64::new(Utc::now().timestamp_millis() as u64),
             cancel_token,
         };
 
         Ok(data_registry)
     }
 
+    /// Rebuilds the namespace from the last checkpoint and the edit log.
+    /// Block locations are only known again once the datanodes have sent
+    /// their block reports.
+    pub(crate) async fn restore(&self) -> CuddlyResult<()> {
+        let mut operation_logger = self.operation_logger.lock().await;
+        if let Some(image) = operation_logger.load_image().await? {
+            let fs_directory: NamenodeState = serde_json::from_str(&image)?;
+            let blocks = fs_directory.blocks();
+            info!("Loaded namespace image with {} blocks", blocks.len());
+            let mut block_to_datanodes = self.block_to_datanodes.write().unwrap();
+            for block in blocks {
+                self.observe_generation(block.generation);
+                block_to_datanodes.insert_data(block.id, block);
+            }
+            *self.fs_directory.write().unwrap() = fs_directory;
+        }
+
+        let ops = operation_logger.restore().await?;
+        info!("Replaying {} operations from the edit log", ops.len());
+        for op in ops {
+            match op {
+                EditOperation::Mkdir(path) => self.non_logging_make_dir(&path)?,
+                EditOperation::AddFile(path, blocks, replication, block_size) => self
+                    .non_logging_finish_file(
+                        &path,
+                        &blocks,
+                        FileLayout {
+                            replication,
+                            block_size,
+                            erasure_coding_policy: None,
+                        },
+                    )?,
+                EditOperation::Delete(path) => self.non_logging_delete(&path)?,
+                EditOperation::Rename(src, dst) => self.non_logging_rename(&src, &dst)?,
+                EditOperation::Append(path, blocks) => self.non_logging_append(&path, &blocks)?,
+                EditOperation::Truncate(path, new_length, generation) => {
+                    self.non_logging_truncate(&path, new_length, generation)?
+                }
+                EditOperation::Concat(target, sources) => {
+                    self.non_logging_concat(&target, &sources)?
+                }
+                EditOperation::SetQuota(path, quota) => self.non_logging_set_quota(&path, quota)?,
+                EditOperation::CreateSnapshot(path, name) => {
+                    self.non_logging_create_snapshot(&path, &name)?
+                }
+                EditOperation::DeleteSnapshot(path, name) => {
+                    self.non_logging_delete_snapshot(&path, &name)?
+                }
+                EditOperation::CreateSymlink(path, target, overwrite) => {
+                    self.non_logging_create_symlink(&path, &target, overwrite)?
+                }
+                EditOperation::SetXAttr(path, name, value) => {
+                    self.non_logging_set_xattr(&path, &name, &value)?
+                }
+                EditOperation::RemoveXAttr(path, name) => {
+                    self.non_logging_remove_xattr(&path, &name)?
+                }
+                EditOperation::SetReplication(path, replication) => {
+                    self.non_logging_set_replication(&path, replication)?
+                }
+                EditOperation::SetStoragePolicy(path, policy) => {
+                    self.non_logging_set_storage_policy(&path, policy)?
+                }
+                EditOperation::AddStripedFile(path, blocks, policy, block_size) => self
+                    .non_logging_finish_file(
+                        &path,
+                        &blocks,
+                        FileLayout {
+                            replication: 1,
+                            block_size,
+                            erasure_coding_policy: Some(policy),
+                        },
+                    )?,
+                EditOperation::SetErasureCodingPolicy(path, policy) => {
+                    self.non_logging_set_erasure_coding_policy(&path, policy)?
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub(crate) async fn run(&self) {
         tokio::select! {
             _ = self.cancel_token.cancelled() => {
@@ -105,6 +268,12 @@ impl DataRegistry {
             _ = self.do_heartbeat_monitoring() => {
                 info!("Heartbeat monitor finished");
             }
+            _ = self.do_balancing() => {
+                info!("Balancer finished");
+            }
+            _ = self.do_trash_checkpoints() => {
+                info!("Trash checkpointing finished");
+            }
         }
 
         info!("DataRegistry run finished");
@@ -127,42 +296,80 @@ impl DataRegistry {
             .unwrap()
             .unwrap();
 
+        let host_name = datanode_registration
+            .datanode_id
+            .as_ref()
+            .map(|id| id.host_name.as_str())
+            .unwrap_or_default();
+
+        let (included, excluded) = {
+            let host_filter = self.host_filter.read().unwrap();
+            (
+                host_filter.is_included(&datanode_socket, host_name),
+                host_filter.is_excluded(&datanode_socket, host_name),
+            )
+        };
+        if !included {
+            warn!(
+                "Rejected heartbeat from {}, host is not in the include list",
+                datanode_socket
+            );
+            return cuddlyproto::HeartbeatResponse {
+                status: Some(cuddlyproto::StatusCode {
+                    success: false,
+                    code: cuddlyproto::StatusEnum::EPerm as i32,
+                    message: "Datanode host is not in the include list".to_string(),
+                }),
+                ha_status: Some(cuddlyproto::NnhaStatusHeartbeatProto {
+                    state: cuddlyproto::nnha_status_heartbeat_proto::State::Active as i32,
+                    txid: uuid::Uuid::new_v4().to_string(),
+                }),
+                commands: vec![],
+            };
+        }
+
         if let Some(uuid) = &datanode_uuid {
-            let r = self
-                .heartbeat_cache
-                .lock()
-                .unwrap()
-                .put(Uuid::parse_str(&uuid).unwrap(), Utc::now());
-
-            match r {
-                Some(_previous_instant) => {
-                    // info!(
-                    //     "Updated heartbeat for {}. Previous heartbeat was at {:?}",
-                    //     uuid, previous_instant
-                    // );
-                }
-                None => {
-                    info!("New Datanode Connected with uuid: {}", uuid);
+            let datanode_uuid = Uuid::parse_str(uuid).unwrap();
+            let new = self.datanode_manager.heartbeat(
+                datanode_uuid,
+                datanode_socket,
+                host_name,
+                storage_reports.iter().map(|report| report.capacity).sum(),
+                // Space taken by other files is not available to blocks either
+                storage_reports
+                    .iter()
+                    .map(|report| report.capacity.saturating_sub(report.remaining))
+                    .sum(),
+                Utc::now(),
+            );
+            self.datanode_manager.update_storages(
+                datanode_uuid,
+                storage_reports.iter().map(DatanodeStorage::from).collect(),
+            );
+            if new && excluded {
+                info!("Datanode {} is in the exclude list", datanode_socket);
+                if let Err(err) = self.decommission_datanode(uuid) {
+                    warn!("Failed to decommission {}: {}", datanode_socket, err);
                 }
             }
 
-            let mut datanode_to_blocks = self.datanode_to_blocks.write().unwrap();
-            let datanode_info = DatanodeInfo {
-                socket_address: datanode_registration
-                    .datanode_id
-                    .as_ref()
-                    .and_then(|id| SocketAddr::from_str(&id.socket_addr).ok())
-                    .unwrap(),
+            let mut topology = self.topology.write().unwrap();
+            let location = topology.resolve(
+                datanode_socket.ip(),
+                host_name,
+                &datanode_registration.network_location,
+            );
+            topology.add(datanode_uuid, datanode_socket.ip(), location);
+            drop(topology);
 
-                datanode_uuid: Uuid::parse_str(&datanode_uuid.as_ref().unwrap()).unwrap(),
-                total_capacity: storage_reports.iter().map(|report| report.capacity).sum(),
-                used_capacity: storage_reports.iter().map(|report| report.dfs_used).sum(),
-            };
-            datanode_to_blocks.update_data(datanode_info.datanode_uuid, datanode_info);
+            let commands = self
+                .pending_commands
+                .lock()
+                .unwrap()
+                .remove(&datanode_uuid)
+                .unwrap_or_default();
 
-            let mut socket_to_uuid = self.socket_to_uuid.write().unwrap();
-            socket_to_uuid.put(datanode_socket, Uuid::parse_str(&uuid).unwrap());
-            let response = cuddlyproto::HeartbeatResponse {
+            cuddlyproto::HeartbeatResponse {
                 status: Some(cuddlyproto::StatusCode {
                     success: true,
                     code: cuddlyproto::StatusEnum::Ok as i32,
@@ -172,13 +379,12 @@ impl DataRegistry {
                     state: cuddlyproto::nnha_status_heartbeat_proto::State::Active as i32,
                     txid: uuid::Uuid::new_v4().to_string(),
                 }),
-            };
-
-            return response;
+                commands,
+            }
         } else {
             info!("Datanode registration failed, request did not contain a UUID");
 
-            let response = cuddlyproto::HeartbeatResponse {
+            cuddlyproto::HeartbeatResponse {
                 status: Some(cuddlyproto::StatusCode {
                     success: false,
                     code: cuddlyproto::StatusEnum::EInval as i32,
@@ -188,311 +394,2414 @@ impl DataRegistry {
                     state: cuddlyproto::nnha_status_heartbeat_proto::State::Active as i32,
                     txid: uuid::Uuid::new_v4().to_string(),
                 }),
-            };
+                commands: vec![],
+            }
+        }
+    }
 
-            return response;
+    /// Moves datanodes that missed heartbeats to stale or dead.
+    fn check_datanode_liveness(&self) {
+        let dead = self.datanode_manager.update_states(
+            Utc::now(),
+            APP_CONFIG.namenode.stale_datanode_interval as i64,
+            APP_CONFIG.namenode.dead_datanode_interval as i64,
+        );
+        for uuid in dead {
+            self.remove_dead_datanode(uuid);
         }
     }
 
-    fn remove_invalid_datanodes(&self) {
-        let mut cache = self.heartbeat_cache.lock().unwrap();
-        let now = Utc::now();
-        let mut to_remove = Vec::new();
+    /// Forgets the replicas of a dead datanode and queues them for
+    /// re-replication.
+    fn remove_dead_datanode(&self, uuid: Uuid) {
+        self.topology.write().unwrap().remove(&uuid);
+        self.pending_commands.lock().unwrap().remove(&uuid);
+        for holders in self.corrupt_replicas.lock().unwrap().values_mut() {
+            holders.remove(&uuid);
+        }
 
-        for (uuid, instant) in cache.iter() {
-            if now.signed_duration_since(*instant).num_seconds() > HEARTBEAT_TIMEOUT {
-                to_remove.push(uuid.clone());
+        let blocks = self.datanode_manager.remove_dead(&uuid);
+        {
+            let mut block_to_datanodes = self.block_to_datanodes.write().unwrap();
+            for block_id in &blocks {
+                block_to_datanodes.remove_id_for_key(block_id, &uuid);
             }
         }
+        info!(
+            "Removed Datanode with uuid (did not receive heartbeat): {}, {} blocks need re-replication",
+            uuid,
+            blocks.len()
+        );
+        self.under_replicated_blocks.lock().unwrap().extend(blocks);
+    }
 
-        for uuid in to_remove {
-            cache.pop(&uuid);
-            let socket_to_uuid = self.socket_to_uuid.read().unwrap();
-            let socket = socket_to_uuid
+    /// Sends commands to copy under-replicated blocks from a live replica to
+    /// new targets chosen by the placement policy.
+    fn schedule_replications(&self) {
+        let now = Utc::now();
+        {
+            let mut pending_replications = self.pending_replications.lock().unwrap();
+            let expired = pending_replications
                 .iter()
-                .find(|(_, v)| v == &&uuid)
-                .map(|(k, _)| k);
-            if let Some(socket) = socket {
-                let mut socket_to_uuid = self.socket_to_uuid.write().unwrap();
-                socket_to_uuid.pop(socket);
+                .filter(|(_, started)| {
+                    now.signed_duration_since(**started).num_seconds() > PENDING_REPLICATION_TIMEOUT
+                })
+                .map(|(block_id, _)| *block_id)
+                .collect::<Vec<_>>();
+            let mut under_replicated_blocks = self.under_replicated_blocks.lock().unwrap();
+            for block_id in expired {
+                pending_replications.remove(&block_id);
+                under_replicated_blocks.insert(block_id);
             }
-            info!(
-                "Removed Datanode with uuid (did not receive heartbeat): {}",
-                uuid
-            );
         }
-    }
 
-    async fn do_heartbeat_monitoring(&self) {
-        let mut heartbeat_tick =
-            time::interval(time::Duration::from_secs(HEARTBEAT_RECHECK_INTERVAL));
-        loop {
-            heartbeat_tick.tick().await;
-            self.remove_invalid_datanodes();
+        let queued = std::mem::take(&mut *self.under_replicated_blocks.lock().unwrap());
+        if queued.is_empty() {
+            return;
         }
-    }
-
-    pub(crate) fn block_received(&self, node_id: &str, block: &Block) -> CuddlyResult<()> {
-        info!("Block received from node_id: {}, {}", node_id, block);
 
-        // let datanode_uuid = match Uuid::parse_str(node_id) {
-        //     Ok(datanode_uuid) => datanode_uuid,
-        //     Err(_) => return Err(CuddlyError::FSError(format!("Invalid UUID: {}", node_id))),
-        // };
+        let alive_nodes = self.get_alive_datanodes();
+        let replication = self.block_replication();
+        let (policies, striped) = {
+            let fs_directory = self.fs_directory.read().unwrap();
+            (
+                fs_directory.block_storage_policies(),
+                fs_directory.striped_blocks(),
+            )
+        };
+        let mut requeue = Vec::new();
+        for block_id in queued {
+            let (block, holders) = {
+                let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+                match block_to_datanodes.get_data(&block_id) {
+                    Some(block) => (
+                        *block,
+                        block_to_datanodes
+                            .get_ids_for_key(&block_id)
+                            .cloned()
+                            .unwrap_or_default(),
+                    ),
+                    None => continue,
+                }
+            };
 
-        let datanode_uuid = {
-            let mut socket_to_uuid = self.socket_to_uuid.write().unwrap();
-            match socket_to_uuid.get(&SocketAddr::from_str(node_id).unwrap()) {
-                Some(uuid) => uuid.clone(),
-                None => {
-                    return Err(CuddlyError::FSError(format!(
-                        "Block received from unregistered datanode '{}'.",
-                        node_id
-                    )))
+            let holder_nodes = alive_nodes
+                .iter()
+                .filter(|node| holders.contains(&node.datanode_uuid))
+                .collect::<Vec<_>>();
+            let replica_types = holder_nodes
+                .iter()
+                .filter(|node| node.state.counts_as_replica())
+                .map(|node| {
+                    self.datanode_manager
+                        .storage_type_of(&node.datanode_uuid, &block_id)
+                        .unwrap_or_default()
+                })
+                .collect::<Vec<_>>();
+            let expected = expected_replicas(&replication, &block_id);
+            let needed = expected.saturating_sub(replica_types.len());
+            if needed == 0 {
+                self.invalidate_corrupt_replicas(&block);
+                continue;
+            }
+            // The new replicas go to the storage types the policy misses most
+            let policy = policies.get(&block_id).copied().unwrap_or_default();
+            let mut storage_types = policy.storage_types(expected);
+            for storage_type in &replica_types {
+                if let Some(index) = storage_types.iter().position(|t| t == storage_type) {
+                    storage_types.remove(index);
                 }
             }
-        };
+            storage_types.truncate(needed);
+            // Nodes in maintenance may be down, so they are never used as source.
+            let mut sources = holder_nodes
+                .into_iter()
+                .filter(|node| node.state != DatanodeState::InMaintenance)
+                .collect::<Vec<_>>();
+            sources.sort_by_key(|node| node.state == DatanodeState::Stale);
+            let Some(source) = sources.first() else {
+                // A block of an erasure-coded file is rebuilt from its group
+                if let Some(group) = striped.get(&block_id) {
+                    if !self.schedule_reconstruction(&block, group, policy) {
+                        requeue.push(block_id);
+                    }
+                    continue;
+                }
+                warn!("Block {} has no live replicas left", block_id);
+                continue;
+            };
 
-        let new_reported = {
-            let mut block_to_datanodes = self.block_to_datanodes.write().unwrap();
-            block_to_datanodes.insert_id_for_key(block.id, *block, datanode_uuid)
-        };
+            let targets = self.choose_targets(policy, &storage_types, &holders, None, block.len);
+            if targets.is_empty() {
+                requeue.push(block_id);
+                continue;
+            }
 
-        if new_reported {
-            let mut progress_tracker = self.namenode_progress_tracker.write().unwrap();
-            progress_tracker.increment_replication(block.id);
+            debug!(
+                "Scheduling replication of {} from {} to {:?}",
+                block, source.datanode_uuid, targets
+            );
+            self.queue_command(
+                source.datanode_uuid,
+                CommandType::ReplicateBlock,
+                &block,
+                &targets,
+            );
+            self.pending_replications
+                .lock()
+                .unwrap()
+                .insert(block_id, now);
         }
 
-        let insert_id_success = {
-            let mut datanode_to_blocks = self.datanode_to_blocks.write().unwrap();
-            datanode_to_blocks.insert_id_for_key_if_present(datanode_uuid, block.id)
-        };
+        self.under_replicated_blocks.lock().unwrap().extend(requeue);
+    }
 
-        if !insert_id_success {
-            return Err(CuddlyError::FSError(format!(
-                "Block received from unregistered datanode '{}'.",
-                node_id
-            )));
+    /// Asks a datanode outside the group of a lost block to rebuild it from
+    /// the other blocks of the group. Returns false if no datanode can take
+    /// the block yet.
+    fn schedule_reconstruction(
+        &self,
+        block: &Block,
+        group: &BlockGroup,
+        policy: StoragePolicy,
+    ) -> bool {
+        let (locations, excluded) = {
+            let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+            let mut excluded = HashSet::new();
+            let locations = group
+                .blocks
+                .iter()
+                .map(|internal| {
+                    let holders = block_to_datanodes
+                        .get_ids_for_key(&internal.id)
+                        .cloned()
+                        .unwrap_or_default();
+                    excluded.extend(holders.iter().copied());
+                    holders
+                        .iter()
+                        .filter_map(|uuid| self.datanode_manager.get(uuid))
+                        .filter(|holder| holder.state != DatanodeState::Dead)
+                        .map(|holder| holder.socket_address.to_string())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            (locations, excluded)
+        };
+        // Empty blocks need no replica to be read
+        let available = group
+            .blocks
+            .iter()
+            .zip(&locations)
+            .filter(|(internal, locations)| internal.len == 0 || !locations.is_empty())
+            .count();
+        if available < group.policy.data_units() {
+            warn!(
+                "Block {} cannot be rebuilt, only {} blocks of its {} group are left",
+                block, available, group.policy
+            );
+            return true;
         }
 
-        Ok(())
+        let Some(target) = self
+            .choose_targets(policy, &policy.storage_types(1), &excluded, None, block.len)
+            .into_iter()
+            .next()
+        else {
+            return false;
+        };
+        debug!(
+            "Scheduling reconstruction of {} on {}",
+            block, target.0.datanode_uuid
+        );
+        self.pending_commands
+            .lock()
+            .unwrap()
+            .entry(target.0.datanode_uuid)
+            .or_default()
+            .push(cuddlyproto::DatanodeCommandProto {
+                command_type: CommandType::ReconstructBlock as i32,
+                block: Some((*block).into()),
+                targets: vec![target.0.socket_address.to_string()],
+                storage_types: vec![target.1.to_proto()],
+                block_group: Some(group.to_proto(locations)),
+            });
+        self.pending_replications
+            .lock()
+            .unwrap()
+            .insert(block.id, Utc::now());
+        true
     }
 
-    async fn log_operation(&self, op: EditOperation) {
-        let mut edit_logger = self.operation_logger.lock().await;
-        edit_logger.log_operation(&op).await;
-    }
+    /// Marks decommissioning datanodes as decommissioned once every block
+    /// they hold has enough replicas elsewhere.
+    fn check_decommissions(&self) {
+        let decommissioning = self
+            .datanode_manager
+            .datanodes_in_state(DatanodeState::DecommissionInProgress);
+        if decommissioning.is_empty() {
+            return;
+        }
+        let replication = self.block_replication();
+        for uuid in decommissioning {
+            let missing = {
+                let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+                self.datanode_manager
+                    .blocks_of(&uuid)
+                    .into_iter()
+                    .filter(|block_id| {
+                        let replicas = block_to_datanodes
+                            .get_ids_for_key(block_id)
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|holder| self.datanode_manager.get(holder))
+                            .filter(|holder| holder.state.counts_as_replica())
+                            .count();
+                        replicas < expected_replicas(&replication, block_id)
+                    })
+                    .collect::<Vec<_>>()
+            };
 
-    pub(crate) fn report_datanodes(&self) -> CuddlyResult<Vec<DatanodeInfo>> {
-        Ok(self.get_alive_datanodes())
-    }
+            if missing.is_empty() {
+                self.datanode_manager
+                    .set_state(&uuid, DatanodeState::Decommissioned, None);
+                info!("Datanode {} is decommissioned and safe to shut down", uuid);
+                continue;
+            }
 
-    fn get_alive_datanodes(&self) -> Vec<DatanodeInfo> {
-        let datanode_to_blocks = self.datanode_to_blocks.read().unwrap();
-        self.heartbeat_cache
-            .lock()
-            .unwrap()
-            .iter()
-            .map(|(uuid, _instant)| datanode_to_blocks.get_data(uuid).unwrap().to_owned())
-            .collect::<Vec<_>>()
+            debug!(
+                "Datanode {} still has {} blocks to re-replicate",
+                uuid,
+                missing.len()
+            );
+            let pending_replications = self.pending_replications.lock().unwrap();
+            let mut under_replicated_blocks = self.under_replicated_blocks.lock().unwrap();
+            under_replicated_blocks.extend(
+                missing
+                    .into_iter()
+                    .filter(|block_id| !pending_replications.contains_key(block_id)),
+            );
+        }
     }
 
-    pub(crate) async fn make_dir(&self, path: &str) -> CuddlyResult<()> {
-        self.non_logging_make_dir(path)?;
-        self.log_operation(EditOperation::Mkdir(path.to_owned()))
-            .await;
-        Ok(())
+    /// Stops placing new blocks on a datanode and re-replicates all of its
+    /// blocks, after which it is reported as decommissioned.
+    pub(crate) fn decommission_datanode(&self, datanode: &str) -> CuddlyResult<DatanodeInfo> {
+        let uuid = self.resolve_datanode(datanode)?;
+        let info = self
+            .datanode_manager
+            .set_state(&uuid, DatanodeState::DecommissionInProgress, None)
+            .ok_or_else(|| unknown_datanode(datanode))?;
+        let blocks = self.datanode_manager.blocks_of(&uuid);
+        info!(
+            "Decommissioning datanode {}, {} blocks to re-replicate",
+            uuid,
+            blocks.len()
+        );
+        self.under_replicated_blocks.lock().unwrap().extend(blocks);
+        Ok(info)
     }
 
-    fn non_logging_make_dir(&self, path: &str) -> CuddlyResult<()> {
-        let mut fs_directory = self.fs_directory.write().unwrap();
-        fs_directory.make_dir(path)
+    /// Puts a datanode into maintenance for `duration` seconds. Its replicas
+    /// are not re-replicated during that time.
+    pub(crate) fn start_datanode_maintenance(
+        &self,
+        datanode: &str,
+        duration: u64,
+    ) -> CuddlyResult<DatanodeInfo> {
+        let uuid = self.resolve_datanode(datanode)?;
+        let end = Utc::now() + chrono::Duration::seconds(duration as i64);
+        info!("Datanode {} in maintenance until {}", uuid, end);
+        self.datanode_manager
+            .set_state(&uuid, DatanodeState::InMaintenance, Some(end))
+            .ok_or_else(|| unknown_datanode(datanode))
     }
 
-    pub(crate) fn list(&self, path: &str) -> CuddlyResult<Vec<String>> {
-        let fs_directory = self.fs_directory.read().unwrap();
-        Ok(fs_directory
-            .list(path)?
-            .iter()
-            .map(|s| String::from(*s))
-            .collect())
+    /// Returns a decommissioning, decommissioned or maintained datanode to
+    /// normal operation.
+    pub(crate) fn recommission_datanode(&self, datanode: &str) -> CuddlyResult<DatanodeInfo> {
+        let uuid = self.resolve_datanode(datanode)?;
+        self.datanode_manager
+            .set_state(&uuid, DatanodeState::Live, None)
+            .ok_or_else(|| unknown_datanode(datanode))
     }
 
-    pub(crate) fn open_file(&self, path: &str) -> CuddlyResult<Vec<(Block, Vec<DatanodeInfo>)>> {
-        let fs_directory = self.fs_directory.read().unwrap();
-        let file_blocks = fs_directory.open_file(path)?;
-        let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+    /// Re-reads the include and exclude files. Newly excluded datanodes are
+    /// decommissioned and datanodes that left the exclude file are
+    /// recommissioned. Datanodes that are no longer included are rejected on
+    /// their next heartbeat and eventually declared dead.
+    pub(crate) fn refresh_nodes(&self) -> CuddlyResult<RefreshedNodes> {
+        let new_filter = HostFilter::load(
+            APP_CONFIG.namenode.hosts_include_file.as_deref(),
+            APP_CONFIG.namenode.hosts_exclude_file.as_deref(),
+        )?;
+        let old_filter = std::mem::replace(&mut *self.host_filter.write().unwrap(), new_filter);
+        let host_filter = self.host_filter.read().unwrap();
 
-        Ok(file_blocks
-            .iter()
-            .map(|block| {
-                let datanodes = block_to_datanodes
-                    .get_ids_for_key(&block.id)
-                    .expect("If file with block exists, then this block should be replicated")
-                    .iter()
-                    .map(|s| {
-                        self.datanode_to_blocks
-                            .read()
-                            .unwrap()
-                            .get_data(s)
-                            .expect("If block exists, then datanode should have it")
-                            .clone()
-                    })
-                    .collect();
-                (*block, datanodes)
-            })
-            .collect())
+        let mut refreshed = RefreshedNodes {
+            included_hosts: host_filter.included_hosts(),
+            excluded_hosts: host_filter.excluded_hosts(),
+            ..Default::default()
+        };
+        for datanode in self.datanode_manager.alive_datanodes() {
+            let host_name = self.datanode_manager.host_name(&datanode.datanode_uuid);
+            let retiring = matches!(
+                datanode.state,
+                DatanodeState::DecommissionInProgress | DatanodeState::Decommissioned
+            );
+            let uuid = datanode.datanode_uuid.to_string();
+            if host_filter.is_excluded(&datanode.socket_address, &host_name) {
+                if !retiring {
+                    self.decommission_datanode(&uuid)?;
+                    refreshed.decommissioning.push(datanode.datanode_uuid);
+                }
+            } else if retiring && old_filter.is_excluded(&datanode.socket_address, &host_name) {
+                self.recommission_datanode(&uuid)?;
+                refreshed.recommissioned.push(datanode.datanode_uuid);
+            }
+        }
+        Ok(refreshed)
     }
 
-    pub(crate) fn start_file_create(
-        &self,
-        path: &str,
-    ) -> CuddlyResult<Option<(Block, Vec<DatanodeInfo>)>> {
-        let fs_directory = self.fs_directory.read().unwrap();
-        fs_directory.check_file_creation(path)?;
-        self.namenode_progress_tracker
-            .write()
-            .unwrap()
-            .add_file(path.to_owned())?;
-
-        let mut target_nodes = HashSet::new();
-        let mut available_nodes = self.get_alive_datanodes();
-        debug!("Available nodes: {:?}", available_nodes);
-        available_nodes.shuffle(&mut thread_rng());
-
-        for node_info in available_nodes {
-            debug!("Checking node: {:?}", node_info);
-            if node_info.free_capacity() > APP_CONFIG.block_size {
-                debug!("Node has enough capacity: {:?}", node_info.free_capacity());
-                target_nodes.insert(node_info);
-            }
-            if target_nodes.len() as u64 >= APP_CONFIG.replication_factor as u64 {
-                debug!("Found enough available nodes for file creation");
-                let block_id = self.next_block_id();
-                let seq = self
-                    .namenode_progress_tracker
-                    .write()
-                    .unwrap()
-                    .add_block(path, block_id)?;
-                let block = Block::new(block_id, 0, seq);
-                let mut blocks = HashSet::new();
-                blocks.insert(block);
-                debug!("Returning block: {:?}, targets: {:?}", block, target_nodes);
-                return Ok(Some((block, target_nodes.into_iter().collect())));
-            }
+    fn resolve_datanode(&self, datanode: &str) -> CuddlyResult<Uuid> {
+        let uuid = self
+            .datanode_manager
+            .resolve(datanode)
+            .ok_or_else(|| unknown_datanode(datanode))?;
+        match self.datanode_manager.get(&uuid) {
+            Some(info) if info.state == DatanodeState::Dead => Err(CuddlyError::FSError(format!(
+                "Datanode '{}' is dead",
+                datanode
+            ))),
+            _ => Ok(uuid),
         }
+    }
 
-        debug!("Not enough available nodes found for file creation");
-        Ok(None)
+    /// Queues a command for a datanode, delivered with its next heartbeat.
+    fn queue_command(
+        &self,
+        datanode_uuid: Uuid,
+        command_type: CommandType,
+        block: &Block,
+        targets: &[Target],
+    ) {
+        self.pending_commands
+            .lock()
+            .unwrap()
+            .entry(datanode_uuid)
+            .or_default()
+            .push(cuddlyproto::DatanodeCommandProto {
+                command_type: command_type as i32,
+                block: Some((*block).into()),
+                targets: targets
+                    .iter()
+                    .map(|(node, _)| node.socket_address.to_string())
+                    .collect(),
+                storage_types: targets
+                    .iter()
+                    .map(|(_, storage_type)| storage_type.to_proto())
+                    .collect(),
+                block_group: None,
+            });
     }
 
-    fn next_block_id(&self) -> Uuid {
-        let block_to_datanodes = self.block_to_datanodes.read().unwrap();
-        let creates_in_progress = self.namenode_progress_tracker.read().unwrap();
+    async fn do_balancing(&self) {
+        let config = &APP_CONFIG.namenode.balancer;
+        if !config.enabled {
+            return std::future::pending().await;
+        }
+        let mut balancer_tick = time::interval(time::Duration::from_secs(config.interval));
         loop {
-            let block_id = uuid::Uuid::new_v4();
-            if block_to_datanodes.contains_key(&block_id)
-                || creates_in_progress.contains_block(&block_id)
-            {
+            balancer_tick.tick().await;
+            if self.safe_mode.is_on() {
                 continue;
-            } else {
-                return block_id;
             }
+            self.balance(config.bandwidth * config.interval);
         }
     }
 
-    pub(crate) async fn finish_file_create(&self, path: &str) -> CuddlyResult<()> {
-        let blocks = self.internal_finish_file_create(path)?;
-        self.non_logging_finish_file(path, blocks.as_slice())?;
-        self.log_operation(EditOperation::AddFile(path.to_owned(), blocks))
-            .await;
+    /// Leaves the startup safe mode if enough blocks were reported. Returns
+    /// whether safe mode is still on.
+    fn update_safe_mode(&self) -> bool {
+        let (reported_blocks, total_blocks, live_datanodes) = self.safe_mode_counts();
+        self.safe_mode
+            .update(reported_blocks, total_blocks, live_datanodes)
+    }
 
-        Ok(())
+    fn safe_mode_counts(&self) -> (u64, u64, u32) {
+        let (reported_blocks, total_blocks) = {
+            let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+            (
+                block_to_datanodes.count_keys_with_ids() as u64,
+                block_to_datanodes.len() as u64,
+            )
+        };
+        let live_datanodes = self.datanode_manager.alive_datanodes().len() as u32;
+        (reported_blocks, total_blocks, live_datanodes)
     }
 
-    fn internal_finish_file_create(&self, path: &str) -> CuddlyResult<Vec<Block>> {
-        let namenode_progress_tracker = self.namenode_progress_tracker.read().unwrap();
-        let block_ids = namenode_progress_tracker.get_block_ids(path)?;
-        for block_id in block_ids {
-            let replication_count = namenode_progress_tracker.get_replication_count(*block_id);
-            if replication_count < APP_CONFIG.replication_factor as u64 {
-                return Err(CuddlyError::WaitingForReplication(format!(
-                    "Block {} has been replicated only {} times, but {} replications are required",
-                    block_id, replication_count, APP_CONFIG.replication_factor,
-                )));
-            }
-        }
+    pub(crate) fn safe_mode_status(&self) -> SafeModeStatus {
+        let (reported_blocks, total_blocks, live_datanodes) = self.safe_mode_counts();
+        self.safe_mode
+            .status(reported_blocks, total_blocks, live_datanodes)
+    }
 
-        let block_to_datanodes = self.block_to_datanodes.read().unwrap();
-        let blocks = block_ids
-            .iter()
-            .map(|id| block_to_datanodes.get_data(id).unwrap().to_owned())
-            .collect();
-        Ok(blocks)
+    pub(crate) fn enter_safe_mode(&self) -> SafeModeStatus {
+        self.safe_mode.enter();
+        self.safe_mode_status()
     }
 
-    fn non_logging_finish_file(&self, path: &str, blocks: &[Block]) -> CuddlyResult<()> {
-        let mut fs_directory = self.fs_directory.write().unwrap();
-        fs_directory.create_file(path, blocks)?;
-        let mut block_to_datanodes = self.block_to_datanodes.write().unwrap();
-        for block in blocks {
-            block_to_datanodes.insert_data(block.id, *block);
-        }
-        Ok(())
+    pub(crate) fn leave_safe_mode(&self) -> SafeModeStatus {
+        self.safe_mode.leave();
+        self.safe_mode_status()
     }
 
-    pub(crate) fn abort_file_create(&self, path: &str) -> CuddlyResult<()> {
-        let mut namenode_progress_tracker = self.namenode_progress_tracker.write().unwrap();
-        namenode_progress_tracker.remove_file(path)?;
-        Ok(())
+    /// Saves the namespace as a new image and empties the edit log. Returns
+    /// the number of files and directories in the image.
+    pub(crate) async fn checkpoint(&self) -> CuddlyResult<(u64, u64)> {
+        // Holding the logger lock keeps mutations out until the logs are
+        // emptied, so none of them is lost.
+        let mut operation_logger = self.operation_logger.lock().await;
+        let (image, counts) = {
+            let fs_directory = self.fs_directory.read().unwrap();
+            (serde_json::to_string(&*fs_directory)?, fs_directory.count())
+        };
+        operation_logger.checkpoint(&image).await?;
+        info!(
+            "Saved namespace image with {} files and {} directories",
+            counts.0, counts.1
+        );
+        Ok(counts)
     }
 
-    fn check_all_blocks_replicated(&self, path: &str) -> CuddlyResult<()> {
-        let namenode_progress_tracker = self.namenode_progress_tracker.read().unwrap();
-        let block_ids = namenode_progress_tracker.get_block_ids(path)?;
-        for block_id in block_ids {
-            let replication_count = self
-                .namenode_progress_tracker
-                .read()
-                .unwrap()
-                .get_replication_count(*block_id);
-            if replication_count < APP_CONFIG.replication_factor as u64 {
-                return Err(CuddlyError::WaitingForReplication(format!(
-                    "Block {} has been replicated only {} times, but {} replications are required",
-                    block_id, replication_count, APP_CONFIG.replication_factor,
-                )));
-            }
-        }
-        Ok(())
+    /// The capacity and usage of the cluster by storage type.
+    pub(crate) fn storage_type_usage(&self) -> BTreeMap<StorageType, (u64, u64)> {
+        self.datanode_manager.storage_type_usage()
     }
 
-    pub(crate) fn start_another_block(
+    /// Number of known blocks and of blocks with fewer counted replicas
+    /// than their file's replication.
+    pub(crate) fn block_counts(&self) -> (u64, u64) {
+        let replication = self.block_replication();
+        let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+        let under_replicated = block_to_datanodes
+            .iter_data()
+            .filter(|(block_id, _)| {
+                let replicas = block_to_datanodes
+                    .get_ids_for_key(block_id)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|holder| self.datanode_manager.get(holder))
+                    .filter(|holder| holder.state.counts_as_replica())
+                    .count();
+                replicas < expected_replicas(&replication, block_id)
+            })
+            .count();
+        (block_to_datanodes.len() as u64, under_replicated as u64)
+    }
+
+    /// Returns the holders of every block and the blocks of every datanode.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn dump_metadata(
         &self,
-        path: &str,
-    ) -> CuddlyResult<Option<(Block, Vec<DatanodeInfo>)>> {
-        self.check_all_blocks_replicated(path)?;
+    ) -> (Vec<(Block, Vec<Uuid>)>, Vec<(DatanodeInfo, Vec<Uuid>)>) {
+        let mut blocks = {
+            let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+            block_to_datanodes
+                .iter_data()
+                .map(|(block_id, block)| {
+                    let mut holders = block_to_datanodes
+                        .get_ids_for_key(block_id)
+                        .into_iter()
+                        .flatten()
+                        .copied()
+                        .collect::<Vec<_>>();
+                    holders.sort();
+                    (*block, holders)
+                })
+                .collect::<Vec<_>>()
+        };
+        blocks.sort_by_key(|(block, _)| block.id);
 
-        let mut target_nodes = HashSet::new();
-        let mut available_nodes = self.get_alive_datanodes();
-        available_nodes.shuffle(&mut thread_rng());
+        let mut datanodes = self
+            .datanode_manager
+            .all_datanodes()
+            .into_iter()
+            .map(|datanode| {
+                let mut blocks = self
+                    .datanode_manager
+                    .blocks_of(&datanode.datanode_uuid)
+                    .into_iter()
+                    .collect::<Vec<_>>();
+                blocks.sort();
+                (datanode, blocks)
+            })
+            .collect::<Vec<_>>();
+        datanodes.sort_by_key(|(datanode, _)| datanode.datanode_uuid);
+        (blocks, datanodes)
+    }
 
-        for node_info in available_nodes {
-            if node_info.free_capacity() > APP_CONFIG.block_size {
-                target_nodes.insert(node_info);
-            }
-            if target_nodes.len() as u64 >= APP_CONFIG.replication_factor as u64 {
-                let block_id = self.next_block_id();
-                let seq = self
-                    .namenode_progress_tracker
-                    .write()
-                    .unwrap()
-                    .add_block(path, block_id)?;
-                let block = Block::new(block_id, 0, seq);
-                return Ok(Some((block, target_nodes.into_iter().collect())));
+    /// Checks the health of every block of the files at or below `path`,
+    /// then moves or deletes the files with missing or corrupt blocks.
+    pub(crate) async fn fsck(
+        &self,
+        path: &str,
+        list_locations: bool,
+        action: FsckAction,
+    ) -> CuddlyResult<FsckReport> {
+        let files = self.fs_directory.read().unwrap().files_under(path)?;
+        let replication = self.block_replication();
+        let mut report = FsckReport::default();
+        {
+            let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+            let corrupt_replicas = self.corrupt_replicas.lock().unwrap();
+            for (path, blocks) in files {
+                let blocks = blocks
+                    .into_iter()
+                    .map(|block| {
+                        let holders = block_to_datanodes
+                            .get_ids_for_key(&block.id)
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|uuid| self.datanode_manager.get(uuid))
+                            .filter(|holder| holder.state != DatanodeState::Dead)
+                            .collect::<Vec<_>>();
+                        let replicas = holders
+                            .iter()
+                            .filter(|holder| holder.state.counts_as_replica())
+                            .count();
+                        let corrupt = corrupt_replicas.get(&block.id).map_or(0, |c| c.len());
+                        FsckBlock {
+                            block,
+                            health: BlockHealth::classify(
+                                replicas,
+                                holders.len(),
+                                corrupt,
+                                expected_replicas(&replication, &block.id),
+                            ),
+                            replicas,
+                            locations: holders.iter().map(|h| h.socket_address).collect(),
+                        }
+                    })
+                    .collect();
+                report.add(FsckFile { path, blocks }, list_locations);
             }
         }
 
-        Ok(None)
+        for path in report.corrupt_files() {
+            let res = match action {
+                FsckAction::None => continue,
+                FsckAction::Move => self.move_to_lost_and_found(&path).await,
+                FsckAction::Delete => self.delete(&path, false).await,
+            };
+            match (res, action) {
+                (Ok(()), FsckAction::Move) => report.moved.push(path),
+                (Ok(()), _) => report.deleted.push(path),
+                (Err(err), _) => warn!("fsck could not {:?} '{}': {}", action, path, err),
+            }
+        }
+        info!(
+            "fsck of '{}': {} files, {} blocks, {} under-replicated, {} missing, {} corrupt",
+            path,
+            report.total_files,
+            report.total_blocks,
+            report.under_replicated_blocks,
+            report.missing_blocks,
+            report.corrupt_blocks
+        );
+        Ok(report)
+    }
+
+    /// Moves a path into the trash of `user` instead of deleting it and
+    /// returns where it went. Paths are deleted right away if the trash is
+    /// disabled, `user` is `None` or they are in a trash already. A symbolic
+    /// link at the end of `path` is deleted itself unless `follow` is set.
+    pub(crate) async fn delete_to_trash(
+        &self,
+        path: &str,
+        recursive: bool,
+        follow: bool,
+        user: Option<&str>,
+    ) -> CuddlyResult<Option<String>> {
+        let path = &self
+            .fs_directory
+            .read()
+            .unwrap()
+            .resolve_path(path, follow)?;
+        let user = match user {
+            Some(user) if APP_CONFIG.namenode.trash.interval > 0 && !trash::is_in_trash(path) => {
+                user
+            }
+            _ => return self.delete(path, recursive).await.map(|()| None),
+        };
+        if user.contains('/') {
+            return Err(CuddlyError::FSError(format!(
+                "'{}' is not a valid user name",
+                user
+            )));
+        }
+        self.safe_mode.check("delete")?;
+        self.check_deletable(path, recursive)?;
+
+        let mut dst = trash::current_path(user, path);
+        if self.fs_directory.read().unwrap().is_directory(&dst).is_ok() {
+            // An earlier delete of the same path is still in the trash
+            dst = format!("{}.{}", dst, Utc::now().timestamp_millis());
+        }
+        let parent = &dst[..dst.rfind('/').unwrap_or_default()];
+        self.make_dir(parent).await?;
+        self.rename(path, &dst).await?;
+        info!("Moved '{}' to trash at '{}'", path, dst);
+        Ok(Some(dst))
+    }
+
+    /// Moves a path out of a trash, to where it was deleted from unless
+    /// `dst` is given. Returns where it went.
+    pub(crate) async fn restore_from_trash(
+        &self,
+        trash_path: &str,
+        dst: Option<&str>,
+    ) -> CuddlyResult<String> {
+        let dst = match dst {
+            Some(dst) => dst.to_owned(),
+            None => trash::original_path(trash_path)
+                .ok_or_else(|| CuddlyError::FSError(format!("'{}': Not in a trash", trash_path)))?,
+        };
+        let parent = &dst[..dst.rfind('/').unwrap_or_default()];
+        if !parent.is_empty() {
+            self.make_dir(parent).await?;
+        }
+        self.rename(trash_path, &dst).await?;
+        Ok(dst)
+    }
+
+    /// Moves the current trash of `user`, or of every user if `None`, into
+    /// a new checkpoint and deletes checkpoints older than the trash
+    /// interval, or all of them if `immediate` is set. Returns the deleted
+    /// checkpoints.
+    pub(crate) async fn expunge(
+        &self,
+        user: Option<&str>,
+        immediate: bool,
+    ) -> CuddlyResult<Vec<String>> {
+        let users = match user {
+            Some(user) => vec![user.to_owned()],
+            None => self
+                .fs_directory
+                .read()
+                .unwrap()
+                .list(trash::USER_DIR)
+                .map(|users| users.into_iter().map(str::to_owned).collect())
+                .unwrap_or_default(),
+        };
+        let now = Utc::now();
+        let deadline = now - chrono::Duration::seconds(APP_CONFIG.namenode.trash.interval as i64);
+        let mut deleted = vec![];
+        for user in users {
+            let root = trash::trash_root(&user);
+            let current = format!("{}/{}", root, trash::CURRENT);
+            if self
+                .fs_directory
+                .read()
+                .unwrap()
+                .is_directory(&current)
+                .is_ok()
+            {
+                self.rename(
+                    &current,
+                    &format!("{}/{}", root, trash::checkpoint_name(now)),
+                )
+                .await?;
+            }
+            let checkpoints = match self.fs_directory.read().unwrap().list(&root) {
+                Ok(checkpoints) => checkpoints
+                    .into_iter()
+                    .filter(|name| {
+                        trash::checkpoint_time(name)
+                            .is_some_and(|time| immediate || time < deadline)
+                    })
+                    .map(|name| format!("{}/{}", root, name))
+                    .collect::<Vec<_>>(),
+                Err(_) => continue,
+            };
+            for checkpoint in checkpoints {
+                self.delete(&checkpoint, true).await?;
+                deleted.push(checkpoint);
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn do_trash_checkpoints(&self) {
+        let config = &APP_CONFIG.namenode.trash;
+        if config.interval == 0 {
+            return std::future::pending().await;
+        }
+        let mut trash_tick =
+            time::interval(time::Duration::from_secs(config.checkpoint_interval.max(1)));
+        loop {
+            trash_tick.tick().await;
+            if self.safe_mode.is_on() {
+                continue;
+            }
+            match self.expunge(None, false).await {
+                Ok(deleted) if !deleted.is_empty() => {
+                    info!("Deleted trash checkpoints {:?}", deleted)
+                }
+                Ok(_) => {}
+                Err(err) => warn!("Could not checkpoint trash: {}", err),
+            }
+        }
+    }
+
+    async fn move_to_lost_and_found(&self, path: &str) -> CuddlyResult<()> {
+        let dst = format!("/{}{}", LOST_AND_FOUND, path);
+        let parent = &dst[..dst.rfind('/').unwrap_or_default()];
+        self.make_dir(parent).await?;
+        self.rename(path, &dst).await
+    }
+
+    /// Runs one balancer iteration, moving at most `budget` bytes.
+    fn balance(&self, budget: u64) {
+        let now = Utc::now();
+        self.balancer.expire(now, PENDING_REPLICATION_TIMEOUT);
+
+        let nodes = self
+            .get_alive_datanodes()
+            .into_iter()
+            .filter(|node| node.state == DatanodeState::Live)
+            .collect::<Vec<_>>();
+        let replicas_of = |datanode_uuid: &Uuid| {
+            let block_ids = self.datanode_manager.blocks_of(datanode_uuid);
+            let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+            block_ids
+                .iter()
+                .filter_map(|block_id| {
+                    let block = block_to_datanodes.get_data(block_id)?;
+                    let holders = block_to_datanodes.get_ids_for_key(block_id)?;
+                    Some((*block, holders.clone()))
+                })
+                .collect()
+        };
+
+        for block_move in self.balancer.plan(
+            &nodes,
+            replicas_of,
+            self.placement_policy.as_ref(),
+            budget,
+            now,
+        ) {
+            // The copy stays on the same type of storage
+            let storage_type = self
+                .datanode_manager
+                .storage_type_of(&block_move.source, &block_move.block.id)
+                .unwrap_or_default();
+            self.queue_command(
+                block_move.source,
+                CommandType::ReplicateBlock,
+                &block_move.block,
+                &[(block_move.target, storage_type)],
+            );
+        }
+    }
+
+    /// Copies the replicas that are on the wrong type of storage for the
+    /// storage policy of their file to a storage of the right type.
+    fn schedule_moves(&self) {
+        let now = Utc::now();
+        self.mover.expire(now, PENDING_REPLICATION_TIMEOUT);
+        let queued = self.mover.take_queued();
+        if queued.is_empty() {
+            return;
+        }
+
+        let policies = self.fs_directory.read().unwrap().block_storage_policies();
+        let candidates = self
+            .get_alive_datanodes()
+            .into_iter()
+            .filter(|node| node.state.accepts_new_replicas())
+            .collect::<Vec<_>>();
+        let mut requeue = vec![];
+        for block_id in queued {
+            // Deleted since it was queued
+            let Some(&policy) = policies.get(&block_id) else {
+                continue;
+            };
+            let (block, holders) = {
+                let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+                let Some(block) = block_to_datanodes.get_data(&block_id).copied() else {
+                    continue;
+                };
+                let holders = block_to_datanodes
+                    .get_ids_for_key(&block_id)
+                    .cloned()
+                    .unwrap_or_default();
+                (block, holders)
+            };
+            // Only replicas on nodes that are up can be copied
+            let replicas = holders
+                .iter()
+                .filter(|uuid| {
+                    self.datanode_manager
+                        .get(uuid)
+                        .is_some_and(|node| node.state.accepts_new_replicas())
+                })
+                .filter_map(|uuid| {
+                    let storage_type = self.datanode_manager.storage_type_of(uuid, &block_id)?;
+                    Some((*uuid, storage_type))
+                })
+                .collect::<Vec<_>>();
+
+            let mut excluded = holders;
+            for (source, storage_type) in plan_moves(policy, &replicas) {
+                let Some(target) = self
+                    .choose_targets_of_type(
+                        &candidates,
+                        storage_type,
+                        1,
+                        &excluded,
+                        None,
+                        block.len,
+                    )
+                    .pop()
+                else {
+                    debug!(
+                        "No {} storage to move the replica of {} on {} to",
+                        storage_type, block, source
+                    );
+                    requeue.push(block_id);
+                    break;
+                };
+                debug!(
+                    "Moving replica of {} from {} to {} storage on {}",
+                    block, source, storage_type, target.0.datanode_uuid
+                );
+                excluded.insert(target.0.datanode_uuid);
+                self.queue_command(source, CommandType::ReplicateBlock, &block, &[target]);
+                self.mover.start(BlockMove {
+                    block,
+                    source,
+                    target: target.0,
+                    started: now,
+                });
+            }
+        }
+        self.mover.queue(requeue);
+    }
+
+    /// Drops the source replica of a finished balancer or mover move.
+    fn finish_move(&self, block_move: BlockMove) {
+        let BlockMove { block, source, .. } = block_move;
+        self.block_to_datanodes
+            .write()
+            .unwrap()
+            .remove_id_for_key(&block.id, &source);
+        self.datanode_manager.remove_block(&source, &block.id);
+        self.queue_command(source, CommandType::DeleteBlock, &block, &[]);
+    }
+
+    pub(crate) fn balancer_status(&self) -> BalancerStatus {
+        self.balancer.status()
+    }
+
+    async fn do_heartbeat_monitoring(&self) {
+        let mut heartbeat_tick = time::interval(time::Duration::from_secs(
+            APP_CONFIG.namenode.heartbeat_recheck_interval,
+        ));
+        loop {
+            heartbeat_tick.tick().await;
+            self.check_datanode_liveness();
+            self.recover_expired_leases(Utc::now()).await;
+            if self.update_safe_mode() {
+                continue;
+            }
+            self.check_decommissions();
+            self.schedule_replications();
+            self.schedule_moves();
+        }
+    }
+
+    /// Recovers the files whose writer has not added a block for
+    /// LEASE_HARD_LIMIT seconds before `now`.
+    async fn recover_expired_leases(&self, now: DateTime<Utc>) {
+        if self.safe_mode.is_on() {
+            return;
+        }
+        let deadline = now - chrono::Duration::seconds(LEASE_HARD_LIMIT);
+        let expired = self
+            .namenode_progress_tracker
+            .read()
+            .unwrap()
+            .expired_leases(deadline);
+        for path in expired {
+            warn!("Lease on '{}' expired, recovering its blocks", path);
+            // The lease is kept, so the recovery is retried on the next check
+            if let Err(e) = self.recover_lease(&path).await {
+                warn!("Failed to recover the lease on '{}': {}", path, e);
+                continue;
+            }
+            // Whatever was not committed is dropped with the lease
+            if let Err(e) = self.close_file(&path) {
+                warn!(
+                    "Failed to close '{}' after recovering its lease: {}",
+                    path, e
+                );
+            }
+        }
+    }
+
+    /// Commits the blocks of a file written so far, up to the first one that
+    /// no datanode has received, with the length their replicas were
+    /// received with. An append adds them to the file, a new file is created
+    /// with them. Blocks short of replicas are re-replicated afterwards.
+    async fn recover_lease(&self, path: &str) -> CuddlyResult<()> {
+        let mut operation_logger = self.operation_logger.lock().await;
+        let (append, layout, blocks) = {
+            let progress_tracker = self.namenode_progress_tracker.read().unwrap();
+            let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+            let mut blocks = progress_tracker
+                .get_block_ids(path)?
+                .iter()
+                .take_while(|block_id| progress_tracker.get_replication_count(**block_id) > 0)
+                .filter_map(|block_id| block_to_datanodes.get_data(block_id).copied())
+                .collect::<Vec<_>>();
+            let layout = progress_tracker.get_layout(path)?;
+            // Only whole groups can be decoded
+            if let Some(policy) = layout.erasure_coding_policy {
+                blocks.truncate(blocks.len() / policy.group_width() * policy.group_width());
+            }
+            (progress_tracker.is_append(path), layout, blocks)
+        };
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        let op = self.commit_file(path, blocks.clone(), append, layout)?;
+        operation_logger.log_operation(&op).await;
+        info!("Recovered {} blocks of '{}'", blocks.len(), path);
+        self.under_replicated_blocks
+            .lock()
+            .unwrap()
+            .extend(blocks.iter().map(|block| block.id));
+        Ok(())
+    }
+
+    pub(crate) fn block_received(
+        &self,
+        node_id: &str,
+        block: &Block,
+        storage_type: StorageType,
+    ) -> CuddlyResult<()> {
+        info!(
+            "Block received from node_id: {}, {} on {}",
+            node_id, block, storage_type
+        );
+
+        // let datanode_uuid = match Uuid::parse_str(node_id) {
+        //     Ok(datanode_uuid) => datanode_uuid,
+        //     Err(_) => return Err(CuddlyError::FSError(format!("Invalid UUID: {}", node_id))),
+        // };
+
+        let socket_address = SocketAddr::from_str(node_id)
+            .map_err(|_| CuddlyError::FSError(format!("Invalid address: {}", node_id)))?;
+        let datanode_uuid = match self.datanode_manager.uuid_for_address(&socket_address) {
+            Some(uuid) => uuid,
+            None => {
+                return Err(CuddlyError::FSError(format!(
+                    "Block received from unregistered datanode '{}'.",
+                    node_id
+                )))
+            }
+        };
+
+        let (under_construction, generation) = {
+            let progress_tracker = self.namenode_progress_tracker.read().unwrap();
+            (
+                progress_tracker.contains_block(&block.id),
+                progress_tracker.generation(&block.id),
+            )
+        };
+        let generation = generation.or_else(|| {
+            self.block_to_datanodes
+                .read()
+                .unwrap()
+                .get_data(&block.id)
+                .map(|known| known.generation)
+        });
+        if let Some(generation) = generation.filter(|generation| block.generation < *generation) {
+            warn!(
+                "Stale replica of {} on {}, the block is at generation {}",
+                block, node_id, generation
+            );
+            self.queue_command(datanode_uuid, CommandType::DeleteBlock, block, &[]);
+            return Err(CuddlyError::FSError(format!(
+                "Replica of {} is stale, the block is at generation {}",
+                block, generation
+            )));
+        }
+        let block = &Block {
+            generation: generation.unwrap_or(block.generation),
+            ..*block
+        };
+
+        let replica_count = {
+            let mut block_to_datanodes = self.block_to_datanodes.write().unwrap();
+            block_to_datanodes.insert_id_for_key(block.id, *block, datanode_uuid);
+            // A block reopened for append has grown
+            if under_construction {
+                block_to_datanodes.update_data(block.id, *block);
+            }
+            block_to_datanodes
+                .get_ids_for_key(&block.id)
+                .map_or(0, |ids| ids.len())
+        };
+
+        if under_construction {
+            self.namenode_progress_tracker
+                .write()
+                .unwrap()
+                .add_replica(block.id, datanode_uuid);
+        }
+
+        let pending = self
+            .pending_replications
+            .lock()
+            .unwrap()
+            .contains_key(&block.id);
+        let corrupt = self
+            .corrupt_replicas
+            .lock()
+            .unwrap()
+            .contains_key(&block.id);
+        if (pending || corrupt)
+            && replica_count >= expected_replicas(&self.block_replication(), &block.id)
+        {
+            self.pending_replications.lock().unwrap().remove(&block.id);
+            self.invalidate_corrupt_replicas(block);
+        }
+
+        if !self
+            .datanode_manager
+            .add_block(datanode_uuid, block.id, storage_type)
+        {
+            return Err(CuddlyError::FSError(format!(
+                "Block received from unregistered datanode '{}'.",
+                node_id
+            )));
+        }
+
+        if let Some(block_move) = self.balancer.complete(&block.id, &datanode_uuid) {
+            self.finish_move(block_move);
+        }
+        if let Some(block_move) = self.mover.complete(&block.id, &datanode_uuid) {
+            self.finish_move(block_move);
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the corrupt replicas of a block once it has enough healthy
+    /// ones again.
+    fn invalidate_corrupt_replicas(&self, block: &Block) {
+        let holders = self
+            .corrupt_replicas
+            .lock()
+            .unwrap()
+            .remove(&block.id)
+            .unwrap_or_default();
+        for holder in holders {
+            info!("Deleting corrupt replica of {} on {}", block, holder);
+            self.queue_command(holder, CommandType::DeleteBlock, block, &[]);
+        }
+    }
+
+    /// Forgets the replicas a datanode lost, with a failed volume or as
+    /// corrupt, and queues their blocks for re-replication.
+    pub(crate) fn blocks_lost(&self, node_id: &str, block_ids: &[Uuid]) -> CuddlyResult<()> {
+        let socket_address = SocketAddr::from_str(node_id)
+            .map_err(|_| CuddlyError::FSError(format!("Invalid address: {}", node_id)))?;
+        let datanode_uuid = self
+            .datanode_manager
+            .uuid_for_address(&socket_address)
+            .ok_or_else(|| {
+                CuddlyError::FSError(format!(
+                    "Lost blocks from unregistered datanode '{}'.",
+                    node_id
+                ))
+            })?;
+        warn!("{} replicas lost on {}", block_ids.len(), node_id);
+
+        {
+            let mut block_to_datanodes = self.block_to_datanodes.write().unwrap();
+            for block_id in block_ids {
+                block_to_datanodes.remove_id_for_key(block_id, &datanode_uuid);
+            }
+        }
+        for block_id in block_ids {
+            self.datanode_manager.remove_block(&datanode_uuid, block_id);
+        }
+        self.under_replicated_blocks
+            .lock()
+            .unwrap()
+            .extend(block_ids.iter().copied());
+        Ok(())
+    }
+
+    /// Records the replicas a datanode holds. `being_written` are replicas
+    /// the datanode found unfinished when it started; the ones with the
+    /// length of a complete block count as replicas of it and the block is
+    /// returned, for the datanode to finalize them with its generation. The
+    /// ones of blocks under construction are returned apart, for the datanode
+    /// to keep them.
+    pub(crate) fn block_report(
+        &self,
+        node_id: &str,
+        blocks: &[(Block, StorageType)],
+        being_written: &[(Block, StorageType)],
+    ) -> CuddlyResult<(Vec<Block>, Vec<Block>)> {
+        let socket_address = SocketAddr::from_str(node_id)
+            .map_err(|_| CuddlyError::FSError(format!("Invalid address: {}", node_id)))?;
+        let datanode_uuid = self
+            .datanode_manager
+            .uuid_for_address(&socket_address)
+            .ok_or_else(|| {
+                CuddlyError::FSError(format!(
+                    "Block report from unregistered datanode '{}'.",
+                    node_id
+                ))
+            })?;
+
+        // Replicas of a block reopened for append grow one after the other
+        let under_construction = {
+            let progress_tracker = self.namenode_progress_tracker.read().unwrap();
+            blocks
+                .iter()
+                .chain(being_written)
+                .filter(|(block, _)| progress_tracker.contains_block(&block.id))
+                .map(|(block, _)| block.id)
+                .collect::<HashSet<_>>()
+        };
+        let complete = {
+            let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+            being_written
+                .iter()
+                .filter(|(block, _)| !under_construction.contains(&block.id))
+                .filter_map(|(block, storage_type)| {
+                    block_to_datanodes
+                        .get_data(&block.id)
+                        .filter(|known| known.len == block.len)
+                        .map(|known| (*known, *storage_type))
+                })
+                .collect::<Vec<_>>()
+        };
+        let blocks = blocks.iter().chain(&complete).copied().collect::<Vec<_>>();
+        let mut corrupt_blocks = vec![];
+        let mut blocks_to_truncate = vec![];
+        let known_blocks = {
+            let mut block_to_datanodes = self.block_to_datanodes.write().unwrap();
+            let mut corrupt_replicas = self.corrupt_replicas.lock().unwrap();
+            let truncated_blocks = self.truncated_blocks.lock().unwrap();
+            blocks
+                .iter()
+                .filter(|(block, _)| {
+                    let Some(&known) = block_to_datanodes.get_data(&block.id) else {
+                        return false;
+                    };
+                    // A replica that missed an append or a truncation
+                    let stale = block.generation < known.generation;
+                    if known.len != block.len || stale {
+                        if under_construction.contains(&block.id) {
+                            return false;
+                        }
+                        if known.len < block.len && truncated_blocks.contains(&block.id) {
+                            blocks_to_truncate.push(known);
+                            block_to_datanodes
+                                .insert_id_for_key_if_present(block.id, datanode_uuid);
+                            return true;
+                        }
+                        warn!(
+                            "Corrupt replica of {} on {}: {} bytes at generation {} instead of {} at generation {}",
+                            block.id, node_id, block.len, block.generation, known.len, known.generation
+                        );
+                        corrupt_replicas
+                            .entry(block.id)
+                            .or_default()
+                            .insert(datanode_uuid);
+                        block_to_datanodes.remove_id_for_key(&block.id, &datanode_uuid);
+                        corrupt_blocks.push(block.id);
+                        return false;
+                    }
+                    if let Some(holders) = corrupt_replicas.get_mut(&block.id) {
+                        holders.remove(&datanode_uuid);
+                    }
+                    block_to_datanodes.insert_id_for_key_if_present(block.id, datanode_uuid);
+                    true
+                })
+                .map(|(block, storage_type)| (block.id, *storage_type))
+                .collect::<Vec<_>>()
+        };
+        for block_id in &corrupt_blocks {
+            self.datanode_manager.remove_block(&datanode_uuid, block_id);
+        }
+        for block in &blocks_to_truncate {
+            self.queue_command(datanode_uuid, CommandType::TruncateBlock, block, &[]);
+        }
+        self.under_replicated_blocks
+            .lock()
+            .unwrap()
+            .extend(corrupt_blocks);
+        for (block_id, storage_type) in &known_blocks {
+            self.datanode_manager
+                .add_block(datanode_uuid, *block_id, *storage_type);
+        }
+        info!(
+            "Block report from {}: {} blocks, {} belong to files",
+            node_id,
+            blocks.len(),
+            known_blocks.len()
+        );
+
+        if self.safe_mode.is_on() {
+            self.update_safe_mode();
+        }
+        let under_construction = being_written
+            .iter()
+            .filter(|(block, _)| under_construction.contains(&block.id))
+            .map(|(block, _)| *block)
+            .collect();
+        Ok((
+            complete.into_iter().map(|(block, _)| block).collect(),
+            under_construction,
+        ))
+    }
+
+    /// Reports every known datanode, including dead ones.
+    pub(crate) fn report_datanodes(&self) -> CuddlyResult<Vec<DatanodeInfo>> {
+        Ok(self.datanode_manager.all_datanodes())
+    }
+
+    /// Chooses a target for each of `storage_types` among the datanodes that
+    /// accept new replicas. Where no datanode has room on a storage of the
+    /// wanted type, the fallback of `policy` is tried.
+    fn choose_targets(
+        &self,
+        policy: StoragePolicy,
+        storage_types: &[StorageType],
+        excluded: &HashSet<Uuid>,
+        writer_location: Option<IpAddr>,
+        block_size: u64,
+    ) -> Vec<Target> {
+        let candidates = self
+            .get_alive_datanodes()
+            .into_iter()
+            .filter(|node| node.state.accepts_new_replicas())
+            .collect::<Vec<_>>();
+        let mut wanted: Vec<(StorageType, usize)> = vec![];
+        for storage_type in storage_types {
+            match wanted.iter_mut().find(|(t, _)| t == storage_type) {
+                Some((_, count)) => *count += 1,
+                None => wanted.push((*storage_type, 1)),
+            }
+        }
+
+        let mut excluded = excluded.clone();
+        let mut targets = vec![];
+        for (storage_type, count) in wanted {
+            let mut chosen = self.choose_targets_of_type(
+                &candidates,
+                storage_type,
+                count,
+                &excluded,
+                writer_location,
+                block_size,
+            );
+            excluded.extend(chosen.iter().map(|(node, _)| node.datanode_uuid));
+            if let Some(fallback) = policy
+                .fallback(storage_type)
+                .filter(|_| chosen.len() < count)
+            {
+                let more = self.choose_targets_of_type(
+                    &candidates,
+                    fallback,
+                    count - chosen.len(),
+                    &excluded,
+                    writer_location,
+                    block_size,
+                );
+                excluded.extend(more.iter().map(|(node, _)| node.datanode_uuid));
+                chosen.extend(more);
+            }
+            targets.extend(chosen);
+        }
+        targets
+    }
+
+    /// Chooses up to `replication` of `candidates` that have a storage of
+    /// `storage_type` with room for the block. Stale datanodes are only used
+    /// if there are not enough live ones.
+    fn choose_targets_of_type(
+        &self,
+        candidates: &[DatanodeInfo],
+        storage_type: StorageType,
+        replication: usize,
+        excluded: &HashSet<Uuid>,
+        writer_location: Option<IpAddr>,
+        block_size: u64,
+    ) -> Vec<Target> {
+        let (live_nodes, stale_nodes): (Vec<_>, Vec<_>) = self
+            .datanode_manager
+            .with_storage_type(candidates, storage_type)
+            .into_iter()
+            .partition(|node| node.state == DatanodeState::Live);
+
+        let mut targets = self.placement_policy.choose_targets(
+            &live_nodes,
+            replication,
+            excluded,
+            writer_location,
+            block_size,
+        );
+        if targets.len() < replication && !stale_nodes.is_empty() {
+            let mut excluded = excluded.clone();
+            excluded.extend(targets.iter().map(|node| node.datanode_uuid));
+            targets.extend(self.placement_policy.choose_targets(
+                &stale_nodes,
+                replication - targets.len(),
+                &excluded,
+                writer_location,
+                block_size,
+            ));
+        }
+        targets
+            .into_iter()
+            .map(|node| (node, storage_type))
+            .collect()
+    }
+
+    fn get_alive_datanodes(&self) -> Vec<DatanodeInfo> {
+        self.datanode_manager.alive_datanodes()
+    }
+
+    pub(crate) async fn make_dir(&self, path: &str) -> CuddlyResult<()> {
+        self.safe_mode.check("create directory")?;
+        let mut operation_logger = self.operation_logger.lock().await;
+        {
+            let fs_directory = self.fs_directory.read().unwrap();
+            let new_directories = fs_directory.missing_directories(path)?;
+            fs_directory.check_quota(path, new_directories, 0)?;
+        }
+        self.non_logging_make_dir(path)?;
+        operation_logger
+            .log_operation(&EditOperation::Mkdir(path.to_owned()))
+            .await;
+        Ok(())
+    }
+
+    fn non_logging_make_dir(&self, path: &str) -> CuddlyResult<()> {
+        let mut fs_directory = self.fs_directory.write().unwrap();
+        fs_directory.make_dir(path)
+    }
+
+    /// Removes a file, or a directory if it is empty or `recursive` is set,
+    /// and deletes the replicas of blocks no snapshot refers to.
+    pub(crate) async fn delete(&self, path: &str, recursive: bool) -> CuddlyResult<()> {
+        self.safe_mode.check("delete")?;
+        let mut operation_logger = self.operation_logger.lock().await;
+        self.check_deletable(path, recursive)?;
+        self.non_logging_delete(path)?;
+        operation_logger
+            .log_operation(&EditOperation::Delete(path.to_owned()))
+            .await;
+        Ok(())
+    }
+
+    fn check_deletable(&self, path: &str, recursive: bool) -> CuddlyResult<()> {
+        let fs_directory = self.fs_directory.read().unwrap();
+        let is_directory = fs_directory.stat(path, false)?.file_type == FileType::Directory;
+        if !recursive && is_directory && !fs_directory.list(path)?.is_empty() {
+            return Err(CuddlyError::FSError(format!(
+                "'{}': Directory is not empty",
+                path
+            )));
+        }
+        Ok(())
+    }
+
+    fn non_logging_delete(&self, path: &str) -> CuddlyResult<()> {
+        let blocks = {
+            let mut fs_directory = self.fs_directory.write().unwrap();
+            let blocks = fs_directory.delete(path)?;
+            fs_directory.unreferenced(blocks)
+        };
+        self.remove_blocks(blocks);
+        Ok(())
+    }
+
+    /// Forgets blocks that no longer belong to a file and deletes their
+    /// replicas.
+    fn remove_blocks(&self, blocks: Vec<Block>) {
+        for block in blocks {
+            let holders = {
+                let mut block_to_datanodes = self.block_to_datanodes.write().unwrap();
+                let holders = block_to_datanodes.take_ids_for_key(&block.id);
+                block_to_datanodes.remove_key(&block.id);
+                holders
+            };
+            self.under_replicated_blocks
+                .lock()
+                .unwrap()
+                .remove(&block.id);
+            self.pending_replications.lock().unwrap().remove(&block.id);
+            self.truncated_blocks.lock().unwrap().remove(&block.id);
+            let corrupt = self
+                .corrupt_replicas
+                .lock()
+                .unwrap()
+                .remove(&block.id)
+                .unwrap_or_default();
+            for holder in holders.into_iter().chain(corrupt) {
+                self.datanode_manager.remove_block(&holder, &block.id);
+                self.queue_command(holder, CommandType::DeleteBlock, &block, &[]);
+            }
+        }
+    }
+
+    /// Shortens a file. Blocks past the new length are deleted and the block
+    /// the new length falls into is truncated on its datanodes.
+    pub(crate) async fn truncate(&self, path: &str, new_length: u64) -> CuddlyResult<()> {
+        self.safe_mode.check("truncate")?;
+        self.check_not_open(path)?;
+        let mut operation_logger = self.operation_logger.lock().await;
+        let generation = self.next_generation.fetch_add(1, Ordering::SeqCst);
+        self.non_logging_truncate(path, new_length, generation)?;
+        operation_logger
+            .log_operation(&EditOperation::Truncate(
+                path.to_owned(),
+                new_length,
+                generation,
+            ))
+            .await;
+        Ok(())
+    }
+
+    /// The block the new length falls into gets `generation`, so that the
+    /// replicas that miss the truncation are stale.
+    fn non_logging_truncate(
+        &self,
+        path: &str,
+        new_length: u64,
+        generation: u64,
+    ) -> CuddlyResult<()> {
+        self.observe_generation(generation);
+        let (removed, shortened) = {
+            let mut fs_directory = self.fs_directory.write().unwrap();
+            let (removed, shortened) = fs_directory.truncate(path, new_length, generation)?;
+            (fs_directory.unreferenced(removed), shortened)
+        };
+        self.remove_blocks(removed);
+        if let Some(block) = shortened {
+            let holders = {
+                let mut block_to_datanodes = self.block_to_datanodes.write().unwrap();
+                block_to_datanodes.update_data(block.id, block);
+                block_to_datanodes
+                    .get_ids_for_key(&block.id)
+                    .cloned()
+                    .unwrap_or_default()
+            };
+            self.truncated_blocks.lock().unwrap().insert(block.id);
+            for holder in holders {
+                self.queue_command(holder, CommandType::TruncateBlock, &block, &[]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the blocks of `sources` to the end of `target` and removes the
+    /// sources, in one namespace operation.
+    pub(crate) async fn concat(&self, target: &str, sources: &[String]) -> CuddlyResult<()> {
+        self.safe_mode.check("concat")?;
+        self.check_not_open(target)?;
+        for source in sources {
+            self.check_not_open(source)?;
+        }
+        let mut operation_logger = self.operation_logger.lock().await;
+        self.fs_directory
+            .read()
+            .unwrap()
+            .check_concat_quota(target, sources)?;
+        self.non_logging_concat(target, sources)?;
+        operation_logger
+            .log_operation(&EditOperation::Concat(target.to_owned(), sources.to_vec()))
+            .await;
+        Ok(())
+    }
+
+    fn non_logging_concat(&self, target: &str, sources: &[String]) -> CuddlyResult<()> {
+        let mut fs_directory = self.fs_directory.write().unwrap();
+        let blocks = fs_directory.concat(target, sources)?;
+        let mut block_to_datanodes = self.block_to_datanodes.write().unwrap();
+        for block in blocks {
+            block_to_datanodes.update_data(block.id, block);
+        }
+        Ok(())
+    }
+
+    fn check_not_open(&self, path: &str) -> CuddlyResult<()> {
+        if self.namenode_progress_tracker.read().unwrap().is_open(path) {
+            return Err(CuddlyError::FSError(format!(
+                "'{}': File is open for writing",
+                path
+            )));
+        }
+        Ok(())
+    }
+
+    /// Moves a file or directory to `dst`, whose parent has to exist.
+    pub(crate) async fn rename(&self, src: &str, dst: &str) -> CuddlyResult<()> {
+        self.safe_mode.check("rename")?;
+        let mut operation_logger = self.operation_logger.lock().await;
+        self.fs_directory
+            .read()
+            .unwrap()
+            .check_rename_quota(src, dst)?;
+        self.non_logging_rename(src, dst)?;
+        operation_logger
+            .log_operation(&EditOperation::Rename(src.to_owned(), dst.to_owned()))
+            .await;
+        Ok(())
+    }
+
+    fn non_logging_rename(&self, src: &str, dst: &str) -> CuddlyResult<()> {
+        self.fs_directory.write().unwrap().rename(src, dst)
+    }
+
+    pub(crate) fn list(&self, path: &str) -> CuddlyResult<Vec<String>> {
+        let fs_directory = self.fs_directory.read().unwrap();
+        Ok(fs_directory
+            .list(path)?
+            .iter()
+            .map(|s| String::from(*s))
+            .collect())
+    }
+
+    /// Returns the blocks of a file, with their locations sorted by distance
+    /// to the reader.
+    pub(crate) fn open_file(
+        &self,
+        path: &str,
+        reader_location: Option<IpAddr>,
+    ) -> CuddlyResult<Vec<(Block, Vec<DatanodeInfo>)>> {
+        let fs_directory = self.fs_directory.read().unwrap();
+        let file_blocks = fs_directory.open_file(path)?;
+        let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+        let topology = self.topology.read().unwrap();
+
+        Ok(file_blocks
+            .iter()
+            .map(|block| {
+                let mut datanodes = readable_holders(
+                    block_to_datanodes
+                        .get_ids_for_key(&block.id)
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|uuid| self.datanode_manager.get(uuid))
+                        .collect(),
+                );
+                topology.sort_by_distance(reader_location, &mut datanodes);
+                (*block, datanodes)
+            })
+            .collect())
+    }
+
+    /// Starts writing a new file with the given replication and block size,
+    /// or the cluster defaults, and returns its first block with targets.
+    /// Below a directory with an erasure coding policy, the file is written
+    /// in block groups and the replication is ignored.
+    pub(crate) fn start_file_create(
+        &self,
+        path: &str,
+        writer_location: Option<IpAddr>,
+        replication: Option<u64>,
+        block_size: Option<u64>,
+    ) -> CuddlyResult<Option<Allocation>> {
+        self.safe_mode.check("create file")?;
+        let (layout, policy) = {
+            let fs_directory = self.fs_directory.read().unwrap();
+            let erasure_coding_policy = fs_directory.erasure_coding_policy(path)?;
+            let layout = FileLayout {
+                replication: match erasure_coding_policy {
+                    Some(_) => 1,
+                    None => replication.unwrap_or(APP_CONFIG.replication_factor),
+                },
+                block_size: block_size.unwrap_or(APP_CONFIG.block_size),
+                erasure_coding_policy,
+            };
+            // Erasure-coded files are not replicated
+            if layout.erasure_coding_policy.is_none() {
+                check_replication(layout.replication)?;
+            }
+            check_block_size(layout.block_size)?;
+            fs_directory.check_file_creation(path)?;
+            fs_directory.check_quota(path, 1, layout.allocation_space())?;
+            (layout, fs_directory.storage_policy(path)?)
+        };
+        self.namenode_progress_tracker
+            .write()
+            .unwrap()
+            .add_file(path.to_owned(), layout)?;
+
+        let allocated = self.allocate_block(path, policy, writer_location);
+        if !matches!(allocated, Ok(Some(_))) {
+            self.close_file(path)?;
+        }
+        allocated
+    }
+
+    /// Chooses targets for the next block of `path`, or the next group of
+    /// an erasure-coded file, using the configured placement policy and the
+    /// storage policy of the file, and reserves the space of a full block
+    /// under the quotas above the file. Returns `None` if not enough
+    /// datanodes are available.
+    fn allocate_block(
+        &self,
+        path: &str,
+        policy: StoragePolicy,
+        writer_location: Option<IpAddr>,
+    ) -> CuddlyResult<Option<Allocation>> {
+        let layout = self
+            .namenode_progress_tracker
+            .read()
+            .unwrap()
+            .get_layout(path)?;
+        // The blocks of a group each go to a different datanode
+        let replication = match layout.erasure_coding_policy {
+            Some(erasure_coding_policy) => erasure_coding_policy.group_width(),
+            None => layout.replication as usize,
+        };
+        let target_nodes = self.choose_targets(
+            policy,
+            &policy.storage_types(replication),
+            &HashSet::new(),
+            writer_location,
+            layout.block_size,
+        );
+        if target_nodes.len() < replication {
+            debug!("Not enough available nodes found for block allocation");
+            return Ok(None);
+        }
+        self.fs_directory
+            .write()
+            .unwrap()
+            .reserve(path, layout.allocation_space())?;
+
+        let Some(erasure_coding_policy) = layout.erasure_coding_policy else {
+            let block = self.new_block(path)?;
+            debug!("Returning block: {:?}, targets: {:?}", block, target_nodes);
+            return Ok(Some(Allocation::Replicated(block, target_nodes)));
+        };
+        let blocks = target_nodes
+            .into_iter()
+            .map(|target| Ok((self.new_block(path)?, target)))
+            .collect::<CuddlyResult<Vec<_>>>()?;
+        debug!("Returning block group: {:?}", blocks);
+        Ok(Some(Allocation::Striped(
+            erasure_coding_policy,
+            ErasureCodingPolicy::cell_size(layout.block_size),
+            blocks,
+        )))
+    }
+
+    /// Adds an empty block with a new id and generation to a file being
+    /// written.
+    fn new_block(&self, path: &str) -> CuddlyResult<Block> {
+        let block_id = self.next_block_id();
+        let generation = self.next_generation.fetch_add(1, Ordering::SeqCst);
+        let seq = self
+            .namenode_progress_tracker
+            .write()
+            .unwrap()
+            .add_block(path, block_id, generation)?;
+        Ok(Block {
+            generation,
+            ..Block::new(block_id, 0, seq)
+        })
+    }
+
+    /// Makes sure generations issued from now on are newer than one that
+    /// was persisted.
+    fn observe_generation(&self, generation: u64) {
+        self.next_generation
+            .fetch_max(generation + 1, Ordering::SeqCst);
+    }
+
+    fn next_block_id(&self) -> Uuid {
+        let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+        let creates_in_progress = self.namenode_progress_tracker.read().unwrap();
+        loop {
+            let block_id = uuid::Uuid::new_v4();
+            if block_to_datanodes.contains_key(&block_id)
+                || creates_in_progress.contains_block(&block_id)
+            {
+                continue;
+            } else {
+                return block_id;
+            }
+        }
+    }
+
+    pub(crate) async fn finish_file_create(&self, path: &str) -> CuddlyResult<()> {
+        self.safe_mode.check("complete file")?;
+        let blocks = self.internal_finish_file_create(path)?;
+        let (append, layout) = {
+            let progress_tracker = self.namenode_progress_tracker.read().unwrap();
+            (
+                progress_tracker.is_append(path),
+                progress_tracker.get_layout(path)?,
+            )
+        };
+        let mut operation_logger = self.operation_logger.lock().await;
+        let op = self.commit_file(path, blocks, append, layout)?;
+        operation_logger.log_operation(&op).await;
+        self.close_file(path)?;
+
+        Ok(())
+    }
+
+    /// Adds the written blocks to the namespace, appended to the file or as
+    /// a new file, and returns the operation to log.
+    fn commit_file(
+        &self,
+        path: &str,
+        blocks: Vec<Block>,
+        append: bool,
+        layout: FileLayout,
+    ) -> CuddlyResult<EditOperation> {
+        if append {
+            self.non_logging_append(path, &blocks)?;
+            return Ok(EditOperation::Append(path.to_owned(), blocks));
+        }
+        self.non_logging_finish_file(path, &blocks, layout)?;
+        Ok(match layout.erasure_coding_policy {
+            Some(policy) => {
+                EditOperation::AddStripedFile(path.to_owned(), blocks, policy, layout.block_size)
+            }
+            None => EditOperation::AddFile(
+                path.to_owned(),
+                blocks,
+                layout.replication,
+                layout.block_size,
+            ),
+        })
+    }
+
+    /// Reopens a file for appending and hands its lease to the caller.
+    /// Returns the last block with its holders and a new generation if the
+    /// block is partial and enough holders accept writes, or else a new
+    /// block. Returns `None` if no datanodes are available for a new block.
+    pub(crate) fn append_file(
+        &self,
+        path: &str,
+        writer_location: Option<IpAddr>,
+    ) -> CuddlyResult<Option<AppendTarget>> {
+        self.safe_mode.check("append to file")?;
+        let (last_block, layout, policy) = {
+            let fs_directory = self.fs_directory.read().unwrap();
+            fs_directory.check_replicated(path)?;
+            let status = fs_directory.stat(path, true)?;
+            let layout = FileLayout {
+                replication: status.replication,
+                block_size: status.block_size,
+                erasure_coding_policy: None,
+            };
+            (
+                fs_directory.open_file(path)?.last().copied(),
+                layout,
+                fs_directory.storage_policy(path)?,
+            )
+        };
+        let next_seq = last_block.map_or(0, |block| block.seq + 1);
+        // Appending to a block in a snapshot would change the snapshot
+        let reopened = last_block
+            .filter(|block| block.len < layout.block_size)
+            .filter(|block| !self.fs_directory.read().unwrap().in_snapshot(&block.id))
+            .and_then(|block| {
+                let mut holders: Vec<DatanodeInfo> = self
+                    .block_to_datanodes
+                    .read()
+                    .unwrap()
+                    .get_ids_for_key(&block.id)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|uuid| self.datanode_manager.get(uuid))
+                    .filter(|datanode| datanode.state.accepts_new_replicas())
+                    .collect();
+                if holders.len() < layout.replication as usize {
+                    return None;
+                }
+                self.topology
+                    .read()
+                    .unwrap()
+                    .sort_by_distance(writer_location, &mut holders);
+                // The replicas are appended to where they are
+                let holders = holders
+                    .into_iter()
+                    .map(|holder| {
+                        let storage_type = self
+                            .datanode_manager
+                            .storage_type_of(&holder.datanode_uuid, &block.id)
+                            .unwrap_or_default();
+                        (holder, storage_type)
+                    })
+                    .collect::<Vec<_>>();
+                Some((block, holders))
+            });
+
+        let generation = reopened
+            .as_ref()
+            .map(|_| self.next_generation.fetch_add(1, Ordering::SeqCst));
+        self.namenode_progress_tracker
+            .write()
+            .unwrap()
+            .start_append(
+                path.to_owned(),
+                next_seq,
+                reopened.as_ref().map(|(block, _)| block.id).zip(generation),
+                layout,
+            )?;
+        if let Some(((block, holders), generation)) = reopened.zip(generation) {
+            // The rest of the block counts towards the quotas like a new one
+            let reserved = self
+                .fs_directory
+                .write()
+                .unwrap()
+                .reserve(path, (layout.block_size - block.len) * layout.replication);
+            if let Err(e) = reserved {
+                self.close_file(path)?;
+                return Err(e);
+            }
+            info!(
+                "Reopened {} of '{}' for append with generation {}",
+                block, path, generation
+            );
+            return Ok(Some((block, holders, Some(generation))));
+        }
+
+        let allocated = self.allocate_block(path, policy, writer_location);
+        if !matches!(allocated, Ok(Some(_))) {
+            self.close_file(path)?;
+        }
+        Ok(allocated?.map(|allocation| match allocation {
+            Allocation::Replicated(block, targets) => (block, targets, None),
+            Allocation::Striped(..) => unreachable!("erasure-coded files are not appended to"),
+        }))
+    }
+
+    fn non_logging_append(&self, path: &str, blocks: &[Block]) -> CuddlyResult<()> {
+        for block in blocks {
+            self.observe_generation(block.generation);
+        }
+        let mut fs_directory = self.fs_directory.write().unwrap();
+        fs_directory.append_blocks(path, blocks)?;
+        let mut block_to_datanodes = self.block_to_datanodes.write().unwrap();
+        for block in blocks {
+            block_to_datanodes.update_data(block.id, *block);
+        }
+        Ok(())
+    }
+
+    fn internal_finish_file_create(&self, path: &str) -> CuddlyResult<Vec<Block>> {
+        let namenode_progress_tracker = self.namenode_progress_tracker.read().unwrap();
+        let block_ids = namenode_progress_tracker.get_block_ids(path)?;
+        let replication = namenode_progress_tracker.get_layout(path)?.replication;
+        for block_id in block_ids {
+            let replication_count = namenode_progress_tracker.get_replication_count(*block_id);
+            if replication_count < replication {
+                return Err(CuddlyError::WaitingForReplication(format!(
+                    "Block {} has been replicated only {} times, but {} replications are required",
+                    block_id, replication_count, replication,
+                )));
+            }
+        }
+
+        let block_to_datanodes = self.block_to_datanodes.read().unwrap();
+        let blocks = block_ids
+            .iter()
+            .map(|id| block_to_datanodes.get_data(id).unwrap().to_owned())
+            .collect();
+        Ok(blocks)
+    }
+
+    fn non_logging_finish_file(
+        &self,
+        path: &str,
+        blocks: &[Block],
+        layout: FileLayout,
+    ) -> CuddlyResult<()> {
+        for block in blocks {
+            self.observe_generation(block.generation);
+        }
+        let mut fs_directory = self.fs_directory.write().unwrap();
+        match layout.erasure_coding_policy {
+            Some(policy) => {
+                fs_directory.create_striped_file(path, blocks, policy, layout.block_size)?
+            }
+            None => {
+                fs_directory.create_file(path, blocks, layout.replication, layout.block_size)?
+            }
+        }
+        let mut block_to_datanodes = self.block_to_datanodes.write().unwrap();
+        for block in blocks {
+            block_to_datanodes.insert_data(block.id, *block);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn abort_file_create(&self, path: &str) -> CuddlyResult<()> {
+        self.close_file(path)
+    }
+
+    /// Stops tracking a file that is no longer being written and releases
+    /// the space reserved for it.
+    fn close_file(&self, path: &str) -> CuddlyResult<()> {
+        self.namenode_progress_tracker
+            .write()
+            .unwrap()
+            .remove_file(path)?;
+        self.fs_directory.write().unwrap().release(path);
+        Ok(())
+    }
+
+    fn check_all_blocks_replicated(&self, path: &str) -> CuddlyResult<()> {
+        let namenode_progress_tracker = self.namenode_progress_tracker.read().unwrap();
+        let block_ids = namenode_progress_tracker.get_block_ids(path)?;
+        let replication = namenode_progress_tracker.get_layout(path)?.replication;
+        for block_id in block_ids {
+            let replication_count = self
+                .namenode_progress_tracker
+                .read()
+                .unwrap()
+                .get_replication_count(*block_id);
+            if replication_count < replication {
+                return Err(CuddlyError::WaitingForReplication(format!(
+                    "Block {} has been replicated only {} times, but {} replications are required",
+                    block_id, replication_count, replication,
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn start_another_block(
+        &self,
+        path: &str,
+        writer_location: Option<IpAddr>,
+    ) -> CuddlyResult<Option<Allocation>> {
+        self.safe_mode.check("add block")?;
+        self.check_all_blocks_replicated(path)?;
+        let policy = self.fs_directory.read().unwrap().storage_policy(path)?;
+        self.allocate_block(path, policy, writer_location)
+    }
+
+    /// Sets or, with `None`, clears the namespace or space quota of a
+    /// directory.
+    pub(crate) async fn set_quota(
+        &self,
+        path: &str,
+        namespace: bool,
+        limit: Option<u64>,
+    ) -> CuddlyResult<()> {
+        self.safe_mode.check("set quota")?;
+        let mut operation_logger = self.operation_logger.lock().await;
+        let mut quota = self.content_summary(path)?.quota;
+        if namespace {
+            quota.namespace = limit;
+        } else {
+            quota.space = limit;
+        }
+        self.non_logging_set_quota(path, quota)?;
+        operation_logger
+            .log_operation(&EditOperation::SetQuota(path.to_owned(), quota))
+            .await;
+        Ok(())
+    }
+
+    fn non_logging_set_quota(&self, path: &str, quota: Quota) -> CuddlyResult<()> {
+        self.fs_directory.write().unwrap().set_quota(path, quota)
+    }
+
+    /// Takes a read-only snapshot of a directory.
+    pub(crate) async fn create_snapshot(&self, path: &str, name: &str) -> CuddlyResult<()> {
+        self.safe_mode.check("create snapshot")?;
+        let mut operation_logger = self.operation_logger.lock().await;
+        self.non_logging_create_snapshot(path, name)?;
+        operation_logger
+            .log_operation(&EditOperation::CreateSnapshot(
+                path.to_owned(),
+                name.to_owned(),
+            ))
+            .await;
+        Ok(())
+    }
+
+    fn non_logging_create_snapshot(&self, path: &str, name: &str) -> CuddlyResult<()> {
+        self.fs_directory
+            .write()
+            .unwrap()
+            .create_snapshot(path, name)
+    }
+
+    /// Removes a snapshot and deletes the replicas of the blocks only it
+    /// referred to.
+    pub(crate) async fn delete_snapshot(&self, path: &str, name: &str) -> CuddlyResult<()> {
+        self.safe_mode.check("delete snapshot")?;
+        let mut operation_logger = self.operation_logger.lock().await;
+        self.non_logging_delete_snapshot(path, name)?;
+        operation_logger
+            .log_operation(&EditOperation::DeleteSnapshot(
+                path.to_owned(),
+                name.to_owned(),
+            ))
+            .await;
+        Ok(())
+    }
+
+    fn non_logging_delete_snapshot(&self, path: &str, name: &str) -> CuddlyResult<()> {
+        let blocks = self
+            .fs_directory
+            .write()
+            .unwrap()
+            .delete_snapshot(path, name)?;
+        self.remove_blocks(blocks);
+        Ok(())
+    }
+
+    pub(crate) fn list_snapshots(&self, path: &str) -> CuddlyResult<Vec<String>> {
+        let fs_directory = self.fs_directory.read().unwrap();
+        let names = fs_directory.list_snapshots(path)?;
+        Ok(names.into_iter().map(str::to_owned).collect())
+    }
+
+    pub(crate) fn snapshot_diff(
+        &self,
+        path: &str,
+        from: &str,
+        to: Option<&str>,
+    ) -> CuddlyResult<Vec<(DiffType, String)>> {
+        self.fs_directory
+            .read()
+            .unwrap()
+            .snapshot_diff(path, from, to)
+    }
+
+    /// Creates a symbolic link at `path` pointing at `target`, replacing a
+    /// link at `path` if `overwrite` is set.
+    pub(crate) async fn create_symlink(
+        &self,
+        path: &str,
+        target: &str,
+        overwrite: bool,
+    ) -> CuddlyResult<()> {
+        self.safe_mode.check("create symbolic link")?;
+        let mut operation_logger = self.operation_logger.lock().await;
+        {
+            let fs_directory = self.fs_directory.read().unwrap();
+            // Replacing a link takes no new namespace entry
+            if fs_directory.stat(path, false).is_err() {
+                fs_directory.check_quota(path, 1, 0)?;
+            }
+        }
+        self.non_logging_create_symlink(path, target, overwrite)?;
+        operation_logger
+            .log_operation(&EditOperation::CreateSymlink(
+                path.to_owned(),
+                target.to_owned(),
+                overwrite,
+            ))
+            .await;
+        Ok(())
+    }
+
+    fn non_logging_create_symlink(
+        &self,
+        path: &str,
+        target: &str,
+        overwrite: bool,
+    ) -> CuddlyResult<()> {
+        self.fs_directory
+            .write()
+            .unwrap()
+            .create_symlink(path, target, overwrite)
+    }
+
+    pub(crate) fn stat(&self, path: &str, follow: bool) -> CuddlyResult<FileStatus> {
+        self.fs_directory.read().unwrap().stat(path, follow)
+    }
+
+    /// Sets an extended attribute of a file or directory. Attributes in the
+    /// `system.` namespace cannot be set by clients.
+    pub(crate) async fn set_xattr(&self, path: &str, name: &str, value: &[u8]) -> CuddlyResult<()> {
+        self.safe_mode.check("set extended attribute")?;
+        check_client_xattr(name)?;
+        let config = &APP_CONFIG.namenode;
+        if name.len() + value.len() > config.xattr_max_size {
+            return Err(CuddlyError::FSError(format!(
+                "'{}': Attribute '{}' exceeds the limit of {} bytes",
+                path, name, config.xattr_max_size
+            )));
+        }
+        let mut operation_logger = self.operation_logger.lock().await;
+        {
+            let fs_directory = self.fs_directory.read().unwrap();
+            let xattrs = fs_directory.xattrs(path)?;
+            if !xattrs.contains_key(name) && xattrs.len() >= config.max_xattrs_per_inode {
+                return Err(CuddlyError::FSError(format!(
+                    "'{}': Cannot have more than {} attributes",
+                    path, config.max_xattrs_per_inode
+                )));
+            }
+        }
+        self.non_logging_set_xattr(path, name, value)?;
+        operation_logger
+            .log_operation(&EditOperation::SetXAttr(
+                path.to_owned(),
+                name.to_owned(),
+                value.to_vec(),
+            ))
+            .await;
+        Ok(())
+    }
+
+    fn non_logging_set_xattr(&self, path: &str, name: &str, value: &[u8]) -> CuddlyResult<()> {
+        self.fs_directory
+            .write()
+            .unwrap()
+            .set_xattr(path, name, value)
+    }
+
+    pub(crate) async fn remove_xattr(&self, path: &str, name: &str) -> CuddlyResult<()> {
+        self.safe_mode.check("remove extended attribute")?;
+        check_client_xattr(name)?;
+        let mut operation_logger = self.operation_logger.lock().await;
+        self.non_logging_remove_xattr(path, name)?;
+        operation_logger
+            .log_operation(&EditOperation::RemoveXAttr(
+                path.to_owned(),
+                name.to_owned(),
+            ))
+            .await;
+        Ok(())
+    }
+
+    fn non_logging_remove_xattr(&self, path: &str, name: &str) -> CuddlyResult<()> {
+        self.fs_directory.write().unwrap().remove_xattr(path, name)
+    }
+
+    /// Extended attributes of a file or directory, all of them if `names`
+    /// is empty.
+    pub(crate) fn get_xattrs(
+        &self,
+        path: &str,
+        names: &[String],
+    ) -> CuddlyResult<Vec<(String, Vec<u8>)>> {
+        let fs_directory = self.fs_directory.read().unwrap();
+        let xattrs = fs_directory.xattrs(path)?;
+        if names.is_empty() {
+            return Ok(xattrs
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect());
+        }
+        names
+            .iter()
+            .map(|name| match xattrs.get(name) {
+                Some(value) => Ok((name.clone(), value.clone())),
+                None => Err(CuddlyError::FSError(format!(
+                    "'{}': No such attribute '{}'",
+                    path, name
+                ))),
+            })
+            .collect()
+    }
+
+    pub(crate) fn list_xattrs(&self, path: &str) -> CuddlyResult<Vec<String>> {
+        let fs_directory = self.fs_directory.read().unwrap();
+        Ok(fs_directory.xattrs(path)?.keys().cloned().collect())
+    }
+
+    pub(crate) fn content_summary(&self, path: &str) -> CuddlyResult<ContentSummary> {
+        self.fs_directory.read().unwrap().content_summary(path)
+    }
+
+    /// Changes how many replicas the blocks of a file should have. Missing
+    /// replicas are re-replicated and excess ones deleted.
+    pub(crate) async fn set_replication(&self, path: &str, replication: u64) -> CuddlyResult<()> {
+        self.safe_mode.check("set replication")?;
+        check_replication(replication)?;
+        self.check_not_open(path)?;
+        let mut operation_logger = self.operation_logger.lock().await;
+        {
+            let fs_directory = self.fs_directory.read().unwrap();
+            let status = fs_directory.stat(path, true)?;
+            if status.file_type == FileType::File && replication > status.replication {
+                let space = status.length * (replication - status.replication);
+                fs_directory.check_quota(path, 0, space)?;
+            }
+        }
+        self.non_logging_set_replication(path, replication)?;
+        operation_logger
+            .log_operation(&EditOperation::SetReplication(path.to_owned(), replication))
+            .await;
+        Ok(())
+    }
+
+    fn non_logging_set_replication(&self, path: &str, replication: u64) -> CuddlyResult<()> {
+        let (blocks, expected) = {
+            let mut fs_directory = self.fs_directory.write().unwrap();
+            let blocks = fs_directory.set_replication(path, replication)?;
+            (blocks, fs_directory.block_replication())
+        };
+        for block in blocks {
+            self.adjust_replicas(&block, expected_replicas(&expected, &block.id));
+        }
+        Ok(())
+    }
+
+    /// Queues a block for re-replication if it has fewer than `expected`
+    /// replicas, or deletes the replicas past `expected` from the fullest
+    /// datanodes.
+    fn adjust_replicas(&self, block: &Block, expected: usize) {
+        let holders = self
+            .block_to_datanodes
+            .read()
+            .unwrap()
+            .get_ids_for_key(&block.id)
+            .cloned()
+            .unwrap_or_default();
+        // Replicas are only known once reported, which they are not while
+        // the edit log is replayed
+        if holders.is_empty() {
+            return;
+        }
+        let mut replicas = holders
+            .iter()
+            .filter_map(|uuid| self.datanode_manager.get(uuid))
+            .filter(|holder| holder.state.counts_as_replica())
+            .collect::<Vec<_>>();
+        if replicas.len() < expected {
+            self.under_replicated_blocks
+                .lock()
+                .unwrap()
+                .insert(block.id);
+            return;
+        }
+
+        let excess = replicas.len() - expected;
+        replicas.sort_by_key(|holder| holder.free_capacity());
+        for holder in replicas.into_iter().take(excess) {
+            debug!(
+                "Deleting excess replica of {} on {}",
+                block, holder.datanode_uuid
+            );
+            self.block_to_datanodes
+                .write()
+                .unwrap()
+                .remove_id_for_key(&block.id, &holder.datanode_uuid);
+            self.datanode_manager
+                .remove_block(&holder.datanode_uuid, &block.id);
+            self.queue_command(holder.datanode_uuid, CommandType::DeleteBlock, block, &[]);
+        }
+    }
+
+    /// Sets the storage policy of a file or directory, or with `None` lets it
+    /// inherit the policy of its parent. The mover then migrates the replicas
+    /// of the files below it to the storage types of the new policy.
+    pub(crate) async fn set_storage_policy(
+        &self,
+        path: &str,
+        policy: Option<StoragePolicy>,
+    ) -> CuddlyResult<()> {
+        self.safe_mode.check("set storage policy")?;
+        let mut operation_logger = self.operation_logger.lock().await;
+        self.non_logging_set_storage_policy(path, policy)?;
+        operation_logger
+            .log_operation(&EditOperation::SetStoragePolicy(path.to_owned(), policy))
+            .await;
+        Ok(())
+    }
+
+    fn non_logging_set_storage_policy(
+        &self,
+        path: &str,
+        policy: Option<StoragePolicy>,
+    ) -> CuddlyResult<()> {
+        let blocks = self
+            .fs_directory
+            .write()
+            .unwrap()
+            .set_storage_policy(path, policy)?;
+        self.mover.queue(blocks.into_iter().map(|block| block.id));
+        Ok(())
+    }
+
+    /// The storage policy of an existing path, set on it or inherited.
+    pub(crate) fn storage_policy(&self, path: &str) -> CuddlyResult<StoragePolicy> {
+        let fs_directory = self.fs_directory.read().unwrap();
+        fs_directory.stat(path, true)?;
+        fs_directory.storage_policy(path)
+    }
+
+    /// Sets the erasure coding policy of a directory, or with `None` lets it
+    /// inherit the policy of its parent. Files already written keep their
+    /// layout.
+    pub(crate) async fn set_erasure_coding_policy(
+        &self,
+        path: &str,
+        policy: Option<ErasureCodingPolicy>,
+    ) -> CuddlyResult<()> {
+        self.safe_mode.check("set erasure coding policy")?;
+        let mut operation_logger = self.operation_logger.lock().await;
+        self.non_logging_set_erasure_coding_policy(path, policy)?;
+        operation_logger
+            .log_operation(&EditOperation::SetErasureCodingPolicy(
+                path.to_owned(),
+                policy,
+            ))
+            .await;
+        Ok(())
+    }
+
+    fn non_logging_set_erasure_coding_policy(
+        &self,
+        path: &str,
+        policy: Option<ErasureCodingPolicy>,
+    ) -> CuddlyResult<()> {
+        self.fs_directory
+            .write()
+            .unwrap()
+            .set_erasure_coding_policy(path, policy)
+    }
+
+    /// The erasure coding policy of a file, or the one a file created at
+    /// `path` gets. `None` means replicated.
+    pub(crate) fn erasure_coding_policy(
+        &self,
+        path: &str,
+    ) -> CuddlyResult<Option<ErasureCodingPolicy>> {
+        self.fs_directory
+            .read()
+            .unwrap()
+            .erasure_coding_policy(path)
+    }
+
+    /// Replicas each block should have, including the blocks being written.
+    fn block_replication(&self) -> HashMap<Uuid, u64> {
+        let mut replication = self.fs_directory.read().unwrap().block_replication();
+        replication.extend(
+            self.namenode_progress_tracker
+                .read()
+                .unwrap()
+                .block_replication(),
+        );
+        replication
     }
 
     pub(crate) fn abort_block(&self, path: &str, block: &Block) -> CuddlyResult<()> {
@@ -501,3 +2810,381 @@ impl DataRegistry {
         Ok(())
     }
 }
+
+/// Replicas a block should have according to `replication`, or the cluster
+/// default for blocks no file refers to.
+fn expected_replicas(replication: &HashMap<Uuid, u64>, block_id: &Uuid) -> usize {
+    replication
+        .get(block_id)
+        .copied()
+        .unwrap_or(APP_CONFIG.replication_factor) as usize
+}
+
+fn check_replication(replication: u64) -> CuddlyResult<()> {
+    let (min, max) = (
+        APP_CONFIG.namenode.min_replication,
+        APP_CONFIG.namenode.max_replication,
+    );
+    if !(min..=max).contains(&replication) {
+        return Err(CuddlyError::FSError(format!(
+            "Replication {} is not between {} and {}",
+            replication, min, max
+        )));
+    }
+    Ok(())
+}
+
+fn check_block_size(block_size: u64) -> CuddlyResult<()> {
+    let (min, max) = (
+        APP_CONFIG.namenode.min_block_size,
+        APP_CONFIG.namenode.max_block_size,
+    );
+    if !(min..=max).contains(&block_size) {
+        return Err(CuddlyError::FSError(format!(
+            "Block size {} is not between {} and {}",
+            block_size, min, max
+        )));
+    }
+    Ok(())
+}
+
+fn check_client_xattr(name: &str) -> CuddlyResult<()> {
+    if name.starts_with("system.") {
+        return Err(CuddlyError::FSError(format!(
+            "'{}': The system namespace is reserved",
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn unknown_datanode(datanode: &str) -> CuddlyError {
+    CuddlyError::FSError(format!("'{}': No such datanode", datanode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    use crate::config::{AppConfig, NamenodeConfig};
+
+    fn config(name_dir: &Path) -> AppConfig {
+        AppConfig {
+            namenode: NamenodeConfig {
+                name_dir: name_dir.to_path_buf(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    // A data registry out of safe mode, logging to `name_dir`
+    fn open_data_registry(name_dir: &Path) -> DataRegistry {
+        let data_registry = DataRegistry::with_operation_logger(
+            CancellationToken::new(),
+            OperationLogger::open(&config(name_dir)).unwrap(),
+        )
+        .unwrap();
+        data_registry.safe_mode.leave();
+        data_registry
+    }
+
+    fn data_registry() -> (DataRegistry, PathBuf) {
+        let name_dir = std::env::temp_dir()
+            .join("cuddlyfs")
+            .join(format!("name-{}", Uuid::new_v4()));
+        (open_data_registry(&name_dir), name_dir)
+    }
+
+    // Registers a datanode with an empty disk of 1 GiB, or sends its next
+    // heartbeat, and returns the commands it gets
+    fn heartbeat(
+        data_registry: &DataRegistry,
+        uuid: Uuid,
+        address: &str,
+    ) -> Vec<cuddlyproto::DatanodeCommandProto> {
+        let registration = cuddlyproto::DatanodeRegistrationProto {
+            datanode_id: Some(cuddlyproto::DatanodeIdProto {
+                socket_addr: address.to_owned(),
+                datanode_uuid: uuid.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let report = cuddlyproto::StorageReportProto {
+            capacity: 1 << 30,
+            remaining: 1 << 30,
+            ..Default::default()
+        };
+        data_registry
+            .handle_heartbeat(registration, vec![report])
+            .commands
+    }
+
+    fn deleted_blocks(commands: &[cuddlyproto::DatanodeCommandProto]) -> Vec<Block> {
+        commands
+            .iter()
+            .filter(|command| command.command_type == CommandType::DeleteBlock as i32)
+            .filter_map(|command| command.block.clone())
+            .map(Block::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_retry_create_without_datanodes() {
+        let (data_registry, name_dir) = data_registry();
+        // Without datanodes no block can be placed, and the file is not kept
+        // open
+        for _ in 0..2 {
+            assert!(data_registry
+                .start_file_create("/a", None, None, None)
+                .unwrap()
+                .is_none());
+        }
+        assert!(!data_registry
+            .namenode_progress_tracker
+            .read()
+            .unwrap()
+            .is_open("/a"));
+        std::fs::remove_dir_all(name_dir).unwrap();
+    }
+
+    #[test]
+    fn test_create_erasure_coded_file_ignores_replication() {
+        let (data_registry, name_dir) = data_registry();
+        data_registry.non_logging_make_dir("/ec").unwrap();
+        data_registry
+            .non_logging_set_erasure_coding_policy("/ec", Some(ErasureCodingPolicy::Rs3_2))
+            .unwrap();
+        assert!(data_registry
+            .start_file_create("/a", None, Some(0), None)
+            .is_err());
+        // Files below the directory get no replicas, so no replication is
+        // out of range
+        assert!(data_registry
+            .start_file_create("/ec/a", None, Some(0), None)
+            .unwrap()
+            .is_none());
+        std::fs::remove_dir_all(name_dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_replicas() {
+        let (data_registry, name_dir) = data_registry();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        heartbeat(&data_registry, first, "127.0.0.1:50010");
+        heartbeat(&data_registry, second, "127.0.0.2:50010");
+        let block = Block {
+            generation: 5,
+            ..Block::new(Uuid::new_v4(), 10, 0)
+        };
+        let layout = FileLayout {
+            replication: 1,
+            block_size: APP_CONFIG.block_size,
+            erasure_coding_policy: None,
+        };
+        data_registry
+            .non_logging_finish_file("/a", &[block], layout)
+            .unwrap();
+        let stale = Block {
+            generation: 4,
+            ..block
+        };
+
+        // A received replica that missed an append is deleted right away
+        assert!(data_registry
+            .block_received("127.0.0.1:50010", &stale, StorageType::Disk)
+            .is_err());
+        assert_eq!(
+            deleted_blocks(&heartbeat(&data_registry, first, "127.0.0.1:50010")),
+            vec![stale]
+        );
+
+        // A reported one once the block has a replica of its generation
+        data_registry
+            .block_report("127.0.0.2:50010", &[(stale, StorageType::Disk)], &[])
+            .unwrap();
+        assert!(data_registry.open_file("/a", None).unwrap()[0].1.is_empty());
+        assert!(deleted_blocks(&heartbeat(&data_registry, second, "127.0.0.2:50010")).is_empty());
+        data_registry
+            .block_received("127.0.0.1:50010", &block, StorageType::Disk)
+            .unwrap();
+        let holders = &data_registry.open_file("/a", None).unwrap()[0].1;
+        assert_eq!(holders.len(), 1);
+        assert_eq!(holders[0].datanode_uuid, first);
+        assert_eq!(
+            deleted_blocks(&heartbeat(&data_registry, second, "127.0.0.2:50010")),
+            vec![block]
+        );
+        std::fs::remove_dir_all(name_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_generations_after_replay() {
+        let name_dir = std::env::temp_dir()
+            .join("cuddlyfs")
+            .join(format!("name-{}", Uuid::new_v4()));
+        // Generations ahead of the clock the next ones are seeded from
+        let generation = Utc::now().timestamp_millis() as u64 + 1_000_000;
+        let mut operation_logger = OperationLogger::open(&config(&name_dir)).unwrap();
+        for op in [
+            EditOperation::AddFile(
+                "/a".to_owned(),
+                vec![Block {
+                    generation,
+                    ..Block::new(Uuid::new_v4(), 10, 0)
+                }],
+                1,
+                APP_CONFIG.block_size,
+            ),
+            EditOperation::Truncate("/a".to_owned(), 5, generation + 10),
+        ] {
+            operation_logger.log_operation(&op).await;
+        }
+        drop(operation_logger);
+
+        let data_registry = open_data_registry(&name_dir);
+        data_registry.restore().await.unwrap();
+        let layout = FileLayout {
+            replication: 1,
+            block_size: APP_CONFIG.block_size,
+            erasure_coding_policy: None,
+        };
+        data_registry
+            .namenode_progress_tracker
+            .write()
+            .unwrap()
+            .add_file("/b".to_owned(), layout)
+            .unwrap();
+        assert!(data_registry.new_block("/b").unwrap().generation > generation + 10);
+        std::fs::remove_dir_all(name_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry_failed_lease_recovery() {
+        let (data_registry, name_dir) = data_registry();
+        let datanode = Uuid::new_v4();
+        heartbeat(&data_registry, datanode, "127.0.0.1:50010");
+        let layout = FileLayout {
+            replication: 1,
+            block_size: APP_CONFIG.block_size,
+            erasure_coding_policy: None,
+        };
+        data_registry
+            .namenode_progress_tracker
+            .write()
+            .unwrap()
+            .add_file("/a".to_owned(), layout)
+            .unwrap();
+        let block = Block {
+            len: 10,
+            ..data_registry.new_block("/a").unwrap()
+        };
+        data_registry
+            .block_received("127.0.0.1:50010", &block, StorageType::Disk)
+            .unwrap();
+        let expired = Utc::now() + chrono::Duration::seconds(LEASE_HARD_LIMIT + 1);
+
+        // The file cannot be created while a directory takes its path, its
+        // received blocks are kept until it can
+        data_registry.non_logging_make_dir("/a").unwrap();
+        data_registry.recover_expired_leases(expired).await;
+        assert!(data_registry
+            .namenode_progress_tracker
+            .read()
+            .unwrap()
+            .is_open("/a"));
+
+        data_registry.non_logging_delete("/a").unwrap();
+        data_registry.recover_expired_leases(expired).await;
+        assert!(!data_registry
+            .namenode_progress_tracker
+            .read()
+            .unwrap()
+            .is_open("/a"));
+        assert_eq!(data_registry.open_file("/a", None).unwrap()[0].0.len, 10);
+        std::fs::remove_dir_all(name_dir).unwrap();
+    }
+
+    #[test]
+    fn test_decommission() {
+        let (data_registry, name_dir) = data_registry();
+        let nodes = [
+            (Uuid::new_v4(), "127.0.0.1:50010"),
+            (Uuid::new_v4(), "127.0.0.2:50010"),
+            (Uuid::new_v4(), "127.0.0.3:50010"),
+        ];
+        for (uuid, address) in nodes {
+            heartbeat(&data_registry, uuid, address);
+        }
+        let block = Block::new(Uuid::new_v4(), 10, 0);
+        let layout = FileLayout {
+            replication: 2,
+            block_size: APP_CONFIG.block_size,
+            erasure_coding_policy: None,
+        };
+        data_registry
+            .non_logging_finish_file("/a", &[block], layout)
+            .unwrap();
+        for (_, address) in &nodes[..2] {
+            data_registry
+                .block_received(address, &block, StorageType::Disk)
+                .unwrap();
+        }
+        let state = |uuid: Uuid| data_registry.datanode_manager.get(&uuid).unwrap().state;
+
+        let (decommissioned, _) = nodes[0];
+        data_registry
+            .decommission_datanode(&decommissioned.to_string())
+            .unwrap();
+        assert_eq!(state(decommissioned), DatanodeState::DecommissionInProgress);
+        assert!(data_registry
+            .under_replicated_blocks
+            .lock()
+            .unwrap()
+            .contains(&block.id));
+
+        // New blocks go to the other datanodes only
+        assert!(data_registry
+            .start_file_create("/b", None, Some(3), None)
+            .unwrap()
+            .is_none());
+        let Some(Allocation::Replicated(_, targets)) = data_registry
+            .start_file_create("/c", None, Some(2), None)
+            .unwrap()
+        else {
+            panic!("No block allocated");
+        };
+        let mut targets = targets
+            .iter()
+            .map(|(node, _)| node.datanode_uuid)
+            .collect::<Vec<_>>();
+        targets.sort();
+        let mut others = vec![nodes[1].0, nodes[2].0];
+        others.sort();
+        assert_eq!(targets, others);
+
+        // Its replica no longer counts, so the block is copied to the
+        // datanode that does not hold it
+        data_registry.check_decommissions();
+        assert_eq!(state(decommissioned), DatanodeState::DecommissionInProgress);
+        data_registry.schedule_replications();
+        let replications = nodes[..2]
+            .iter()
+            .flat_map(|(uuid, address)| heartbeat(&data_registry, *uuid, address))
+            .filter(|command| command.command_type == CommandType::ReplicateBlock as i32)
+            .collect::<Vec<_>>();
+        assert_eq!(replications.len(), 1);
+        assert_eq!(replications[0].block.clone().map(Block::from), Some(block));
+        assert_eq!(replications[0].targets, vec![nodes[2].1.to_owned()]);
+
+        // It is decommissioned once the block is fully replicated elsewhere
+        data_registry
+            .block_received(nodes[2].1, &block, StorageType::Disk)
+            .unwrap();
+        data_registry.check_decommissions();
+        assert_eq!(state(decommissioned), DatanodeState::Decommissioned);
+        std::fs::remove_dir_all(name_dir).unwrap();
+    }
+}
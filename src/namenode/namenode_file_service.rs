@@ -4,16 +4,30 @@ use log::{debug, info};
 use tonic::{Request, Response, Status};
 
 use crate::{
+    block::Block,
     cuddlyproto::{
         self, file_service_server::FileService, AbortBlockWriteRequest, AddBlockRequest,
-        AddBlockResponse, CreateDirectoryRequest, CreateDirectoryResponse, CreateFileRequest,
-        CreateFileResponse, ListDirectoryRequest, ListDirectoryResponse, OpenFileRequest,
-        OpenFileResponse, ReportDatanodesRequest, ReportDatanodesResponse, StatusCode,
+        AddBlockResponse, AppendRequest, AppendResponse, ConcatRequest, ContentSummaryRequest,
+        ContentSummaryResponse, CreateDirectoryRequest, CreateDirectoryResponse, CreateFileRequest,
+        CreateFileResponse, CreateSymlinkRequest, DeleteRequest, DeleteResponse, ExpungeRequest,
+        ExpungeResponse, GetErasureCodingPolicyRequest, GetErasureCodingPolicyResponse,
+        GetStoragePolicyRequest, GetStoragePolicyResponse, GetXAttrsRequest, GetXAttrsResponse,
+        ListDirectoryRequest, ListDirectoryResponse, ListSnapshotsRequest, ListSnapshotsResponse,
+        ListXAttrsRequest, ListXAttrsResponse, OpenFileRequest, OpenFileResponse,
+        RemoveXAttrRequest, ReportDatanodesRequest, ReportDatanodesResponse, RestoreRequest,
+        RestoreResponse, SetErasureCodingPolicyRequest, SetReplicationRequest,
+        SetStoragePolicyRequest, SetXAttrRequest, SnapshotDiffRequest, SnapshotDiffResponse,
+        SnapshotRequest, StatRequest, StatResponse, StatusCode, TruncateRequest,
     },
     errors::CuddlyError,
 };
 
-use super::namenode_data_registry::DataRegistry;
+use super::{
+    erasure_coding_policy::ErasureCodingPolicy,
+    namenode_data_registry::{Allocation, DataRegistry, Target},
+    namenode_state::{DiffType, FileType},
+    storage_policy::StoragePolicy,
+};
 
 pub struct NamenodeFileService {
     data_registry: Arc<DataRegistry>,
@@ -25,6 +39,42 @@ impl NamenodeFileService {
     }
 }
 
+fn block_with_targets(block: Block, targets: Vec<Target>) -> cuddlyproto::BlockWithTargets {
+    let (targets, storage_types): (Vec<_>, Vec<_>) = targets
+        .into_iter()
+        .map(|(node, storage_type)| (node.into(), storage_type.to_proto()))
+        .unzip();
+    cuddlyproto::BlockWithTargets {
+        block: Some(block.into()),
+        targets,
+        storage_types,
+    }
+}
+
+/// Splits an allocation into the block of a replicated file or the group of
+/// an erasure-coded one, as sent to the writer.
+fn allocation_to_proto(
+    allocation: Allocation,
+) -> (
+    Option<cuddlyproto::BlockWithTargets>,
+    Option<cuddlyproto::BlockGroupWithTargets>,
+) {
+    match allocation {
+        Allocation::Replicated(block, targets) => (Some(block_with_targets(block, targets)), None),
+        Allocation::Striped(policy, cell_size, blocks) => (
+            None,
+            Some(cuddlyproto::BlockGroupWithTargets {
+                policy: policy.to_string(),
+                cell_size,
+                blocks: blocks
+                    .into_iter()
+                    .map(|(block, target)| block_with_targets(block, vec![target]))
+                    .collect(),
+            }),
+        ),
+    }
+}
+
 #[tonic::async_trait]
 impl FileService for NamenodeFileService {
     async fn report_datanodes(
@@ -33,7 +83,7 @@ impl FileService for NamenodeFileService {
     ) -> Result<tonic::Response<ReportDatanodesResponse>, tonic::Status> {
         match self.data_registry.report_datanodes() {
             Ok(dat) => Ok(tonic::Response::new(ReportDatanodesResponse {
-                datanodes: dat.iter().map(|d| d.clone().into()).collect(),
+                datanodes: dat.iter().map(|d| (*d).into()).collect(),
             })),
             Err(e) => Err(tonic::Status::internal(e.to_string())),
         }
@@ -60,7 +110,10 @@ impl FileService for NamenodeFileService {
             Err(e) => Ok(tonic::Response::new(CreateDirectoryResponse {
                 status: Some(StatusCode {
                     success: false,
-                    code: 1,
+                    code: match e {
+                        CuddlyError::QuotaExceeded(_) => cuddlyproto::StatusEnum::EDquot as i32,
+                        _ => 1,
+                    },
                     message: e.to_string(),
                 }),
             })),
@@ -97,13 +150,16 @@ impl FileService for NamenodeFileService {
         &self,
         request: Request<OpenFileRequest>,
     ) -> Result<Response<OpenFileResponse>, Status> {
+        let reader_location = request.remote_addr().map(|addr| addr.ip());
         let request = request.into_inner();
         info!("Received request to open file: {:?}", request);
-        let blocks_with_locations = self.data_registry.open_file(&request.file_path);
+        let blocks_with_locations = self
+            .data_registry
+            .open_file(&request.file_path, reader_location);
 
         match blocks_with_locations {
             Ok(blocks_with_locations) => {
-                let res = blocks_with_locations
+                let mut res = blocks_with_locations
                     .into_iter()
                     .map(|(block, locations)| cuddlyproto::BlockWithLocations {
                         block: Some(block.into()),
@@ -112,11 +168,28 @@ impl FileService for NamenodeFileService {
                             .map(|location| location.socket_address.to_string())
                             .collect(),
                     })
-                    .collect();
+                    .collect::<Vec<_>>();
+                // The blocks of an erasure-coded file are read by group
+                let status = self
+                    .data_registry
+                    .stat(&request.file_path, true)
+                    .map_err(|err| Status::invalid_argument(err.to_string()))?;
+                let block_groups = match status.erasure_coding_policy {
+                    Some(policy) => std::mem::take(&mut res)
+                        .chunks(policy.group_width())
+                        .map(|blocks| cuddlyproto::BlockGroupProto {
+                            policy: policy.to_string(),
+                            cell_size: ErasureCodingPolicy::cell_size(status.block_size),
+                            blocks: blocks.to_vec(),
+                        })
+                        .collect(),
+                    None => vec![],
+                };
 
                 debug!("Returning file open response with blocks: {:?}", res);
                 Ok(Response::new(OpenFileResponse {
                     blocks_with_locations: res,
+                    block_groups,
                     status: Some(cuddlyproto::StatusCode {
                         success: true,
                         code: cuddlyproto::StatusEnum::Ok as i32,
@@ -133,23 +206,31 @@ impl FileService for NamenodeFileService {
         request: Request<CreateFileRequest>,
     ) -> Result<Response<CreateFileResponse>, Status> {
         debug!("Received request to create file: {:?}", request);
+        let writer_location = request.remote_addr().map(|addr| addr.ip());
         let request = request.into_inner();
-        let res = self.data_registry.start_file_create(&request.file_path);
+        let res = self.data_registry.start_file_create(
+            &request.file_path,
+            writer_location,
+            Some(request.replication).filter(|replication| *replication > 0),
+            Some(request.block_size).filter(|block_size| *block_size > 0),
+        );
         match res {
-            Ok(Some((block, targets))) => {
-                let block = Some(block.into());
-                let targets = targets.into_iter().map(|info| info.into()).collect();
+            Ok(Some(allocation)) => {
+                let (block_with_targets, block_group) = allocation_to_proto(allocation);
                 info!(
-                    "Returning file create response with Block: {:?}, Targets: {:?}",
-                    block, targets
+                    "Returning file create response with Block: {:?}, Block group: {:?}",
+                    block_with_targets, block_group
                 );
                 Ok(Response::new(CreateFileResponse {
-                    block_with_targets: Some(cuddlyproto::BlockWithTargets { block, targets }),
+                    block_with_targets,
+                    block_group,
                 }))
             }
             Ok(None) => Err(Status::failed_precondition(
                 "Cannot create file, not enough avaialable datanodes with free space",
             )),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(CuddlyError::QuotaExceeded(err)) => Err(Status::resource_exhausted(err)),
             Err(err) => Err(Status::invalid_argument(err.to_string())),
         }
     }
@@ -170,6 +251,7 @@ impl FileService for NamenodeFileService {
                 message: "File created successfully".to_string(),
             })),
             Err(CuddlyError::WaitingForReplication(err)) => Err(Status::unavailable(err)),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
             Err(err) => Err(Status::invalid_argument(err.to_string())),
         }
     }
@@ -193,27 +275,27 @@ impl FileService for NamenodeFileService {
         &self,
         request: Request<AddBlockRequest>,
     ) -> Result<Response<AddBlockResponse>, Status> {
+        let writer_location = request.remote_addr().map(|addr| addr.ip());
         let request = request.into_inner();
 
-        let res = self.data_registry.start_another_block(&request.path);
+        let res = self
+            .data_registry
+            .start_another_block(&request.path, writer_location);
 
         match res {
-            Ok(Some((block, targets))) => {
-                let block: cuddlyproto::Block = block.into();
-                let targets: Vec<cuddlyproto::DatanodeInfo> =
-                    targets.into_iter().map(|info| info.into()).collect();
-
+            Ok(Some(allocation)) => {
+                let (block_with_targets, block_group) = allocation_to_proto(allocation);
                 Ok(Response::new(AddBlockResponse {
-                    block_with_targets: Some(cuddlyproto::BlockWithTargets {
-                        block: Some(block),
-                        targets,
-                    }),
+                    block_with_targets,
+                    block_group,
                 }))
             }
             Ok(None) => Err(Status::failed_precondition(
                 "Unable to create another block: insufficient available datanodes with free space",
             )),
             Err(CuddlyError::WaitingForReplication(err)) => Err(Status::unavailable(err)),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(CuddlyError::QuotaExceeded(err)) => Err(Status::resource_exhausted(err)),
             Err(err) => Err(Status::invalid_argument(err.to_string())),
         }
     }
@@ -233,4 +315,472 @@ impl FileService for NamenodeFileService {
             Err(err) => Err(Status::invalid_argument(err.to_string())),
         }
     }
+    async fn append(
+        &self,
+        request: Request<AppendRequest>,
+    ) -> Result<Response<AppendResponse>, Status> {
+        let writer_location = request.remote_addr().map(|addr| addr.ip());
+        let request = request.into_inner();
+        info!("Received request to append to file: {:?}", request);
+
+        match self
+            .data_registry
+            .append_file(&request.file_path, writer_location)
+        {
+            Ok(Some((block, targets, generation))) => {
+                // The file is leased to this writer, so it is still there
+                let block_size = self
+                    .data_registry
+                    .stat(&request.file_path, true)
+                    .map_err(|err| Status::internal(err.to_string()))?
+                    .block_size;
+                Ok(Response::new(AppendResponse {
+                    block_with_targets: Some(block_with_targets(block, targets)),
+                    reopened: generation.is_some(),
+                    generation: generation.unwrap_or_default(),
+                    block_size,
+                }))
+            }
+            Ok(None) => Err(Status::failed_precondition(
+                "Cannot append to file, not enough available datanodes with free space",
+            )),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(CuddlyError::QuotaExceeded(err)) => Err(Status::resource_exhausted(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+    async fn truncate(
+        &self,
+        request: Request<TruncateRequest>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let request = request.into_inner();
+        info!("Received request to truncate file: {:?}", request);
+        match self
+            .data_registry
+            .truncate(&request.file_path, request.new_length)
+            .await
+        {
+            Ok(()) => Ok(Response::new(StatusCode {
+                success: true,
+                code: cuddlyproto::StatusEnum::Ok as i32,
+                message: "File truncated successfully".to_string(),
+            })),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn concat(
+        &self,
+        request: Request<ConcatRequest>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let request = request.into_inner();
+        info!("Received request to concatenate files: {:?}", request);
+        match self
+            .data_registry
+            .concat(&request.target, &request.sources)
+            .await
+        {
+            Ok(()) => Ok(Response::new(StatusCode {
+                success: true,
+                code: cuddlyproto::StatusEnum::Ok as i32,
+                message: "Files concatenated successfully".to_string(),
+            })),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+    async fn content_summary(
+        &self,
+        request: Request<ContentSummaryRequest>,
+    ) -> Result<Response<ContentSummaryResponse>, Status> {
+        let request = request.into_inner();
+        match self.data_registry.content_summary(&request.path) {
+            Ok(summary) => Ok(Response::new(ContentSummaryResponse {
+                directories: summary.directories,
+                files: summary.files,
+                length: summary.length,
+                space_consumed: summary.space_consumed,
+                namespace_quota: summary.quota.namespace.unwrap_or_default(),
+                space_quota: summary.quota.space.unwrap_or_default(),
+            })),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn create_snapshot(
+        &self,
+        request: Request<SnapshotRequest>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let request = request.into_inner();
+        info!("Received request to create snapshot: {:?}", request);
+        match self
+            .data_registry
+            .create_snapshot(&request.path, &request.name)
+            .await
+        {
+            Ok(()) => Ok(Response::new(StatusCode {
+                success: true,
+                code: cuddlyproto::StatusEnum::Ok as i32,
+                message: "Snapshot created successfully".to_string(),
+            })),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn delete_snapshot(
+        &self,
+        request: Request<SnapshotRequest>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let request = request.into_inner();
+        info!("Received request to delete snapshot: {:?}", request);
+        match self
+            .data_registry
+            .delete_snapshot(&request.path, &request.name)
+            .await
+        {
+            Ok(()) => Ok(Response::new(StatusCode {
+                success: true,
+                code: cuddlyproto::StatusEnum::Ok as i32,
+                message: "Snapshot deleted successfully".to_string(),
+            })),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn list_snapshots(
+        &self,
+        request: Request<ListSnapshotsRequest>,
+    ) -> Result<Response<ListSnapshotsResponse>, Status> {
+        let request = request.into_inner();
+        match self.data_registry.list_snapshots(&request.path) {
+            Ok(names) => Ok(Response::new(ListSnapshotsResponse { names })),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn snapshot_diff(
+        &self,
+        request: Request<SnapshotDiffRequest>,
+    ) -> Result<Response<SnapshotDiffResponse>, Status> {
+        use cuddlyproto::snapshot_diff_entry::DiffType as ProtoDiffType;
+
+        let request = request.into_inner();
+        let to = Some(request.to.as_str()).filter(|to| !to.is_empty());
+        match self
+            .data_registry
+            .snapshot_diff(&request.path, &request.from, to)
+        {
+            Ok(changes) => Ok(Response::new(SnapshotDiffResponse {
+                entries: changes
+                    .into_iter()
+                    .map(|(diff_type, path)| {
+                        let diff_type = match diff_type {
+                            DiffType::Created => ProtoDiffType::Created,
+                            DiffType::Deleted => ProtoDiffType::Deleted,
+                            DiffType::Modified => ProtoDiffType::Modified,
+                        };
+                        cuddlyproto::SnapshotDiffEntry {
+                            diff_type: diff_type as i32,
+                            path,
+                        }
+                    })
+                    .collect(),
+            })),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let request = request.into_inner();
+        info!("Received delete request: {:?}", request);
+        let user =
+            Some(request.user.as_str()).filter(|user| !request.skip_trash && !user.is_empty());
+        match self
+            .data_registry
+            .delete_to_trash(&request.path, request.recursive, !request.no_follow, user)
+            .await
+        {
+            Ok(trash_path) => Ok(Response::new(DeleteResponse {
+                trash_path: trash_path.unwrap_or_default(),
+            })),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(CuddlyError::QuotaExceeded(err)) => Err(Status::resource_exhausted(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn expunge(
+        &self,
+        request: Request<ExpungeRequest>,
+    ) -> Result<Response<ExpungeResponse>, Status> {
+        let request = request.into_inner();
+        info!("Received expunge request: {:?}", request);
+        if request.user.is_empty() {
+            return Err(Status::invalid_argument("User required"));
+        }
+        match self
+            .data_registry
+            .expunge(Some(&request.user), request.immediate)
+            .await
+        {
+            Ok(deleted) => Ok(Response::new(ExpungeResponse { deleted })),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn restore(
+        &self,
+        request: Request<RestoreRequest>,
+    ) -> Result<Response<RestoreResponse>, Status> {
+        let request = request.into_inner();
+        info!("Received restore request: {:?}", request);
+        let destination = Some(request.destination.as_str()).filter(|dst| !dst.is_empty());
+        match self
+            .data_registry
+            .restore_from_trash(&request.trash_path, destination)
+            .await
+        {
+            Ok(path) => Ok(Response::new(RestoreResponse { path })),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(CuddlyError::QuotaExceeded(err)) => Err(Status::resource_exhausted(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn create_symlink(
+        &self,
+        request: Request<CreateSymlinkRequest>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let request = request.into_inner();
+        info!("Received request to create symbolic link: {:?}", request);
+        match self
+            .data_registry
+            .create_symlink(&request.path, &request.target, request.overwrite)
+            .await
+        {
+            Ok(()) => Ok(Response::new(StatusCode {
+                success: true,
+                code: cuddlyproto::StatusEnum::Ok as i32,
+                message: "Symbolic link created successfully".to_string(),
+            })),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(CuddlyError::QuotaExceeded(err)) => Err(Status::resource_exhausted(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn stat(&self, request: Request<StatRequest>) -> Result<Response<StatResponse>, Status> {
+        use cuddlyproto::stat_response::FileType as ProtoFileType;
+
+        let request = request.into_inner();
+        match self.data_registry.stat(&request.path, !request.no_follow) {
+            Ok(status) => {
+                let file_type = match status.file_type {
+                    FileType::File => ProtoFileType::File,
+                    FileType::Directory => ProtoFileType::Directory,
+                    FileType::Symlink => ProtoFileType::Symlink,
+                };
+                Ok(Response::new(StatResponse {
+                    file_type: file_type as i32,
+                    length: status.length,
+                    symlink_target: status.symlink_target.unwrap_or_default(),
+                    replication: status.replication,
+                    block_size: status.block_size,
+                }))
+            }
+            Err(err) => Err(Status::not_found(err.to_string())),
+        }
+    }
+
+    async fn set_x_attr(
+        &self,
+        request: Request<SetXAttrRequest>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let request = request.into_inner();
+        let xattr = request
+            .xattr
+            .ok_or_else(|| Status::invalid_argument("Attribute required"))?;
+        info!(
+            "Received request to set attribute '{}' of '{}'",
+            xattr.name, request.path
+        );
+        match self
+            .data_registry
+            .set_xattr(&request.path, &xattr.name, &xattr.value)
+            .await
+        {
+            Ok(()) => Ok(Response::new(StatusCode {
+                success: true,
+                code: cuddlyproto::StatusEnum::Ok as i32,
+                message: "Attribute set successfully".to_string(),
+            })),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn get_x_attrs(
+        &self,
+        request: Request<GetXAttrsRequest>,
+    ) -> Result<Response<GetXAttrsResponse>, Status> {
+        let request = request.into_inner();
+        match self.data_registry.get_xattrs(&request.path, &request.names) {
+            Ok(xattrs) => Ok(Response::new(GetXAttrsResponse {
+                xattrs: xattrs
+                    .into_iter()
+                    .map(|(name, value)| cuddlyproto::XAttr {
+                        name,
+                        value: value.into(),
+                    })
+                    .collect(),
+            })),
+            Err(err) => Err(Status::not_found(err.to_string())),
+        }
+    }
+
+    async fn list_x_attrs(
+        &self,
+        request: Request<ListXAttrsRequest>,
+    ) -> Result<Response<ListXAttrsResponse>, Status> {
+        let request = request.into_inner();
+        match self.data_registry.list_xattrs(&request.path) {
+            Ok(names) => Ok(Response::new(ListXAttrsResponse { names })),
+            Err(err) => Err(Status::not_found(err.to_string())),
+        }
+    }
+
+    async fn remove_x_attr(
+        &self,
+        request: Request<RemoveXAttrRequest>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let request = request.into_inner();
+        info!("Received request to remove attribute: {:?}", request);
+        match self
+            .data_registry
+            .remove_xattr(&request.path, &request.name)
+            .await
+        {
+            Ok(()) => Ok(Response::new(StatusCode {
+                success: true,
+                code: cuddlyproto::StatusEnum::Ok as i32,
+                message: "Attribute removed successfully".to_string(),
+            })),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn set_replication(
+        &self,
+        request: Request<SetReplicationRequest>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let request = request.into_inner();
+        info!("Received request to set replication: {:?}", request);
+        match self
+            .data_registry
+            .set_replication(&request.path, request.replication)
+            .await
+        {
+            Ok(()) => Ok(Response::new(StatusCode {
+                success: true,
+                code: cuddlyproto::StatusEnum::Ok as i32,
+                message: "Replication set successfully".to_string(),
+            })),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(CuddlyError::QuotaExceeded(err)) => Err(Status::resource_exhausted(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn set_storage_policy(
+        &self,
+        request: Request<SetStoragePolicyRequest>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let request = request.into_inner();
+        info!("Received request to set storage policy: {:?}", request);
+        let policy = match request.policy.as_str() {
+            "" => None,
+            name => Some(
+                name.parse::<StoragePolicy>()
+                    .map_err(|err| Status::invalid_argument(err.to_string()))?,
+            ),
+        };
+        match self
+            .data_registry
+            .set_storage_policy(&request.path, policy)
+            .await
+        {
+            Ok(()) => Ok(Response::new(StatusCode {
+                success: true,
+                code: cuddlyproto::StatusEnum::Ok as i32,
+                message: "Storage policy set successfully".to_string(),
+            })),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn get_storage_policy(
+        &self,
+        request: Request<GetStoragePolicyRequest>,
+    ) -> Result<Response<GetStoragePolicyResponse>, Status> {
+        let request = request.into_inner();
+        match self.data_registry.storage_policy(&request.path) {
+            Ok(policy) => Ok(Response::new(GetStoragePolicyResponse {
+                policy: policy.to_string(),
+            })),
+            Err(err) => Err(Status::not_found(err.to_string())),
+        }
+    }
+
+    async fn set_erasure_coding_policy(
+        &self,
+        request: Request<SetErasureCodingPolicyRequest>,
+    ) -> Result<Response<StatusCode>, Status> {
+        let request = request.into_inner();
+        info!(
+            "Received request to set erasure coding policy: {:?}",
+            request
+        );
+        let policy = match request.policy.as_str() {
+            "" => None,
+            name => Some(
+                name.parse::<ErasureCodingPolicy>()
+                    .map_err(|err| Status::invalid_argument(err.to_string()))?,
+            ),
+        };
+        match self
+            .data_registry
+            .set_erasure_coding_policy(&request.path, policy)
+            .await
+        {
+            Ok(()) => Ok(Response::new(StatusCode {
+                success: true,
+                code: cuddlyproto::StatusEnum::Ok as i32,
+                message: "Erasure coding policy set successfully".to_string(),
+            })),
+            Err(CuddlyError::SafeModeError(err)) => Err(Status::unavailable(err)),
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn get_erasure_coding_policy(
+        &self,
+        request: Request<GetErasureCodingPolicyRequest>,
+    ) -> Result<Response<GetErasureCodingPolicyResponse>, Status> {
+        let request = request.into_inner();
+        match self.data_registry.erasure_coding_policy(&request.path) {
+            Ok(policy) => Ok(Response::new(GetErasureCodingPolicyResponse {
+                policy: policy.map(|policy| policy.to_string()).unwrap_or_default(),
+            })),
+            Err(err) => Err(Status::not_found(err.to_string())),
+        }
+    }
 }
@@ -1,23 +1,40 @@
 use std::sync::Arc;
 
+use log::debug;
 use tonic::{Request, Response};
+use uuid::Uuid;
 
-use super::namenode_data_registry::DataRegistry;
+use super::{namenode_data_registry::DataRegistry, storage_policy::StorageType};
 use crate::{
     block::Block,
     cuddlyproto::{
         node_service_server::NodeService, BlockReceivedRequest, BlockReceivedResponse,
+        BlockReportRequest, BlockReportResponse, BlocksLostRequest, BlocksLostResponse,
         HeartbeatRequest, HeartbeatResponse, StatusCode, StatusEnum,
     },
+    utils::fault_injection::{FaultAction, FaultInjector, FaultPoint},
 };
 
 pub struct NamenodeNodeService {
     data_registry: Arc<DataRegistry>,
+    fault_injector: Arc<FaultInjector>,
 }
 
 impl NamenodeNodeService {
-    pub(super) fn new(data_registry: Arc<DataRegistry>) -> Self {
-        Self { data_registry }
+    pub(super) fn new(
+        data_registry: Arc<DataRegistry>,
+        fault_injector: Arc<FaultInjector>,
+    ) -> Self {
+        Self {
+            data_registry,
+            fault_injector,
+        }
+    }
+
+    fn should_drop(&self, point: FaultPoint) -> bool {
+        self.fault_injector
+            .check(point)
+            .is_some_and(|fault| fault.action == FaultAction::Drop)
     }
 }
 
@@ -27,12 +44,27 @@ impl NodeService for NamenodeNodeService {
         &self,
         request: Request<BlockReceivedRequest>,
     ) -> Result<Response<BlockReceivedResponse>, tonic::Status> {
+        if self.should_drop(FaultPoint::NamenodeBlockReceived) {
+            debug!("Dropping BlockReceived request");
+            return Err(tonic::Status::unavailable("BlockReceived dropped"));
+        }
+
         let request: BlockReceivedRequest = request.into_inner();
-        let BlockReceivedRequest { address, block } = request;
+        let BlockReceivedRequest {
+            address,
+            block,
+            storage,
+        } = request;
         let block: Block = block.unwrap_or_default().into();
         // todo : may need something other than default
+        let storage_type = storage
+            .map(|storage| StorageType::from_proto(storage.storage_type))
+            .unwrap_or_default();
 
-        match self.data_registry.block_received(&address, &block) {
+        match self
+            .data_registry
+            .block_received(&address, &block, storage_type)
+        {
             Ok(()) => Ok(Response::new(BlockReceivedResponse {
                 status: Some(StatusCode {
                     success: true,
@@ -44,10 +76,82 @@ impl NodeService for NamenodeNodeService {
         }
     }
 
+    async fn block_report(
+        &self,
+        request: Request<BlockReportRequest>,
+    ) -> Result<Response<BlockReportResponse>, tonic::Status> {
+        let BlockReportRequest { address, reports } = request.into_inner();
+        let (mut blocks, mut being_written) = (vec![], vec![]);
+        for report in reports {
+            let storage_type = report
+                .storage
+                .map(|storage| StorageType::from_proto(storage.storage_type))
+                .unwrap_or_default();
+            blocks.extend(
+                report
+                    .blocks
+                    .into_iter()
+                    .map(|block| (Block::from(block), storage_type)),
+            );
+            being_written.extend(
+                report
+                    .being_written
+                    .into_iter()
+                    .map(|block| (Block::from(block), storage_type)),
+            );
+        }
+
+        match self
+            .data_registry
+            .block_report(&address, &blocks, &being_written)
+        {
+            Ok((complete, under_construction)) => Ok(Response::new(BlockReportResponse {
+                status: Some(StatusCode {
+                    success: true,
+                    code: StatusEnum::Ok as i32,
+                    message: "Block report received".to_string(),
+                }),
+                complete: complete.into_iter().map(Into::into).collect(),
+                under_construction: under_construction.into_iter().map(Into::into).collect(),
+            })),
+            Err(e) => Err(tonic::Status::invalid_argument(e.to_string())),
+        }
+    }
+
+    async fn blocks_lost(
+        &self,
+        request: Request<BlocksLostRequest>,
+    ) -> Result<Response<BlocksLostResponse>, tonic::Status> {
+        let BlocksLostRequest {
+            address, block_ids, ..
+        } = request.into_inner();
+        let block_ids = block_ids
+            .iter()
+            .map(|id| Uuid::parse_str(id))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+
+        match self.data_registry.blocks_lost(&address, &block_ids) {
+            Ok(()) => Ok(Response::new(BlocksLostResponse {
+                status: Some(StatusCode {
+                    success: true,
+                    code: StatusEnum::Ok as i32,
+                    message: "Lost blocks received".to_string(),
+                }),
+            })),
+            Err(e) => Err(tonic::Status::invalid_argument(e.to_string())),
+        }
+    }
+
     async fn heartbeat(
         &self,
         request: Request<HeartbeatRequest>,
     ) -> Result<Response<HeartbeatResponse>, tonic::Status> {
+        if self.should_drop(FaultPoint::NamenodeHeartbeat) {
+            debug!("Dropping heartbeat request");
+            return Err(tonic::Status::unavailable("Heartbeat dropped"));
+        }
+
         let request_data = request.into_inner();
 
         let response = self
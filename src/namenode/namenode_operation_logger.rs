@@ -1,9 +1,16 @@
 use crate::block::Block;
 use crate::config::AppConfig;
-use crate::errors::CuddlyResult;
+use crate::errors::{CuddlyError, CuddlyResult};
+
+use super::{
+    erasure_coding_policy::ErasureCodingPolicy,
+    namenode_state::{default_block_size, default_replication, Quota},
+    storage_policy::StoragePolicy,
+};
 
 use std::fs::OpenOptions;
 use std::io::SeekFrom;
+use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
@@ -16,14 +23,71 @@ use log::error;
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub enum EditOperation {
     Mkdir(String),
-    AddFile(String, Vec<Block>),
+    /// Path, blocks, replication and block size of a new file. Logs written
+    /// before files had their own layout lack the last two, and the files
+    /// get the defaults.
+    AddFile(
+        String,
+        Vec<Block>,
+        #[serde(default = "default_replication")] u64,
+        #[serde(default = "default_block_size")] u64,
+    ),
+    Delete(String),
+    Rename(String, String),
+    /// Blocks written by an append. A block replaces the file's block with
+    /// the same id, others are added at the end.
+    Append(String, Vec<Block>),
+    /// Path, new length and the new generation of the block it falls into.
+    /// Logs written before blocks had generations lack the last one, which
+    /// is then 0 like the generation of their blocks.
+    Truncate(String, u64, #[serde(default)] u64),
+    /// Target and source files of a concat
+    Concat(String, Vec<String>),
+    SetQuota(String, Quota),
+    /// Directory and name of a snapshot
+    CreateSnapshot(String, String),
+    DeleteSnapshot(String, String),
+    /// Link, target and whether an existing link is replaced
+    CreateSymlink(String, String, bool),
+    /// Path, name and value of an extended attribute
+    SetXAttr(String, String, Vec<u8>),
+    RemoveXAttr(String, String),
+    /// Path and new replication of a file
+    SetReplication(String, u64),
+    /// Path and storage policy, `None` to inherit the parent's
+    SetStoragePolicy(String, Option<StoragePolicy>),
+    /// Path, blocks, erasure coding policy and block size of a new
+    /// erasure-coded file
+    AddStripedFile(String, Vec<Block>, ErasureCodingPolicy, u64),
+    /// Directory and erasure coding policy, `None` to inherit the parent's
+    SetErasureCodingPolicy(String, Option<ErasureCodingPolicy>),
+}
+
+/// A line of the edit log. Each operation gets the transaction id after the
+/// previous one's, and logs written before ids were introduced hold bare
+/// operations.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LogEntry {
+    Logged { txid: u64, op: EditOperation },
+    Legacy(EditOperation),
 }
 
 /// OperationLogger is responsible to log all namenode modifications.
+/// A checkpoint saves an image of the namespace next to the logs and
+/// empties them. The image records the id of the last operation it
+/// contains, so operations left in the logs by an interrupted checkpoint are
+/// not applied twice.
 #[derive(Debug)]
 pub struct OperationLogger {
+    name_dir: PathBuf,
     old_log: BufStream<File>,
     new_log: BufStream<File>,
+    // Id of the last operation logged or restored
+    last_txid: u64,
+    // Id of the last operation in the loaded image, `None` for an image
+    // saved before ids were introduced
+    image_txid: Option<u64>,
 }
 
 #[allow(dead_code)]
@@ -38,6 +102,7 @@ impl OperationLogger {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(old_log)?;
 
         let new_log = config.namenode.name_dir.join("new-edits");
@@ -45,11 +110,15 @@ impl OperationLogger {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(new_log)?;
 
         Ok(Self {
+            name_dir: config.namenode.name_dir.clone(),
             old_log: BufStream::new(File::from_std(old_log)),
             new_log: BufStream::new(File::from_std(new_log)),
+            last_txid: 0,
+            image_txid: None,
         })
     }
 
@@ -57,30 +126,31 @@ impl OperationLogger {
     /// If it cannot be appended, namenode crashes completely, to prevent
     /// any inconsistencies.
     pub async fn log_operation(&mut self, op: &EditOperation) {
-        match self.non_exiting_log_operation(&op).await {
-            Err(e) => {
-                // TODO: Maybe we should panic here?
-                error!(
-                    "Failed to log operation '{:?}'.
-                    Error: {:?}.This operation is not recoverable.
-                    Exiting now.",
-                    op, e
-                );
-                std::process::exit(1);
-            }
-            Ok(()) => (),
+        if let Err(e) = self.non_exiting_log_operation(op).await {
+            // TODO: Maybe we should panic here?
+            error!(
+                "Failed to log operation '{:?}'.
+                Error: {:?}.This operation is not recoverable.
+                Exiting now.",
+                op, e
+            );
+            std::process::exit(1);
         };
     }
 
     async fn non_exiting_log_operation(&mut self, op: &EditOperation) -> CuddlyResult<()> {
-        let op = serde_json::to_string(&op)? + "\n";
-        self.new_log.write_all(op.as_bytes()).await?;
+        let txid = self.last_txid + 1;
+        let entry = serde_json::json!({ "txid": txid, "op": op });
+        let line = serde_json::to_string(&entry)? + "\n";
+        self.new_log.write_all(line.as_bytes()).await?;
         self.new_log.flush().await?;
+        self.last_txid = txid;
         Ok(())
     }
 
     /// Returns a list of EditOperations which are needed to restore
-    /// the state of the namenode.
+    /// the state of the namenode, leaving out those already in the image
+    /// returned by `load_image`.
     pub async fn restore(&mut self) -> CuddlyResult<Vec<EditOperation>> {
         self.merge().await?;
 
@@ -90,9 +160,23 @@ impl OperationLogger {
             match self.old_log.read_line(&mut buffer).await? {
                 0 => break,
                 _ => {
-                    let op: EditOperation = serde_json::from_str(&buffer)?;
-                    ops.push(op);
+                    let (txid, op) = match serde_json::from_str(&buffer)? {
+                        LogEntry::Logged { txid, op } => (Some(txid), op),
+                        LogEntry::Legacy(op) => (None, op),
+                    };
                     buffer.clear();
+                    // Bare operations predate any image with an id
+                    let applied = match (txid, self.image_txid) {
+                        (Some(txid), Some(image_txid)) => txid <= image_txid,
+                        (None, image_txid) => image_txid.is_some(),
+                        (Some(_), None) => false,
+                    };
+                    if let Some(txid) = txid {
+                        self.last_txid = self.last_txid.max(txid);
+                    }
+                    if !applied {
+                        ops.push(op);
+                    }
                 }
             }
         }
@@ -100,6 +184,60 @@ impl OperationLogger {
         Ok(ops)
     }
 
+    /// Returns the namespace image saved by the last checkpoint, if any.
+    /// The operations returned by `restore` have to be replayed on top of it.
+    pub async fn load_image(&mut self) -> CuddlyResult<Option<String>> {
+        let image = match tokio::fs::read_to_string(self.name_dir.join("fsimage")).await {
+            Ok(image) => image,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // The image starts with a `txid <id>` line
+        let Some((txid, image)) = image
+            .strip_prefix("txid ")
+            .and_then(|image| image.split_once('\n'))
+        else {
+            return Ok(Some(image));
+        };
+        let txid = txid
+            .parse::<u64>()
+            .map_err(|_| CuddlyError::FSError(format!("Invalid image txid '{}'", txid)))?;
+        self.image_txid = Some(txid);
+        self.last_txid = self.last_txid.max(txid);
+        Ok(Some(image.to_owned()))
+    }
+
+    /// Replaces the namespace image and empties both logs. The caller must
+    /// make sure no operation is logged in between taking the image and this
+    /// call. Operations left in the logs by a crash before they are emptied
+    /// are skipped by `restore`.
+    pub async fn checkpoint(&mut self, image: &str) -> CuddlyResult<()> {
+        self.save_image(image).await?;
+
+        for log in [&mut self.old_log, &mut self.new_log] {
+            log.flush().await?;
+            log.get_mut().set_len(0).await?;
+            log.get_mut().seek(SeekFrom::Start(0)).await?;
+        }
+        Ok(())
+    }
+
+    /// Atomically replaces the image with `image`, covering the operations
+    /// logged so far.
+    async fn save_image(&mut self, image: &str) -> CuddlyResult<()> {
+        let image_path = self.name_dir.join("fsimage");
+        let temp_path = self.name_dir.join("fsimage.tmp");
+        let mut temp_file = File::create(&temp_path).await?;
+        temp_file
+            .write_all(format!("txid {}\n", self.last_txid).as_bytes())
+            .await?;
+        temp_file.write_all(image.as_bytes()).await?;
+        temp_file.sync_all().await?;
+        tokio::fs::rename(&temp_path, &image_path).await?;
+        self.image_txid = Some(self.last_txid);
+        Ok(())
+    }
+
     /// Merges the new log stream into the old one. New one gets truncated.
     async fn merge(&mut self) -> CuddlyResult<()> {
         self.new_log.flush().await?;
@@ -116,3 +254,89 @@ impl OperationLogger {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NamenodeConfig;
+
+    fn config(name_dir: PathBuf) -> AppConfig {
+        AppConfig {
+            namenode: NamenodeConfig {
+                name_dir,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_checkpoint() {
+        let name_dir = std::env::temp_dir()
+            .join("cuddlyfs")
+            .join(format!("name-{}", uuid::Uuid::new_v4()));
+        let config = config(name_dir.clone());
+
+        let mut logger = OperationLogger::open(&config).unwrap();
+        logger
+            .log_operation(&EditOperation::Mkdir("/a".to_owned()))
+            .await;
+        logger
+            .log_operation(&EditOperation::Delete("/a".to_owned()))
+            .await;
+        // A crash after the image is saved leaves its operations in the logs
+        logger.save_image("{}").await.unwrap();
+        logger
+            .log_operation(&EditOperation::Mkdir("/b".to_owned()))
+            .await;
+        drop(logger);
+
+        let mut logger = OperationLogger::open(&config).unwrap();
+        assert_eq!(logger.load_image().await.unwrap().as_deref(), Some("{}"));
+        assert_eq!(
+            logger.restore().await.unwrap(),
+            vec![EditOperation::Mkdir("/b".to_owned())]
+        );
+        // Ids keep growing after a restart
+        logger
+            .log_operation(&EditOperation::Mkdir("/c".to_owned()))
+            .await;
+        logger.checkpoint("{}").await.unwrap();
+        drop(logger);
+
+        let mut logger = OperationLogger::open(&config).unwrap();
+        logger.load_image().await.unwrap();
+        assert_eq!(logger.image_txid, Some(4));
+        assert!(logger.restore().await.unwrap().is_empty());
+        std::fs::remove_dir_all(&name_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_old_log() {
+        let name_dir = std::env::temp_dir()
+            .join("cuddlyfs")
+            .join(format!("name-{}", uuid::Uuid::new_v4()));
+        let config = config(name_dir.clone());
+        drop(OperationLogger::open(&config).unwrap());
+        std::fs::write(
+            name_dir.join("edits"),
+            "{\"AddFile\":[\"/a\",[]]}\n{\"Truncate\":[\"/a\",0]}\n",
+        )
+        .unwrap();
+
+        let mut logger = OperationLogger::open(&config).unwrap();
+        assert_eq!(
+            logger.restore().await.unwrap(),
+            vec![
+                EditOperation::AddFile(
+                    "/a".to_owned(),
+                    vec![],
+                    default_replication(),
+                    default_block_size()
+                ),
+                EditOperation::Truncate("/a".to_owned(), 0, 0)
+            ]
+        );
+        std::fs::remove_dir_all(&name_dir).unwrap();
+    }
+}
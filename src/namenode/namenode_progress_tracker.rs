@@ -1,22 +1,59 @@
-use std::{collections::HashMap, sync::atomic};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::atomic,
+};
 
+use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
 use crate::errors::{CuddlyError, CuddlyResult};
 
+use super::erasure_coding_policy::ErasureCodingPolicy;
+
+/// How the blocks of a file being written are stored.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct FileLayout {
+    pub(crate) replication: u64,
+    pub(crate) block_size: u64,
+    // Set if the blocks are allocated in groups, each block stored once
+    pub(crate) erasure_coding_policy: Option<ErasureCodingPolicy>,
+}
+
+impl FileLayout {
+    /// Bytes the next block, or block group, of the file may take up.
+    pub(crate) fn allocation_space(&self) -> u64 {
+        match self.erasure_coding_policy {
+            Some(policy) => self.block_size * policy.group_width() as u64,
+            None => self.block_size * self.replication,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct NamenodeProgressTracker {
     filename_to_blocks: HashMap<String, Vec<Uuid>>,
     filename_to_block_seq: HashMap<String, atomic::AtomicU64>,
-    block_to_replication_count: HashMap<Uuid, u64>,
+    block_to_replicas: HashMap<Uuid, HashSet<Uuid>>,
+    // Generation the replicas of each block being written get
+    block_generations: HashMap<Uuid, u64>,
+    // The writer of a file holds its lease until the file is finished or
+    // aborted. Renewed whenever a block is added.
+    leases: HashMap<String, DateTime<Utc>>,
+    // Files that existed before and are being appended to
+    appending: HashSet<String>,
+    filename_to_layout: HashMap<String, FileLayout>,
 }
 
 impl NamenodeProgressTracker {
     pub(crate) fn new() -> Self {
         Self {
             filename_to_blocks: HashMap::new(),
-            block_to_replication_count: HashMap::new(),
+            block_to_replicas: HashMap::new(),
+            block_generations: HashMap::new(),
             filename_to_block_seq: HashMap::new(),
+            leases: HashMap::new(),
+            appending: HashSet::new(),
+            filename_to_layout: HashMap::new(),
         }
     }
 
@@ -31,19 +68,40 @@ impl NamenodeProgressTracker {
         }
     }
 
+    pub(crate) fn get_layout(&self, filename: &str) -> CuddlyResult<FileLayout> {
+        self.filename_to_layout
+            .get(filename)
+            .copied()
+            .ok_or_else(|| {
+                CuddlyError::FSError(format!("'{}': File creation has not started yet", filename))
+            })
+    }
+
+    /// Replicas each block being written should have.
+    pub(crate) fn block_replication(&self) -> HashMap<Uuid, u64> {
+        self.filename_to_blocks
+            .iter()
+            .flat_map(|(filename, blocks)| {
+                let replication = self.filename_to_layout[filename].replication;
+                blocks.iter().map(move |block_id| (*block_id, replication))
+            })
+            .collect()
+    }
+
     /// Returns the replication count for a given block ID.
     pub(crate) fn get_replication_count(&self, block_id: Uuid) -> u64 {
-        *self.block_to_replication_count.get(&block_id).unwrap_or(&0)
+        self.block_to_replicas
+            .get(&block_id)
+            .map_or(0, |replicas| replicas.len() as u64)
     }
-    /// Increments the replication count for the given block ID.
-    /// Increments the replication count for the given block ID.
-    pub(crate) fn increment_replication(&mut self, block_id: Uuid) {
-        let prev_count = *self.block_to_replication_count.get(&block_id).unwrap_or(&0);
-        self.block_to_replication_count
-            .insert(block_id, prev_count + 1);
+    /// Records that `datanode` holds the written replica of the given block ID.
+    pub(crate) fn add_replica(&mut self, block_id: Uuid, datanode: Uuid) {
+        if let Some(replicas) = self.block_to_replicas.get_mut(&block_id) {
+            replicas.insert(datanode);
+        }
     }
     /// Adds a new file to the tracker. Returns an error if the file already exists.
-    pub(crate) fn add_file(&mut self, filename: String) -> CuddlyResult<()> {
+    pub(crate) fn add_file(&mut self, filename: String, layout: FileLayout) -> CuddlyResult<()> {
         if self.filename_to_blocks.contains_key(&filename) {
             return Err(CuddlyError::FSError(format!(
                 "'{}': File creation already in progress",
@@ -53,16 +111,76 @@ impl NamenodeProgressTracker {
         self.filename_to_blocks.insert(filename.clone(), Vec::new());
         self.filename_to_block_seq
             .insert(filename.clone(), 0.into());
+        self.filename_to_layout.insert(filename.clone(), layout);
+        self.leases.insert(filename, Utc::now());
+        Ok(())
+    }
+
+    /// Reopens a finished file for appending. New blocks continue at
+    /// `next_seq`, and `last_block` is tracked again if it is reopened, with
+    /// its new generation.
+    pub(crate) fn start_append(
+        &mut self,
+        filename: String,
+        next_seq: u64,
+        last_block: Option<(Uuid, u64)>,
+        layout: FileLayout,
+    ) -> CuddlyResult<()> {
+        if self.filename_to_blocks.contains_key(&filename) {
+            return Err(CuddlyError::FSError(format!(
+                "'{}': File is already open for writing",
+                filename
+            )));
+        }
+        if let Some((block_id, generation)) = last_block {
+            self.block_to_replicas.insert(block_id, HashSet::new());
+            self.block_generations.insert(block_id, generation);
+        }
+        self.filename_to_blocks.insert(
+            filename.clone(),
+            last_block
+                .map(|(block_id, _)| block_id)
+                .into_iter()
+                .collect(),
+        );
+        self.filename_to_block_seq
+            .insert(filename.clone(), next_seq.into());
+        self.filename_to_layout.insert(filename.clone(), layout);
+        self.leases.insert(filename.clone(), Utc::now());
+        self.appending.insert(filename);
         Ok(())
     }
 
+    /// Whether the file is being created or appended to.
+    pub(crate) fn is_open(&self, filename: &str) -> bool {
+        self.filename_to_blocks.contains_key(filename)
+    }
+
+    /// Whether the file is being appended to rather than created.
+    pub(crate) fn is_append(&self, filename: &str) -> bool {
+        self.appending.contains(filename)
+    }
+
+    /// Files whose lease was last renewed before `deadline`.
+    pub(crate) fn expired_leases(&self, deadline: DateTime<Utc>) -> Vec<String> {
+        self.leases
+            .iter()
+            .filter(|(_, renewed)| **renewed < deadline)
+            .map(|(filename, _)| filename.clone())
+            .collect()
+    }
+
     /// Removes a file and its associated blocks from the tracker.
     pub(crate) fn remove_file(&mut self, filename: &str) -> CuddlyResult<()> {
         if let Some(blocks) = self.filename_to_blocks.remove(filename) {
             for block_id in blocks {
-                self.block_to_replication_count.remove(&block_id);
+                self.block_to_replicas.remove(&block_id);
+                self.block_generations.remove(&block_id);
             }
             self.filename_to_block_seq.remove(filename);
+            self.filename_to_layout.remove(filename);
+            self.leases.remove(filename);
+            self.appending.remove(filename);
             Ok(())
         } else {
             Err(CuddlyError::FSError(format!(
@@ -74,14 +192,26 @@ impl NamenodeProgressTracker {
 
     /// Checks if the given block ID is being tracked.
     pub(crate) fn contains_block(&self, block_id: &Uuid) -> bool {
-        self.block_to_replication_count.contains_key(block_id)
+        self.block_to_replicas.contains_key(block_id)
     }
 
-    pub(crate) fn add_block(&mut self, filename: &str, block_id: Uuid) -> CuddlyResult<u64> {
+    /// Generation of a block being written.
+    pub(crate) fn generation(&self, block_id: &Uuid) -> Option<u64> {
+        self.block_generations.get(block_id).copied()
+    }
+
+    pub(crate) fn add_block(
+        &mut self,
+        filename: &str,
+        block_id: Uuid,
+        generation: u64,
+    ) -> CuddlyResult<u64> {
         let blocks = self.filename_to_blocks.get_mut(filename);
         if let Some(blocks) = blocks {
             blocks.push(block_id);
-            self.block_to_replication_count.insert(block_id, 0);
+            self.block_to_replicas.insert(block_id, HashSet::new());
+            self.block_generations.insert(block_id, generation);
+            self.leases.insert(filename.to_owned(), Utc::now());
             let val = self
                 .filename_to_block_seq
                 .get_mut(filename)
@@ -101,6 +231,7 @@ impl NamenodeProgressTracker {
         let blocks = self.filename_to_blocks.get_mut(filename);
         if let Some(blocks) = blocks {
             blocks.retain(|&id| id != block_id);
+            self.block_generations.remove(&block_id);
             Ok(())
         } else {
             Err(CuddlyError::FSError(format!(
@@ -110,3 +241,56 @@ impl NamenodeProgressTracker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_append_continues_sequence() {
+        let mut tracker = NamenodeProgressTracker::new();
+        let last_block = Uuid::from_u128(1);
+        let layout = FileLayout {
+            replication: 2,
+            block_size: 1024,
+            erasure_coding_policy: None,
+        };
+        tracker
+            .start_append("/a".to_owned(), 3, Some((last_block, 7)), layout)
+            .unwrap();
+        assert!(tracker.is_append("/a"));
+        assert!(tracker
+            .start_append("/a".to_owned(), 3, None, layout)
+            .is_err());
+        assert_eq!(tracker.get_layout("/a").unwrap(), layout);
+        assert_eq!(tracker.add_block("/a", Uuid::from_u128(2), 8).unwrap(), 3);
+        assert_eq!(tracker.generation(&last_block), Some(7));
+        assert_eq!(tracker.generation(&Uuid::from_u128(2)), Some(8));
+        assert_eq!(
+            tracker.get_block_ids("/a").unwrap(),
+            &[last_block, Uuid::from_u128(2)]
+        );
+        assert_eq!(tracker.block_replication()[&last_block], 2);
+
+        // Reporting the same replica twice counts once
+        let datanode = Uuid::from_u128(10);
+        tracker.add_replica(last_block, datanode);
+        tracker.add_replica(last_block, datanode);
+        assert_eq!(tracker.get_replication_count(last_block), 1);
+
+        assert!(tracker
+            .expired_leases(Utc::now() - Duration::seconds(60))
+            .is_empty());
+        assert_eq!(
+            tracker.expired_leases(Utc::now() + Duration::seconds(60)),
+            vec!["/a".to_owned()]
+        );
+
+        tracker.remove_file("/a").unwrap();
+        assert!(!tracker.is_append("/a"));
+        assert!(!tracker.contains_block(&last_block));
+        assert_eq!(tracker.generation(&last_block), None);
+    }
+}
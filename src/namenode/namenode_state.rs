@@ -1,97 +1,558 @@
-use crate::block::Block;
+use crate::block::{Block, BlockGroup};
 use crate::errors::{CuddlyError, CuddlyResult};
+use crate::APP_CONFIG;
 
-use std::collections::HashMap;
+use super::erasure_coding_policy::ErasureCodingPolicy;
+use super::storage_policy::StoragePolicy;
 
-const ALLOWED_CHARACTERS: &str = "=-_";
+use uuid::Uuid;
 
-#[derive(Debug)]
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+const ALLOWED_CHARACTERS: &str = "=-_.";
+
+/// Directory below the root that fsck moves damaged files to, the only name
+/// that may hold a `+`.
+pub(crate) const LOST_AND_FOUND: &str = "lost+found";
+
+/// Limits on what a directory tree may consume. `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Quota {
+    /// Maximum number of files and directories, including the directory
+    /// itself.
+    pub namespace: Option<u64>,
+    /// Maximum number of bytes, counting every replica.
+    pub space: Option<u64>,
+}
+
+/// Files and directories, and bytes counting every replica, that count
+/// towards a quota.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Usage {
+    inodes: u64,
+    space: u64,
+}
+
+/// What a directory tree consumes, along with the quota of its root.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ContentSummary {
+    pub directories: u64,
+    pub files: u64,
+    pub length: u64,
+    pub space_consumed: u64,
+    pub quota: Quota,
+}
+
+// Directory name under which the snapshots of a directory can be read
+const SNAPSHOT_DIR: &str = ".snapshot";
+
+// Prefixes of extended attribute names. `system.` attributes are reserved
+// for the filesystem itself.
+const XATTR_NAMESPACES: [&str; 3] = ["user.", "trusted.", "system."];
+
+// Symbolic links followed while resolving one path before giving up
+const MAX_SYMLINK_HOPS: usize = 32;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileType {
+    Directory,
+    File,
+    Symlink,
+}
+
+/// What `stat` reports about a path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileStatus {
+    pub file_type: FileType,
+    /// Bytes of a file, 0 otherwise.
+    pub length: u64,
+    /// Replicas each block of a file should have, 0 for other types.
+    pub replication: u64,
+    /// Block size of a file, 0 for other types.
+    pub block_size: u64,
+    /// Set for a file whose blocks are erasure-coded rather than replicated.
+    pub erasure_coding_policy: Option<ErasureCodingPolicy>,
+    /// Path a symbolic link points at.
+    pub symlink_target: Option<String>,
+}
+
+/// Kinds of changes reported by a snapshot diff.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiffType {
+    Created,
+    Deleted,
+    /// A file whose blocks or replication changed, or a path whose
+    /// extended attributes or link target changed.
+    Modified,
+}
+
+/// Nodes are shared between the live tree and its snapshots, and are copied
+/// when they are changed while shared.
+#[derive(Clone, Debug, Deserialize, Serialize)]
 enum IndexTreeNode {
     Directory {
         name: String,
-        children: HashMap<String, IndexTreeNode>,
+        children: HashMap<String, Arc<IndexTreeNode>>,
+        #[serde(default)]
+        quota: Quota,
+        #[serde(default)]
+        snapshots: BTreeMap<String, Arc<IndexTreeNode>>,
+        #[serde(default)]
+        xattrs: BTreeMap<String, Vec<u8>>,
+        /// Inherited from the parent if unset.
+        #[serde(default)]
+        storage_policy: Option<StoragePolicy>,
+        /// Given to the files created below the directory. Inherited from
+        /// the parent if unset.
+        #[serde(default)]
+        erasure_coding_policy: Option<ErasureCodingPolicy>,
     },
     File {
         name: String,
         blocks: Vec<Block>,
+        /// Replicas each block should have.
+        #[serde(default = "default_replication")]
+        replication: u64,
+        #[serde(default = "default_block_size")]
+        block_size: u64,
+        #[serde(default)]
+        xattrs: BTreeMap<String, Vec<u8>>,
+        #[serde(default)]
+        storage_policy: Option<StoragePolicy>,
+        /// Set if the blocks form groups of data and parity blocks, each
+        /// stored once, instead of being replicated.
+        #[serde(default)]
+        erasure_coding_policy: Option<ErasureCodingPolicy>,
     },
+    /// Points at another path, absolute or relative to the link's directory.
+    /// The target does not need to exist.
+    Symlink { name: String, target: String },
 }
 
 impl IndexTreeNode {
     fn add_directory(&mut self, name: &str) -> CuddlyResult<&mut IndexTreeNode> {
         match self {
-            IndexTreeNode::Directory { name: _, children } => {
-                let child = children
-                    .entry(name.to_owned())
-                    .or_insert(IndexTreeNode::Directory {
+            IndexTreeNode::Directory {
+                name: _, children, ..
+            } => {
+                let child = children.entry(name.to_owned()).or_insert_with(|| {
+                    Arc::new(IndexTreeNode::Directory {
                         name: name.to_owned(),
                         children: HashMap::new(),
-                    });
-                Ok(child)
+                        quota: Quota::default(),
+                        snapshots: BTreeMap::new(),
+                        xattrs: BTreeMap::new(),
+                        storage_policy: None,
+                        erasure_coding_policy: None,
+                    })
+                });
+                Ok(Arc::make_mut(child))
             }
 
-            IndexTreeNode::File { name, blocks: _ } => Err(CuddlyError::FSError(format!(
-                "'{}' s not a directory",
-                name
-            ))),
+            IndexTreeNode::File { name, .. } | IndexTreeNode::Symlink { name, .. } => Err(
+                CuddlyError::FSError(format!("'{}' s not a directory", name)),
+            ),
+        }
+    }
+
+    fn xattrs(&self) -> Option<&BTreeMap<String, Vec<u8>>> {
+        match self {
+            IndexTreeNode::Directory { xattrs, .. } | IndexTreeNode::File { xattrs, .. } => {
+                Some(xattrs)
+            }
+            IndexTreeNode::Symlink { .. } => None,
+        }
+    }
+
+    fn storage_policy(&self) -> Option<StoragePolicy> {
+        match self {
+            IndexTreeNode::Directory { storage_policy, .. }
+            | IndexTreeNode::File { storage_policy, .. } => *storage_policy,
+            IndexTreeNode::Symlink { .. } => None,
+        }
+    }
+
+    fn erasure_coding_policy(&self) -> Option<ErasureCodingPolicy> {
+        match self {
+            IndexTreeNode::Directory {
+                erasure_coding_policy,
+                ..
+            }
+            | IndexTreeNode::File {
+                erasure_coding_policy,
+                ..
+            } => *erasure_coding_policy,
+            IndexTreeNode::Symlink { .. } => None,
         }
     }
 
     fn get_name(&self) -> &str {
         match self {
-            IndexTreeNode::Directory { name, children: _ } => name,
-            IndexTreeNode::File { name, blocks: _ } => name,
+            IndexTreeNode::Directory { name, .. } => name,
+            IndexTreeNode::File { name, .. } => name,
+            IndexTreeNode::Symlink { name, .. } => name,
         }
     }
 
     fn list(&self) -> Vec<&str> {
         match self {
-            IndexTreeNode::Directory { name: _, children } => {
-                children.values().map(|child| child.get_name()).collect()
-            }
-            IndexTreeNode::File { name, blocks: _ } => vec![name],
+            IndexTreeNode::Directory {
+                name: _, children, ..
+            } => children.values().map(|child| child.get_name()).collect(),
+            IndexTreeNode::File { name, .. } | IndexTreeNode::Symlink { name, .. } => vec![name],
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "StoredState")]
 pub struct NamenodeState {
     root: IndexTreeNode,
+    /// The quota and the usage of each directory that has a quota, by
+    /// resolved path. Kept up to date as the tree changes, so that checking
+    /// a quota does not walk the tree.
+    #[serde(skip)]
+    quota_usage: HashMap<String, (Quota, Usage)>,
+    /// What the files being written have been allocated, by resolved path.
+    /// Counts towards the quotas above them until the files are committed.
+    #[serde(skip)]
+    reservations: HashMap<String, Usage>,
+    /// How many snapshots refer to each block that is in a snapshot.
+    #[serde(skip)]
+    snapshot_blocks: HashMap<Uuid, usize>,
+}
+
+/// What the image holds. Nodes are stored once each, children first, and
+/// refer to their children and snapshots by index, which keeps the nodes
+/// shared between the live tree and its snapshots shared when the image is
+/// loaded. The root is the last node. Older images hold the tree itself.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum StoredState {
+    Shared { nodes: Vec<StoredNode> },
+    Nested { root: IndexTreeNode },
+}
+
+#[derive(Deserialize, Serialize)]
+struct StoredNode {
+    /// The node without its children and snapshots.
+    node: IndexTreeNode,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    children: BTreeMap<String, usize>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    snapshots: BTreeMap<String, usize>,
+}
+
+impl Serialize for NamenodeState {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        fn store(
+            node: &IndexTreeNode,
+            ids: &mut HashMap<*const IndexTreeNode, usize>,
+            nodes: &mut Vec<StoredNode>,
+        ) -> usize {
+            let mut store_shared = |node: &Arc<IndexTreeNode>| match ids.get(&Arc::as_ptr(node)) {
+                Some(id) => *id,
+                None => {
+                    let id = store(node, ids, nodes);
+                    ids.insert(Arc::as_ptr(node), id);
+                    id
+                }
+            };
+            let mut stored = StoredNode {
+                node: node.clone(),
+                children: BTreeMap::new(),
+                snapshots: BTreeMap::new(),
+            };
+            if let IndexTreeNode::Directory {
+                children,
+                snapshots,
+                ..
+            } = &mut stored.node
+            {
+                for (name, child) in children.drain() {
+                    stored.children.insert(name, store_shared(&child));
+                }
+                for (name, snapshot) in std::mem::take(snapshots) {
+                    stored.snapshots.insert(name, store_shared(&snapshot));
+                }
+            }
+            nodes.push(stored);
+            nodes.len() - 1
+        }
+
+        let mut nodes = vec![];
+        store(&self.root, &mut HashMap::new(), &mut nodes);
+        StoredState::Shared { nodes }.serialize(serializer)
+    }
+}
+
+impl TryFrom<StoredState> for NamenodeState {
+    type Error = String;
+
+    fn try_from(stored: StoredState) -> Result<Self, Self::Error> {
+        let root = match stored {
+            StoredState::Nested { root } => root,
+            StoredState::Shared { nodes } => {
+                let mut loaded: Vec<Arc<IndexTreeNode>> = Vec::with_capacity(nodes.len());
+                for (id, stored) in nodes.into_iter().enumerate() {
+                    let mut node = stored.node;
+                    let get = |child_id: usize| {
+                        loaded.get(child_id).cloned().ok_or_else(|| {
+                            format!("Node {} refers to unknown node {}", id, child_id)
+                        })
+                    };
+                    if let IndexTreeNode::Directory {
+                        children,
+                        snapshots,
+                        ..
+                    } = &mut node
+                    {
+                        for (name, child_id) in stored.children {
+                            children.insert(name, get(child_id)?);
+                        }
+                        for (name, child_id) in stored.snapshots {
+                            snapshots.insert(name, get(child_id)?);
+                        }
+                    }
+                    loaded.push(Arc::new(node));
+                }
+                let root = loaded.pop().ok_or("Image holds no nodes")?;
+                drop(loaded);
+                Arc::unwrap_or_clone(root)
+            }
+        };
+        Ok(Self::with_root(root))
+    }
 }
 
 impl NamenodeState {
     pub fn new() -> Self {
+        Self::with_root(IndexTreeNode::Directory {
+            name: "".into(),
+            children: HashMap::new(),
+            quota: Quota::default(),
+            snapshots: BTreeMap::new(),
+            xattrs: BTreeMap::new(),
+            storage_policy: None,
+            erasure_coding_policy: None,
+        })
+    }
+
+    /// Builds the state around a loaded tree, counting the usage of the
+    /// quotas and the blocks in snapshots.
+    fn with_root(root: IndexTreeNode) -> Self {
+        fn collect(
+            node: &IndexTreeNode,
+            path: &str,
+            quota_usage: &mut HashMap<String, (Quota, Usage)>,
+            snapshot_blocks: &mut HashMap<Uuid, usize>,
+        ) {
+            let IndexTreeNode::Directory {
+                children,
+                quota,
+                snapshots,
+                ..
+            } = node
+            else {
+                return;
+            };
+            if *quota != Quota::default() {
+                quota_usage.insert(path.to_owned(), (*quota, usage(node)));
+            }
+            for snapshot in snapshots.values() {
+                count_snapshot_blocks(snapshot_blocks, snapshot, 1);
+            }
+            for (name, child) in children {
+                let path = format!("{}/{}", path.trim_end_matches('/'), name);
+                collect(child, &path, quota_usage, snapshot_blocks);
+            }
+        }
+
+        let mut quota_usage = HashMap::new();
+        let mut snapshot_blocks = HashMap::new();
+        collect(&root, "/", &mut quota_usage, &mut snapshot_blocks);
         Self {
-            root: IndexTreeNode::Directory {
-                name: "".into(),
-                children: HashMap::new(),
-            },
+            root,
+            quota_usage,
+            reservations: HashMap::new(),
+            snapshot_blocks,
+        }
+    }
+
+    /// Number of files and directories, not counting the root.
+    pub fn count(&self) -> (u64, u64) {
+        fn count_node(node: &IndexTreeNode, counts: &mut (u64, u64)) {
+            match node {
+                IndexTreeNode::Directory {
+                    name: _, children, ..
+                } => {
+                    for child in children.values() {
+                        if let IndexTreeNode::Directory { .. } = **child {
+                            counts.1 += 1;
+                        }
+                        count_node(child, counts);
+                    }
+                }
+                IndexTreeNode::File { .. } => counts.0 += 1,
+                IndexTreeNode::Symlink { .. } => {}
+            }
+        }
+        let mut counts = (0, 0);
+        count_node(&self.root, &mut counts);
+        counts
+    }
+
+    /// All blocks of all files, including the files in snapshots. Where a
+    /// snapshot holds an older version of a block, the live one is returned.
+    pub fn blocks(&self) -> Vec<Block> {
+        let mut blocks = HashMap::new();
+        collect_blocks(&self.root, false, &mut HashSet::new(), &mut blocks);
+        collect_blocks(&self.root, true, &mut HashSet::new(), &mut blocks);
+        blocks.into_values().collect()
+    }
+
+    /// Keeps the blocks that no snapshot refers to, of blocks that were
+    /// just removed from the live tree.
+    pub fn unreferenced(&self, blocks: Vec<Block>) -> Vec<Block> {
+        blocks
+            .into_iter()
+            .filter(|block| !self.in_snapshot(&block.id))
+            .collect()
+    }
+
+    /// Replicas each block should have: the highest replication of the files
+    /// referring to it, live or in a snapshot.
+    pub fn block_replication(&self) -> HashMap<Uuid, u64> {
+        fn collect(
+            node: &IndexTreeNode,
+            visited: &mut HashSet<*const IndexTreeNode>,
+            replication: &mut HashMap<Uuid, u64>,
+        ) {
+            match node {
+                IndexTreeNode::Directory {
+                    children,
+                    snapshots,
+                    ..
+                } => {
+                    for child in children.values().chain(snapshots.values()) {
+                        if visited.insert(Arc::as_ptr(child)) {
+                            collect(child, visited, replication);
+                        }
+                    }
+                }
+                IndexTreeNode::File {
+                    blocks,
+                    replication: file_replication,
+                    ..
+                } => {
+                    for block in blocks {
+                        let entry = replication.entry(block.id).or_default();
+                        *entry = (*entry).max(*file_replication);
+                    }
+                }
+                IndexTreeNode::Symlink { .. } => {}
+            }
+        }
+
+        let mut replication = HashMap::new();
+        collect(&self.root, &mut HashSet::new(), &mut replication);
+        replication
+    }
+
+    /// The storage policy of the file each block belongs to. Where a file in
+    /// a snapshot shares a block with a live file, the live file's policy is
+    /// used.
+    pub fn block_storage_policies(&self) -> HashMap<Uuid, StoragePolicy> {
+        fn collect(
+            node: &IndexTreeNode,
+            inherited: Option<StoragePolicy>,
+            include_snapshots: bool,
+            policies: &mut HashMap<Uuid, StoragePolicy>,
+        ) {
+            let policy = node.storage_policy().or(inherited);
+            match node {
+                IndexTreeNode::Directory {
+                    children,
+                    snapshots,
+                    ..
+                } => {
+                    for child in children.values() {
+                        collect(child, policy, include_snapshots, policies);
+                    }
+                    if include_snapshots {
+                        for snapshot in snapshots.values() {
+                            collect(snapshot, policy, include_snapshots, policies);
+                        }
+                    }
+                }
+                IndexTreeNode::File { blocks, .. } => {
+                    for block in blocks {
+                        policies
+                            .entry(block.id)
+                            .or_insert(policy.unwrap_or_default());
+                    }
+                }
+                IndexTreeNode::Symlink { .. } => {}
+            }
         }
+
+        let mut policies = HashMap::new();
+        collect(&self.root, None, false, &mut policies);
+        collect(&self.root, None, true, &mut policies);
+        policies
+    }
+
+    /// Whether a file in a snapshot refers to the block.
+    pub fn in_snapshot(&self, block_id: &Uuid) -> bool {
+        self.snapshot_blocks.contains_key(block_id)
     }
 
     pub fn make_dir(&mut self, path: &str) -> CuddlyResult<()> {
         let path = starts_with_root_directory(path)?;
-        let mut node = &mut self.root;
         for part in path.split('/') {
             if part.is_empty() {
                 return Err(CuddlyError::FSError(
                     "Directory name cannot be empty".to_owned(),
                 ));
             }
-            if !is_valid_filename(part) {
+            if !is_valid_filename(part) || matches!(part, "." | ".." | SNAPSHOT_DIR) {
                 return Err(CuddlyError::FSError(format!(
                     "'{}' is not a valid directory name.",
                     part
                 )));
             }
+        }
+        // Links on the way are followed, so only the directories past them
+        // are created
+        let path = self.resolve(path, true)?;
+        let created = self.missing_directories(&format!("/{}", path))?;
+        let mut node = &mut self.root;
+        for part in path.split('/').filter(|part| !part.is_empty()) {
             node = node.add_directory(part)?;
         }
+        self.charge(
+            &format!("/{}", path),
+            Usage::default(),
+            Usage {
+                inodes: created,
+                space: 0,
+            },
+        );
 
         Ok(())
     }
 
     pub fn list(&self, path: &str) -> CuddlyResult<Vec<&str>> {
         let path = starts_with_root_directory(path)?;
+        if let Some(directory) = path
+            .strip_suffix(SNAPSHOT_DIR)
+            .filter(|directory| directory.is_empty() || directory.ends_with('/'))
+        {
+            return self.list_snapshots(&format!("/{}", directory));
+        }
         if path.is_empty() {
             Ok(self.root.list())
         } else {
@@ -105,49 +566,997 @@ impl NamenodeState {
         let node = self.get_node(path)?;
 
         match node {
-            IndexTreeNode::Directory {
-                name: _,
-                children: _,
-            } => Err(CuddlyError::FSError(format!(
+            IndexTreeNode::Directory { .. } => Err(CuddlyError::FSError(format!(
                 "'{}': Is not a file but a directory",
                 path
             ))),
-            IndexTreeNode::File { name: _, blocks } => Ok(blocks.as_slice()),
+            IndexTreeNode::File { blocks, .. } => Ok(blocks.as_slice()),
+            IndexTreeNode::Symlink { .. } => unreachable!("links are followed"),
         }
     }
 
-    pub fn create_file(&mut self, path: &str, blocks: &[Block]) -> CuddlyResult<()> {
-        let path = starts_with_root_directory(path)?;
+    pub fn create_file(
+        &mut self,
+        path: &str,
+        blocks: &[Block],
+        replication: u64,
+        block_size: u64,
+    ) -> CuddlyResult<()> {
+        self.tracked(path, true, |state| {
+            let path = starts_with_root_directory(path)?;
 
-        let parts = path.split('/').collect::<Vec<_>>();
-        let node = self.get_parent_node_mut(&parts)?;
-        let filename = parts[parts.len() - 1];
+            let parts = path.split('/').collect::<Vec<_>>();
+            let node = state.get_parent_node_mut(&parts)?;
+            let filename = parts[parts.len() - 1];
 
-        match node {
-            IndexTreeNode::Directory {
-                name: _,
-                ref mut children,
-            } => match children.get(filename) {
-                Some(_) => Err(CuddlyError::FSError(format!(
+            match node {
+                IndexTreeNode::Directory {
+                    ref mut children, ..
+                } => match children.get(filename) {
+                    Some(_) => Err(CuddlyError::FSError(format!(
+                        "'{}': File or directory already exists",
+                        filename
+                    ))),
+                    None => {
+                        let file = IndexTreeNode::File {
+                            name: filename.to_owned(),
+                            blocks: blocks.into(),
+                            replication,
+                            block_size,
+                            xattrs: BTreeMap::new(),
+                            storage_policy: None,
+                            erasure_coding_policy: None,
+                        };
+                        children.insert(filename.to_owned(), Arc::new(file));
+                        Ok(())
+                    }
+                },
+                IndexTreeNode::File { name, .. } | IndexTreeNode::Symlink { name, .. } => Err(
+                    CuddlyError::FSError(format!("'{}': Directory expected, but got file", name)),
+                ),
+            }
+        })
+    }
+
+    /// Adds the blocks written by an append to a file. A block replaces the
+    /// file's block with the same id, others are added at the end.
+    pub fn append_blocks(&mut self, path: &str, new_blocks: &[Block]) -> CuddlyResult<()> {
+        self.tracked(path, true, |state| {
+            let blocks = state.file_blocks_mut(path)?;
+            for new_block in new_blocks {
+                match blocks.iter_mut().find(|block| block.id == new_block.id) {
+                    Some(block) => *block = *new_block,
+                    None => blocks.push(*new_block),
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Shortens a file to `new_length` bytes. Returns the blocks past the new
+    /// length and, if the new length falls inside a block, that block with
+    /// its new length and `generation`.
+    pub fn truncate(
+        &mut self,
+        path: &str,
+        new_length: u64,
+        generation: u64,
+    ) -> CuddlyResult<(Vec<Block>, Option<Block>)> {
+        self.tracked(path, true, |state| {
+            state.check_replicated(path)?;
+            let blocks = state.open_file(path)?;
+            let length = blocks.iter().map(|block| block.len).sum::<u64>();
+            if new_length > length {
+                return Err(CuddlyError::FSError(format!(
+                    "'{}': Cannot truncate a file of {} bytes to {} bytes",
+                    path, length, new_length
+                )));
+            }
+
+            let mut offset = 0;
+            let mut kept = 0;
+            for block in blocks.iter() {
+                if offset >= new_length {
+                    break;
+                }
+                kept += 1;
+                offset += block.len;
+            }
+            // The replicas are shortened in place, which a snapshot of the
+            // block would not survive
+            if offset > new_length && state.in_snapshot(&blocks[kept - 1].id) {
+                return Err(CuddlyError::FSError(format!(
+                    "'{}': Cannot truncate within {}, which is part of a snapshot",
+                    path,
+                    blocks[kept - 1]
+                )));
+            }
+
+            let blocks = state.file_blocks_mut(path)?;
+            let mut shortened = None;
+            if offset > new_length {
+                let block = &mut blocks[kept - 1];
+                block.len -= offset - new_length;
+                block.generation = generation;
+                shortened = Some(*block);
+            }
+            Ok((blocks.split_off(kept), shortened))
+        })
+    }
+
+    /// Moves the blocks of `sources`, in order, to the end of `target` and
+    /// removes the sources. Returns the blocks of `target`, which are
+    /// renumbered to keep them in order.
+    pub fn concat(&mut self, target: &str, sources: &[String]) -> CuddlyResult<Vec<Block>> {
+        self.tracked(target, true, |state| {
+            if sources.is_empty() {
+                return Err(CuddlyError::FSError(
+                    "At least one source file required".to_owned(),
+                ));
+            }
+            state.open_file(target)?;
+            let mut seen = HashSet::new();
+            for source in sources {
+                if source == target || !seen.insert(source) {
+                    return Err(CuddlyError::FSError(format!(
+                        "'{}': Cannot concatenate a file more than once",
+                        source
+                    )));
+                }
+                state.open_file(source)?;
+                state.check_replicated(source)?;
+            }
+            state.check_replicated(target)?;
+
+            let mut moved = vec![];
+            for source in sources {
+                let source = state.resolve_path(source, true)?;
+                moved.extend(state.delete(&source)?);
+            }
+            let blocks = state.file_blocks_mut(target)?;
+            blocks.extend(moved);
+            for (seq, block) in blocks.iter_mut().enumerate() {
+                block.seq = seq as u64;
+            }
+            Ok(blocks.clone())
+        })
+    }
+
+    /// Returns the full path and the blocks of every file at or below `path`,
+    /// sorted by path.
+    pub fn files_under(&self, path: &str) -> CuddlyResult<Vec<(String, Vec<Block>)>> {
+        fn collect(node: &IndexTreeNode, path: String, files: &mut Vec<(String, Vec<Block>)>) {
+            match node {
+                IndexTreeNode::Directory {
+                    name: _, children, ..
+                } => {
+                    for (name, child) in children {
+                        collect(
+                            child,
+                            format!("{}/{}", path.trim_end_matches('/'), name),
+                            files,
+                        );
+                    }
+                }
+                IndexTreeNode::File { blocks, .. } => files.push((path, blocks.clone())),
+                // Links are not followed, or files would be listed twice
+                IndexTreeNode::Symlink { .. } => {}
+            }
+        }
+
+        let relative = starts_with_root_directory(path)?.trim_end_matches('/');
+        let node = if relative.is_empty() {
+            &self.root
+        } else {
+            self.get_node(relative)?
+        };
+        let mut files = vec![];
+        collect(node, format!("/{}", relative), &mut files);
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(files)
+    }
+
+    /// Removes a file or a directory with everything below it and returns
+    /// the blocks of the removed files. Directories with snapshots below
+    /// them cannot be removed.
+    /// A symbolic link is removed itself, not its target.
+    pub fn delete(&mut self, path: &str) -> CuddlyResult<Vec<Block>> {
+        let resolved = self.resolve_path(path, false)?;
+        let blocks = self.tracked(path, false, |state| {
+            let (parent, filename) = state.split_path(path.trim_end_matches('/'))?;
+            let node = parent.get(filename).ok_or_else(|| {
+                CuddlyError::FSError(format!("'{}': No such file or directory", path))
+            })?;
+            if has_snapshots(node) {
+                return Err(CuddlyError::FSError(format!(
+                    "'{}': Cannot delete a directory with snapshots",
+                    path
+                )));
+            }
+            let node = parent.remove(filename).unwrap();
+            Ok(match Arc::unwrap_or_clone(node) {
+                IndexTreeNode::File { blocks, .. } => blocks,
+                directory => {
+                    let mut blocks = HashMap::new();
+                    collect_blocks(&directory, false, &mut HashSet::new(), &mut blocks);
+                    blocks.into_values().collect()
+                }
+            })
+        })?;
+        self.quota_usage
+            .retain(|directory, _| *directory != resolved && !is_below(directory, &resolved));
+        Ok(blocks)
+    }
+
+    /// Whether a path is a directory. Fails if it does not exist.
+    pub fn is_directory(&self, path: &str) -> CuddlyResult<bool> {
+        Ok(matches!(
+            self.node_at(path)?,
+            IndexTreeNode::Directory { .. }
+        ))
+    }
+
+    /// Describes a path. A symbolic link at the end of `path` is described
+    /// itself unless `follow` is set.
+    pub fn stat(&self, path: &str, follow: bool) -> CuddlyResult<FileStatus> {
+        let resolved = self.resolve(starts_with_root_directory(path)?, follow)?;
+        Ok(match self.lookup(&resolved)? {
+            IndexTreeNode::Directory { .. } => FileStatus {
+                file_type: FileType::Directory,
+                length: 0,
+                replication: 0,
+                block_size: 0,
+                erasure_coding_policy: None,
+                symlink_target: None,
+            },
+            IndexTreeNode::File {
+                blocks,
+                replication,
+                block_size,
+                erasure_coding_policy,
+                ..
+            } => FileStatus {
+                file_type: FileType::File,
+                length: file_length(blocks, *erasure_coding_policy),
+                replication: *replication,
+                block_size: *block_size,
+                erasure_coding_policy: *erasure_coding_policy,
+                symlink_target: None,
+            },
+            IndexTreeNode::Symlink { name: _, target } => FileStatus {
+                file_type: FileType::Symlink,
+                length: 0,
+                replication: 0,
+                block_size: 0,
+                erasure_coding_policy: None,
+                symlink_target: Some(target.clone()),
+            },
+        })
+    }
+
+    /// Creates a symbolic link at `path` pointing at `target`. With
+    /// `overwrite` an existing link at `path` is replaced, which repoints it
+    /// in one step.
+    pub fn create_symlink(
+        &mut self,
+        path: &str,
+        target: &str,
+        overwrite: bool,
+    ) -> CuddlyResult<()> {
+        if target.is_empty() {
+            return Err(CuddlyError::FSError(
+                "Symbolic link target required".to_owned(),
+            ));
+        }
+        let path = self.resolve_path(path, false)?;
+        let (parent, filename) = self.split_path(&path)?;
+        if filename == SNAPSHOT_DIR {
+            return Err(CuddlyError::FSError(format!(
+                "'{}' is reserved for snapshots",
+                SNAPSHOT_DIR
+            )));
+        }
+        match parent.get(filename).map(Arc::as_ref) {
+            None => {}
+            Some(IndexTreeNode::Symlink { .. }) if overwrite => {}
+            Some(_) => {
+                return Err(CuddlyError::FSError(format!(
                     "'{}': File or directory already exists",
-                    filename
+                    path
+                )))
+            }
+        }
+        let link = IndexTreeNode::Symlink {
+            name: filename.to_owned(),
+            target: target.to_owned(),
+        };
+        if parent.insert(filename.to_owned(), Arc::new(link)).is_none() {
+            let link_usage = Usage {
+                inodes: 1,
+                space: 0,
+            };
+            self.charge(&path, Usage::default(), link_usage);
+        }
+        Ok(())
+    }
+
+    /// Extended attributes of a file or directory, by name.
+    pub fn xattrs(&self, path: &str) -> CuddlyResult<&BTreeMap<String, Vec<u8>>> {
+        self.node_at(path)?
+            .xattrs()
+            .ok_or_else(|| CuddlyError::FSError(format!("'{}': No such file or directory", path)))
+    }
+
+    /// Sets an extended attribute of a file or directory, replacing its
+    /// value if it exists.
+    pub fn set_xattr(&mut self, path: &str, name: &str, value: &[u8]) -> CuddlyResult<()> {
+        check_xattr_name(name)?;
+        match self.get_node_mut(path)? {
+            IndexTreeNode::Directory { xattrs, .. } | IndexTreeNode::File { xattrs, .. } => {
+                xattrs.insert(name.to_owned(), value.to_vec());
+                Ok(())
+            }
+            IndexTreeNode::Symlink { .. } => unreachable!("links are followed"),
+        }
+    }
+
+    pub fn remove_xattr(&mut self, path: &str, name: &str) -> CuddlyResult<()> {
+        check_xattr_name(name)?;
+        match self.get_node_mut(path)? {
+            IndexTreeNode::Directory { xattrs, .. } | IndexTreeNode::File { xattrs, .. } => {
+                xattrs.remove(name).map(|_| ()).ok_or_else(|| {
+                    CuddlyError::FSError(format!("'{}': No such attribute '{}'", path, name))
+                })
+            }
+            IndexTreeNode::Symlink { .. } => unreachable!("links are followed"),
+        }
+    }
+
+    /// Changes the replication of a file and returns its blocks. Files in
+    /// snapshots keep their replication.
+    pub fn set_replication(&mut self, path: &str, replication: u64) -> CuddlyResult<Vec<Block>> {
+        self.tracked(path, true, |state| {
+            state.check_replicated(path)?;
+            match state.get_node_mut(path)? {
+                IndexTreeNode::File {
+                    blocks,
+                    replication: file_replication,
+                    ..
+                } => {
+                    *file_replication = replication;
+                    Ok(blocks.clone())
+                }
+                IndexTreeNode::Directory { .. } => Err(CuddlyError::FSError(format!(
+                    "'{}': Is not a file but a directory",
+                    path
                 ))),
-                None => {
-                    let file = IndexTreeNode::File {
-                        name: filename.to_owned(),
-                        blocks: blocks.into(),
-                    };
-                    children.insert(filename.to_owned(), file);
-                    Ok(())
+                IndexTreeNode::Symlink { .. } => unreachable!("links are followed"),
+            }
+        })
+    }
+
+    /// The storage policy of `path`, set on it or inherited from the closest
+    /// ancestor that has one. Components that do not exist yet are skipped,
+    /// so this also gives the policy of a file about to be created.
+    pub fn storage_policy(&self, path: &str) -> CuddlyResult<StoragePolicy> {
+        Ok(self
+            .inherited(path, IndexTreeNode::storage_policy)?
+            .unwrap_or_default())
+    }
+
+    /// What `get` gives for `path` or, if nothing, for its closest ancestor
+    /// that has a value. Components that do not exist yet are skipped.
+    fn inherited<T>(
+        &self,
+        path: &str,
+        get: impl Fn(&IndexTreeNode) -> Option<T>,
+    ) -> CuddlyResult<Option<T>> {
+        let resolved = self.resolve(starts_with_root_directory(path)?, true)?;
+        let mut node = &self.root;
+        let mut value = get(node);
+        let mut parts = resolved.split('/').filter(|part| !part.is_empty());
+        while let Some(part) = parts.next() {
+            let IndexTreeNode::Directory {
+                children,
+                snapshots,
+                ..
+            } = node
+            else {
+                break;
+            };
+            let child = if part == SNAPSHOT_DIR {
+                parts.next().and_then(|name| snapshots.get(name))
+            } else {
+                children.get(part)
+            };
+            let Some(child) = child else {
+                break;
+            };
+            node = child;
+            value = get(node).or(value);
+        }
+        Ok(value)
+    }
+
+    /// Sets the storage policy of a file or directory, or with `None` lets
+    /// it inherit the policy of its parent. Returns the blocks of the files
+    /// at or below `path`, whose replicas may have to move.
+    pub fn set_storage_policy(
+        &mut self,
+        path: &str,
+        policy: Option<StoragePolicy>,
+    ) -> CuddlyResult<Vec<Block>> {
+        match self.get_node_mut(path)? {
+            IndexTreeNode::Directory { storage_policy, .. }
+            | IndexTreeNode::File { storage_policy, .. } => *storage_policy = policy,
+            IndexTreeNode::Symlink { .. } => unreachable!("links are followed"),
+        }
+        Ok(self
+            .files_under(path)?
+            .into_iter()
+            .flat_map(|(_, blocks)| blocks)
+            .collect())
+    }
+
+    /// The erasure coding policy a file was written with, or the one files
+    /// created at `path` get, set on the closest directory above. `None`
+    /// means replicated.
+    pub fn erasure_coding_policy(&self, path: &str) -> CuddlyResult<Option<ErasureCodingPolicy>> {
+        // A file keeps the layout it was written with
+        if let Ok(IndexTreeNode::File {
+            erasure_coding_policy,
+            ..
+        }) = self.node_at(path)
+        {
+            return Ok(*erasure_coding_policy);
+        }
+        self.inherited(path, IndexTreeNode::erasure_coding_policy)
+    }
+
+    /// Sets the erasure coding policy of a directory, or with `None` lets it
+    /// inherit the policy of its parent. Only files created afterwards are
+    /// affected.
+    pub fn set_erasure_coding_policy(
+        &mut self,
+        path: &str,
+        policy: Option<ErasureCodingPolicy>,
+    ) -> CuddlyResult<()> {
+        match self.get_node_mut(path)? {
+            IndexTreeNode::Directory {
+                erasure_coding_policy,
+                ..
+            } => {
+                *erasure_coding_policy = policy;
+                Ok(())
+            }
+            IndexTreeNode::File { .. } => Err(CuddlyError::FSError(format!(
+                "'{}': Erasure coding policies can only be set on directories",
+                path
+            ))),
+            IndexTreeNode::Symlink { .. } => unreachable!("links are followed"),
+        }
+    }
+
+    /// Creates a file whose blocks are groups of `policy`, each block of a
+    /// group stored once.
+    pub fn create_striped_file(
+        &mut self,
+        path: &str,
+        blocks: &[Block],
+        policy: ErasureCodingPolicy,
+        block_size: u64,
+    ) -> CuddlyResult<()> {
+        self.create_file(path, blocks, 1, block_size)?;
+        if let IndexTreeNode::File {
+            erasure_coding_policy,
+            ..
+        } = self.get_node_mut(path)?
+        {
+            *erasure_coding_policy = Some(policy);
+        }
+        Ok(())
+    }
+
+    /// The group each block of an erasure-coded file belongs to, for the
+    /// files live or in a snapshot.
+    pub fn striped_blocks(&self) -> HashMap<Uuid, BlockGroup> {
+        fn collect(
+            node: &IndexTreeNode,
+            visited: &mut HashSet<*const IndexTreeNode>,
+            groups: &mut HashMap<Uuid, BlockGroup>,
+        ) {
+            match node {
+                IndexTreeNode::Directory {
+                    children,
+                    snapshots,
+                    ..
+                } => {
+                    for child in children.values().chain(snapshots.values()) {
+                        if visited.insert(Arc::as_ptr(child)) {
+                            collect(child, visited, groups);
+                        }
+                    }
                 }
-            },
-            IndexTreeNode::File { name, blocks: _ } => Err(CuddlyError::FSError(format!(
-                "'{}': Directory expected, but got file",
+                IndexTreeNode::File {
+                    blocks,
+                    block_size,
+                    erasure_coding_policy: Some(policy),
+                    ..
+                } => {
+                    for group in block_groups(blocks, *policy, *block_size) {
+                        for block in &group.blocks {
+                            groups.insert(block.id, group.clone());
+                        }
+                    }
+                }
+                IndexTreeNode::File { .. } | IndexTreeNode::Symlink { .. } => {}
+            }
+        }
+
+        let mut groups = HashMap::new();
+        collect(&self.root, &mut HashSet::new(), &mut groups);
+        groups
+    }
+
+    /// Fails for erasure-coded files, whose blocks cannot be changed one by
+    /// one.
+    pub fn check_replicated(&self, path: &str) -> CuddlyResult<()> {
+        match self.erasure_coding_policy(path)? {
+            Some(policy) if !self.is_directory(path)? => Err(CuddlyError::FSError(format!(
+                "'{}': Not supported on files erasure-coded with {}",
+                path, policy
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Replaces the symbolic links in `path` by their targets. The link at
+    /// the end of `path` is only followed if `follow` is set.
+    pub fn resolve_path(&self, path: &str, follow: bool) -> CuddlyResult<String> {
+        let relative = starts_with_root_directory(path)?;
+        Ok(format!("/{}", self.resolve(relative, follow)?))
+    }
+
+    /// Moves a file or directory to `dst`, whose parent has to exist.
+    pub fn rename(&mut self, src: &str, dst: &str) -> CuddlyResult<()> {
+        let src = &self.resolve_path(src, false)?;
+        let dst = &self.resolve_path(dst, false)?;
+        if dst
+            .trim_end_matches('/')
+            .starts_with(&format!("{}/", src.trim_end_matches('/')))
+        {
+            return Err(CuddlyError::FSError(format!(
+                "'{}': Cannot move a directory into itself",
+                src
+            )));
+        }
+        self.check_file_creation(dst)?;
+        let moved = self.counted_usage(src);
+        let (parent, filename) = self.split_path(src)?;
+        let mut node = parent
+            .remove(filename)
+            .ok_or_else(|| CuddlyError::FSError(format!("'{}': No such file or directory", src)))?;
+
+        let (parent, filename) = self.split_path(dst)?;
+        match Arc::make_mut(&mut node) {
+            IndexTreeNode::Directory { name, .. } => *name = filename.to_owned(),
+            IndexTreeNode::File { name, .. } | IndexTreeNode::Symlink { name, .. } => {
+                *name = filename.to_owned()
+            }
+        }
+        parent.insert(filename.to_owned(), node);
+
+        self.charge(src, moved, Usage::default());
+        // Quotas of the moved tree go along with it
+        let moved_quotas = self
+            .quota_usage
+            .keys()
+            .filter(|directory| *directory == src || is_below(directory, src))
+            .cloned()
+            .collect::<Vec<_>>();
+        for directory in moved_quotas {
+            let quota_usage = self.quota_usage.remove(&directory).unwrap();
+            self.quota_usage
+                .insert(format!("{}{}", dst, &directory[src.len()..]), quota_usage);
+        }
+        self.charge(dst, Usage::default(), moved);
+        Ok(())
+    }
+
+    /// Replaces the quota of a directory.
+    pub fn set_quota(&mut self, path: &str, new_quota: Quota) -> CuddlyResult<()> {
+        match self.get_node_mut(path)? {
+            IndexTreeNode::Directory { quota, .. } => *quota = new_quota,
+            _ => {
+                return Err(CuddlyError::FSError(format!(
+                    "'{}': Quotas can only be set on directories",
+                    path
+                )))
+            }
+        }
+        let resolved = self.resolve_path(path, true)?;
+        if new_quota == Quota::default() {
+            self.quota_usage.remove(&resolved);
+        } else {
+            let usage = usage(self.node_at(&resolved)?);
+            self.quota_usage.insert(resolved, (new_quota, usage));
+        }
+        Ok(())
+    }
+
+    /// Counts what the tree at `path` consumes, with every block stored as
+    /// many times as its file's replication.
+    pub fn content_summary(&self, path: &str) -> CuddlyResult<ContentSummary> {
+        let relative = starts_with_root_directory(path)?.trim_end_matches('/');
+        let node = if relative.is_empty() {
+            &self.root
+        } else {
+            self.get_node(relative)?
+        };
+        Ok(summarize(node))
+    }
+
+    /// Checks that adding `inodes` files or directories and `space` bytes,
+    /// counting every replica, at `path` keeps every directory above it
+    /// within its quota.
+    pub fn check_quota(&self, path: &str, inodes: u64, space: u64) -> CuddlyResult<()> {
+        let resolved = self.resolve_path(path, true)?;
+        self.check_usage(&resolved, Usage { inodes, space }, &[])
+    }
+
+    /// Checks that moving `src` to `dst` keeps the directories that contain
+    /// `dst`, but not `src`, within their quotas.
+    pub fn check_rename_quota(&self, src: &str, dst: &str) -> CuddlyResult<()> {
+        let src = self.resolve_path(src, false)?;
+        let dst = self.resolve_path(dst, false)?;
+        let moved = self.counted_usage(&src);
+        self.check_usage(&dst, moved, &[(src, moved)])
+    }
+
+    /// Checks that moving the blocks of `sources` to `target`, where they
+    /// take the replication of `target`, keeps every directory within its
+    /// quota.
+    pub fn check_concat_quota(&self, target: &str, sources: &[String]) -> CuddlyResult<()> {
+        if self.quota_usage.is_empty() {
+            return Ok(());
+        }
+        let target = self.resolve_path(target, true)?;
+        let replication = self.stat(&target, true)?.replication;
+        let mut added = Usage::default();
+        let mut released = vec![];
+        for source in sources {
+            let source = self.resolve_path(source, true)?;
+            if let IndexTreeNode::File { blocks, .. } = self.node_at(&source)? {
+                added.space += blocks.iter().map(|block| block.len).sum::<u64>() * replication;
+            }
+            let usage = self.counted_usage(&source);
+            released.push((source, usage));
+        }
+        self.check_usage(&target, added, &released)
+    }
+
+    /// Checks that adding `added` at the resolved `path`, while the usage in
+    /// `released` leaves the resolved paths given with it, keeps every
+    /// directory with a quota within it. What the files being written have
+    /// been allocated counts as used. A change that does not add to the
+    /// usage of a directory passes even if the directory is over its quota.
+    fn check_usage(
+        &self,
+        path: &str,
+        added: Usage,
+        released: &[(String, Usage)],
+    ) -> CuddlyResult<()> {
+        for (directory, (quota, usage)) in &self.quota_usage {
+            if *directory != path && !is_below(path, directory) {
+                continue;
+            }
+            let mut used = *usage;
+            for (file, reservation) in &self.reservations {
+                if is_below(file, directory) {
+                    used.inodes += reservation.inodes;
+                    used.space += reservation.space;
+                }
+            }
+            let mut needed = used;
+            needed.inodes += added.inodes;
+            needed.space += added.space;
+            for (released_path, released) in released {
+                if released_path == directory || is_below(released_path, directory) {
+                    needed.inodes = needed.inodes.saturating_sub(released.inodes);
+                    needed.space = needed.space.saturating_sub(released.space);
+                }
+            }
+
+            if let Some(limit) = quota
+                .namespace
+                .filter(|limit| needed.inodes > used.inodes && needed.inodes > *limit)
+            {
+                return Err(CuddlyError::QuotaExceeded(format!(
+                    "'{}': Namespace quota of {} exceeded, {} files and directories in use",
+                    directory, limit, used.inodes
+                )));
+            }
+            if let Some(limit) = quota
+                .space
+                .filter(|limit| needed.space > used.space && needed.space > *limit)
+            {
+                return Err(CuddlyError::QuotaExceeded(format!(
+                    "'{}': Space quota of {} bytes exceeded, {} bytes in use, {} more needed",
+                    directory,
+                    limit,
+                    used.space,
+                    needed.space - used.space
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reserves `space` more bytes, counting every replica, for the blocks
+    /// allocated to the file being written at `path`. A file that is not in
+    /// the namespace yet also takes a namespace entry. Fails if a quota above
+    /// the file would be exceeded.
+    pub fn reserve(&mut self, path: &str, space: u64) -> CuddlyResult<()> {
+        let resolved = self.resolve_path(path, true)?;
+        let inodes =
+            match self.reservations.contains_key(&resolved) || self.node_at(&resolved).is_ok() {
+                true => 0,
+                false => 1,
+            };
+        self.check_usage(&resolved, Usage { inodes, space }, &[])?;
+        let reservation = self.reservations.entry(resolved).or_default();
+        reservation.inodes += inodes;
+        reservation.space += space;
+        Ok(())
+    }
+
+    /// Releases what was reserved for the file at `path`, once its blocks
+    /// are committed or it is abandoned.
+    pub fn release(&mut self, path: &str) {
+        if let Ok(resolved) = self.resolve_path(path, true) {
+            self.reservations.remove(&resolved);
+        }
+    }
+
+    /// Runs `change` on the node at `path` and charges the directories with
+    /// a quota above it for what the change added or removed.
+    fn tracked<T>(
+        &mut self,
+        path: &str,
+        follow: bool,
+        change: impl FnOnce(&mut Self) -> CuddlyResult<T>,
+    ) -> CuddlyResult<T> {
+        if self.quota_usage.is_empty() {
+            return change(self);
+        }
+        let resolved = self.resolve_path(path, follow)?;
+        let before = self.counted_usage(&resolved);
+        let result = change(self)?;
+        let after = self.counted_usage(&resolved);
+        self.charge(&resolved, before, after);
+        Ok(result)
+    }
+
+    /// What the node at the resolved `path` counts towards the quotas above
+    /// it. Nothing is counted while no directory has a quota.
+    fn counted_usage(&self, path: &str) -> Usage {
+        if self.quota_usage.is_empty() {
+            return Usage::default();
+        }
+        self.lookup(path).map(usage).unwrap_or_default()
+    }
+
+    /// Updates the usage of the directories with a quota above the resolved
+    /// `path` for the node there changing from `before` to `after`.
+    fn charge(&mut self, path: &str, before: Usage, after: Usage) {
+        for (directory, (_, usage)) in &mut self.quota_usage {
+            if is_below(path, directory) {
+                usage.inodes = (usage.inodes + after.inodes).saturating_sub(before.inodes);
+                usage.space = (usage.space + after.space).saturating_sub(before.space);
+            }
+        }
+    }
+
+    /// Number of directories `make_dir` would create for `path`.
+    pub fn missing_directories(&self, path: &str) -> CuddlyResult<u64> {
+        let resolved = self.resolve_path(path, true)?;
+        let relative = starts_with_root_directory(&resolved)?;
+        let parts = relative
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>();
+        let mut node = &self.root;
+        for (i, part) in parts.iter().enumerate() {
+            match node {
+                IndexTreeNode::Directory { children, .. } => match children.get(*part) {
+                    Some(child) => node = child,
+                    None => return Ok((parts.len() - i) as u64),
+                },
+                IndexTreeNode::File { .. } | IndexTreeNode::Symlink { .. } => return Ok(0),
+            }
+        }
+        Ok(0)
+    }
+
+    /// Takes a read-only snapshot of a directory, readable below
+    /// `<path>/.snapshot/<name>`. The snapshot shares all nodes with the
+    /// directory until they change.
+    pub fn create_snapshot(&mut self, path: &str, name: &str) -> CuddlyResult<()> {
+        if name.is_empty() || !is_valid_filename(name) {
+            return Err(CuddlyError::FSError(format!(
+                "'{}' is not a valid snapshot name.",
                 name
+            )));
+        }
+        let snapshot = match self.get_node_mut(path)? {
+            IndexTreeNode::Directory {
+                name: directory_name,
+                children,
+                quota,
+                snapshots,
+                xattrs,
+                storage_policy,
+                erasure_coding_policy,
+            } => {
+                if snapshots.contains_key(name) {
+                    return Err(CuddlyError::FSError(format!(
+                        "'{}': Snapshot '{}' already exists",
+                        path, name
+                    )));
+                }
+                let snapshot = IndexTreeNode::Directory {
+                    name: directory_name.clone(),
+                    children: children.clone(),
+                    quota: *quota,
+                    snapshots: BTreeMap::new(),
+                    xattrs: xattrs.clone(),
+                    storage_policy: *storage_policy,
+                    erasure_coding_policy: *erasure_coding_policy,
+                };
+                let snapshot = Arc::new(snapshot);
+                snapshots.insert(name.to_owned(), snapshot.clone());
+                snapshot
+            }
+            _ => {
+                return Err(CuddlyError::FSError(format!(
+                    "'{}': Snapshots can only be taken of directories",
+                    path
+                )))
+            }
+        };
+        count_snapshot_blocks(&mut self.snapshot_blocks, &snapshot, 1);
+        Ok(())
+    }
+
+    /// Removes a snapshot and returns the blocks that no file refers to
+    /// anymore. Only if no other snapshot refers to some of its blocks are
+    /// the live files searched for them.
+    pub fn delete_snapshot(&mut self, path: &str, name: &str) -> CuddlyResult<Vec<Block>> {
+        let snapshot = match self.get_node_mut(path)? {
+            IndexTreeNode::Directory { snapshots, .. } => {
+                snapshots.remove(name).ok_or_else(|| {
+                    CuddlyError::FSError(format!("'{}': No such snapshot '{}'", path, name))
+                })?
+            }
+            _ => return Err(CuddlyError::FSError(format!("'{}': Not a directory", path))),
+        };
+        count_snapshot_blocks(&mut self.snapshot_blocks, &snapshot, -1);
+        let mut blocks = HashMap::new();
+        collect_blocks(&snapshot, true, &mut HashSet::new(), &mut blocks);
+        blocks.retain(|block_id, _| !self.in_snapshot(block_id));
+        if blocks.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut live = HashMap::new();
+        collect_blocks(&self.root, false, &mut HashSet::new(), &mut live);
+        Ok(blocks
+            .into_values()
+            .filter(|block| !live.contains_key(&block.id))
+            .collect())
+    }
+
+    /// Names of the snapshots of a directory, in order.
+    pub fn list_snapshots(&self, path: &str) -> CuddlyResult<Vec<&str>> {
+        match self.node_at(path)? {
+            IndexTreeNode::Directory { snapshots, .. } => {
+                Ok(snapshots.keys().map(String::as_str).collect())
+            }
+            _ => Err(CuddlyError::FSError(format!("'{}': Not a directory", path))),
+        }
+    }
+
+    /// Lists what changed below a directory between the snapshot `from` and
+    /// the snapshot `to`, or the live directory if `to` is `None`. Renamed
+    /// entries show up as deleted and created.
+    pub fn snapshot_diff(
+        &self,
+        path: &str,
+        from: &str,
+        to: Option<&str>,
+    ) -> CuddlyResult<Vec<(DiffType, String)>> {
+        let directory = self.node_at(path)?;
+        let IndexTreeNode::Directory { snapshots, .. } = directory else {
+            return Err(CuddlyError::FSError(format!("'{}': Not a directory", path)));
+        };
+        let snapshot = |name: &str| {
+            snapshots.get(name).map(Arc::as_ref).ok_or_else(|| {
+                CuddlyError::FSError(format!("'{}': No such snapshot '{}'", path, name))
+            })
+        };
+        let old = snapshot(from)?;
+        let new = match to {
+            Some(to) => snapshot(to)?,
+            None => directory,
+        };
+
+        let mut changes = vec![];
+        diff_nodes(old, new, path.trim_end_matches('/'), &mut changes);
+        changes.sort_by(|a, b| a.1.cmp(&b.1));
+        Ok(changes)
+    }
+
+    fn node_at(&self, path: &str) -> CuddlyResult<&IndexTreeNode> {
+        let relative = starts_with_root_directory(path)?.trim_end_matches('/');
+        if relative.is_empty() {
+            Ok(&self.root)
+        } else {
+            self.get_node(relative)
+        }
+    }
+
+    /// Resolves a path in the live tree, copying the nodes on the way that
+    /// are shared with a snapshot.
+    fn get_node_mut(&mut self, path: &str) -> CuddlyResult<&mut IndexTreeNode> {
+        let resolved = self.resolve_path(path, true)?;
+        if resolved == "/" {
+            return Ok(&mut self.root);
+        }
+        let (parent, filename) = self.split_path(&resolved)?;
+        parent
+            .get_mut(filename)
+            .map(Arc::make_mut)
+            .ok_or_else(|| CuddlyError::FSError(format!("'{}': No such file or directory", path)))
+    }
+
+    fn file_blocks_mut(&mut self, path: &str) -> CuddlyResult<&mut Vec<Block>> {
+        let resolved = self.resolve_path(path, true)?;
+        let (parent, filename) = self.split_path(&resolved)?;
+        match parent.get_mut(filename).map(Arc::make_mut) {
+            Some(IndexTreeNode::File { blocks, .. }) => Ok(blocks),
+            Some(IndexTreeNode::Directory { .. } | IndexTreeNode::Symlink { .. }) => Err(
+                CuddlyError::FSError(format!("'{}': Is not a file but a directory", path)),
+            ),
+            None => Err(CuddlyError::FSError(format!(
+                "'{}': No such file or directory",
+                path
             ))),
         }
     }
 
+    /// Returns the children of the parent directory of `path` and the last
+    /// component of `path`.
+    fn split_path<'a>(
+        &mut self,
+        path: &'a str,
+    ) -> CuddlyResult<(&mut HashMap<String, Arc<IndexTreeNode>>, &'a str)> {
+        let relative = starts_with_root_directory(path)?;
+        if relative.is_empty() {
+            return Err(CuddlyError::FSError(
+                "Path and filename required".to_owned(),
+            ));
+        }
+        let parts = relative.split('/').collect::<Vec<_>>();
+        let filename = parts[parts.len() - 1];
+        match self.get_parent_node_mut(&parts)? {
+            IndexTreeNode::Directory {
+                name: _, children, ..
+            } => Ok((children, filename)),
+            IndexTreeNode::File { name, .. } | IndexTreeNode::Symlink { name, .. } => Err(
+                CuddlyError::FSError(format!("'{}': Directory expected, but got file", name)),
+            ),
+        }
+    }
+
     pub fn check_file_creation(&self, path: &str) -> CuddlyResult<()> {
         let path = starts_with_root_directory(path)?;
         if path.ends_with('/') {
@@ -164,45 +1573,143 @@ impl NamenodeState {
         let parts = path.split('/').collect::<Vec<_>>();
         let node = self.get_parent_node(&parts)?;
         let filename = parts[parts.len() - 1];
+        if filename == SNAPSHOT_DIR {
+            return Err(CuddlyError::FSError(format!(
+                "'{}' is reserved for snapshots",
+                SNAPSHOT_DIR
+            )));
+        }
 
         match node {
-            IndexTreeNode::Directory { name: _, children } => match children.get(filename) {
+            IndexTreeNode::Directory {
+                name: _, children, ..
+            } => match children.get(filename) {
                 Some(_) => Err(CuddlyError::FSError(format!(
                     "'{}': File or directory already exists",
                     filename
                 ))),
                 None => Ok(()),
             },
-            IndexTreeNode::File { name, blocks: _ } => Err(CuddlyError::FSError(format!(
-                "'{}': Directory expected, but got file",
-                name
-            ))),
+            IndexTreeNode::File { name, .. } | IndexTreeNode::Symlink { name, .. } => Err(
+                CuddlyError::FSError(format!("'{}': Directory expected, but got file", name)),
+            ),
+        }
+    }
+
+    /// Replaces the symbolic links in a path relative to the root by their
+    /// targets, keeping `.snapshot/<name>` steps. The link at the end is only
+    /// followed if `follow` is set. Past the first missing component the
+    /// path is kept as it is.
+    fn resolve(&self, path: &str, follow: bool) -> CuddlyResult<String> {
+        let mut remaining = path
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .map(str::to_owned)
+            .collect::<VecDeque<_>>();
+        // Resolved components, a snapshot step being one, with their nodes
+        let mut resolved: Vec<(String, &IndexTreeNode)> = vec![];
+        let mut hops = 0;
+        while let Some(part) = remaining.pop_front() {
+            match part.as_str() {
+                "." => continue,
+                ".." => {
+                    resolved.pop();
+                    continue;
+                }
+                _ => {}
+            }
+            let node = resolved.last().map_or(&self.root, |(_, node)| *node);
+            let (part, child) = match node {
+                IndexTreeNode::Directory { snapshots, .. } if part == SNAPSHOT_DIR => {
+                    match remaining.pop_front() {
+                        Some(name) => {
+                            let child = snapshots.get(&name);
+                            (format!("{}/{}", SNAPSHOT_DIR, name), child)
+                        }
+                        None => (part, None),
+                    }
+                }
+                IndexTreeNode::Directory { children, .. } => {
+                    let child = children.get(&part);
+                    (part, child)
+                }
+                _ => (part, None),
+            };
+            let Some(child) = child else {
+                remaining.push_front(part);
+                break;
+            };
+            match child.as_ref() {
+                IndexTreeNode::Symlink { name: _, target } if follow || !remaining.is_empty() => {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return Err(CuddlyError::FSError(format!(
+                            "'/{}': Too many levels of symbolic links",
+                            path
+                        )));
+                    }
+                    if target.starts_with('/') {
+                        resolved.clear();
+                    }
+                    for part in target.split('/').filter(|part| !part.is_empty()).rev() {
+                        remaining.push_front(part.to_owned());
+                    }
+                }
+                _ => resolved.push((part, child)),
+            }
         }
+        Ok(resolved
+            .into_iter()
+            .map(|(part, _)| part)
+            .chain(remaining)
+            .collect::<Vec<_>>()
+            .join("/"))
     }
 
+    /// Resolves a path, following symbolic links and paths into snapshots
+    /// through `.snapshot`.
     fn get_node(&self, path: &str) -> CuddlyResult<&IndexTreeNode> {
+        self.lookup(&self.resolve(path, true)?)
+    }
+
+    /// Looks up a path relative to the root without following links.
+    fn lookup(&self, path: &str) -> CuddlyResult<&IndexTreeNode> {
         let mut node = &self.root;
-        for part in path.split('/') {
+        let mut parts = path.split('/').filter(|part| !part.is_empty());
+        while let Some(part) = parts.next() {
             match node {
-                IndexTreeNode::Directory { name: _, children } => {
-                    node = children.get(part).ok_or_else(|| {
+                IndexTreeNode::Directory {
+                    name: _,
+                    children,
+                    snapshots,
+                    ..
+                } => {
+                    let child = if part == SNAPSHOT_DIR {
+                        parts.next().and_then(|name| snapshots.get(name))
+                    } else {
+                        children.get(part)
+                    };
+                    node = child.ok_or_else(|| {
                         CuddlyError::FSError(format!("'{}': No such file or directory", path))
                     })?;
                 }
-                IndexTreeNode::File { name: _, blocks: _ } => {
-                    return Err(CuddlyError::FSError(format!("'{}': Not a directory", path)))
-                }
+                _ => return Err(CuddlyError::FSError(format!("'{}': Not a directory", path))),
             };
         }
         Ok(node)
     }
 
+    /// Resolves the parent directory of the path made of `parts`, following
+    /// symbolic links.
     fn get_parent_node(&self, parts: &[&str]) -> CuddlyResult<&IndexTreeNode> {
+        let parent = self.resolve(&parts[..parts.len() - 1].join("/"), true)?;
         let mut node = &self.root;
 
-        for &part in parts.iter().take(parts.len() - 1) {
+        for part in parent.split('/').filter(|part| !part.is_empty()) {
             node = match node {
-                IndexTreeNode::Directory { name: _, children } => match children.get(part) {
+                IndexTreeNode::Directory {
+                    name: _, children, ..
+                } => match children.get(part) {
                     Some(node) => node,
                     None => {
                         return Err(CuddlyError::FSError(format!(
@@ -211,7 +1718,7 @@ impl NamenodeState {
                         )))
                     }
                 },
-                IndexTreeNode::File { name, blocks: _ } => {
+                IndexTreeNode::File { name, .. } | IndexTreeNode::Symlink { name, .. } => {
                     return Err(CuddlyError::FSError(format!(
                         "'{}': Directory expected, but got file",
                         name
@@ -224,12 +1731,15 @@ impl NamenodeState {
     }
 
     fn get_parent_node_mut(&mut self, parts: &[&str]) -> CuddlyResult<&mut IndexTreeNode> {
+        let parent = self.resolve(&parts[..parts.len() - 1].join("/"), true)?;
         let mut node = &mut self.root;
 
-        for &part in parts.iter().take(parts.len() - 1) {
+        for part in parent.split('/').filter(|part| !part.is_empty()) {
             node = match node {
-                IndexTreeNode::Directory { name: _, children } => match children.get_mut(part) {
-                    Some(node) => node,
+                IndexTreeNode::Directory {
+                    name: _, children, ..
+                } => match children.get_mut(part) {
+                    Some(node) => Arc::make_mut(node),
                     None => {
                         return Err(CuddlyError::FSError(format!(
                             "'{}': No such directory",
@@ -237,7 +1747,7 @@ impl NamenodeState {
                         )))
                     }
                 },
-                IndexTreeNode::File { name, blocks: _ } => {
+                IndexTreeNode::File { name, .. } | IndexTreeNode::Symlink { name, .. } => {
                     return Err(CuddlyError::FSError(format!(
                         "'{}': Directory expected, but got file",
                         name
@@ -250,13 +1760,761 @@ impl NamenodeState {
     }
 }
 
+fn has_snapshots(node: &IndexTreeNode) -> bool {
+    match node {
+        IndexTreeNode::Directory {
+            children,
+            snapshots,
+            ..
+        } => !snapshots.is_empty() || children.values().any(|child| has_snapshots(child)),
+        IndexTreeNode::File { .. } | IndexTreeNode::Symlink { .. } => false,
+    }
+}
+
+/// Adds the blocks of all files below `node` to `blocks`, unless a block is
+/// already there. Shared nodes are only visited once.
+fn collect_blocks(
+    node: &IndexTreeNode,
+    include_snapshots: bool,
+    visited: &mut HashSet<*const IndexTreeNode>,
+    blocks: &mut HashMap<Uuid, Block>,
+) {
+    match node {
+        IndexTreeNode::Directory {
+            children,
+            snapshots,
+            ..
+        } => {
+            let snapshots = snapshots.values().filter(|_| include_snapshots);
+            for child in children.values().chain(snapshots) {
+                if visited.insert(Arc::as_ptr(child)) {
+                    collect_blocks(child, include_snapshots, visited, blocks);
+                }
+            }
+        }
+        IndexTreeNode::File {
+            blocks: file_blocks,
+            ..
+        } => {
+            for block in file_blocks {
+                blocks.entry(block.id).or_insert(*block);
+            }
+        }
+        IndexTreeNode::Symlink { .. } => {}
+    }
+}
+
+/// Adds `delta` to the number of snapshots referring to each block of
+/// `snapshot`, including the snapshots taken below it.
+fn count_snapshot_blocks(
+    snapshot_blocks: &mut HashMap<Uuid, usize>,
+    snapshot: &IndexTreeNode,
+    delta: isize,
+) {
+    let mut blocks = HashMap::new();
+    collect_blocks(snapshot, true, &mut HashSet::new(), &mut blocks);
+    for block_id in blocks.into_keys() {
+        let count = snapshot_blocks.entry(block_id).or_default();
+        *count = count.saturating_add_signed(delta);
+        if *count == 0 {
+            snapshot_blocks.remove(&block_id);
+        }
+    }
+}
+
+fn diff_nodes(
+    old: &IndexTreeNode,
+    new: &IndexTreeNode,
+    path: &str,
+    changes: &mut Vec<(DiffType, String)>,
+) {
+    let (
+        IndexTreeNode::Directory {
+            children: old_children,
+            ..
+        },
+        IndexTreeNode::Directory {
+            children: new_children,
+            ..
+        },
+    ) = (old, new)
+    else {
+        return;
+    };
+    for (name, old_child) in old_children {
+        let child_path = format!("{}/{}", path, name);
+        let Some(new_child) = new_children.get(name) else {
+            changes.push((DiffType::Deleted, child_path));
+            continue;
+        };
+        if Arc::ptr_eq(old_child, new_child) {
+            continue;
+        }
+        match (old_child.as_ref(), new_child.as_ref()) {
+            (IndexTreeNode::Directory { .. }, IndexTreeNode::Directory { .. }) => {
+                if old_child.xattrs() != new_child.xattrs() {
+                    changes.push((DiffType::Modified, child_path.clone()));
+                }
+                diff_nodes(old_child, new_child, &child_path, changes)
+            }
+            (
+                IndexTreeNode::File {
+                    blocks: old_blocks,
+                    replication: old_replication,
+                    ..
+                },
+                IndexTreeNode::File {
+                    blocks: new_blocks,
+                    replication: new_replication,
+                    ..
+                },
+            ) => {
+                let changed = old_child.xattrs() != new_child.xattrs()
+                    || old_replication != new_replication
+                    || old_blocks.len() != new_blocks.len()
+                    || old_blocks
+                        .iter()
+                        .zip(new_blocks)
+                        .any(|(old, new)| old.id != new.id || old.len != new.len);
+                if changed {
+                    changes.push((DiffType::Modified, child_path));
+                }
+            }
+            (
+                IndexTreeNode::Symlink {
+                    name: _,
+                    target: old_target,
+                },
+                IndexTreeNode::Symlink {
+                    name: _,
+                    target: new_target,
+                },
+            ) => {
+                if old_target != new_target {
+                    changes.push((DiffType::Modified, child_path));
+                }
+            }
+            _ => {
+                changes.push((DiffType::Deleted, child_path.clone()));
+                changes.push((DiffType::Created, child_path));
+            }
+        }
+    }
+    for name in new_children.keys() {
+        if !old_children.contains_key(name) {
+            changes.push((DiffType::Created, format!("{}/{}", path, name)));
+        }
+    }
+}
+
+/// Whether the resolved `path` is strictly below the resolved `directory`.
+fn is_below(path: &str, directory: &str) -> bool {
+    path.strip_prefix(directory.trim_end_matches('/'))
+        .is_some_and(|rest| rest.len() > 1 && rest.starts_with('/'))
+}
+
+/// What a tree consumes towards the quotas above it.
+fn usage(node: &IndexTreeNode) -> Usage {
+    let summary = summarize(node);
+    Usage {
+        inodes: summary.directories + summary.files,
+        space: summary.space_consumed,
+    }
+}
+
+fn summarize(node: &IndexTreeNode) -> ContentSummary {
+    fn count(node: &IndexTreeNode, summary: &mut ContentSummary) {
+        match node {
+            IndexTreeNode::Directory { children, .. } => {
+                summary.directories += 1;
+                children.values().for_each(|child| count(child, summary));
+            }
+            IndexTreeNode::File {
+                blocks,
+                replication,
+                erasure_coding_policy,
+                ..
+            } => {
+                // Parity blocks take up space but hold no file data
+                let stored = blocks.iter().map(|block| block.len).sum::<u64>();
+                summary.files += 1;
+                summary.length += file_length(blocks, *erasure_coding_policy);
+                summary.space_consumed += stored * replication;
+            }
+            // Links take up a namespace entry like files
+            IndexTreeNode::Symlink { .. } => summary.files += 1,
+        }
+    }
+
+    let mut summary = ContentSummary::default();
+    count(node, &mut summary);
+    if let IndexTreeNode::Directory { quota, .. } = node {
+        summary.quota = *quota;
+    }
+    summary
+}
+
+/// Splits the blocks of an erasure-coded file into its groups.
+fn block_groups(blocks: &[Block], policy: ErasureCodingPolicy, block_size: u64) -> Vec<BlockGroup> {
+    blocks
+        .chunks(policy.group_width())
+        .map(|blocks| BlockGroup {
+            policy,
+            cell_size: ErasureCodingPolicy::cell_size(block_size),
+            blocks: blocks.to_vec(),
+        })
+        .collect()
+}
+
+/// Bytes of file data in `blocks`, leaving out the parity blocks of an
+/// erasure-coded file.
+fn file_length(blocks: &[Block], erasure_coding_policy: Option<ErasureCodingPolicy>) -> u64 {
+    match erasure_coding_policy {
+        Some(policy) => blocks
+            .chunks(policy.group_width())
+            .flat_map(|group| group.iter().take(policy.data_units()))
+            .map(|block| block.len)
+            .sum(),
+        None => blocks.iter().map(|block| block.len).sum(),
+    }
+}
+
+pub(crate) fn default_replication() -> u64 {
+    APP_CONFIG.replication_factor
+}
+
+pub(crate) fn default_block_size() -> u64 {
+    APP_CONFIG.block_size
+}
+
+fn check_xattr_name(name: &str) -> CuddlyResult<()> {
+    let valid = XATTR_NAMESPACES.iter().any(|namespace| {
+        name.strip_prefix(namespace)
+            .is_some_and(|name| !name.is_empty())
+    });
+    if !valid {
+        return Err(CuddlyError::FSError(format!(
+            "'{}': Attribute names need a namespace, one of {}",
+            name,
+            XATTR_NAMESPACES.join(", ")
+        )));
+    }
+    Ok(())
+}
+
 fn is_valid_filename(filename: &str) -> bool {
-    filename
-        .chars()
-        .all(|c| char::is_alphanumeric(c) || ALLOWED_CHARACTERS.contains(c))
+    filename == LOST_AND_FOUND
+        || filename
+            .chars()
+            .all(|c| char::is_alphanumeric(c) || ALLOWED_CHARACTERS.contains(c))
 }
 
 fn starts_with_root_directory(path: &str) -> CuddlyResult<&str> {
     path.strip_prefix("/")
         .ok_or_else(|| CuddlyError::FSError("Has to start with root directory".to_owned()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocks(lens: &[u64]) -> Vec<Block> {
+        lens.iter()
+            .enumerate()
+            .map(|(i, len)| Block::new(Uuid::new_v4(), *len, i as u64))
+            .collect()
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut state = NamenodeState::new();
+        let file_blocks = blocks(&[10, 10, 5]);
+        state.create_file("/a", &file_blocks, 3, 1024).unwrap();
+
+        assert!(state.truncate("/a", 26, 1).is_err());
+
+        let (removed, shortened) = state.truncate("/a", 20, 1).unwrap();
+        assert_eq!(removed, vec![file_blocks[2]]);
+        assert!(shortened.is_none());
+
+        let (removed, shortened) = state.truncate("/a", 4, 2).unwrap();
+        assert_eq!(removed, vec![file_blocks[1]]);
+        assert_eq!(
+            shortened.map(|block| (block.len, block.generation)),
+            Some((4, 2))
+        );
+        assert_eq!(state.open_file("/a").unwrap()[0].len, 4);
+        assert_eq!(state.open_file("/a").unwrap()[0].generation, 2);
+
+        let (removed, shortened) = state.truncate("/a", 0, 3).unwrap();
+        assert_eq!(removed, vec![file_blocks[0]]);
+        assert!(shortened.is_none());
+        assert!(state.open_file("/a").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_truncate_in_snapshot() {
+        let mut state = NamenodeState::new();
+        let file_blocks = blocks(&[10, 10]);
+        state.make_dir("/d").unwrap();
+        state.create_file("/d/a", &file_blocks, 3, 1024).unwrap();
+        state.create_snapshot("/d", "s1").unwrap();
+
+        // The last block would be shortened in place under the snapshot
+        assert!(state.truncate("/d/a", 15, 1).is_err());
+        assert_eq!(state.open_file("/d/a").unwrap(), &file_blocks[..]);
+        assert_eq!(
+            state.open_file("/d/.snapshot/s1/a").unwrap(),
+            &file_blocks[..]
+        );
+
+        // Whole blocks can go, the snapshot keeps them
+        let (removed, shortened) = state.truncate("/d/a", 10, 1).unwrap();
+        assert!(shortened.is_none());
+        assert!(state.unreferenced(removed).is_empty());
+        assert_eq!(
+            state.open_file("/d/.snapshot/s1/a").unwrap(),
+            &file_blocks[..]
+        );
+    }
+
+    #[test]
+    fn test_image_keeps_snapshots_shared() {
+        let mut state = NamenodeState::new();
+        let a = blocks(&[10]);
+        let b = blocks(&[7, 3]);
+        state.make_dir("/d/sub").unwrap();
+        state.create_file("/d/a", &a, 3, 1024).unwrap();
+        state.create_file("/d/sub/b", &b, 3, 1024).unwrap();
+        state.create_snapshot("/d", "s1").unwrap();
+        state.create_snapshot("/d/sub", "t1").unwrap();
+        state.delete("/d/a").unwrap();
+
+        // Each file is stored once, however many snapshots share it
+        let image = serde_json::to_string(&state).unwrap();
+        assert_eq!(image.matches(&b[0].id.to_string()).count(), 1);
+        let loaded: NamenodeState = serde_json::from_str(&image).unwrap();
+        let shared = |state: &NamenodeState| {
+            std::ptr::eq(
+                state.node_at("/d/sub/b").unwrap(),
+                state.node_at("/d/.snapshot/s1/sub/b").unwrap(),
+            )
+        };
+        assert!(shared(&state));
+        assert!(shared(&loaded));
+        assert_eq!(loaded.open_file("/d/.snapshot/s1/a").unwrap(), &a[..]);
+        assert!(loaded.in_snapshot(&a[0].id));
+        assert!(loaded.in_snapshot(&b[1].id));
+
+        // Images holding the tree itself still load
+        let nested = serde_json::json!({ "root": &state.root }).to_string();
+        let mut loaded: NamenodeState = serde_json::from_str(&nested).unwrap();
+        assert_eq!(loaded.open_file("/d/sub/b").unwrap(), &b[..]);
+        assert!(loaded.delete_snapshot("/d/sub", "t1").unwrap().is_empty());
+        assert_eq!(loaded.delete_snapshot("/d", "s1").unwrap(), a);
+        assert!(!loaded.in_snapshot(&b[0].id));
+    }
+
+    #[test]
+    fn test_quota() {
+        let mut state = NamenodeState::new();
+        state.make_dir("/q/sub").unwrap();
+        state.create_file("/q/a", &blocks(&[10]), 3, 1024).unwrap();
+        state
+            .set_quota(
+                "/q",
+                Quota {
+                    namespace: Some(4),
+                    space: Some(100),
+                },
+            )
+            .unwrap();
+        assert!(state.set_quota("/q/a", Quota::default()).is_err());
+
+        let summary = state.content_summary("/q").unwrap();
+        assert_eq!(summary.directories, 2);
+        assert_eq!(summary.files, 1);
+        assert_eq!(summary.space_consumed, 30);
+
+        assert_eq!(state.missing_directories("/q/sub/x/y").unwrap(), 2);
+        assert!(state.check_quota("/q/sub/b", 1, 70).is_ok());
+        assert!(matches!(
+            state.check_quota("/q/sub/x/y", 2, 0),
+            Err(CuddlyError::QuotaExceeded(_))
+        ));
+        assert!(matches!(
+            state.check_quota("/q/b", 1, 71),
+            Err(CuddlyError::QuotaExceeded(_))
+        ));
+        // Only directories above the path count
+        assert!(state.check_quota("/other", 100, 1000).is_ok());
+    }
+
+    #[test]
+    fn test_quota_usage() {
+        let mut state = NamenodeState::new();
+        state.make_dir("/q/sub").unwrap();
+        state.make_dir("/other").unwrap();
+        state
+            .set_quota(
+                "/q",
+                Quota {
+                    namespace: None,
+                    space: Some(100),
+                },
+            )
+            .unwrap();
+        let exceeded =
+            |result: CuddlyResult<()>| matches!(result, Err(CuddlyError::QuotaExceeded(_)));
+
+        // Blocks being written count until their file is committed
+        state.reserve("/q/sub/a", 60).unwrap();
+        assert!(exceeded(state.reserve("/q/b", 41)));
+        state
+            .create_file("/q/sub/a", &blocks(&[20]), 3, 1024)
+            .unwrap();
+        state.release("/q/sub/a");
+        assert!(state.check_quota("/q/b", 1, 40).is_ok());
+        assert!(exceeded(state.check_quota("/q/b", 1, 41)));
+
+        state
+            .create_file("/other/big", &blocks(&[50]), 1, 1024)
+            .unwrap();
+        assert!(exceeded(state.check_rename_quota("/other/big", "/q/big")));
+        assert!(exceeded(
+            state.check_concat_quota("/q/sub/a", &["/other/big".to_owned()])
+        ));
+        assert!(state.check_rename_quota("/q/sub/a", "/q/a").is_ok());
+        state.rename("/q/sub/a", "/other/a").unwrap();
+        state.rename("/other/big", "/q/big").unwrap();
+        assert!(exceeded(state.check_quota("/q/b", 1, 51)));
+
+        // The quota moves with its directory and is rebuilt from an image
+        state.rename("/q", "/r").unwrap();
+        let image = serde_json::to_string(&state).unwrap();
+        let mut state: NamenodeState = serde_json::from_str(&image).unwrap();
+        assert!(state.check_quota("/r/b", 1, 50).is_ok());
+        assert!(exceeded(state.check_quota("/r/b", 1, 51)));
+        state.set_replication("/r/big", 2).unwrap();
+        assert!(exceeded(state.check_quota("/r/b", 1, 1)));
+        state.delete("/r/big").unwrap();
+        assert!(state.check_quota("/r/b", 1, 100).is_ok());
+        assert!(state.check_quota("/q/b", 1, 1000).is_ok());
+    }
+
+    #[test]
+    fn test_concat() {
+        let mut state = NamenodeState::new();
+        let target_blocks = blocks(&[10]);
+        let source_blocks = blocks(&[10, 3]);
+        state
+            .create_file("/target", &target_blocks, 3, 1024)
+            .unwrap();
+        state
+            .create_file("/source", &source_blocks, 3, 1024)
+            .unwrap();
+        state.create_file("/other", &blocks(&[1]), 3, 1024).unwrap();
+
+        assert!(state
+            .concat("/target", &["/source".to_owned(), "/target".to_owned()])
+            .is_err());
+        assert!(state
+            .concat("/target", &["/source".to_owned(), "/missing".to_owned()])
+            .is_err());
+        // Failed concats leave the sources in place
+        assert_eq!(state.open_file("/source").unwrap().len(), 2);
+
+        let blocks = state
+            .concat("/target", &["/source".to_owned(), "/other".to_owned()])
+            .unwrap();
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks[1], source_blocks[0]);
+        assert_eq!(
+            blocks.iter().map(|block| block.seq).collect::<Vec<_>>(),
+            vec![0, 1, 2, 3]
+        );
+        assert!(state.open_file("/source").is_err());
+        assert!(state.open_file("/other").is_err());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut state = NamenodeState::new();
+        let a = blocks(&[10, 5]);
+        let b = blocks(&[7]);
+        state.make_dir("/d/sub").unwrap();
+        state.create_file("/d/a", &a, 3, 1024).unwrap();
+        state.create_file("/d/sub/b", &b, 3, 1024).unwrap();
+        state.create_snapshot("/d", "s1").unwrap();
+        assert!(state.create_snapshot("/d", "s1").is_err());
+        assert!(state.create_snapshot("/d/a", "s2").is_err());
+        assert!(state.check_file_creation("/d/.snapshot").is_err());
+
+        assert_eq!(state.list("/d/.snapshot").unwrap(), vec!["s1"]);
+        assert_eq!(state.open_file("/d/.snapshot/s1/sub/b").unwrap(), &b[..]);
+
+        // The live file changes, the snapshot keeps the old blocks
+        assert!(state.truncate("/d/a", 12, 1).is_err());
+        let (removed, _) = state.truncate("/d/a", 10, 1).unwrap();
+        assert!(state.unreferenced(removed).is_empty());
+        assert!(state.in_snapshot(&a[1].id));
+        assert_eq!(state.open_file("/d/.snapshot/s1/a").unwrap(), &a[..]);
+        let removed = state.delete("/d/sub/b").unwrap();
+        assert!(state.unreferenced(removed).is_empty());
+        state.create_file("/d/c", &blocks(&[1]), 3, 1024).unwrap();
+        assert_eq!(state.blocks().len(), 4);
+
+        assert_eq!(
+            state.snapshot_diff("/d", "s1", None).unwrap(),
+            vec![
+                (DiffType::Modified, "/d/a".to_owned()),
+                (DiffType::Created, "/d/c".to_owned()),
+                (DiffType::Deleted, "/d/sub/b".to_owned()),
+            ]
+        );
+        state.create_snapshot("/d", "s2").unwrap();
+        assert!(state.snapshot_diff("/d", "s2", None).unwrap().is_empty());
+
+        let mut removed = state.delete_snapshot("/d", "s1").unwrap();
+        removed.sort_by_key(|block| block.len);
+        assert_eq!(removed, vec![a[1], b[0]]);
+        assert!(state.delete_snapshot("/d", "s1").is_err());
+        assert_eq!(state.list_snapshots("/d").unwrap(), vec!["s2"]);
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut state = NamenodeState::new();
+        let a = blocks(&[10]);
+        let b = blocks(&[5, 5]);
+        state.make_dir("/user/alice/.Trash/Current/d/sub").unwrap();
+        assert!(state.make_dir("/d/..").is_err());
+        assert!(state.make_dir("/d/.snapshot").is_err());
+        assert!(state.make_dir("/d/a+b").is_err());
+        state.make_dir("/lost+found/d").unwrap();
+        state.make_dir("/d/sub").unwrap();
+        state.create_file("/d/a", &a, 3, 1024).unwrap();
+        state.create_file("/d/sub/b", &b, 3, 1024).unwrap();
+        assert!(state.is_directory("/d").unwrap());
+        assert!(!state.is_directory("/d/a").unwrap());
+
+        state
+            .rename("/d", "/user/alice/.Trash/Current/d/sub/d")
+            .unwrap();
+        assert!(state.is_directory("/d").is_err());
+
+        state.create_snapshot("/user/alice", "s").unwrap();
+        assert!(state.delete("/user").is_err());
+        state.delete_snapshot("/user/alice", "s").unwrap();
+
+        let mut removed = state.delete("/user/alice/.Trash/Current").unwrap();
+        removed.sort_by_key(|block| (block.len, block.seq));
+        assert_eq!(removed, vec![b[0], b[1], a[0]]);
+        assert!(state.list("/user/alice/.Trash").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_symlink() {
+        let mut state = NamenodeState::new();
+        let v1 = blocks(&[10]);
+        let v2 = blocks(&[20]);
+        state.make_dir("/data/v1").unwrap();
+        state.make_dir("/data/v2").unwrap();
+        state.create_file("/data/v1/f", &v1, 3, 1024).unwrap();
+        state.create_file("/data/v2/f", &v2, 3, 1024).unwrap();
+
+        state.create_symlink("/current", "/data/v1", false).unwrap();
+        assert_eq!(state.open_file("/current/f").unwrap(), &v1[..]);
+        assert_eq!(state.list("/current").unwrap(), vec!["f"]);
+        assert!(state.create_symlink("/current", "data/v2", false).is_err());
+        assert!(state.create_symlink("/data", "data/v2", true).is_err());
+        state.create_symlink("/current", "data/v2", true).unwrap();
+        assert_eq!(state.open_file("/current/f").unwrap(), &v2[..]);
+
+        let status = state.stat("/current", false).unwrap();
+        assert_eq!(status.file_type, FileType::Symlink);
+        assert_eq!(status.symlink_target.as_deref(), Some("data/v2"));
+        assert_eq!(
+            state.stat("/current", true).unwrap().file_type,
+            FileType::Directory
+        );
+        assert_eq!(state.stat("/current/f", false).unwrap().length, 20);
+
+        // Creations below a link land in its target
+        state.check_file_creation("/current/g").unwrap();
+        state.create_file("/current/g", &[], 3, 1024).unwrap();
+        state.make_dir("/current/sub").unwrap();
+        assert_eq!(state.list("/data/v2").unwrap().len(), 3);
+
+        state
+            .create_symlink("/data/v2/prev", "../v1", false)
+            .unwrap();
+        assert_eq!(state.open_file("/current/prev/f").unwrap(), &v1[..]);
+
+        state.create_symlink("/loop1", "/loop2", false).unwrap();
+        state.create_symlink("/loop2", "loop1", false).unwrap();
+        assert!(state.open_file("/loop1").is_err());
+        assert!(state.delete("/loop1").unwrap().is_empty());
+
+        // Deleting a link keeps its target
+        assert!(state.delete("/current").unwrap().is_empty());
+        assert_eq!(state.open_file("/data/v2/f").unwrap(), &v2[..]);
+    }
+
+    #[test]
+    fn test_xattrs() {
+        let mut state = NamenodeState::new();
+        state.make_dir("/data").unwrap();
+        state
+            .create_file("/data/f", &blocks(&[1]), 3, 1024)
+            .unwrap();
+        state.create_symlink("/current", "/data", false).unwrap();
+
+        state.set_xattr("/current/f", "user.schema", b"v1").unwrap();
+        state.set_xattr("/data", "trusted.owner", b"etl").unwrap();
+        assert!(state.set_xattr("/data", "schema", b"v1").is_err());
+        assert!(state.set_xattr("/data", "user.", b"v1").is_err());
+        assert_eq!(
+            state.xattrs("/data/f").unwrap().get("user.schema").unwrap(),
+            b"v1"
+        );
+
+        state.create_snapshot("/data", "s").unwrap();
+        state.set_xattr("/data/f", "user.schema", b"v2").unwrap();
+        assert_eq!(state.xattrs("/data/f").unwrap()["user.schema"], b"v2");
+        assert_eq!(
+            state.xattrs("/data/.snapshot/s/f").unwrap()["user.schema"],
+            b"v1"
+        );
+        assert_eq!(
+            state.xattrs("/data/.snapshot/s").unwrap()["trusted.owner"],
+            b"etl"
+        );
+
+        state.remove_xattr("/data/f", "user.schema").unwrap();
+        assert!(state.remove_xattr("/data/f", "user.schema").is_err());
+        assert!(state.xattrs("/data/f").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_replication() {
+        let mut state = NamenodeState::new();
+        let a = blocks(&[10, 5]);
+        state.make_dir("/d").unwrap();
+        state.create_file("/d/a", &a, 2, 1024).unwrap();
+        state.create_file("/d/b", &blocks(&[4]), 1, 2048).unwrap();
+
+        let status = state.stat("/d/a", true).unwrap();
+        assert_eq!((status.replication, status.block_size), (2, 1024));
+        assert_eq!(state.content_summary("/d").unwrap().space_consumed, 34);
+
+        state.create_snapshot("/d", "s").unwrap();
+        assert_eq!(state.set_replication("/d/a", 1).unwrap(), a);
+        assert!(state.set_replication("/d", 1).is_err());
+        assert_eq!(state.stat("/d/a", true).unwrap().replication, 1);
+        assert_eq!(state.stat("/d/.snapshot/s/a", true).unwrap().replication, 2);
+        assert_eq!(state.content_summary("/d").unwrap().space_consumed, 19);
+        // The snapshot still needs two replicas
+        assert_eq!(state.block_replication()[&a[0].id], 2);
+        assert_eq!(
+            state.snapshot_diff("/d", "s", None).unwrap(),
+            vec![(DiffType::Modified, "/d/a".to_owned())]
+        );
+
+        state.set_replication("/d/a", 4).unwrap();
+        assert_eq!(state.block_replication()[&a[1].id], 4);
+    }
+
+    #[test]
+    fn test_storage_policy() {
+        let mut state = NamenodeState::new();
+        let a = blocks(&[10]);
+        state.make_dir("/cold/logs").unwrap();
+        state.create_file("/cold/logs/a", &a, 3, 1024).unwrap();
+        assert_eq!(
+            state.storage_policy("/cold/logs/a").unwrap(),
+            StoragePolicy::Hot
+        );
+
+        assert_eq!(
+            state
+                .set_storage_policy("/cold", Some(StoragePolicy::Cold))
+                .unwrap(),
+            a
+        );
+        assert_eq!(
+            state.storage_policy("/cold/logs/a").unwrap(),
+            StoragePolicy::Cold
+        );
+        // Files that are about to be created inherit the policy too
+        assert_eq!(
+            state.storage_policy("/cold/logs/new").unwrap(),
+            StoragePolicy::Cold
+        );
+        assert_eq!(
+            state.block_storage_policies()[&a[0].id],
+            StoragePolicy::Cold
+        );
+
+        state.create_snapshot("/cold", "s").unwrap();
+        state
+            .set_storage_policy("/cold/logs/a", Some(StoragePolicy::OneSsd))
+            .unwrap();
+        assert_eq!(
+            state.storage_policy("/cold/.snapshot/s/logs/a").unwrap(),
+            StoragePolicy::Cold
+        );
+        assert_eq!(
+            state.block_storage_policies()[&a[0].id],
+            StoragePolicy::OneSsd
+        );
+
+        state.set_storage_policy("/cold/logs/a", None).unwrap();
+        state.set_storage_policy("/cold", None).unwrap();
+        assert_eq!(
+            state.storage_policy("/cold/logs/a").unwrap(),
+            StoragePolicy::Hot
+        );
+    }
+
+    #[test]
+    fn test_erasure_coding_policy() {
+        let mut state = NamenodeState::new();
+        let policy = ErasureCodingPolicy::Rs3_2;
+        state.make_dir("/ec/logs").unwrap();
+        assert!(state.set_erasure_coding_policy("/ec", Some(policy)).is_ok());
+        assert_eq!(
+            state.erasure_coding_policy("/ec/logs/new").unwrap(),
+            Some(policy)
+        );
+
+        // A group of three data blocks and two parity blocks
+        let group = blocks(&[1024, 1024, 500, 1024, 1024]);
+        state
+            .create_striped_file("/ec/logs/a", &group, policy, 1024)
+            .unwrap();
+        assert!(state.set_erasure_coding_policy("/ec/logs/a", None).is_err());
+        let status = state.stat("/ec/logs/a", true).unwrap();
+        assert_eq!(status.length, 2548);
+        assert_eq!(status.replication, 1);
+        assert_eq!(status.erasure_coding_policy, Some(policy));
+
+        let striped = state.striped_blocks();
+        assert_eq!(striped.len(), 5);
+        assert_eq!(striped[&group[4].id].blocks, group);
+        assert_eq!(striped[&group[4].id].data_len(), 2548);
+
+        state.create_file("/b", &blocks(&[10]), 3, 1024).unwrap();
+        assert!(state.truncate("/ec/logs/a", 10, 1).is_err());
+        assert!(state.set_replication("/ec/logs/a", 3).is_err());
+        assert!(state.concat("/b", &["/ec/logs/a".to_owned()]).is_err());
+
+        // Files keep the layout they were written with
+        state.set_erasure_coding_policy("/ec", None).unwrap();
+        assert_eq!(state.erasure_coding_policy("/ec/logs/new").unwrap(), None);
+        assert_eq!(
+            state.erasure_coding_policy("/ec/logs/a").unwrap(),
+            Some(policy)
+        );
+    }
+}
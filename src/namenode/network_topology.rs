@@ -0,0 +1,264 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::IpAddr,
+    path::Path,
+};
+
+use log::info;
+use uuid::Uuid;
+
+use crate::errors::{CuddlyError, CuddlyResult};
+
+use super::datanode_info::DatanodeInfo;
+
+/// Location of datanodes that did not declare one and are not in the mapping.
+pub(crate) const DEFAULT_RACK: &str = "/default-rack";
+
+#[derive(Debug, Default)]
+struct InnerNode {
+    children: BTreeMap<String, InnerNode>,
+    leaves: HashSet<Uuid>,
+}
+
+impl InnerNode {
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.leaves.is_empty()
+    }
+
+    fn count_racks(&self) -> usize {
+        let own = usize::from(!self.leaves.is_empty());
+        own + self
+            .children
+            .values()
+            .map(|child| child.count_racks())
+            .sum::<usize>()
+    }
+}
+
+/// A tree of network locations such as `/dc1/rack3`, with datanodes as leaves.
+///
+/// Locations are either declared by the datanodes themselves or taken from a
+/// mapping file on the namenode, which maps IP addresses or hostnames to
+/// locations and wins over the declared location.
+#[derive(Debug, Default)]
+pub(crate) struct NetworkTopology {
+    root: InnerNode,
+    node_locations: HashMap<Uuid, (IpAddr, String)>,
+    mapping: HashMap<String, String>,
+}
+
+impl NetworkTopology {
+    pub(crate) fn new(mapping: HashMap<String, String>) -> Self {
+        Self {
+            mapping,
+            ..Default::default()
+        }
+    }
+
+    /// Loads a mapping file with one `<ip or hostname> <location>` pair per
+    /// line. Empty lines and lines starting with `#` are ignored.
+    pub(crate) fn load_mapping(path: &Path) -> CuddlyResult<HashMap<String, String>> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut mapping = HashMap::new();
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut columns = line.split_whitespace();
+            match (columns.next(), columns.next(), columns.next()) {
+                (Some(host), Some(location), None) => {
+                    mapping.insert(host.to_owned(), normalize_location(location)?);
+                }
+                _ => {
+                    return Err(CuddlyError::ConfigError(format!(
+                        "{:?}:{}: expected '<host> <location>'",
+                        path,
+                        line_number + 1
+                    )))
+                }
+            }
+        }
+        info!("Loaded {} topology mappings from {:?}", mapping.len(), path);
+        Ok(mapping)
+    }
+
+    /// Decides the location of a datanode, preferring the mapping file over
+    /// the location the datanode declared.
+    pub(crate) fn resolve(&self, ip: IpAddr, hostname: &str, declared: &str) -> String {
+        if let Some(location) = self
+            .mapping
+            .get(&ip.to_string())
+            .or_else(|| self.mapping.get(hostname))
+        {
+            return location.clone();
+        }
+        normalize_location(declared).unwrap_or_else(|_| DEFAULT_RACK.to_owned())
+    }
+
+    /// Adds a datanode at `location`, moving it if it was known elsewhere.
+    pub(crate) fn add(&mut self, datanode_uuid: Uuid, ip: IpAddr, location: String) {
+        match self.node_locations.get(&datanode_uuid) {
+            Some((current_ip, current)) if *current_ip == ip && *current == location => return,
+            Some(_) => self.remove(&datanode_uuid),
+            None => (),
+        }
+
+        let mut node = &mut self.root;
+        for part in location_parts(&location) {
+            node = node.children.entry(part.to_owned()).or_default();
+        }
+        node.leaves.insert(datanode_uuid);
+        info!(
+            "Datanode {} added at {}, topology now has {} racks",
+            datanode_uuid,
+            location,
+            self.num_racks()
+        );
+        self.node_locations.insert(datanode_uuid, (ip, location));
+    }
+
+    pub(crate) fn remove(&mut self, datanode_uuid: &Uuid) {
+        let Some((_, location)) = self.node_locations.remove(datanode_uuid) else {
+            return;
+        };
+
+        fn remove_leaf(node: &mut InnerNode, parts: &[&str], datanode_uuid: &Uuid) {
+            match parts.split_first() {
+                None => {
+                    node.leaves.remove(datanode_uuid);
+                }
+                Some((first, rest)) => {
+                    if let Some(child) = node.children.get_mut(*first) {
+                        remove_leaf(child, rest, datanode_uuid);
+                        if child.is_empty() {
+                            node.children.remove(*first);
+                        }
+                    }
+                }
+            }
+        }
+        let parts = location_parts(&location).collect::<Vec<_>>();
+        remove_leaf(&mut self.root, &parts, datanode_uuid);
+    }
+
+    pub(crate) fn location_of(&self, datanode_uuid: &Uuid) -> Option<&str> {
+        self.node_locations
+            .get(datanode_uuid)
+            .map(|(_, location)| location.as_str())
+    }
+
+    /// Location of a client, either from the mapping file or because a
+    /// datanode runs on the same host.
+    pub(crate) fn location_of_ip(&self, ip: IpAddr) -> Option<&str> {
+        self.mapping
+            .get(&ip.to_string())
+            .or_else(|| {
+                self.node_locations
+                    .values()
+                    .find(|(node_ip, _)| *node_ip == ip)
+                    .map(|(_, location)| location)
+            })
+            .map(String::as_str)
+    }
+
+    pub(crate) fn num_racks(&self) -> usize {
+        self.root.count_racks()
+    }
+
+    /// Number of hops between two datanodes at the given locations, counting
+    /// the step from each node up to its rack.
+    pub(crate) fn distance(first: &str, second: &str) -> usize {
+        let first = location_parts(first).collect::<Vec<_>>();
+        let second = location_parts(second).collect::<Vec<_>>();
+        let common = first
+            .iter()
+            .zip(second.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        (first.len() - common) + (second.len() - common) + 2
+    }
+
+    /// Sorts `nodes` so that the ones closest to `reader` come first. Nodes
+    /// are left in their order if the reader's location is not known.
+    pub(crate) fn sort_by_distance(&self, reader: Option<IpAddr>, nodes: &mut [DatanodeInfo]) {
+        let Some(reader) = reader else {
+            return;
+        };
+        let reader_location = self.location_of_ip(reader);
+        nodes.sort_by_cached_key(|node| {
+            if node.socket_address.ip() == reader {
+                return 0;
+            }
+            match (reader_location, self.location_of(&node.datanode_uuid)) {
+                (Some(reader_location), Some(node_location)) => {
+                    Self::distance(reader_location, node_location)
+                }
+                _ => usize::MAX,
+            }
+        });
+    }
+}
+
+fn location_parts(location: &str) -> impl Iterator<Item = &str> {
+    location.split('/').filter(|part| !part.is_empty())
+}
+
+fn normalize_location(location: &str) -> CuddlyResult<String> {
+    if !location.starts_with('/') || location_parts(location).next().is_none() {
+        return Err(CuddlyError::ConfigError(format!(
+            "'{}' is not a valid network location",
+            location
+        )));
+    }
+    Ok(location_parts(location).fold(String::new(), |acc, part| acc + "/" + part))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(NetworkTopology::distance("/dc1/rack1", "/dc1/rack1"), 2);
+        assert_eq!(NetworkTopology::distance("/dc1/rack1", "/dc1/rack2"), 4);
+        assert_eq!(NetworkTopology::distance("/dc1/rack1", "/dc2/rack1"), 6);
+    }
+
+    #[test]
+    fn test_add_move_and_remove() {
+        let mut topology = NetworkTopology::default();
+        let first = Uuid::from_u128(1);
+        let second = Uuid::from_u128(2);
+        topology.add(first, [10, 0, 0, 1].into(), "/dc1/rack1".into());
+        topology.add(second, [10, 0, 0, 2].into(), "/dc1/rack2".into());
+        assert_eq!(topology.num_racks(), 2);
+
+        topology.add(second, [10, 0, 0, 2].into(), "/dc1/rack1".into());
+        assert_eq!(topology.num_racks(), 1);
+        assert_eq!(topology.location_of(&second), Some("/dc1/rack1"));
+
+        topology.remove(&first);
+        topology.remove(&second);
+        assert_eq!(topology.num_racks(), 0);
+        assert!(topology.root.is_empty());
+    }
+
+    #[test]
+    fn test_mapping_wins_over_declared_location() {
+        let mapping = HashMap::from([("10.0.0.1".to_owned(), "/dc2/rack9".to_owned())]);
+        let topology = NetworkTopology::new(mapping);
+        assert_eq!(
+            topology.resolve([10, 0, 0, 1].into(), "host1", "/dc1/rack1"),
+            "/dc2/rack9"
+        );
+        assert_eq!(
+            topology.resolve([10, 0, 0, 2].into(), "host2", "/dc1/rack1/"),
+            "/dc1/rack1"
+        );
+        assert_eq!(
+            topology.resolve([10, 0, 0, 3].into(), "host3", ""),
+            DEFAULT_RACK
+        );
+    }
+}
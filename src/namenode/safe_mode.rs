@@ -0,0 +1,178 @@
+use std::sync::Mutex;
+
+use log::info;
+
+use crate::{
+    cuddlyproto,
+    errors::{CuddlyError, CuddlyResult},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct SafeModeStatus {
+    pub(crate) on: bool,
+    pub(crate) manual: bool,
+    pub(crate) reported_blocks: u64,
+    pub(crate) total_blocks: u64,
+    pub(crate) live_datanodes: u32,
+    pub(crate) threshold: f64,
+    pub(crate) min_datanodes: u32,
+}
+
+impl From<SafeModeStatus> for cuddlyproto::SafeModeResponse {
+    fn from(value: SafeModeStatus) -> Self {
+        Self {
+            on: value.on,
+            manual: value.manual,
+            reported_blocks: value.reported_blocks,
+            total_blocks: value.total_blocks,
+            live_datanodes: value.live_datanodes,
+            threshold: value.threshold,
+            min_datanodes: value.min_datanodes,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SafeModeState {
+    on: bool,
+    manual: bool,
+}
+
+/// Read-only mode of the namenode. It is on after startup until enough
+/// blocks have been reported by enough datanodes, or while an admin holds it.
+#[derive(Debug)]
+pub(super) struct SafeMode {
+    state: Mutex<SafeModeState>,
+    threshold: f64,
+    min_datanodes: u32,
+}
+
+impl SafeMode {
+    pub(super) fn new(threshold: f64, min_datanodes: u32) -> Self {
+        info!(
+            "Starting in safe mode until {:.1}% of blocks are reported by at least {} datanodes",
+            threshold * 100.0,
+            min_datanodes
+        );
+        Self {
+            state: Mutex::new(SafeModeState {
+                on: true,
+                manual: false,
+            }),
+            threshold,
+            min_datanodes,
+        }
+    }
+
+    pub(super) fn is_on(&self) -> bool {
+        self.state.lock().unwrap().on
+    }
+
+    /// Fails with `SafeModeError` if mutations are not allowed.
+    pub(super) fn check(&self, operation: &str) -> CuddlyResult<()> {
+        if self.is_on() {
+            return Err(CuddlyError::SafeModeError(format!(
+                "Cannot {}, the namenode is in safe mode",
+                operation
+            )));
+        }
+        Ok(())
+    }
+
+    pub(super) fn enter(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.on = true;
+        state.manual = true;
+        info!("Safe mode entered manually");
+    }
+
+    pub(super) fn leave(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.on = false;
+        state.manual = false;
+        info!("Safe mode left manually");
+    }
+
+    /// Leaves safe mode if it was entered on startup and the thresholds are
+    /// reached. Returns whether safe mode is still on.
+    pub(super) fn update(
+        &self,
+        reported_blocks: u64,
+        total_blocks: u64,
+        live_datanodes: u32,
+    ) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.on
+            && !state.manual
+            && self.thresholds_reached(reported_blocks, total_blocks, live_datanodes)
+        {
+            state.on = false;
+            info!(
+                "Leaving safe mode, {} of {} blocks reported by {} live datanodes",
+                reported_blocks, total_blocks, live_datanodes
+            );
+        }
+        state.on
+    }
+
+    fn thresholds_reached(
+        &self,
+        reported_blocks: u64,
+        total_blocks: u64,
+        live_datanodes: u32,
+    ) -> bool {
+        let reported_fraction = if total_blocks == 0 {
+            1.0
+        } else {
+            reported_blocks as f64 / total_blocks as f64
+        };
+        reported_fraction >= self.threshold && live_datanodes >= self.min_datanodes
+    }
+
+    pub(super) fn status(
+        &self,
+        reported_blocks: u64,
+        total_blocks: u64,
+        live_datanodes: u32,
+    ) -> SafeModeStatus {
+        let state = self.state.lock().unwrap();
+        SafeModeStatus {
+            on: state.on,
+            manual: state.manual,
+            reported_blocks,
+            total_blocks,
+            live_datanodes,
+            threshold: self.threshold,
+            min_datanodes: self.min_datanodes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leaves_once_thresholds_are_reached() {
+        let safe_mode = SafeMode::new(0.9, 2);
+        assert!(safe_mode.check("mkdir").is_err());
+        assert!(safe_mode.update(95, 100, 1));
+        assert!(safe_mode.update(80, 100, 3));
+        assert!(!safe_mode.update(90, 100, 2));
+        assert!(safe_mode.check("mkdir").is_ok());
+
+        // Once left, it is not re-entered automatically.
+        assert!(!safe_mode.update(0, 100, 0));
+    }
+
+    #[test]
+    fn test_manual_safe_mode_is_held() {
+        let safe_mode = SafeMode::new(0.5, 0);
+        assert!(!safe_mode.update(0, 0, 0));
+        safe_mode.enter();
+        assert!(safe_mode.update(10, 10, 5));
+        assert!(safe_mode.status(10, 10, 5).manual);
+        safe_mode.leave();
+        assert!(!safe_mode.is_on());
+    }
+}
@@ -0,0 +1,214 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cuddlyproto,
+    errors::{CuddlyError, CuddlyResult},
+};
+
+/// The media a datanode storage is on.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageType {
+    #[default]
+    Disk,
+    Ssd,
+    Archive,
+    RamDisk,
+    Provided,
+    Nvdimm,
+}
+
+impl From<cuddlyproto::StorageTypeProto> for StorageType {
+    fn from(value: cuddlyproto::StorageTypeProto) -> Self {
+        match value {
+            cuddlyproto::StorageTypeProto::Disk => Self::Disk,
+            cuddlyproto::StorageTypeProto::Ssd => Self::Ssd,
+            cuddlyproto::StorageTypeProto::Archive => Self::Archive,
+            cuddlyproto::StorageTypeProto::RamDisk => Self::RamDisk,
+            cuddlyproto::StorageTypeProto::Provided => Self::Provided,
+            cuddlyproto::StorageTypeProto::Nvdimm => Self::Nvdimm,
+        }
+    }
+}
+
+impl From<StorageType> for cuddlyproto::StorageTypeProto {
+    fn from(value: StorageType) -> Self {
+        match value {
+            StorageType::Disk => Self::Disk,
+            StorageType::Ssd => Self::Ssd,
+            StorageType::Archive => Self::Archive,
+            StorageType::RamDisk => Self::RamDisk,
+            StorageType::Provided => Self::Provided,
+            StorageType::Nvdimm => Self::Nvdimm,
+        }
+    }
+}
+
+impl StorageType {
+    /// Reads a storage type sent as a protobuf enum value. Unknown values
+    /// are taken as disk.
+    pub(crate) fn from_proto(value: i32) -> Self {
+        cuddlyproto::StorageTypeProto::try_from(value)
+            .map(Self::from)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn to_proto(self) -> i32 {
+        cuddlyproto::StorageTypeProto::from(self) as i32
+    }
+}
+
+impl fmt::Display for StorageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Disk => "DISK",
+            Self::Ssd => "SSD",
+            Self::Archive => "ARCHIVE",
+            Self::RamDisk => "RAM_DISK",
+            Self::Provided => "PROVIDED",
+            Self::Nvdimm => "NVDIMM",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Decides on which storage types the replicas of a file are placed. Set on
+/// a file or directory, and inherited from the closest ancestor otherwise.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum StoragePolicy {
+    /// Every replica on disk.
+    #[default]
+    Hot,
+    /// The first replica on disk, the others on archive.
+    Warm,
+    /// Every replica on archive.
+    Cold,
+    /// The first replica on SSD, the others on disk.
+    OneSsd,
+    /// Every replica on SSD.
+    AllSsd,
+}
+
+impl StoragePolicy {
+    pub const ALL: [StoragePolicy; 5] = [
+        StoragePolicy::Hot,
+        StoragePolicy::Warm,
+        StoragePolicy::Cold,
+        StoragePolicy::OneSsd,
+        StoragePolicy::AllSsd,
+    ];
+
+    /// The storage type of each of `replication` replicas.
+    pub fn storage_types(self, replication: usize) -> Vec<StorageType> {
+        let (first, others) = match self {
+            Self::Hot => (StorageType::Disk, StorageType::Disk),
+            Self::Warm => (StorageType::Disk, StorageType::Archive),
+            Self::Cold => (StorageType::Archive, StorageType::Archive),
+            Self::OneSsd => (StorageType::Ssd, StorageType::Disk),
+            Self::AllSsd => (StorageType::Ssd, StorageType::Ssd),
+        };
+        std::iter::once(first)
+            .chain(std::iter::repeat(others))
+            .take(replication)
+            .collect()
+    }
+
+    /// The storage type a new replica goes to when no datanode has room on
+    /// `storage_type`, if the policy allows one.
+    pub fn fallback(self, storage_type: StorageType) -> Option<StorageType> {
+        match (self, storage_type) {
+            (Self::Warm, StorageType::Disk) => Some(StorageType::Archive),
+            (Self::Warm, StorageType::Archive) => Some(StorageType::Disk),
+            (Self::OneSsd | Self::AllSsd, StorageType::Ssd) => Some(StorageType::Disk),
+            _ => None,
+        }
+    }
+
+    /// Compares the storage types of the existing replicas of a block with
+    /// the ones this policy wants for as many replicas. Returns the types
+    /// that are missing and the types that are in excess, which have the
+    /// same length.
+    pub fn mismatch(self, existing: &[StorageType]) -> (Vec<StorageType>, Vec<StorageType>) {
+        let mut missing = self.storage_types(existing.len());
+        let mut excess = vec![];
+        for storage_type in existing {
+            match missing.iter().position(|wanted| wanted == storage_type) {
+                Some(index) => {
+                    missing.swap_remove(index);
+                }
+                None => excess.push(*storage_type),
+            }
+        }
+        missing.sort();
+        excess.sort();
+        (missing, excess)
+    }
+}
+
+impl fmt::Display for StoragePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Hot => "HOT",
+            Self::Warm => "WARM",
+            Self::Cold => "COLD",
+            Self::OneSsd => "ONE_SSD",
+            Self::AllSsd => "ALL_SSD",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for StoragePolicy {
+    type Err = CuddlyError;
+
+    fn from_str(name: &str) -> CuddlyResult<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|policy| policy.to_string().eq_ignore_ascii_case(name))
+            .ok_or_else(|| CuddlyError::FSError(format!("'{}': No such storage policy", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_types() {
+        use StorageType::*;
+
+        assert_eq!(StoragePolicy::Hot.storage_types(3), vec![Disk, Disk, Disk]);
+        assert_eq!(
+            StoragePolicy::OneSsd.storage_types(3),
+            vec![Ssd, Disk, Disk]
+        );
+        assert_eq!(StoragePolicy::Warm.storage_types(2), vec![Disk, Archive]);
+        assert!(StoragePolicy::Cold.storage_types(0).is_empty());
+        assert_eq!(StoragePolicy::OneSsd.fallback(Ssd), Some(Disk));
+        assert_eq!(StoragePolicy::Cold.fallback(Archive), None);
+
+        assert_eq!(
+            StoragePolicy::Cold.mismatch(&[Disk, Archive, Disk]),
+            (vec![Archive, Archive], vec![Disk, Disk])
+        );
+        assert_eq!(
+            StoragePolicy::OneSsd.mismatch(&[Disk, Disk, Ssd]),
+            (vec![], vec![])
+        );
+        assert_eq!(
+            StoragePolicy::OneSsd.mismatch(&[Disk, Disk]),
+            (vec![Ssd], vec![Disk])
+        );
+
+        assert_eq!(
+            "one_ssd".parse::<StoragePolicy>().unwrap(),
+            StoragePolicy::OneSsd
+        );
+        assert_eq!(StoragePolicy::AllSsd.to_string(), "ALL_SSD");
+        assert!("LUKEWARM".parse::<StoragePolicy>().is_err());
+    }
+}
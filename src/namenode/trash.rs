@@ -0,0 +1,87 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+/// Directory below a user's home that holds the trash.
+pub(super) const TRASH_DIR: &str = ".Trash";
+/// Trash directory that deleted paths are moved to until the next checkpoint.
+pub(super) const CURRENT: &str = "Current";
+pub(super) const USER_DIR: &str = "/user";
+const CHECKPOINT_FORMAT: &str = "%y%m%d%H%M%S";
+
+/// Root of the trash of `user`.
+pub(super) fn trash_root(user: &str) -> String {
+    format!("{}/{}/{}", USER_DIR, user, TRASH_DIR)
+}
+
+/// Where a deleted path goes in the trash of `user`. The path keeps its
+/// location below the trash, so it can be restored later.
+pub(super) fn current_path(user: &str, path: &str) -> String {
+    format!(
+        "{}/{}{}",
+        trash_root(user),
+        CURRENT,
+        path.trim_end_matches('/')
+    )
+}
+
+/// Whether a path is a trash or lies in one.
+pub(super) fn is_in_trash(path: &str) -> bool {
+    path.split('/').any(|part| part == TRASH_DIR)
+}
+
+/// The path a path in a trash was deleted from, or `None` if it is not
+/// below a trash checkpoint or `Current`.
+pub(super) fn original_path(trash_path: &str) -> Option<String> {
+    let mut parts = trash_path
+        .strip_prefix(USER_DIR)?
+        .strip_prefix('/')?
+        .splitn(4, '/');
+    let (_user, trash, _checkpoint, path) =
+        (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if trash != TRASH_DIR || path.trim_end_matches('/').is_empty() {
+        return None;
+    }
+    Some(format!("/{}", path.trim_end_matches('/')))
+}
+
+/// Name of a checkpoint taken at `time`.
+pub(super) fn checkpoint_name(time: DateTime<Utc>) -> String {
+    time.format(CHECKPOINT_FORMAT).to_string()
+}
+
+/// When the checkpoint `name` was taken, or `None` if `name` is not a
+/// checkpoint.
+pub(super) fn checkpoint_time(name: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(name, CHECKPOINT_FORMAT)
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_trash_paths() {
+        let trash_path = current_path("alice", "/data/logs/");
+        assert_eq!(trash_path, "/user/alice/.Trash/Current/data/logs");
+        assert!(is_in_trash(&trash_path));
+        assert!(!is_in_trash("/data/logs"));
+        assert_eq!(original_path(&trash_path).as_deref(), Some("/data/logs"));
+        assert_eq!(
+            original_path("/user/alice/.Trash/240101120000/data").as_deref(),
+            Some("/data")
+        );
+        assert_eq!(original_path("/user/alice/.Trash/Current"), None);
+        assert_eq!(original_path("/user/alice/data/logs"), None);
+    }
+
+    #[test]
+    fn test_checkpoint_name() {
+        let time = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        assert_eq!(checkpoint_name(time), "240102030405");
+        assert_eq!(checkpoint_time(&checkpoint_name(time)), Some(time));
+        assert_eq!(checkpoint_time(CURRENT), None);
+    }
+}
@@ -0,0 +1,65 @@
+// CRC-32 of IEEE 802.3, with the reversed polynomial of
+// x^32 + x^26 + x^23 + x^22 + x^16 + x^12 + x^11 + x^10 + x^8 + x^7 + x^5 +
+// x^4 + x^2 + x + 1
+const POLYNOMIAL: u32 = 0xedb8_8320;
+
+const fn table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut value = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            value = if value & 1 != 0 {
+                (value >> 1) ^ POLYNOMIAL
+            } else {
+                value >> 1
+            };
+            bit += 1;
+        }
+        table[i] = value;
+        i += 1;
+    }
+    table
+}
+
+static TABLE: [u32; 256] = table();
+
+/// Running CRC-32 of data fed to it in pieces.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub(crate) fn new() -> Self {
+        Self(!0)
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 = TABLE[((self.0 ^ *byte as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub(crate) fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        let checksum = |pieces: &[&[u8]]| {
+            let mut crc = Crc32::new();
+            for piece in pieces {
+                crc.update(piece);
+            }
+            crc.finish()
+        };
+        assert_eq!(checksum(&[]), 0);
+        assert_eq!(checksum(&[b"123456789"]), 0xcbf4_3926);
+        assert_eq!(checksum(&[b"1234", b"", b"56789"]), 0xcbf4_3926);
+    }
+}
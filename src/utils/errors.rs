@@ -12,6 +12,8 @@ pub enum CuddlyError {
     ArgMissingError(String),
     WaitingForReplication(String),
     ProtoError(String),
+    SafeModeError(String),
+    QuotaExceeded(String),
 }
 
 impl Display for CuddlyError {
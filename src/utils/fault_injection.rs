@@ -0,0 +1,274 @@
+use std::sync::Mutex;
+
+use log::warn;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+
+use crate::config::AppConfig;
+
+/// The places in the data path and RPC layer where a fault can be injected.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultPoint {
+    /// Every packet received by `DatanodeDataHandler` while writing a block.
+    DatanodePacket,
+    /// The `WriteBlockResponse` sent back up the pipeline.
+    DatanodeAck,
+    /// `DatanodeDataRegistry::start_block_creation`.
+    DatanodeStartBlock,
+    /// `DatanodeDataRegistry::finish_block_creation`.
+    DatanodeFinishBlock,
+    /// Heartbeats received by the namenode `NodeService`.
+    NamenodeHeartbeat,
+    /// `BlockReceived` reports received by the namenode `NodeService`.
+    NamenodeBlockReceived,
+}
+
+impl FaultPoint {
+    /// The actions that have an effect at this point.
+    pub fn actions(self) -> &'static [FaultAction] {
+        match self {
+            Self::DatanodePacket => &[FaultAction::DropConnection, FaultAction::CorruptBytes],
+            Self::DatanodeAck => &[FaultAction::Delay],
+            Self::DatanodeStartBlock => &[FaultAction::DiskFull],
+            Self::DatanodeFinishBlock => &[FaultAction::Fail],
+            Self::NamenodeHeartbeat | Self::NamenodeBlockReceived => &[FaultAction::Drop],
+        }
+    }
+}
+
+/// What happens when a fault fires.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultAction {
+    DropConnection,
+    CorruptBytes,
+    Delay,
+    Fail,
+    DiskFull,
+    Drop,
+}
+
+/// A single entry of a fault schedule.
+///
+/// The rule is hit every time its `point` is reached. The first `after` hits
+/// are ignored, then the rule fires with the given `probability`, at most
+/// `times` times (unbounded if not set). Rules whose action has no effect at
+/// their point are rejected when the config is parsed.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "UncheckedFaultRule")]
+pub struct FaultRule {
+    pub point: FaultPoint,
+    pub action: FaultAction,
+    pub after: u64,
+    pub probability: f64,
+    pub times: Option<u64>,
+    pub delay_ms: u64,
+}
+
+#[derive(Deserialize)]
+struct UncheckedFaultRule {
+    point: FaultPoint,
+    action: FaultAction,
+    #[serde(default)]
+    after: u64,
+    #[serde(default = "default_probability")]
+    probability: f64,
+    #[serde(default)]
+    times: Option<u64>,
+    #[serde(default)]
+    delay_ms: u64,
+}
+
+fn default_probability() -> f64 {
+    1.0
+}
+
+impl TryFrom<UncheckedFaultRule> for FaultRule {
+    type Error = String;
+
+    fn try_from(rule: UncheckedFaultRule) -> Result<Self, Self::Error> {
+        if !rule.point.actions().contains(&rule.action) {
+            return Err(format!(
+                "Fault action {:?} has no effect at {:?}, expected one of {:?}",
+                rule.action,
+                rule.point,
+                rule.point.actions()
+            ));
+        }
+        Ok(Self {
+            point: rule.point,
+            action: rule.action,
+            after: rule.after,
+            probability: rule.probability,
+            times: rule.times,
+            delay_ms: rule.delay_ms,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct ScheduledRule {
+    rule: FaultRule,
+    hits: u64,
+    fired: u64,
+}
+
+#[derive(Debug)]
+struct FaultSchedule {
+    rng: StdRng,
+    rules: Vec<ScheduledRule>,
+}
+
+/// Decides, from a seeded schedule, whether a fault should be injected at a
+/// given point. A disabled injector never fires and never takes its lock.
+#[derive(Debug)]
+pub(crate) struct FaultInjector {
+    enabled: bool,
+    schedule: Mutex<FaultSchedule>,
+}
+
+impl FaultInjector {
+    pub(crate) fn new(seed: u64, rules: Vec<FaultRule>) -> Self {
+        Self {
+            enabled: !rules.is_empty(),
+            schedule: Mutex::new(FaultSchedule {
+                rng: StdRng::seed_from_u64(seed),
+                rules: rules
+                    .into_iter()
+                    .map(|rule| ScheduledRule {
+                        rule,
+                        hits: 0,
+                        fired: 0,
+                    })
+                    .collect(),
+            }),
+        }
+    }
+
+    pub(crate) fn disabled() -> Self {
+        Self::new(0, vec![])
+    }
+
+    pub(crate) fn from_config(config: &AppConfig) -> Self {
+        let fault_config = &config.fault_injection;
+        if !fault_config.enabled {
+            return Self::disabled();
+        }
+        warn!(
+            "Fault injection enabled with seed {} and {} rules",
+            fault_config.seed,
+            fault_config.rules.len()
+        );
+        Self::new(fault_config.seed, fault_config.rules.clone())
+    }
+
+    /// Returns the rule that fires at `point`, if any. Only the first
+    /// matching rule that fires is returned, but every matching rule is hit.
+    pub(crate) fn check(&self, point: FaultPoint) -> Option<FaultRule> {
+        if !self.enabled {
+            return None;
+        }
+
+        let mut schedule = self.schedule.lock().unwrap();
+        let FaultSchedule { rng, rules } = &mut *schedule;
+        let mut result = None;
+        for scheduled in rules.iter_mut().filter(|r| r.rule.point == point) {
+            scheduled.hits += 1;
+            if result.is_some() || scheduled.hits <= scheduled.rule.after {
+                continue;
+            }
+            if let Some(times) = scheduled.rule.times {
+                if scheduled.fired >= times {
+                    continue;
+                }
+            }
+            if rng.gen_bool(scheduled.rule.probability.clamp(0.0, 1.0)) {
+                scheduled.fired += 1;
+                warn!("Injecting fault {:?} at {:?}", scheduled.rule.action, point);
+                result = Some(scheduled.rule.clone());
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(point: FaultPoint, action: FaultAction) -> FaultRule {
+        FaultRule {
+            point,
+            action,
+            after: 0,
+            probability: 1.0,
+            times: None,
+            delay_ms: 0,
+        }
+    }
+
+    #[test]
+    fn test_disabled_injector_never_fires() {
+        let injector = FaultInjector::disabled();
+        for _ in 0..100 {
+            assert_eq!(injector.check(FaultPoint::DatanodePacket), None);
+        }
+    }
+
+    #[test]
+    fn test_after_and_times() {
+        let injector = FaultInjector::new(
+            7,
+            vec![FaultRule {
+                after: 3,
+                times: Some(2),
+                ..rule(FaultPoint::DatanodePacket, FaultAction::DropConnection)
+            }],
+        );
+        let fired = (0..10)
+            .map(|_| injector.check(FaultPoint::DatanodePacket).is_some())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fired,
+            vec![false, false, false, true, true, false, false, false, false, false]
+        );
+        assert_eq!(injector.check(FaultPoint::NamenodeHeartbeat), None);
+    }
+
+    #[test]
+    fn test_rules_are_validated() {
+        let rule = serde_json::from_str::<FaultRule>(
+            r#"{"point": "namenode_heartbeat", "action": "drop", "times": 2}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            rule,
+            FaultRule {
+                times: Some(2),
+                ..self::rule(FaultPoint::NamenodeHeartbeat, FaultAction::Drop)
+            }
+        );
+        assert!(serde_json::from_str::<FaultRule>(
+            r#"{"point": "datanode_ack", "action": "corrupt_bytes"}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_same_seed_same_schedule() {
+        let rules = vec![FaultRule {
+            probability: 0.3,
+            ..rule(FaultPoint::NamenodeHeartbeat, FaultAction::Drop)
+        }];
+        let first = FaultInjector::new(42, rules.clone());
+        let second = FaultInjector::new(42, rules);
+        for _ in 0..200 {
+            assert_eq!(
+                first.check(FaultPoint::NamenodeHeartbeat),
+                second.check(FaultPoint::NamenodeHeartbeat)
+            );
+        }
+    }
+}
@@ -86,17 +86,47 @@ where
         self.inner_map.get(key).map(|info| &info.ids)
     }
 
-    // pub(crate) fn remove_key(&mut self, key: &K) -> Option<D> {
-    //     self.inner_map.remove(key).map(|info| info.data)
-    // }
+    pub(crate) fn get_data_mut(&mut self, key: &K) -> Option<&mut D> {
+        self.inner_map.get_mut(key).map(|info| &mut info.data)
+    }
 
-    // pub(crate) fn remove_id_for_key(&mut self, key: &K, id: &I) -> bool {
-    //     if let Some(info) = self.inner_map.get_mut(key) {
-    //         info.ids.remove(id)
-    //     } else {
-    //         false
-    //     }
-    // }
+    pub(crate) fn len(&self) -> usize {
+        self.inner_map.len()
+    }
+
+    /// Number of keys that have at least one id.
+    pub(crate) fn count_keys_with_ids(&self) -> usize {
+        self.inner_map
+            .values()
+            .filter(|info| !info.ids.is_empty())
+            .count()
+    }
+
+    /// Iterates over all keys and their data.
+    pub(crate) fn iter_data(&self) -> impl Iterator<Item = (&K, &D)> {
+        self.inner_map.iter().map(|(key, info)| (key, &info.data))
+    }
+
+    /// Removes all ids associated with the key and returns them.
+    /// The key and its data stay in the map.
+    pub(crate) fn take_ids_for_key(&mut self, key: &K) -> HashSet<I> {
+        self.inner_map
+            .get_mut(key)
+            .map(|info| std::mem::take(&mut info.ids))
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn remove_key(&mut self, key: &K) -> Option<D> {
+        self.inner_map.remove(key).map(|info| info.data)
+    }
+
+    pub(crate) fn remove_id_for_key(&mut self, key: &K, id: &I) -> bool {
+        if let Some(info) = self.inner_map.get_mut(key) {
+            info.ids.remove(id)
+        } else {
+            false
+        }
+    }
 
     // pub(crate) fn contains_id_for_key(&self, key: &K, id: &I) -> bool {
     //     self.inner_map
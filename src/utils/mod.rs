@@ -2,8 +2,11 @@ use errors::{CuddlyError, CuddlyResult};
 use prost::Message;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
+pub(crate) mod crc32;
 pub mod errors;
+pub(crate) mod fault_injection;
 pub(crate) mod key_to_data_and_id_map;
+pub(crate) mod reed_solomon;
 
 // pub(crate) fn calculate_md5_checksum<T: AsRef<[u8]>>(data: &T) -> String {
 //     let digest = md5::compute(data.as_ref());
@@ -0,0 +1,257 @@
+use crate::errors::{CuddlyError, CuddlyResult};
+
+// Arithmetic in GF(2^8) with the primitive polynomial x^8 + x^4 + x^3 + x^2 + 1
+const POLYNOMIAL: u16 = 0x11d;
+
+struct Tables {
+    // Doubled so that the sum of two logarithms needs no reduction
+    exp: [u8; 510],
+    log: [u8; 256],
+}
+
+const fn tables() -> Tables {
+    let mut exp = [0u8; 510];
+    let mut log = [0u8; 256];
+    let mut value: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = value as u8;
+        exp[i + 255] = value as u8;
+        log[value as usize] = i as u8;
+        value <<= 1;
+        if value & 0x100 != 0 {
+            value ^= POLYNOMIAL;
+        }
+        i += 1;
+    }
+    Tables { exp, log }
+}
+
+static TABLES: Tables = tables();
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    TABLES.exp[TABLES.log[a as usize] as usize + TABLES.log[b as usize] as usize]
+}
+
+fn inverse(a: u8) -> u8 {
+    TABLES.exp[255 - TABLES.log[a as usize] as usize]
+}
+
+fn pow(a: u8, n: usize) -> u8 {
+    (0..n).fold(1, |acc, _| mul(acc, a))
+}
+
+type Matrix = Vec<Vec<u8>>;
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    a.iter()
+        .map(|row| {
+            (0..b[0].len())
+                .map(|column| {
+                    row.iter()
+                        .zip(b)
+                        .fold(0, |acc, (&x, b_row)| acc ^ mul(x, b_row[column]))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Inverts a square matrix by Gauss-Jordan elimination, or returns `None`
+/// if it is singular.
+fn invert(matrix: &Matrix) -> Option<Matrix> {
+    let size = matrix.len();
+    let mut work: Matrix = matrix
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let mut row = row.clone();
+            row.extend((0..size).map(|j| u8::from(i == j)));
+            row
+        })
+        .collect();
+    for column in 0..size {
+        let pivot = (column..size).find(|&row| work[row][column] != 0)?;
+        work.swap(column, pivot);
+        let scale = inverse(work[column][column]);
+        for value in work[column].iter_mut() {
+            *value = mul(*value, scale);
+        }
+        let pivot_row = work[column].clone();
+        for (row, values) in work.iter_mut().enumerate() {
+            let factor = values[column];
+            if row != column && factor != 0 {
+                for (value, &pivot) in values.iter_mut().zip(&pivot_row) {
+                    *value ^= mul(factor, pivot);
+                }
+            }
+        }
+    }
+    Some(work.into_iter().map(|row| row[size..].to_vec()).collect())
+}
+
+/// Systematic Reed-Solomon code over GF(2^8): `data` shards are stored as
+/// they are and `parity` shards are computed from them, so that any `data`
+/// of the shards are enough to rebuild all the others.
+#[derive(Clone, Debug)]
+pub struct ReedSolomon {
+    data: usize,
+    parity: usize,
+    // One row per shard, the first `data` rows being the identity
+    matrix: Matrix,
+}
+
+impl ReedSolomon {
+    pub fn new(data: usize, parity: usize) -> CuddlyResult<Self> {
+        if data == 0 || data + parity > 255 {
+            return Err(CuddlyError::FSError(format!(
+                "Invalid Reed-Solomon code with {} data and {} parity shards",
+                data, parity
+            )));
+        }
+        // Any `data` rows of a Vandermonde matrix are independent, which
+        // stays true once its top is turned into the identity.
+        let vandermonde: Matrix = (0..data + parity)
+            .map(|row| (0..data).map(|column| pow(row as u8, column)).collect())
+            .collect();
+        let top = invert(&vandermonde[..data].to_vec()).expect("Vandermonde rows are independent");
+        Ok(Self {
+            data,
+            parity,
+            matrix: multiply(&vandermonde, &top),
+        })
+    }
+
+    /// Computes the parity shards of `data` shards, which all have the same
+    /// length.
+    pub fn encode<T: AsRef<[u8]>>(&self, data: &[T]) -> CuddlyResult<Vec<Vec<u8>>> {
+        if data.len() != self.data {
+            return Err(CuddlyError::FSError(format!(
+                "Expected {} data shards, got {}",
+                self.data,
+                data.len()
+            )));
+        }
+        let len = data[0].as_ref().len();
+        if data.iter().any(|shard| shard.as_ref().len() != len) {
+            return Err(CuddlyError::FSError(
+                "Data shards have different lengths".to_owned(),
+            ));
+        }
+        Ok(self.matrix[self.data..]
+            .iter()
+            .map(|row| Self::combine(row, data, len))
+            .collect())
+    }
+
+    /// Fills in the missing shards, data then parity, from the ones present.
+    /// Fails if fewer than `data` shards are present.
+    pub fn reconstruct(&self, shards: &mut [Option<Vec<u8>>]) -> CuddlyResult<()> {
+        if shards.len() != self.data + self.parity {
+            return Err(CuddlyError::FSError(format!(
+                "Expected {} shards, got {}",
+                self.data + self.parity,
+                shards.len()
+            )));
+        }
+        let present = shards
+            .iter()
+            .enumerate()
+            .filter_map(|(index, shard)| shard.as_ref().map(|shard| (index, shard)))
+            .take(self.data)
+            .collect::<Vec<_>>();
+        if present.len() < self.data {
+            return Err(CuddlyError::FSError(format!(
+                "Only {} of the {} shards needed are available",
+                present.len(),
+                self.data
+            )));
+        }
+        let len = present[0].1.len();
+        if present.iter().any(|(_, shard)| shard.len() != len) {
+            return Err(CuddlyError::FSError(
+                "Shards have different lengths".to_owned(),
+            ));
+        }
+
+        if shards[..self.data].iter().any(Option::is_none) {
+            let rows = present
+                .iter()
+                .map(|(index, _)| self.matrix[*index].clone())
+                .collect();
+            let decode = invert(&rows).expect("any data rows are independent");
+            let inputs = present
+                .iter()
+                .map(|(_, shard)| shard.as_slice())
+                .collect::<Vec<_>>();
+            let rebuilt = (0..self.data)
+                .filter(|index| shards[*index].is_none())
+                .map(|index| (index, Self::combine(&decode[index], &inputs, len)))
+                .collect::<Vec<_>>();
+            for (index, shard) in rebuilt {
+                shards[index] = Some(shard);
+            }
+        }
+
+        if shards[self.data..].iter().any(Option::is_none) {
+            let data = shards[..self.data]
+                .iter()
+                .map(|shard| shard.as_deref().unwrap_or_default())
+                .collect::<Vec<_>>();
+            let parity = self.encode(&data)?;
+            for (shard, computed) in shards[self.data..].iter_mut().zip(parity) {
+                shard.get_or_insert(computed);
+            }
+        }
+        Ok(())
+    }
+
+    fn combine<T: AsRef<[u8]>>(coefficients: &[u8], inputs: &[T], len: usize) -> Vec<u8> {
+        let mut output = vec![0u8; len];
+        for (&coefficient, input) in coefficients.iter().zip(inputs) {
+            if coefficient == 0 {
+                continue;
+            }
+            for (out, &byte) in output.iter_mut().zip(input.as_ref()) {
+                *out ^= mul(coefficient, byte);
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconstruct() {
+        let codec = ReedSolomon::new(6, 3).unwrap();
+        let data = (0..6)
+            .map(|shard| (0..100).map(|i| (shard * 37 + i * 11) as u8).collect())
+            .collect::<Vec<Vec<u8>>>();
+        let parity = codec.encode(&data).unwrap();
+        assert_eq!(parity.len(), 3);
+        let shards = data.iter().chain(&parity).cloned().collect::<Vec<_>>();
+
+        for missing in [vec![0], vec![1, 4, 5], vec![2, 6, 8], vec![6, 7, 8]] {
+            let mut damaged = shards.iter().cloned().map(Some).collect::<Vec<_>>();
+            for index in &missing {
+                damaged[*index] = None;
+            }
+            codec.reconstruct(&mut damaged).unwrap();
+            let rebuilt = damaged.into_iter().map(Option::unwrap).collect::<Vec<_>>();
+            assert_eq!(rebuilt, shards, "missing {:?}", missing);
+        }
+
+        let mut damaged = shards.iter().cloned().map(Some).collect::<Vec<_>>();
+        for index in [0, 3, 7, 8] {
+            damaged[index] = None;
+        }
+        assert!(codec.reconstruct(&mut damaged).is_err());
+        assert!(ReedSolomon::new(0, 2).is_err());
+    }
+}